@@ -11,6 +11,7 @@
 //!
 //! See also: [crate::mem::SafeRead] and [crate::mem::SafeWrite].
 
+use crate::call_trace::PendingCall;
 use crate::cpu::Cpu;
 use crate::mem::{ConstPtr, ConstVoidPtr, GuestUSize, Mem, MutPtr, MutVoidPtr, Ptr, SafeRead};
 use crate::Environment;
@@ -169,6 +170,28 @@ pub trait CallFromGuest {
     fn call_from_guest(&self, env: &mut Environment);
 }
 
+/// Part of `--trace-calls=` support (see [crate::call_trace]). `format_args`
+/// is only called if this call is being traced. Every [CallFromGuest]
+/// implementation must call this before doing anything else.
+pub fn trace_call(
+    env: &mut Environment,
+    format_args: impl FnOnce() -> Vec<String>,
+) -> Option<PendingCall> {
+    let tracer = env.call_tracer.as_mut()?;
+    let call = tracer.take_pending()?;
+    tracer.trace_call(&call, &format_args().join(", "));
+    Some(call)
+}
+pub fn trace_return<R: GuestRet>(env: &mut Environment, call: Option<PendingCall>, retval: &R) {
+    if let Some(call) = call {
+        let retval = format!("{:?}", retval);
+        env.call_tracer
+            .as_mut()
+            .unwrap()
+            .trace_return(&call, &retval);
+    }
+}
+
 macro_rules! impl_CallFromGuest {
     ( $($p:tt => $P:ident),* ) => {
         impl<R, $($P),*> CallFromGuest for fn(&mut Environment, $($P),*) -> R
@@ -185,8 +208,10 @@ macro_rules! impl_CallFromGuest {
                     ($(read_next_arg::<$P>(&mut reg_offset, regs, Ptr::from_bits(regs[Cpu::SP]), &env.mem),)*)
                 };
                 log_dbg!("CallFromGuest {:?}", args);
+                let trace = trace_call(env, || vec![$(format!("{:?}", args.$p)),*]);
                 let retval = self(env, $(args.$p),*);
                 log_dbg!("CallFromGuest => {:?}", retval);
+                trace_return(env, trace, &retval);
                 if let Some(retval_ptr) = retval_ptr {
                     retval.to_mem(retval_ptr, &mut env.mem);
                 } else {
@@ -212,8 +237,10 @@ macro_rules! impl_CallFromGuest {
                     stack_pointer: Ptr::from_bits(regs[Cpu::SP])
                 });
                 log_dbg!("CallFromGuest {:?}, ...{:?}", args, va_list);
+                let trace = trace_call(env, || vec![$(format!("{:?}", args.$p),)* "...".to_string()]);
                 let retval = self(env, $(args.$p,)* va_list);
                 log_dbg!("CallFromGuest => {:?}", retval);
+                trace_return(env, trace, &retval);
                 if let Some(retval_ptr) = retval_ptr {
                     retval.to_mem(retval_ptr, &mut env.mem);
                 } else {
@@ -669,6 +696,14 @@ impl<T, const MUT: bool> GuestRet for Ptr<T, MUT> {
         <u32 as GuestRet>::to_regs(self.to_bits(), regs)
     }
 }
+impl GuestRet for GuestFunction {
+    fn from_regs(regs: &[u32]) -> Self {
+        GuestFunction(<ConstVoidPtr as GuestRet>::from_regs(regs))
+    }
+    fn to_regs(self, regs: &mut [u32]) {
+        <ConstVoidPtr as GuestRet>::to_regs(self.0, regs)
+    }
+}
 
 // GuestRet implementations for u64-like types
 
@@ -0,0 +1,196 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Tracing of calls from guest code into host functions (see
+//! `--trace-calls=`), a bit like `strace`, and of Objective-C messages (see
+//! `--trace-messages=`).
+//!
+//! The environment decides whether a call should be traced when it dispatches
+//! an SVC to a host function (see [crate::dyld::Dyld::get_svc_handler]), but
+//! the arguments and return value can only be decoded once their types are
+//! known, which is in the [crate::abi::CallFromGuest] implementations. The
+//! [PendingCall] is how the former hands over to the latter.
+//!
+//! Messages are traced by `objc_msgSend` (see [crate::objc]) itself.
+
+use crate::options::Options;
+use crate::ThreadId;
+use std::fs::File;
+use std::io::Write;
+
+pub struct CallTracer {
+    patterns: Vec<String>,
+    message_patterns: Vec<String>,
+    /// How many message sends are currently being handled on each thread,
+    /// indexed by thread ID.
+    message_depths: Vec<usize>,
+    /// If [None], output goes to the log.
+    file: Option<File>,
+    pending: Option<PendingCall>,
+}
+
+/// Information about a call that is about to be made, which is only known to
+/// the caller of [crate::abi::CallFromGuest::call_from_guest].
+pub struct PendingCall {
+    pub symbol: &'static str,
+    pub thread: usize,
+    /// Guest address the host function will return to.
+    pub return_addr: u32,
+}
+
+impl CallTracer {
+    /// Set up tracing, if the options ask for it.
+    pub fn from_options(options: &Options) -> Result<Option<CallTracer>, String> {
+        if options.trace_calls.is_none() && options.trace_messages.is_none() {
+            return Ok(None);
+        }
+        let file = match options.trace_calls_file {
+            Some(ref path) => Some(
+                File::create(path)
+                    .map_err(|e| format!("Could not create {}: {}", path.display(), e))?,
+            ),
+            None => None,
+        };
+        Ok(Some(CallTracer {
+            patterns: options.trace_calls.clone().unwrap_or_default(),
+            message_patterns: options.trace_messages.clone().unwrap_or_default(),
+            message_depths: Vec::new(),
+            file,
+            pending: None,
+        }))
+    }
+
+    /// Check whether calls to the function with the (mangled) name `symbol`
+    /// should be traced.
+    pub fn should_trace(&self, symbol: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), symbol.as_bytes()))
+    }
+
+    /// Check whether `--trace-messages=` is in use. If it is, every message
+    /// send must be reported with [Self::enter_message] and
+    /// [Self::exit_message], so that nesting is tracked correctly.
+    pub fn traces_messages(&self) -> bool {
+        !self.message_patterns.is_empty()
+    }
+
+    /// Check whether a message should be traced. The receiver's class name and
+    /// the selector are both checked against the patterns.
+    pub fn should_trace_message(&self, class_name: &str, selector: &str) -> bool {
+        self.message_patterns.iter().any(|pattern| {
+            glob_match(pattern.as_bytes(), class_name.as_bytes())
+                || glob_match(pattern.as_bytes(), selector.as_bytes())
+        })
+    }
+
+    /// Record that a message send is being dispatched on `thread`.
+    pub fn enter_message(&mut self, thread: ThreadId) {
+        if self.message_depths.len() <= thread {
+            self.message_depths.resize(thread + 1, 0);
+        }
+        self.message_depths[thread] += 1;
+    }
+
+    /// Record that the method called by a message send has returned.
+    pub fn exit_message(&mut self, thread: ThreadId) {
+        self.message_depths[thread] -= 1;
+    }
+
+    /// How many message sends are being dispatched on `thread`, i.e. the
+    /// nesting depth of the next one.
+    pub fn message_depth(&self, thread: ThreadId) -> usize {
+        self.message_depths.get(thread).copied().unwrap_or(0)
+    }
+
+    pub fn trace_message(&mut self, thread: ThreadId, depth: usize, message: &str) {
+        self.write(format_args!(
+            "[thread {}] {:>3} {:indent$}{}",
+            thread,
+            depth,
+            "",
+            message,
+            indent = depth * 2
+        ));
+    }
+
+    /// Set the call that the next [Self::take_pending] will return. This
+    /// replaces any call that was never taken, e.g. because the function
+    /// didn't get as far as decoding its arguments.
+    pub fn set_pending(&mut self, call: PendingCall) {
+        if let Some(stale) = self.pending.replace(call) {
+            log_dbg!("Discarding untaken pending call to {}", stale.symbol);
+        }
+    }
+
+    /// Take the call set by [Self::set_pending], if any. This must be done
+    /// before the host function runs, since it may make further calls.
+    pub fn take_pending(&mut self) -> Option<PendingCall> {
+        self.pending.take()
+    }
+
+    pub fn trace_call(&mut self, call: &PendingCall, args: &str) {
+        self.write(format_args!(
+            "[thread {}] {}({}) from {:#x}",
+            call.thread, call.symbol, args, call.return_addr
+        ));
+    }
+
+    pub fn trace_return(&mut self, call: &PendingCall, retval: &str) {
+        self.write(format_args!(
+            "[thread {}] {} => {}",
+            call.thread, call.symbol, retval
+        ));
+    }
+
+    fn write(&mut self, line: std::fmt::Arguments) {
+        let Some(ref mut file) = self.file else {
+            log!("{}", line);
+            return;
+        };
+        if let Err(e) = writeln!(file, "{}", line) {
+            log!("Warning: Could not write call trace: {}", e);
+            self.file = None;
+        }
+    }
+}
+
+/// Match a string against a glob pattern, where `*` matches any sequence of
+/// characters and `?` matches any single character.
+fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match (pattern.first(), string.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], string)
+                || (!string.is_empty() && glob_match(pattern, &string[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &string[1..]),
+        (Some(p), Some(s)) if p == s => glob_match(&pattern[1..], &string[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_glob_match() {
+        fn glob_match(pattern: &str, string: &str) -> bool {
+            super::glob_match(pattern.as_bytes(), string.as_bytes())
+        }
+
+        assert!(glob_match("_fopen", "_fopen"));
+        assert!(!glob_match("_fopen", "_fopen2"));
+        assert!(!glob_match("_fopen", "fopen"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "_glClear"));
+        assert!(glob_match("_gl*", "_glClear"));
+        assert!(glob_match("_gl*", "_gl"));
+        assert!(!glob_match("_gl*", "_alGetError"));
+        assert!(!glob_match("*Mutex*", "_pthread_mutex_lock"));
+        assert!(glob_match("*mutex*", "_pthread_mutex_lock"));
+        assert!(glob_match("_f?pen", "_fopen"));
+        assert!(!glob_match("_f?pen", "_fpen"));
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! The source of time as seen by the app.
+//!
+//! Normally this just follows the host's clocks. When input is being recorded
+//! or replayed (see [crate::input_recording]), a virtual clock is used instead,
+//! so that the app sees exactly the same times on every run: virtual time
+//! advances by a fixed amount for each CPU tick executed, and jumps forward
+//! when every thread is asleep.
+
+use std::time::{Duration, Instant, SystemTime};
+
+/// Roughly the clock speed of the original iPhone's CPU. dynarmic counts one
+/// tick per instruction, so this is more of a guess than a simulation.
+const VIRTUAL_TICKS_PER_SECOND: u64 = 412_000_000;
+
+pub struct Clock {
+    /// Reference point for [Clock::elapsed], i.e. when the emulator started.
+    startup_instant: Instant,
+    /// Wall-clock time corresponding to `startup_instant`.
+    startup_system_time: SystemTime,
+    /// [None] if this clock follows the host's clocks.
+    virtual_clock: Option<VirtualClock>,
+}
+
+struct VirtualClock {
+    /// CPU ticks executed so far.
+    ticks: u64,
+    /// Time skipped over while all threads were asleep.
+    slept: Duration,
+    /// If [true], don't let virtual time run ahead of real time, so the app
+    /// runs at its normal speed while a human is interacting with it.
+    paced: bool,
+}
+
+impl Clock {
+    pub fn new_real() -> Clock {
+        Clock {
+            startup_instant: Instant::now(),
+            startup_system_time: SystemTime::now(),
+            virtual_clock: None,
+        }
+    }
+
+    /// Create a virtual clock. `startup_system_time` is the wall-clock time the
+    /// app will see at startup.
+    pub fn new_virtual(startup_system_time: SystemTime, paced: bool) -> Clock {
+        Clock {
+            startup_instant: Instant::now(),
+            startup_system_time,
+            virtual_clock: Some(VirtualClock {
+                ticks: 0,
+                slept: Duration::ZERO,
+                paced,
+            }),
+        }
+    }
+
+    /// Time since the emulator started.
+    pub fn elapsed(&self) -> Duration {
+        match self.virtual_clock {
+            Some(VirtualClock { ticks, slept, .. }) => {
+                let nanos =
+                    u128::from(ticks) * 1_000_000_000 / u128::from(VIRTUAL_TICKS_PER_SECOND);
+                Duration::from_nanos(nanos.try_into().unwrap()) + slept
+            }
+            None => self.startup_instant.elapsed(),
+        }
+    }
+
+    /// Monotonic time. Use this rather than [Instant::now] for anything the
+    /// app can observe.
+    pub fn now(&self) -> Instant {
+        self.startup_instant + self.elapsed()
+    }
+
+    /// Wall-clock time. Use this rather than [SystemTime::now] for anything
+    /// the app can observe.
+    pub fn system_time(&self) -> SystemTime {
+        self.startup_system_time + self.elapsed()
+    }
+
+    /// Account for CPU execution. This only affects a virtual clock.
+    pub fn add_ticks(&mut self, ticks: u64) {
+        if let Some(ref mut virtual_clock) = self.virtual_clock {
+            virtual_clock.ticks += ticks;
+        }
+    }
+
+    /// Block until the time `until`. A virtual clock will simply skip ahead,
+    /// unless it is paced.
+    pub fn sleep_until(&mut self, until: Instant) {
+        let now = self.now();
+        let duration = until.saturating_duration_since(now);
+        let Some(ref mut virtual_clock) = self.virtual_clock else {
+            std::thread::sleep(duration);
+            return;
+        };
+        virtual_clock.slept += duration;
+        if virtual_clock.paced {
+            // Both virtual and real time are measured from startup, so this is
+            // how far virtual time would be ahead of real time.
+            let virtual_elapsed = until.saturating_duration_since(self.startup_instant);
+            let real_elapsed = self.startup_instant.elapsed();
+            std::thread::sleep(virtual_elapsed.saturating_sub(real_elapsed));
+        }
+    }
+}
@@ -0,0 +1,242 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Static compatibility report for an app (`--compat-report`).
+//!
+//! This loads the app's binaries without running anything, and checks which of
+//! the functions, constants, classes and selectors it imports touchHLE has
+//! implementations of. It's only a rough guide: an app can import plenty of
+//! things it never uses, and having an implementation of something doesn't
+//! mean that implementation is complete.
+
+use crate::bundle::Bundle;
+use crate::cpu::Arch;
+use crate::dyld;
+use crate::fs::Fs;
+use crate::mach_o::{MachO, SectionType};
+use crate::mem::Mem;
+use crate::objc::ObjC;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Write;
+use std::path::Path;
+
+/// Things of one kind that an app imports, and which of them are missing.
+#[derive(Default)]
+struct Imports {
+    imported: BTreeSet<String>,
+    missing: BTreeSet<String>,
+}
+
+impl Imports {
+    fn add(&mut self, name: &str, implemented: bool) {
+        self.imported.insert(name.to_string());
+        if !implemented {
+            self.missing.insert(name.to_string());
+        }
+    }
+}
+
+struct CompatReport {
+    app_name: String,
+    app_id: String,
+    /// Functions called via lazy-linking stubs.
+    functions: Imports,
+    /// Constants and anything else linked non-lazily (excluding classes).
+    constants: Imports,
+    classes: Imports,
+    /// Selectors count as missing if neither touchHLE nor the app itself has a
+    /// method for them. Some of these will be fine, e.g. ones only used with
+    /// `respondsToSelector:`.
+    selectors: Imports,
+}
+
+impl CompatReport {
+    fn new(bundle: &Bundle, bins: &[MachO], mem: &Mem) -> CompatReport {
+        let mut report = CompatReport {
+            app_name: bundle.display_name().to_string(),
+            app_id: bundle.bundle_identifier().to_string(),
+            functions: Default::default(),
+            constants: Default::default(),
+            classes: Default::default(),
+            selectors: Default::default(),
+        };
+
+        let is_exported = |symbol: &str| {
+            bins.iter()
+                .any(|bin| bin.exported_symbols.contains_key(symbol))
+        };
+
+        for bin in bins {
+            if let Some(stubs) = bin.get_section(SectionType::SymbolStubs) {
+                let info = stubs.dyld_indirect_symbol_info.as_ref().unwrap();
+                for symbol in info.indirect_undef_symbols.iter().flatten() {
+                    let implemented = is_exported(symbol) || dyld::has_host_function(symbol);
+                    report.functions.add(symbol, implemented);
+                }
+            }
+            if let Some(ptrs) = bin.get_section(SectionType::NonLazySymbolPointers) {
+                let info = ptrs.dyld_indirect_symbol_info.as_ref().unwrap();
+                for symbol in info.indirect_undef_symbols.iter().flatten() {
+                    let implemented = is_exported(symbol) || dyld::has_host_constant(symbol);
+                    report.constants.add(symbol, implemented);
+                }
+            }
+
+            for (_addr, symbol) in &bin.external_relocations {
+                if let Some(name) = symbol
+                    .strip_prefix("_OBJC_CLASS_$_")
+                    .or_else(|| symbol.strip_prefix("_OBJC_METACLASS_$_"))
+                {
+                    report.classes.add(name, ObjC::has_host_class(name));
+                } else if !dyld::is_special_relocation(symbol) {
+                    report.constants.add(symbol, is_exported(symbol));
+                }
+            }
+
+            if let Some(ref dyld_info) = bin.dyld_info {
+                for binding in &dyld_info.lazy_bindings {
+                    let symbol = &binding.symbol;
+                    let implemented = is_exported(symbol) || dyld::has_host_function(symbol);
+                    report.functions.add(symbol, implemented);
+                }
+                for binding in &dyld_info.bindings {
+                    let symbol = &binding.symbol;
+                    if let Some(name) = symbol
+                        .strip_prefix("_OBJC_CLASS_$_")
+                        .or_else(|| symbol.strip_prefix("_OBJC_METACLASS_$_"))
+                    {
+                        report.classes.add(name, ObjC::has_host_class(name));
+                    } else if !dyld::is_special_relocation(symbol) {
+                        let implemented = is_exported(symbol) || dyld::has_host_constant(symbol);
+                        report.constants.add(symbol, implemented);
+                    }
+                }
+            }
+        }
+
+        let host_selectors = ObjC::host_selectors();
+        let mut bin_selectors = HashSet::new();
+        for bin in bins {
+            bin_selectors.extend(ObjC::bin_method_names(bin, mem));
+        }
+        for bin in bins {
+            for selector in ObjC::bin_selector_refs(bin, mem) {
+                let implemented =
+                    host_selectors.contains(selector) || bin_selectors.contains(selector);
+                report.selectors.add(selector, implemented);
+            }
+        }
+
+        report
+    }
+
+    fn print(&self) {
+        echo!(
+            "Compatibility report for {} ({}):",
+            self.app_name,
+            self.app_id
+        );
+        for (heading, imports) in self.categories() {
+            echo!(
+                "- {}: {} imported, {} missing",
+                heading,
+                imports.imported.len(),
+                imports.missing.len()
+            );
+            for name in &imports.missing {
+                echo!("    {}", name);
+            }
+        }
+        echo!(
+            "Note: this only covers what the app references, not what it uses. Some missing things may never be needed, and some implementations may be incomplete."
+        );
+    }
+
+    fn to_json(&self) -> String {
+        let mut json = String::new();
+        write!(
+            json,
+            "{{\"name\":{},\"identifier\":{}",
+            json_string(&self.app_name),
+            json_string(&self.app_id)
+        )
+        .unwrap();
+        for (heading, imports) in self.categories() {
+            write!(
+                json,
+                ",{}:{{\"imported\":{},\"missing\":[",
+                json_string(&heading.to_ascii_lowercase()),
+                imports.imported.len()
+            )
+            .unwrap();
+            for (i, name) in imports.missing.iter().enumerate() {
+                if i != 0 {
+                    json.push(',');
+                }
+                json.push_str(&json_string(name));
+            }
+            json.push_str("]}");
+        }
+        json.push_str("}\n");
+        json
+    }
+
+    fn categories(&self) -> [(&'static str, &Imports); 4] {
+        [
+            ("Functions", &self.functions),
+            ("Constants", &self.constants),
+            ("Classes", &self.classes),
+            ("Selectors", &self.selectors),
+        ]
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c < ' ' => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Print a compatibility report for the app, and if `json_path` is given,
+/// also write it there as JSON.
+pub fn compat_report(
+    bundle: &Bundle,
+    fs: &Fs,
+    preferred_arch: Option<Arch>,
+    json_path: Option<&Path>,
+) -> Result<(), String> {
+    // The binaries are loaded into a throwaway memory space so that their
+    // contents can be inspected, but no linking is done.
+    let mut mem = Mem::new();
+    let bins = dyld::load_bins(&bundle.executable_path(), fs, &mut mem, preferred_arch)?;
+
+    let report = CompatReport::new(bundle, &bins, &mem);
+    report.print();
+
+    if let Some(json_path) = json_path {
+        std::fs::write(json_path, report.to_json()).map_err(|e| {
+            format!(
+                "Could not write compatibility report to {}: {}",
+                json_path.display(),
+                e
+            )
+        })?;
+        echo!(
+            "Wrote JSON compatibility report to {}.",
+            json_path.display()
+        );
+    }
+
+    Ok(())
+}
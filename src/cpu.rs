@@ -8,19 +8,54 @@
 //! Implemented using the C++ library dynarmic, which is a dynamic recompiler.
 //!
 //! iPhone OS apps used either ARMv6 or ARMv7-A, which are both 32-bit ISAs.
-//! For the moment, only ARMv6 has been tested.
+//! dynarmic is configured for whichever of these the app's binary targets (see
+//! [Arch]), so ARMv7-only features like Thumb-2 and NEON are only available to
+//! ARMv7 code. ARMv6 has been much more widely tested.
 
 use crate::abi::GuestFunction;
-use crate::mem::{ConstPtr, GuestUSize, Mem, MutPtr, Ptr, SafeRead, SafeWrite};
+use crate::mem::{
+    guest_size_of, ConstPtr, GuestUSize, Mem, MemoryFault, MutPtr, Protection, Ptr, SafeRead,
+    SafeWrite, WatchpointHit,
+};
 
 // Import functions from C++
 use touchHLE_dynarmic_wrapper::*;
 
 type VAddr = u32;
 
+/// 32-bit ARM architecture version that guest code may target. These are
+/// ordered so that a later version can run code for an earlier one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Arch {
+    ARMv6,
+    ARMv7,
+}
+impl Arch {
+    /// Parse the name used in the `--preferred-arch=` option.
+    pub fn from_short_name(name: &str) -> Result<Self, ()> {
+        match name {
+            "armv6" => Ok(Self::ARMv6),
+            "armv7" => Ok(Self::ARMv7),
+            _ => Err(()),
+        }
+    }
+    pub fn short_name(self) -> &'static str {
+        match self {
+            Self::ARMv6 => "armv6",
+            Self::ARMv7 => "armv7",
+        }
+    }
+}
+
+/// Architectures the CPU backend can run, in order of preference. This is used
+/// to pick a slice from a fat binary when the user hasn't asked for one.
+/// ARMv6 comes first because it's the better-tested option.
+pub const SUPPORTED_ARCHS: &[Arch] = &[Arch::ARMv6, Arch::ARMv7];
+
 fn touchHLE_cpu_read_impl<T: SafeRead + Default>(
     mem: *mut touchHLE_Mem,
     addr: VAddr,
+    access: Protection,
     error: *mut bool,
 ) -> T {
     // If a panic occurs (probably due to a null-pointer access), we can't let
@@ -38,11 +73,15 @@ fn touchHLE_cpu_read_impl<T: SafeRead + Default>(
     // the emulator will crash anyway, maybe this is okay.
     let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
         let mem = unsafe { &mut *mem.cast::<Mem>() };
+        if !mem.check_guest_access(addr, guest_size_of::<T>(), access) {
+            return None;
+        }
         let ptr: ConstPtr<T> = Ptr::from_bits(addr);
-        mem.read(ptr)
+        Some(mem.read(ptr))
     }));
+    let res = res.ok().flatten();
     unsafe {
-        error.write(res.is_err());
+        error.write(res.is_none());
     }
     res.unwrap_or_default()
 }
@@ -51,28 +90,59 @@ fn touchHLE_cpu_write_impl<T: SafeWrite>(mem: *mut touchHLE_Mem, addr: VAddr, va
     // See comments above about catch_unwind
     let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
         let mem = unsafe { &mut *mem.cast::<Mem>() };
+        if !mem.check_guest_access(addr, guest_size_of::<T>(), Protection::WRITE) {
+            return false;
+        }
         let ptr: MutPtr<T> = Ptr::from_bits(addr);
-        mem.write(ptr, value)
+        mem.write(ptr, value);
+        true
     }));
-    res.is_err()
+    !res.unwrap_or(false)
 }
 
 // Export functions for use by C++
 #[no_mangle]
 extern "C" fn touchHLE_cpu_read_u8(mem: *mut touchHLE_Mem, addr: VAddr, error: *mut bool) -> u8 {
-    touchHLE_cpu_read_impl(mem, addr, error)
+    touchHLE_cpu_read_impl(mem, addr, Protection::READ, error)
 }
 #[no_mangle]
 extern "C" fn touchHLE_cpu_read_u16(mem: *mut touchHLE_Mem, addr: VAddr, error: *mut bool) -> u16 {
-    touchHLE_cpu_read_impl(mem, addr, error)
+    touchHLE_cpu_read_impl(mem, addr, Protection::READ, error)
 }
 #[no_mangle]
 extern "C" fn touchHLE_cpu_read_u32(mem: *mut touchHLE_Mem, addr: VAddr, error: *mut bool) -> u32 {
-    touchHLE_cpu_read_impl(mem, addr, error)
+    touchHLE_cpu_read_impl(mem, addr, Protection::READ, error)
 }
 #[no_mangle]
 extern "C" fn touchHLE_cpu_read_u64(mem: *mut touchHLE_Mem, addr: VAddr, error: *mut bool) -> u64 {
-    touchHLE_cpu_read_impl(mem, addr, error)
+    touchHLE_cpu_read_impl(mem, addr, Protection::READ, error)
+}
+#[no_mangle]
+extern "C" fn touchHLE_cpu_read_code(mem: *mut touchHLE_Mem, addr: VAddr, error: *mut bool) -> u32 {
+    // This is called when code is translated, not when it is executed, so
+    // a fault isn't recorded yet (see touchHLE_cpu_record_execute_fault).
+    let mem = unsafe { &mut *mem.cast::<Mem>() };
+    if !mem.protection_at(addr).contains(Protection::EXECUTE)
+        || !mem
+            .protection_at(addr.wrapping_add(3))
+            .contains(Protection::EXECUTE)
+    {
+        unsafe { error.write(true) };
+        return 0;
+    }
+    // Checking for execute rather than read access means read watchpoints
+    // aren't triggered.
+    touchHLE_cpu_read_impl(
+        mem as *mut Mem as *mut touchHLE_Mem,
+        addr,
+        Protection::EXECUTE,
+        error,
+    )
+}
+#[no_mangle]
+extern "C" fn touchHLE_cpu_record_execute_fault(mem: *mut touchHLE_Mem, addr: VAddr) {
+    let mem = unsafe { &mut *mem.cast::<Mem>() };
+    mem.check_guest_access(addr, 4, Protection::EXECUTE);
 }
 #[no_mangle]
 extern "C" fn touchHLE_cpu_write_u8(mem: *mut touchHLE_Mem, addr: VAddr, value: u8) -> bool {
@@ -96,6 +166,8 @@ pub struct Cpu {
     /// Copy of the direct memory access pointer used to check it has not
     /// changed. If this is null, direct memory access is not in use.
     direct_memory_access_ptr: *const std::ffi::c_void,
+    /// The architecture dynarmic was configured for, which can't be changed.
+    arch: Arch,
 }
 
 impl Drop for Cpu {
@@ -105,7 +177,8 @@ impl Drop for Cpu {
 }
 
 /// Object for storing the state of a CPU (registers etc), useful when switching
-/// threads.
+/// threads. This includes the VFP/NEON registers and FPSCR, so floating-point
+/// and SIMD state is preserved too.
 pub struct CpuContext {
     context: *mut Dynarmic_A32_Context,
 }
@@ -114,6 +187,53 @@ impl CpuContext {
         let context = unsafe { touchHLE_DynarmicWrapper_Context_new() };
         CpuContext { context }
     }
+
+    /// Like [Cpu::regs], but for a thread that isn't running.
+    pub fn regs(&self) -> &[u32; 16] {
+        unsafe {
+            let ptr = touchHLE_DynarmicWrapper_Context_regs_const(self.context);
+            &*(ptr as *const [u32; 16])
+        }
+    }
+    pub fn regs_mut(&mut self) -> &mut [u32; 16] {
+        unsafe {
+            let ptr = touchHLE_DynarmicWrapper_Context_regs_mut(self.context);
+            &mut *(ptr as *mut [u32; 16])
+        }
+    }
+
+    /// Like [Cpu::dump_regs], but for a thread that isn't running.
+    pub fn dump_regs(&self) {
+        dump_regs(self.regs())
+    }
+
+    pub fn cpsr(&self) -> u32 {
+        unsafe { touchHLE_DynarmicWrapper_Context_cpsr(self.context) }
+    }
+    pub fn set_cpsr(&mut self, cpsr: u32) {
+        unsafe { touchHLE_DynarmicWrapper_Context_set_cpsr(self.context, cpsr) }
+    }
+
+    /// Like [Cpu::ext_regs], but for a thread that isn't running.
+    pub fn ext_regs(&self) -> &[u32; 64] {
+        unsafe {
+            let ptr = touchHLE_DynarmicWrapper_Context_ext_regs_const(self.context);
+            &*(ptr as *const [u32; 64])
+        }
+    }
+    pub fn ext_regs_mut(&mut self) -> &mut [u32; 64] {
+        unsafe {
+            let ptr = touchHLE_DynarmicWrapper_Context_ext_regs_mut(self.context);
+            &mut *(ptr as *mut [u32; 64])
+        }
+    }
+
+    pub fn fpscr(&self) -> u32 {
+        unsafe { touchHLE_DynarmicWrapper_Context_fpscr(self.context) }
+    }
+    pub fn set_fpscr(&mut self, fpscr: u32) {
+        unsafe { touchHLE_DynarmicWrapper_Context_set_fpscr(self.context, fpscr) }
+    }
 }
 impl Drop for CpuContext {
     fn drop(&mut self) {
@@ -121,6 +241,27 @@ impl Drop for CpuContext {
     }
 }
 
+/// Print the general-purpose registers, four to a line.
+fn dump_regs(regs: &[u32; 16]) {
+    for row in 0..4 {
+        use std::fmt::Write;
+        let mut line = String::new();
+        for col in 0..4 {
+            let reg_idx = row * 4 + col;
+            match reg_idx {
+                Cpu::SP => write!(&mut line, "\t SP: "),
+                Cpu::LR => write!(&mut line, "\t LR: "),
+                Cpu::PC => write!(&mut line, "\t PC: "),
+                _ if reg_idx <= 9 => write!(&mut line, "\t R{}: ", reg_idx),
+                _ => write!(&mut line, "\tR{}: ", reg_idx),
+            }
+            .unwrap();
+            write!(&mut line, "{:#010x}", regs[reg_idx]).unwrap();
+        }
+        echo!("{}", line);
+    }
+}
+
 /// Why CPU execution ended.
 #[derive(Debug)]
 pub enum CpuState {
@@ -136,8 +277,14 @@ pub enum CpuState {
 /// A reason that can cause CPU execution to be interrupted.
 #[derive(Debug)]
 pub enum CpuError {
-    /// Memory error during execution (probably a null page access).
+    /// Memory error during execution that isn't a [CpuError::ProtectionFault].
     MemoryError,
+    /// Guest code tried to access memory in a way the page protection doesn't
+    /// allow, e.g. writing to `__TEXT` or accessing the null page.
+    ProtectionFault(MemoryFault),
+    /// Guest code tried to access memory watched by the debugger. The access
+    /// has not happened yet.
+    Watchpoint(WatchpointHit),
     /// Undefined instruction (perhaps from a GDB software breakpoint).
     UndefinedInstruction,
     /// Breakpoint (`bkpt` instruction).
@@ -159,11 +306,11 @@ impl Cpu {
     /// When this bit is set in CPSR, the CPU is in user mode.
     pub const CPSR_USER_MODE: u32 = 0x00000010;
 
-    /// Construct a new CPU instance. If a mutable reference to a [Mem] instance
-    /// is provided, direct memory access is enabled, and the CPU instance
-    /// becomes bound to that [Mem] instance (subsequent calls must use the same
-    /// one).
-    pub fn new(direct_memory_access: Option<&mut Mem>) -> Cpu {
+    /// Construct a new CPU instance emulating the architecture `arch`. If a
+    /// mutable reference to a [Mem] instance is provided, direct memory access
+    /// is enabled, and the CPU instance becomes bound to that [Mem] instance
+    /// (subsequent calls must use the same one).
+    pub fn new(arch: Arch, direct_memory_access: Option<&mut Mem>) -> Cpu {
         // Safety: the direct memory access pointer will be retained directly by
         // the dynarmic wrapper and indirectly by cached JIT code, so we must
         // ensure we only execute the CPU while holding a &mut on the Mem object
@@ -172,13 +319,21 @@ impl Cpu {
             .map_or(std::ptr::null_mut(), |mem| unsafe {
                 mem.direct_memory_access_ptr()
             });
-        let dynarmic_wrapper = unsafe { touchHLE_DynarmicWrapper_new(direct_memory_access_ptr) };
+        let dynarmic_wrapper =
+            unsafe { touchHLE_DynarmicWrapper_new(direct_memory_access_ptr, arch == Arch::ARMv7) };
         Cpu {
             dynarmic_wrapper,
             direct_memory_access_ptr,
+            arch,
         }
     }
 
+    /// The architecture this CPU instance emulates. Code for a newer one can't
+    /// be run.
+    pub fn arch(&self) -> Arch {
+        self.arch
+    }
+
     pub fn regs(&self) -> &[u32; 16] {
         unsafe {
             let ptr = touchHLE_DynarmicWrapper_regs_const(self.dynarmic_wrapper);
@@ -193,24 +348,7 @@ impl Cpu {
     }
 
     pub fn dump_regs(&self) {
-        let regs = self.regs();
-        for row in 0..4 {
-            use std::fmt::Write;
-            let mut line = String::new();
-            for col in 0..4 {
-                let reg_idx = row * 4 + col;
-                match reg_idx {
-                    Self::SP => write!(&mut line, "\t SP: "),
-                    Self::LR => write!(&mut line, "\t LR: "),
-                    Self::PC => write!(&mut line, "\t PC: "),
-                    _ if reg_idx <= 9 => write!(&mut line, "\t R{}: ", reg_idx),
-                    _ => write!(&mut line, "\tR{}: ", reg_idx),
-                }
-                .unwrap();
-                write!(&mut line, "{:#010x}", regs[reg_idx]).unwrap();
-            }
-            echo!("{}", line);
-        }
+        dump_regs(self.regs())
     }
 
     pub fn cpsr(&self) -> u32 {
@@ -220,6 +358,29 @@ impl Cpu {
         unsafe { touchHLE_DynarmicWrapper_set_cpsr(self.dynarmic_wrapper, cpsr) }
     }
 
+    /// The VFP/NEON register file, viewed as single-precision registers
+    /// (`s0`–`s31` are the first 32). Each double-precision register `dN` is
+    /// the pair `s(2N)`, `s(2N+1)`, with the low half first.
+    pub fn ext_regs(&self) -> &[u32; 64] {
+        unsafe {
+            let ptr = touchHLE_DynarmicWrapper_ext_regs_const(self.dynarmic_wrapper);
+            &*(ptr as *const [u32; 64])
+        }
+    }
+    pub fn ext_regs_mut(&mut self) -> &mut [u32; 64] {
+        unsafe {
+            let ptr = touchHLE_DynarmicWrapper_ext_regs_mut(self.dynarmic_wrapper);
+            &mut *(ptr as *mut [u32; 64])
+        }
+    }
+
+    pub fn fpscr(&self) -> u32 {
+        unsafe { touchHLE_DynarmicWrapper_fpscr(self.dynarmic_wrapper) }
+    }
+    pub fn set_fpscr(&mut self, fpscr: u32) {
+        unsafe { touchHLE_DynarmicWrapper_set_fpscr(self.dynarmic_wrapper, fpscr) }
+    }
+
     /// Swap the current state of the CPU (registers etc) with the state stored
     /// in the context object.
     pub fn swap_context(&mut self, context: &mut CpuContext) {
@@ -264,6 +425,41 @@ impl Cpu {
         }
     }
 
+    /// Pages are only in dynarmic's page table if they can be both read and
+    /// written and have no watchpoints, otherwise accesses need to go through
+    /// the memory callbacks so they can be checked. dynarmic's page table can't
+    /// tell reads and writes apart, so the wrapper's callbacks have a separate
+    /// table for read-only pages (e.g. `__TEXT`, including its literal pools),
+    /// which lets them serve reads directly without calling into Rust. Only
+    /// writes to those pages are checked by [Mem].
+    ///
+    /// Code translated from pages that are no longer executable is discarded,
+    /// so that executing it again is checked.
+    fn sync_page_protections(&mut self, mem: &mut Mem) {
+        for (first_page, count) in mem.take_protection_changes() {
+            for page in first_page..(first_page + count) {
+                let protection = mem.protection_at(page * Mem::PAGE_SIZE);
+                if !protection.contains(Protection::EXECUTE) {
+                    self.invalidate_cache_range(page * Mem::PAGE_SIZE, Mem::PAGE_SIZE);
+                }
+                if self.direct_memory_access_ptr.is_null() {
+                    continue;
+                }
+                let watched = mem.page_is_watched(page);
+                let fast_read = protection.contains(Protection::READ) && !watched;
+                let fast_write = protection.contains(Protection::WRITE) && !watched;
+                unsafe {
+                    touchHLE_DynarmicWrapper_set_page_access(
+                        self.dynarmic_wrapper,
+                        page,
+                        fast_read,
+                        fast_write,
+                    )
+                }
+            }
+        }
+    }
+
     /// Start CPU execution.
     ///
     /// If `ticks` is [Some], it is used as an abstract time limit. The value
@@ -280,6 +476,7 @@ impl Cpu {
         if !self.direct_memory_access_ptr.is_null() {
             assert!(self.direct_memory_access_ptr == unsafe { mem.direct_memory_access_ptr() });
         }
+        self.sync_page_protections(mem);
 
         let res = unsafe {
             touchHLE_DynarmicWrapper_run_or_step(
@@ -290,7 +487,13 @@ impl Cpu {
         };
         match res {
             -1 => CpuState::Normal,
-            -2 => CpuState::Error(CpuError::MemoryError),
+            -2 => CpuState::Error(
+                match (mem.take_guest_access_fault(), mem.take_watchpoint_hit()) {
+                    (Some(fault), _) => CpuError::ProtectionFault(fault),
+                    (None, Some(hit)) => CpuError::Watchpoint(hit),
+                    (None, None) => CpuError::MemoryError,
+                },
+            ),
             -3 => CpuState::Error(CpuError::UndefinedInstruction),
             -4 => CpuState::Error(CpuError::Breakpoint),
             _ if res < -4 => panic!("Unexpected CPU execution result"),
@@ -3,8 +3,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
+#include <array>
 #include <cstdint>
 #include <cstdio>
+#include <cstring>
 
 #include "dynarmic/interface/A32/a32.h"
 #include "dynarmic/interface/A32/config.h"
@@ -21,6 +23,9 @@ std::uint8_t touchHLE_cpu_read_u8(touchHLE_Mem *mem, VAddr addr, bool *error);
 std::uint16_t touchHLE_cpu_read_u16(touchHLE_Mem *mem, VAddr addr, bool *error);
 std::uint32_t touchHLE_cpu_read_u32(touchHLE_Mem *mem, VAddr addr, bool *error);
 std::uint64_t touchHLE_cpu_read_u64(touchHLE_Mem *mem, VAddr addr, bool *error);
+std::uint32_t touchHLE_cpu_read_code(touchHLE_Mem *mem, VAddr addr,
+                                     bool *error);
+void touchHLE_cpu_record_execute_fault(touchHLE_Mem *mem, VAddr addr);
 bool touchHLE_cpu_write_u8(touchHLE_Mem *mem, VAddr addr, std::uint8_t value);
 bool touchHLE_cpu_write_u16(touchHLE_Mem *mem, VAddr addr, std::uint16_t value);
 bool touchHLE_cpu_write_u32(touchHLE_Mem *mem, VAddr addr, std::uint32_t value);
@@ -31,15 +36,43 @@ const auto HaltReasonSvc = Dynarmic::HaltReason::UserDefined1;
 const auto HaltReasonUndefinedInstruction = Dynarmic::HaltReason::UserDefined2;
 const auto HaltReasonBreakpoint = Dynarmic::HaltReason::UserDefined3;
 
+using PageTable = std::array<std::uint8_t *,
+                             Dynarmic::A32::UserConfig::NUM_PAGE_TABLE_ENTRIES>;
+
 class Environment final : public Dynarmic::A32::UserCallbacks {
 public:
   Dynarmic::A32::Jit *cpu = nullptr;
   touchHLE_Mem *mem = nullptr;
   std::uint64_t ticks_remaining;
   uint32_t halting_svc;
+  // Pages that can be read directly even though they're not in dynarmic's
+  // page table, i.e. read-only pages (see DynarmicWrapper::set_page_access).
+  // Null if direct memory access isn't in use.
+  const PageTable *read_page_table = nullptr;
 
 private:
+  // Read from a page in read_page_table without calling into Rust. Returns
+  // false if the slow path is needed.
+  template <typename T> bool FastRead(VAddr vaddr, T *value) {
+    if (!read_page_table) {
+      return false;
+    }
+    constexpr VAddr page_size = 1 << Dynarmic::A32::UserConfig::PAGE_BITS;
+    std::uint8_t *base =
+        (*read_page_table)[vaddr >> Dynarmic::A32::UserConfig::PAGE_BITS];
+    // Accesses that cross into the next page take the slow path.
+    if (!base || (vaddr & (page_size - 1)) > page_size - sizeof(T)) {
+      return false;
+    }
+    std::memcpy(value, base + vaddr, sizeof(T));
+    return true;
+  }
+
   std::uint8_t MemoryRead8(VAddr vaddr) override {
+    std::uint8_t fast_value;
+    if (FastRead(vaddr, &fast_value)) {
+      return fast_value;
+    }
     bool error;
     auto value = touchHLE_cpu_read_u8(mem, vaddr, &error);
     if (error) {
@@ -48,6 +81,10 @@ private:
     return value;
   }
   std::uint16_t MemoryRead16(VAddr vaddr) override {
+    std::uint16_t fast_value;
+    if (FastRead(vaddr, &fast_value)) {
+      return fast_value;
+    }
     bool error;
     auto value = touchHLE_cpu_read_u16(mem, vaddr, &error);
     if (error) {
@@ -56,6 +93,10 @@ private:
     return value;
   }
   std::uint32_t MemoryRead32(VAddr vaddr) override {
+    std::uint32_t fast_value;
+    if (FastRead(vaddr, &fast_value)) {
+      return fast_value;
+    }
     bool error;
     auto value = touchHLE_cpu_read_u32(mem, vaddr, &error);
     if (error) {
@@ -64,6 +105,10 @@ private:
     return value;
   }
   std::uint64_t MemoryRead64(VAddr vaddr) override {
+    std::uint64_t fast_value;
+    if (FastRead(vaddr, &fast_value)) {
+      return fast_value;
+    }
     bool error;
     auto value = touchHLE_cpu_read_u64(mem, vaddr, &error);
     if (error) {
@@ -74,7 +119,7 @@ private:
 
   std::optional<std::uint32_t> MemoryReadCode(VAddr vaddr) override {
     bool error;
-    auto value = touchHLE_cpu_read_u32(mem, vaddr, &error);
+    auto value = touchHLE_cpu_read_code(mem, vaddr, &error);
     if (error) {
       return std::nullopt;
     } else {
@@ -113,6 +158,9 @@ private:
   void ExceptionRaised(VAddr pc, Dynarmic::A32::Exception exception) override {
     // MemoryReadCode returned nullopt
     if (exception == Dynarmic::A32::Exception::NoExecuteFault) {
+      // Code is read when it's translated, which may be well before it's
+      // executed, so the fault is only recorded now.
+      touchHLE_cpu_record_execute_fault(mem, pc);
       cpu->HaltExecution(Dynarmic::HaltReason::MemoryAbort);
     } else if (exception == Dynarmic::A32::Exception::UndefinedInstruction) {
       cpu->HaltExecution(HaltReasonUndefinedInstruction);
@@ -137,18 +185,29 @@ private:
 class DynarmicWrapper {
   Environment env;
   std::unique_ptr<Dynarmic::A32::Jit> cpu;
-  std::array<std::uint8_t *, Dynarmic::A32::UserConfig::NUM_PAGE_TABLE_ENTRIES>
-      page_table;
+  PageTable page_table;
+  PageTable read_page_table;
+  std::uint8_t *direct_memory_access_ptr;
 
 public:
-  DynarmicWrapper(void *direct_memory_access_ptr) {
+  DynarmicWrapper(void *direct_memory_access_ptr, bool armv7)
+      : direct_memory_access_ptr((std::uint8_t *)direct_memory_access_ptr) {
     Dynarmic::A32::UserConfig user_config;
     user_config.callbacks = &env;
+    // The original iPhone and iPhone 3G have an ARM11 (ARMv6K with VFPv2),
+    // later devices have a Cortex-A8 (ARMv7-A with VFPv3 and NEON). dynarmic
+    // defaults to ARMv8, which would silently accept instructions the app's
+    // target device doesn't have.
+    user_config.arch_version = armv7 ? Dynarmic::A32::ArchVersion::v7
+                                     : Dynarmic::A32::ArchVersion::v6K;
     // TODO: only do this in debug builds? it's probably expensive
     user_config.check_halt_on_memory_access = true;
     if (direct_memory_access_ptr) {
       // Allow fast accesses to all pages other than the null page, which will
       // fall back to a memory callback, which will then abort execution.
+      // Pages that aren't readable and writable, or are being watched, are
+      // later removed from the page table (see set_page_access), so that
+      // accesses to them can be checked in the callbacks.
       // TODO: Eventually we should use dynarmic's true fastmem mode, but that
       // requires using mmap/mprotect/etc on the host OS so we can still catch
       // null pointer accesses.
@@ -158,6 +217,10 @@ public:
       page_table[0] = nullptr;
       user_config.page_table = &page_table;
       user_config.absolute_offset_page_table = true;
+      // dynarmic's page table can't tell reads and writes apart, so the
+      // callbacks have their own table for pages that are only readable.
+      read_page_table.fill(nullptr);
+      env.read_page_table = &read_page_table;
     }
     cpu = std::make_unique<Dynarmic::A32::Jit>(user_config);
     env.cpu = cpu.get();
@@ -169,10 +232,28 @@ public:
   std::uint32_t cpsr() const { return cpu->Cpsr(); }
   void set_cpsr(std::uint32_t cpsr) { cpu->SetCpsr(cpsr); }
 
+  const std::uint32_t *ext_regs() const { return &cpu->ExtRegs().front(); }
+  std::uint32_t *ext_regs() { return &cpu->ExtRegs().front(); }
+
+  std::uint32_t fpscr() const { return cpu->Fpscr(); }
+  void set_fpscr(std::uint32_t fpscr) { cpu->SetFpscr(fpscr); }
+
   void invalidate_cache_range(VAddr start, std::uint32_t size) {
     cpu->InvalidateCacheRange(start, size);
   }
 
+  // Reads from a page with fast_read set don't need to be checked by Rust
+  // code, and likewise for writes with fast_write.
+  void set_page_access(std::uint32_t page, bool fast_read, bool fast_write) {
+    if (!direct_memory_access_ptr || page == 0) {
+      return;
+    }
+    page_table[page] =
+        fast_read && fast_write ? direct_memory_access_ptr : nullptr;
+    read_page_table[page] =
+        fast_read && !fast_write ? direct_memory_access_ptr : nullptr;
+  }
+
   void swap_context(void *context) {
     Dynarmic::A32::Context tmp = cpu->SaveContext();
     cpu->LoadContext(*(Dynarmic::A32::Context *)context);
@@ -213,8 +294,9 @@ public:
 
 extern "C" {
 
-DynarmicWrapper *touchHLE_DynarmicWrapper_new(void *direct_memory_access_ptr) {
-  return new DynarmicWrapper(direct_memory_access_ptr);
+DynarmicWrapper *touchHLE_DynarmicWrapper_new(void *direct_memory_access_ptr,
+                                              bool armv7) {
+  return new DynarmicWrapper(direct_memory_access_ptr, armv7);
 }
 void touchHLE_DynarmicWrapper_delete(DynarmicWrapper *cpu) { delete cpu; }
 
@@ -234,6 +316,22 @@ void touchHLE_DynarmicWrapper_set_cpsr(DynarmicWrapper *cpu,
   cpu->set_cpsr(cpsr);
 }
 
+const std::uint32_t *
+touchHLE_DynarmicWrapper_ext_regs_const(const DynarmicWrapper *cpu) {
+  return cpu->ext_regs();
+}
+std::uint32_t *touchHLE_DynarmicWrapper_ext_regs_mut(DynarmicWrapper *cpu) {
+  return cpu->ext_regs();
+}
+
+std::uint32_t touchHLE_DynarmicWrapper_fpscr(const DynarmicWrapper *cpu) {
+  return cpu->fpscr();
+}
+void touchHLE_DynarmicWrapper_set_fpscr(DynarmicWrapper *cpu,
+                                        std::uint32_t fpscr) {
+  cpu->set_fpscr(fpscr);
+}
+
 void touchHLE_DynarmicWrapper_swap_context(DynarmicWrapper *cpu,
                                            void *context) {
   cpu->swap_context(context);
@@ -245,6 +343,12 @@ void touchHLE_DynarmicWrapper_invalidate_cache_range(DynarmicWrapper *cpu,
   cpu->invalidate_cache_range(start, size);
 }
 
+void touchHLE_DynarmicWrapper_set_page_access(DynarmicWrapper *cpu,
+                                              std::uint32_t page,
+                                              bool fast_read, bool fast_write) {
+  cpu->set_page_access(page, fast_read, fast_write);
+}
+
 std::int32_t touchHLE_DynarmicWrapper_run_or_step(DynarmicWrapper *cpu,
                                                   touchHLE_Mem *mem,
                                                   std::uint64_t *ticks) {
@@ -257,6 +361,34 @@ void *touchHLE_DynarmicWrapper_Context_new() {
 void touchHLE_DynarmicWrapper_Context_delete(void *context) {
   delete (Dynarmic::A32::Context *)context;
 }
+const std::uint32_t *
+touchHLE_DynarmicWrapper_Context_regs_const(const void *context) {
+  return &((const Dynarmic::A32::Context *)context)->Regs().front();
+}
+std::uint32_t *touchHLE_DynarmicWrapper_Context_regs_mut(void *context) {
+  return &((Dynarmic::A32::Context *)context)->Regs().front();
+}
+std::uint32_t touchHLE_DynarmicWrapper_Context_cpsr(const void *context) {
+  return ((const Dynarmic::A32::Context *)context)->Cpsr();
+}
+void touchHLE_DynarmicWrapper_Context_set_cpsr(void *context,
+                                               std::uint32_t cpsr) {
+  ((Dynarmic::A32::Context *)context)->SetCpsr(cpsr);
+}
+const std::uint32_t *
+touchHLE_DynarmicWrapper_Context_ext_regs_const(const void *context) {
+  return &((const Dynarmic::A32::Context *)context)->ExtRegs().front();
+}
+std::uint32_t *touchHLE_DynarmicWrapper_Context_ext_regs_mut(void *context) {
+  return &((Dynarmic::A32::Context *)context)->ExtRegs().front();
+}
+std::uint32_t touchHLE_DynarmicWrapper_Context_fpscr(const void *context) {
+  return ((const Dynarmic::A32::Context *)context)->Fpscr();
+}
+void touchHLE_DynarmicWrapper_Context_set_fpscr(void *context,
+                                                std::uint32_t fpscr) {
+  ((Dynarmic::A32::Context *)context)->SetFpscr(fpscr);
+}
 }
 
 } // namespace touchHLE::cpu
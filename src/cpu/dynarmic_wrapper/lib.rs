@@ -28,12 +28,19 @@ type VAddr = u32;
 extern "C" {
     pub fn touchHLE_DynarmicWrapper_new(
         dynamic_memory_access_ptr: *mut std::ffi::c_void,
+        armv7: bool,
     ) -> *mut touchHLE_DynarmicWrapper;
     pub fn touchHLE_DynarmicWrapper_delete(cpu: *mut touchHLE_DynarmicWrapper);
     pub fn touchHLE_DynarmicWrapper_regs_const(cpu: *const touchHLE_DynarmicWrapper) -> *const u32;
     pub fn touchHLE_DynarmicWrapper_regs_mut(cpu: *mut touchHLE_DynarmicWrapper) -> *mut u32;
     pub fn touchHLE_DynarmicWrapper_cpsr(cpu: *const touchHLE_DynarmicWrapper) -> u32;
     pub fn touchHLE_DynarmicWrapper_set_cpsr(cpu: *mut touchHLE_DynarmicWrapper, cpsr: u32);
+    pub fn touchHLE_DynarmicWrapper_ext_regs_const(
+        cpu: *const touchHLE_DynarmicWrapper,
+    ) -> *const u32;
+    pub fn touchHLE_DynarmicWrapper_ext_regs_mut(cpu: *mut touchHLE_DynarmicWrapper) -> *mut u32;
+    pub fn touchHLE_DynarmicWrapper_fpscr(cpu: *const touchHLE_DynarmicWrapper) -> u32;
+    pub fn touchHLE_DynarmicWrapper_set_fpscr(cpu: *mut touchHLE_DynarmicWrapper, fpscr: u32);
     pub fn touchHLE_DynarmicWrapper_swap_context(
         cpu: *mut touchHLE_DynarmicWrapper,
         context: *mut Dynarmic_A32_Context,
@@ -43,6 +50,12 @@ extern "C" {
         start: VAddr,
         size: u32,
     );
+    pub fn touchHLE_DynarmicWrapper_set_page_access(
+        cpu: *mut touchHLE_DynarmicWrapper,
+        page: u32,
+        fast_read: bool,
+        fast_write: bool,
+    );
     pub fn touchHLE_DynarmicWrapper_run_or_step(
         cpu: *mut touchHLE_DynarmicWrapper,
         mem: *mut touchHLE_Mem,
@@ -51,4 +64,23 @@ extern "C" {
 
     pub fn touchHLE_DynarmicWrapper_Context_new() -> *mut Dynarmic_A32_Context;
     pub fn touchHLE_DynarmicWrapper_Context_delete(context: *mut Dynarmic_A32_Context);
+    pub fn touchHLE_DynarmicWrapper_Context_regs_const(
+        context: *const Dynarmic_A32_Context,
+    ) -> *const u32;
+    pub fn touchHLE_DynarmicWrapper_Context_regs_mut(
+        context: *mut Dynarmic_A32_Context,
+    ) -> *mut u32;
+    pub fn touchHLE_DynarmicWrapper_Context_cpsr(context: *const Dynarmic_A32_Context) -> u32;
+    pub fn touchHLE_DynarmicWrapper_Context_set_cpsr(context: *mut Dynarmic_A32_Context, cpsr: u32);
+    pub fn touchHLE_DynarmicWrapper_Context_ext_regs_const(
+        context: *const Dynarmic_A32_Context,
+    ) -> *const u32;
+    pub fn touchHLE_DynarmicWrapper_Context_ext_regs_mut(
+        context: *mut Dynarmic_A32_Context,
+    ) -> *mut u32;
+    pub fn touchHLE_DynarmicWrapper_Context_fpscr(context: *const Dynarmic_A32_Context) -> u32;
+    pub fn touchHLE_DynarmicWrapper_Context_set_fpscr(
+        context: *mut Dynarmic_A32_Context,
+        fpscr: u32,
+    );
 }
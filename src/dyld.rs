@@ -23,16 +23,20 @@
 mod constant_lists;
 mod function_lists;
 
-use crate::abi::{CallFromGuest, GuestFunction};
-use crate::cpu::Cpu;
+use crate::abi::{trace_call, trace_return, CallFromGuest, GuestFunction};
+use crate::cpu::{Arch, Cpu};
 use crate::frameworks::foundation::ns_string;
-use crate::mach_o::{MachO, SectionType};
+use crate::fs::{Fs, GuestPath};
+use crate::mach_o::{DyldInfo, MachO, SectionType};
 use crate::mem::{ConstVoidPtr, GuestUSize, Mem, MutPtr, Ptr};
 use crate::objc::{nil, ObjC};
+use crate::save_state::{Reader, Writer};
+use crate::stubs::Stubs;
 use crate::Environment;
 use std::collections::HashMap;
 
-type HostFunction = &'static dyn CallFromGuest;
+/// A host function that can be called from guest code.
+pub type HostFunction = &'static dyn CallFromGuest;
 
 /// Type for lists of functions exported by host implementations of frameworks.
 ///
@@ -126,6 +130,104 @@ pub fn search_lists<T>(
         .find(|&(sym, _)| *sym == symbol)
 }
 
+/// Check whether there's a host implementation of a function, without linking
+/// it. For static analysis (see [crate::compat_report]).
+pub fn has_host_function(symbol: &str) -> bool {
+    search_lists(function_lists::FUNCTION_LISTS, symbol).is_some()
+}
+
+/// Check whether there's a host implementation of a constant, without linking
+/// it. For static analysis (see [crate::compat_report]).
+pub fn has_host_constant(symbol: &str) -> bool {
+    search_lists(constant_lists::CONSTANT_LISTS, symbol).is_some()
+}
+
+/// Check whether an external relocation or binding symbol is one [Dyld] handles
+/// without looking it up anywhere (classes are not included).
+pub fn is_special_relocation(symbol: &str) -> bool {
+    matches!(
+        symbol,
+        "___CFConstantStringClassReference"
+            | "__objc_empty_vtable"
+            | "__objc_empty_cache"
+            | "dyld_stub_binder"
+    )
+}
+
+/// Libraries other than system frameworks that touchHLE has host
+/// implementations of.
+pub const HOST_LIBRARIES: &[&str] = &["/usr/lib/libSystem.B.dylib", "/usr/lib/libobjc.A.dylib"];
+
+/// Check whether a dylib a binary depends on is a real binary to be loaded,
+/// rather than a library touchHLE has host implementations of.
+pub fn is_bundled_dylib(dylib: &str, fs: &Fs) -> bool {
+    // There are some Free Software libraries bundled with touchHLE and
+    // exposed via the guest file system (see Fs::new()).
+    !HOST_LIBRARIES.contains(&dylib) && fs.is_file(GuestPath::new(dylib))
+}
+
+/// Load the app's executable and the bundled dylibs it depends on. The
+/// executable is always the first of the returned binaries.
+pub fn load_bins(
+    executable_path: &GuestPath,
+    fs: &Fs,
+    mem: &mut Mem,
+    preferred_arch: Option<Arch>,
+) -> Result<Vec<MachO>, String> {
+    let executable = MachO::load_from_file(executable_path, fs, mem, preferred_arch)
+        .map_err(|e| format!("Could not load executable: {}", e))?;
+
+    let mut dylibs = Vec::new();
+    for dylib in &executable.dynamic_libraries {
+        if is_bundled_dylib(dylib, fs) {
+            // Fat dylibs should use the same architecture as the app.
+            let dylib =
+                MachO::load_from_file(GuestPath::new(dylib), fs, mem, Some(executable.arch))
+                    .map_err(|e| format!("Could not load bundled dylib: {}", e))?;
+            dylibs.push(dylib);
+        } else {
+            // System frameworks will have host implementations.
+            // TODO: warn about unimplemented frameworks?
+            if !HOST_LIBRARIES.contains(&dylib.as_str())
+                && !dylib.starts_with("/System/Library/Frameworks/")
+            {
+                log!(
+                    "Warning: app binary depends on unexpected dylib \"{}\"",
+                    dylib
+                );
+            }
+            continue;
+        };
+    }
+
+    let mut bins = dylibs;
+    bins.insert(0, executable);
+    Ok(bins)
+}
+
+/// Host function used in place of a missing function when it's being stubbed
+/// (see [crate::stubs]).
+struct StubFunction(u32);
+impl CallFromGuest for StubFunction {
+    fn call_from_guest(&self, env: &mut Environment) {
+        // The stub's arguments are unknown.
+        let trace = trace_call(env, || vec!["...".to_string()]);
+        trace_return(env, trace, &self.0);
+        // Fill r1 too, in case the caller expects a 64-bit value.
+        env.cpu.regs_mut()[0] = self.0;
+        env.cpu.regs_mut()[1] = 0;
+    }
+}
+
+/// Create a [StubFunction]. These are deliberately leaked, like the host
+/// functions in [FunctionExports], which live forever. There's at most one per
+/// stubbed symbol and linking site, so this doesn't add up to much.
+fn new_stub_function(symbol: &str, return_value: u32) -> (&'static str, HostFunction) {
+    let symbol: &'static str = Box::leak(symbol.to_string().into_boxed_str());
+    let f: HostFunction = Box::leak(Box::new(StubFunction(return_value)));
+    (symbol, f)
+}
+
 fn encode_a32_svc(imm: u32) -> u32 {
     assert!(imm & 0xff000000 == 0);
     imm | 0xef000000
@@ -152,6 +254,24 @@ fn write_return_to_host_routine(mem: &mut Mem, svc: u32) -> GuestFunction {
     assert!(!ptr.is_thumb());
     ptr
 }
+/// Log symbols [Dyld] couldn't link. Collecting everything unhandled for the
+/// same symbol onto one line makes the log output much less spammy.
+fn log_unhandled(kind: &str, bin: &MachO, unhandled: HashMap<&str, Vec<u32>>) {
+    for (name, addrs) in unhandled {
+        log!(
+            "Warning: unhandled {} {:?} in {:?} at {}",
+            kind,
+            name,
+            bin.name,
+            addrs
+                .into_iter()
+                .map(|addr| format!("{:#x}", addr))
+                .collect::<Vec<String>>()
+                .join(", "),
+        );
+    }
+}
+
 pub struct Dyld {
     /// List of host functions that have been "linked" and had SVCs assigned.
     ///
@@ -194,6 +314,75 @@ impl Dyld {
         self.thread_exit_routine.unwrap()
     }
 
+    /// Write the linked host functions to a save state, by symbol name, so that
+    /// the SVCs in guest memory refer to the same functions once it's loaded.
+    /// Returns [Err] if a function from outside
+    /// [function_lists::SAVE_STATE_FUNCTION_LISTS] has been linked.
+    pub fn save_state(&self, w: &mut Writer, mut stubs: Option<&mut Stubs>) -> Result<(), String> {
+        assert!(self.constants_to_link_later.is_empty());
+        w.u32(self.return_to_host_routine.unwrap().addr_with_thumb_bit());
+        w.u32(self.thread_exit_routine.unwrap().addr_with_thumb_bit());
+        w.u32(self.linked_host_functions.len().try_into().unwrap());
+        for &(symbol, _) in &self.linked_host_functions {
+            w.str(symbol);
+            if search_lists(function_lists::SAVE_STATE_FUNCTION_LISTS, symbol).is_some() {
+                w.option_u32(None);
+            } else if let Some(value) = stubs.as_deref_mut().and_then(|s| s.return_value(symbol)) {
+                w.option_u32(Some(value));
+            } else {
+                return Err(format!(
+                    "The app has used {}, which has state that can't be saved yet",
+                    symbol
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read back what [Self::save_state] wrote, replacing all the linked
+    /// host functions.
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.return_to_host_routine = Some(GuestFunction::from_addr_with_thumb_bit(r.u32()?));
+        self.thread_exit_routine = Some(GuestFunction::from_addr_with_thumb_bit(r.u32()?));
+        self.linked_host_functions.clear();
+        for _ in 0..r.u32()? {
+            let symbol = r.str()?;
+            let function = match r.option_u32()? {
+                None => *search_lists(function_lists::SAVE_STATE_FUNCTION_LISTS, symbol)
+                    .ok_or_else(|| format!("Save state refers to unknown function {}", symbol))?,
+                Some(return_value) => new_stub_function(symbol, return_value),
+            };
+            self.linked_host_functions.push(function);
+        }
+        Ok(())
+    }
+
+    /// If `addr` is within a stub that has been rewritten to call a host
+    /// function, get the name of that function. Used for symbolicating stack
+    /// traces.
+    pub fn host_function_at(&self, mem: &Mem, addr: u32) -> Option<&'static str> {
+        // The SVC is the first instruction of the rewritten stub, so we might
+        // be pointing at either it or the return instruction following it.
+        let addr = addr & !3;
+        for addr in [addr, addr.checked_sub(4)?] {
+            let Some(slice) = mem.get_bytes_fallible(Ptr::from_bits(addr), 4) else {
+                continue;
+            };
+            let instr = u32::from_le_bytes(slice.try_into().unwrap());
+            if instr & 0xff000000 != encode_a32_svc(0) {
+                continue;
+            }
+            let svc = instr & 0x00ffffff;
+            let Some(idx) = svc.checked_sub(Self::SVC_LINKED_FUNCTIONS_BASE) else {
+                continue;
+            };
+            if let Some(&(symbol, _)) = self.linked_host_functions.get(idx as usize) {
+                return Some(symbol);
+            }
+        }
+        None
+    }
+
     /// Do linking-related tasks that need doing right after loading the
     /// binaries.
     pub fn do_initial_linking(&mut self, bins: &[MachO], mem: &mut Mem, objc: &mut ObjC) {
@@ -215,12 +404,35 @@ impl Dyld {
             self.do_non_lazy_linking(bin, bins, mem, objc);
         }
 
+        objc.register_bin_protocols(&bins[0], mem);
         objc.register_bin_classes(&bins[0], mem);
         objc.register_bin_categories(&bins[0], mem);
 
         ns_string::register_constant_strings(&bins[0], mem, objc);
     }
 
+    /// [Self::do_initial_linking] but for a dylib loaded after startup by
+    /// `dlopen()`, `bins[bin_idx]`. Any other dylibs loaded along with it must
+    /// already be in `bins`, so that they can use each other's symbols.
+    /// [Self::do_late_linking] must be called afterwards.
+    pub fn do_linking_for_new_bin(
+        &mut self,
+        bins: &[MachO],
+        bin_idx: usize,
+        mem: &mut Mem,
+        objc: &mut ObjC,
+    ) {
+        let bin = &bins[bin_idx];
+
+        objc.register_bin_selectors(bin, mem);
+        self.setup_lazy_linking(bin, mem);
+        self.do_non_lazy_linking(bin, bins, mem, objc);
+        objc.register_bin_protocols(bin, mem);
+        objc.register_bin_classes(bin, mem);
+        objc.register_bin_categories(bin, mem);
+        ns_string::register_constant_strings(bin, mem, objc);
+    }
+
     /// [Self::do_initial_linking] but for when this is the app picker's special
     /// environment with no binary (see [crate::Environment::new_without_app]).
     pub fn do_initial_linking_with_no_bins(&mut self, mem: &mut Mem, objc: &mut ObjC) {
@@ -301,28 +513,7 @@ impl Dyld {
             // offset that should be applied to the external symbol's address.
             // It is often 0, but not always.
             let offset: u32 = mem.read(ptr_ptr).to_bits();
-            let target: ConstVoidPtr = if let Some(name) = name.strip_prefix("_OBJC_CLASS_$_") {
-                objc.link_class(name, /* is_metaclass: */ false, mem)
-                    .cast()
-                    .cast_const()
-            } else if let Some(name) = name.strip_prefix("_OBJC_METACLASS_$_") {
-                objc.link_class(name, /* is_metaclass: */ true, mem)
-                    .cast()
-                    .cast_const()
-            } else if name == "___CFConstantStringClassReference" {
-                // See ns_string::register_constant_strings
-                nil.cast().cast_const()
-            } else if name == "__objc_empty_vtable" || name == "__objc_empty_cache" {
-                // Our Objective-C runtime doesn't use these
-                Ptr::null()
-            } else if let Some(&external_addr) = bins
-                .iter()
-                .flat_map(|other_bin| other_bin.exported_symbols.get(name))
-                .next()
-            {
-                // Often used for C++ RTTI
-                Ptr::from_bits(external_addr)
-            } else {
+            let Some(target) = Self::resolve_non_lazy_symbol(name, bins, mem, objc) else {
                 unhandled_relocations
                     .entry(name)
                     .or_default()
@@ -336,19 +527,13 @@ impl Dyld {
                 Ptr::from_bits(target.to_bits().wrapping_add(offset)),
             )
         }
-        // Collecting unhandled relocations for the same symbol onto one line
-        // makes the log output much less spammy.
-        for (name, addrs) in unhandled_relocations {
-            log!(
-                "Warning: unhandled external relocation {:?} in {:?} at {}",
-                name,
-                bin.name,
-                addrs
-                    .into_iter()
-                    .map(|addr| format!("{:#x}", addr))
-                    .collect::<Vec<String>>()
-                    .join(", "),
-            );
+        log_unhandled("external relocation", bin, unhandled_relocations);
+
+        if let Some(ref dyld_info) = bin.dyld_info {
+            // The indirect symbol table also lists the non-lazy symbol
+            // pointers, but the bindings already cover them.
+            self.do_dyld_info_binding(bin, dyld_info, bins, mem, objc);
+            return;
         }
 
         let Some(ptrs) = bin.get_section(SectionType::NonLazySymbolPointers) else {
@@ -392,6 +577,112 @@ impl Dyld {
         // FIXME: check for internal relocations?
     }
 
+    /// Find the address to link a non-lazy symbol to, if it's an Objective-C
+    /// class, one of a few special symbols, or exported by one of `bins`.
+    fn resolve_non_lazy_symbol(
+        name: &str,
+        bins: &[MachO],
+        mem: &mut Mem,
+        objc: &mut ObjC,
+    ) -> Option<ConstVoidPtr> {
+        if let Some(name) = name.strip_prefix("_OBJC_CLASS_$_") {
+            Some(
+                objc.link_class(name, /* is_metaclass: */ false, mem)
+                    .cast()
+                    .cast_const(),
+            )
+        } else if let Some(name) = name.strip_prefix("_OBJC_METACLASS_$_") {
+            Some(
+                objc.link_class(name, /* is_metaclass: */ true, mem)
+                    .cast()
+                    .cast_const(),
+            )
+        // Keep is_special_relocation() in sync with these cases.
+        } else if name == "___CFConstantStringClassReference" {
+            // See ns_string::register_constant_strings
+            Some(nil.cast().cast_const())
+        } else if name == "__objc_empty_vtable" || name == "__objc_empty_cache" {
+            // Our Objective-C runtime doesn't use these
+            Some(Ptr::null())
+        } else if name == "dyld_stub_binder" {
+            // This is what lazy symbol pointers initially lead to, via
+            // __stub_helper, but setup_lazy_linking() rewrites the stubs so
+            // that they don't use them.
+            Some(Ptr::null())
+        } else {
+            // Often used for C++ RTTI
+            bins.iter()
+                .find_map(|other_bin| other_bin.exported_symbols.get(name))
+                .map(|&external_addr| Ptr::from_bits(external_addr))
+        }
+    }
+
+    /// Do the non-lazy and weak binding for a binary that has dyld info
+    /// (`LC_DYLD_INFO`), which replaces the external relocations and non-lazy
+    /// symbol pointer handling in [Self::do_non_lazy_linking].
+    fn do_dyld_info_binding(
+        &mut self,
+        bin: &MachO,
+        dyld_info: &DyldInfo,
+        bins: &[MachO],
+        mem: &mut Mem,
+        objc: &mut ObjC,
+    ) {
+        let mut unhandled_bindings: HashMap<&str, Vec<u32>> = HashMap::new();
+        for binding in &dyld_info.bindings {
+            let ptr_ptr: MutPtr<ConstVoidPtr> = Ptr::from_bits(binding.addr);
+            let target = if let Some(target) =
+                Self::resolve_non_lazy_symbol(&binding.symbol, bins, mem, objc)
+            {
+                target
+            } else if let Some((_, template)) =
+                search_lists(constant_lists::CONSTANT_LISTS, &binding.symbol)
+            {
+                if binding.addend != 0 {
+                    log!(
+                        "Warning: ignoring addend {} for constant {:?} at {:?} in {:?}",
+                        binding.addend,
+                        binding.symbol,
+                        ptr_ptr,
+                        bin.name
+                    );
+                }
+                // See the equivalent in do_non_lazy_linking().
+                self.constants_to_link_later.push((ptr_ptr, template));
+                continue;
+            } else if binding.weak_import {
+                // The app is expected to check whether this is null.
+                Ptr::null()
+            } else {
+                unhandled_bindings
+                    .entry(&binding.symbol)
+                    .or_default()
+                    .push(binding.addr);
+                continue;
+            };
+            mem.write(
+                ptr_ptr,
+                Ptr::from_bits(target.to_bits().wrapping_add_signed(binding.addend)),
+            );
+        }
+        log_unhandled("binding", bin, unhandled_bindings);
+
+        // Every binary that uses a weak symbol should use the same definition
+        // of it, so the first one in load order is picked. Real dyld would
+        // prefer a non-weak definition if there is one, but that seems to be
+        // rare enough to not matter.
+        for binding in &dyld_info.weak_bindings {
+            let Some(&addr) = bins
+                .iter()
+                .find_map(|other_bin| other_bin.exported_symbols.get(&binding.symbol))
+            else {
+                continue;
+            };
+            let ptr: MutPtr<u32> = Ptr::from_bits(binding.addr);
+            mem.write(ptr, addr.wrapping_add_signed(binding.addend));
+        }
+    }
+
     /// Do linking that can only be done once there is a full [Environment].
     /// Not to be confused with lazy linking.
     pub fn do_late_linking(env: &mut Environment) {
@@ -399,36 +690,50 @@ impl Dyld {
 
         let to_link = std::mem::take(&mut env.dyld.constants_to_link_later);
         for (symbol_ptr_ptr, template) in to_link {
-            let symbol_ptr: ConstVoidPtr = match template {
-                HostConstant::NSString(static_str) => {
-                    let string_ptr = ns_string::get_static_str(env, static_str);
-                    let string_ptr_ptr = env.mem.alloc_and_write(string_ptr);
-                    string_ptr_ptr.cast().cast_const()
-                }
-                HostConstant::NullPtr => {
-                    let null_ptr: ConstVoidPtr = Ptr::null();
-                    let null_ptr_ptr = env.mem.alloc_and_write(null_ptr);
-                    null_ptr_ptr.cast().cast_const()
-                }
-                HostConstant::Custom(f) => f(&mut env.mem),
-            };
+            let symbol_ptr = Self::create_constant(env, template);
             env.mem.write(symbol_ptr_ptr, symbol_ptr.cast());
         }
     }
 
-    /// Return a host function that can be called to handle an SVC instruction
-    /// encountered during CPU emulation. If `None` is returned, the execution
-    /// needs to resume at `svc_pc`.
+    fn create_constant(env: &mut Environment, template: &'static HostConstant) -> ConstVoidPtr {
+        match template {
+            HostConstant::NSString(static_str) => {
+                let string_ptr = ns_string::get_static_str(env, static_str);
+                let string_ptr_ptr = env.mem.alloc_and_write(string_ptr);
+                string_ptr_ptr.cast().cast_const()
+            }
+            HostConstant::NullPtr => {
+                let null_ptr: ConstVoidPtr = Ptr::null();
+                let null_ptr_ptr = env.mem.alloc_and_write(null_ptr);
+                null_ptr_ptr.cast().cast_const()
+            }
+            HostConstant::Custom(f) => f(&mut env.mem),
+        }
+    }
+
+    /// Get the address of a host constant with the (mangled) name `symbol`,
+    /// for use by `dlsym()`. Like with [Self::create_proc_address], a new
+    /// constant is created each time. Returns [None] if there's no such
+    /// constant.
+    pub fn create_constant_address(env: &mut Environment, symbol: &str) -> Option<ConstVoidPtr> {
+        let (_, template) = search_lists(constant_lists::CONSTANT_LISTS, symbol)?;
+        Some(Self::create_constant(env, template))
+    }
+
+    /// Return a host function (and its symbol name) that can be called to
+    /// handle an SVC instruction encountered during CPU emulation. If `None` is
+    /// returned, the execution needs to resume at `svc_pc`.
     pub fn get_svc_handler(
         &mut self,
         bins: &[MachO],
         mem: &mut Mem,
         cpu: &mut Cpu,
+        stubs: Option<&mut Stubs>,
         svc_pc: u32,
         svc: u32,
-    ) -> Option<HostFunction> {
+    ) -> Option<(&'static str, HostFunction)> {
         match svc {
-            Self::SVC_LAZY_LINK => self.do_lazy_link(bins, mem, cpu, svc_pc),
+            Self::SVC_LAZY_LINK => self.do_lazy_link(bins, mem, cpu, stubs, svc_pc),
             Self::SVC_THREAD_EXIT | Self::SVC_RETURN_TO_HOST => unreachable!(), // don't handle here
             Self::SVC_LINKED_FUNCTIONS_BASE.. => {
                 let f = self
@@ -438,7 +743,7 @@ impl Dyld {
                     panic!("Unexpected SVC #{} at {:#x}", svc, svc_pc);
                 };
                 log_dbg!("Call to host function, already linked: {}", symbol);
-                Some(f)
+                Some((symbol, f))
             }
         }
     }
@@ -448,57 +753,71 @@ impl Dyld {
         bins: &[MachO],
         mem: &mut Mem,
         cpu: &mut Cpu,
+        stubs: Option<&mut Stubs>,
         svc_pc: u32,
-    ) -> Option<HostFunction> {
-        let stubs = bins
+    ) -> Option<(&'static str, HostFunction)> {
+        let (bin, stubs_section) = bins
             .iter()
-            .flat_map(|bin| bin.get_section(SectionType::SymbolStubs))
-            .find(|stubs| (stubs.addr..(stubs.addr + stubs.size)).contains(&svc_pc))
+            .find_map(|bin| {
+                let stubs = bin.get_section(SectionType::SymbolStubs)?;
+                (stubs.addr..(stubs.addr + stubs.size))
+                    .contains(&svc_pc)
+                    .then_some((bin, stubs))
+            })
             .unwrap();
 
-        let info = stubs.dyld_indirect_symbol_info.as_ref().unwrap();
+        let info = stubs_section.dyld_indirect_symbol_info.as_ref().unwrap();
 
-        let offset = svc_pc - stubs.addr;
+        let offset = svc_pc - stubs_section.addr;
         assert!(offset % info.entry_size == 0);
         let idx = (offset / info.entry_size) as usize;
 
-        let symbol = info.indirect_undef_symbols[idx].as_deref().unwrap();
-
-        if let Some(&(symbol, f)) = search_lists(function_lists::FUNCTION_LISTS, symbol) {
-            // Allocate an SVC ID for this host function
-            let idx: u32 = self.linked_host_functions.len().try_into().unwrap();
-            let svc = idx + Self::SVC_LINKED_FUNCTIONS_BASE;
-            self.linked_host_functions.push((symbol, f));
-
-            // Rewrite stub function to call this host function
-            let stub_function_ptr: MutPtr<u32> = Ptr::from_bits(svc_pc);
-            mem.write(stub_function_ptr, encode_a32_svc(svc));
-            assert!(mem.read(stub_function_ptr + 1) == encode_a32_ret());
-
-            cpu.invalidate_cache_range(stub_function_ptr.to_bits(), 4);
+        let original_instructions = match info.entry_size {
+            12 => Self::SYMBOL_STUB_INSTRUCTIONS.as_slice(),
+            16 => Self::PIC_SYMBOL_STUB_INSTRUCTIONS.as_slice(),
+            _ => unreachable!(),
+        };
+        let instruction_count: GuestUSize = original_instructions.len().try_into().unwrap();
+
+        // The address of the stub's __la_symbol_ptr follows its instructions,
+        // which setup_lazy_linking() doesn't overwrite.
+        let stub_function_ptr: MutPtr<u32> = Ptr::from_bits(svc_pc);
+        let la_symbol_ptr: MutPtr<u32> = if info.entry_size == 12 {
+            // Normal stub: absolute address
+            let addr = mem.read(stub_function_ptr + instruction_count);
+            Ptr::from_bits(addr)
+        } else {
+            // The PIC (position-independent code) stub uses a
+            // PC-relative offset rather than an absolute address.
+            let offset = mem.read(stub_function_ptr + instruction_count);
+            Ptr::from_bits(stub_function_ptr.to_bits() + offset + 12)
+        };
 
-            log_dbg!(
-                "Linked {} at {:?} to host implementation",
-                symbol,
-                stub_function_ptr
-            );
+        // With dyld info, the lazy binding for the __la_symbol_ptr says which
+        // symbol it's for, otherwise the indirect symbol table does.
+        let symbol = bin
+            .dyld_info
+            .as_ref()
+            .and_then(|dyld_info| {
+                dyld_info
+                    .lazy_bindings
+                    .iter()
+                    .find(|binding| binding.addr == la_symbol_ptr.to_bits())
+            })
+            .map(|binding| binding.symbol.as_str())
+            .or(info.indirect_undef_symbols[idx].as_deref())
+            .unwrap();
 
+        if let Some(&(symbol, f)) = search_lists(function_lists::FUNCTION_LISTS, symbol) {
+            self.link_stub_to_host_function(mem, cpu, svc_pc, symbol, f);
             // Return the host function so that we can call it now that we're
             // done.
-            return Some(f);
+            return Some((symbol, f));
         }
 
         for dylib in &bins[1..] {
             if let Some(&addr) = dylib.exported_symbols.get(symbol) {
-                let original_instructions = match info.entry_size {
-                    12 => Self::SYMBOL_STUB_INSTRUCTIONS.as_slice(),
-                    16 => Self::PIC_SYMBOL_STUB_INSTRUCTIONS.as_slice(),
-                    _ => unreachable!(),
-                };
-                let instruction_count: GuestUSize = original_instructions.len().try_into().unwrap();
-
                 // Restore the original stub, which calls the __la_symbol_ptr
-                let stub_function_ptr: MutPtr<u32> = Ptr::from_bits(svc_pc);
                 for (i, &instr) in original_instructions.iter().enumerate() {
                     mem.write(stub_function_ptr + i.try_into().unwrap(), instr)
                 }
@@ -506,16 +825,6 @@ impl Dyld {
                 cpu.invalidate_cache_range(stub_function_ptr.to_bits(), instruction_count * 4);
 
                 // Update the __la_symbol_ptr
-                let la_symbol_ptr: MutPtr<u32> = if info.entry_size == 12 {
-                    // Normal stub: absolute address
-                    let addr = mem.read(stub_function_ptr + instruction_count);
-                    Ptr::from_bits(addr)
-                } else {
-                    // The PIC (position-independent code) stub uses a
-                    // PC-relative offset rather than an absolute address.
-                    let offset = mem.read(stub_function_ptr + instruction_count);
-                    Ptr::from_bits(stub_function_ptr.to_bits() + offset + 12)
-                };
                 mem.write(la_symbol_ptr, addr);
 
                 log_dbg!(
@@ -532,28 +841,83 @@ impl Dyld {
             }
         }
 
+        if let Some(return_value) = stubs.and_then(|stubs| stubs.return_value(symbol)) {
+            let (symbol, f) = new_stub_function(symbol, return_value);
+            self.link_stub_to_host_function(mem, cpu, svc_pc, symbol, f);
+            return Some((symbol, f));
+        }
+
         panic!("Call to unimplemented function {}", symbol);
     }
 
+    /// Rewrite a symbol stub so it calls a host function.
+    fn link_stub_to_host_function(
+        &mut self,
+        mem: &mut Mem,
+        cpu: &mut Cpu,
+        svc_pc: u32,
+        symbol: &'static str,
+        f: HostFunction,
+    ) {
+        // Allocate an SVC ID for this host function
+        let idx: u32 = self.linked_host_functions.len().try_into().unwrap();
+        let svc = idx + Self::SVC_LINKED_FUNCTIONS_BASE;
+        self.linked_host_functions.push((symbol, f));
+
+        // Rewrite stub function to call this host function
+        let stub_function_ptr: MutPtr<u32> = Ptr::from_bits(svc_pc);
+        mem.write(stub_function_ptr, encode_a32_svc(svc));
+        assert!(mem.read(stub_function_ptr + 1) == encode_a32_ret());
+
+        cpu.invalidate_cache_range(stub_function_ptr.to_bits(), 4);
+
+        log_dbg!(
+            "Linked {} at {:?} to host implementation",
+            symbol,
+            stub_function_ptr
+        );
+    }
+
     /// Creates a guest function that will call a host function with the name
     /// `symbol`. This can be used to implement "get proc address" functions.
     /// Note that no attempt is made to deduplicate or deallocate these, so
     /// excessive use would create a memory leak.
     ///
     /// The name must be the mangled symbol name. Returns [Err] if there's no
-    /// such function.
+    /// such function and it isn't to be stubbed (see [crate::stubs]).
     pub fn create_proc_address(
         &mut self,
         mem: &mut Mem,
         cpu: &mut Cpu,
+        stubs: Option<&mut Stubs>,
         symbol: &str,
     ) -> Result<GuestFunction, ()> {
-        let &(symbol, f) = search_lists(function_lists::FUNCTION_LISTS, symbol).ok_or(())?;
+        let (symbol, f) = match search_lists(function_lists::FUNCTION_LISTS, symbol) {
+            Some(&(symbol, f)) => (symbol, f),
+            None => {
+                let return_value = stubs
+                    .and_then(|stubs| stubs.return_value(symbol))
+                    .ok_or(())?;
+                new_stub_function(symbol, return_value)
+            }
+        };
+        Ok(self.create_guest_function(mem, cpu, symbol, f))
+    }
 
+    /// Creates a guest function that will call the host function `f`. `name`
+    /// is only used for debugging. As with [Self::create_proc_address], these
+    /// aren't deduplicated or deallocated.
+    pub fn create_guest_function(
+        &mut self,
+        mem: &mut Mem,
+        cpu: &mut Cpu,
+        name: &'static str,
+        f: HostFunction,
+    ) -> GuestFunction {
         // Allocate an SVC ID for this host function
         let idx: u32 = self.linked_host_functions.len().try_into().unwrap();
         let svc = idx + Self::SVC_LINKED_FUNCTIONS_BASE;
-        self.linked_host_functions.push((symbol, f));
+        self.linked_host_functions.push((name, f));
 
         // Create guest function to call this host function
         let function_ptr = mem.alloc(8);
@@ -564,8 +928,6 @@ impl Dyld {
         // Just in case
         cpu.invalidate_cache_range(function_ptr.to_bits(), 4);
 
-        Ok(GuestFunction::from_addr_with_thumb_bit(
-            function_ptr.to_bits(),
-        ))
+        GuestFunction::from_addr_with_thumb_bit(function_ptr.to_bits())
     }
 }
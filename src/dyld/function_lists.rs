@@ -11,6 +11,23 @@ use crate::frameworks::{
 };
 use crate::libc;
 
+/// Lists of functions that either have no host-side state, or whose state is
+/// included in save states (see [crate::libc::State::save_state]). A save state
+/// can't be made once any other host function has been linked.
+pub const SAVE_STATE_FUNCTION_LISTS: &[super::FunctionExports] = &[
+    libc::ctype::FUNCTIONS,
+    libc::errno::FUNCTIONS,
+    libc::mach_time::FUNCTIONS,
+    libc::math::FUNCTIONS,
+    libc::setjmp::FUNCTIONS,
+    libc::stdio::printf::FUNCTIONS,
+    libc::stdlib::FUNCTIONS,
+    libc::stdlib::qsort::FUNCTIONS,
+    libc::string::FUNCTIONS,
+    libc::time::FUNCTIONS,
+    libc::wchar::FUNCTIONS,
+];
+
 /// All the lists of functions that the linker should search through.
 pub const FUNCTION_LISTS: &[super::FunctionExports] = &[
     libc::ctype::FUNCTIONS,
@@ -26,9 +43,11 @@ pub const FUNCTION_LISTS: &[super::FunctionExports] = &[
     libc::net::if_::FUNCTIONS,
     libc::posix_io::FUNCTIONS,
     libc::posix_io::stat::FUNCTIONS,
+    libc::pthread::cond::FUNCTIONS,
     libc::pthread::key::FUNCTIONS,
     libc::pthread::mutex::FUNCTIONS,
     libc::pthread::once::FUNCTIONS,
+    libc::pthread::rwlock::FUNCTIONS,
     libc::pthread::thread::FUNCTIONS,
     libc::semaphore::FUNCTIONS,
     libc::setjmp::FUNCTIONS,
@@ -8,19 +8,26 @@
 //! Unlike its siblings, this module should be considered private and only used
 //! via the re-exports one level up.
 
+mod cond;
+mod monitor;
 mod mutex;
+mod rwlock;
+mod save_state;
+mod thread_dump;
 
 use crate::abi::GuestRet;
 use crate::libc::semaphore::sem_t;
 use crate::mem::{MutPtr, MutVoidPtr};
 use crate::{
-    abi, bundle, cpu, dyld, frameworks, fs, gdb, image, libc, mach_o, mem, objc, options, stack,
-    window,
+    abi, bundle, call_trace, clock, cpu, dyld, frameworks, fs, gdb, image, input_recording, libc,
+    mach_o, mem, objc, options, stack, stubs, window,
 };
 use std::net::TcpListener;
 use std::time::{Duration, Instant};
 
+pub use cond::CondId;
 pub use mutex::{MutexId, MutexType, PTHREAD_MUTEX_DEFAULT};
+pub use rwlock::RwLockId;
 
 /// Index into the [Vec] of threads. Thread 0 is always the main thread.
 pub type ThreadId = usize;
@@ -74,12 +81,22 @@ impl Thread {
 
 /// The struct containing the entire emulator state. Methods are provided for
 /// execution and management of threads.
+///
+/// TODO: Full save states. [Self::save_state] only handles programs that use
+/// nothing but simple parts of libc (see [crate::save_state]). Real apps also
+/// need [objc::ObjC]'s host objects, open `GuestFile`s, the blocking state of
+/// threads and [frameworks::State] to be saved, and a hotkey to trigger it. The
+/// blocker is that host objects are trait objects of many types, which
+/// together with the framework state hold handles to OpenGL contexts, OpenAL
+/// sources, SDL resources and `Rc`-shared structures that can't be written out
+/// and would have to be recreated by replaying the API calls that made them.
 pub struct Environment {
-    /// Reference point for various timing functions.
-    pub startup_time: Instant,
+    /// Source of time for anything the app can observe.
+    pub clock: clock::Clock,
     pub bundle: bundle::Bundle,
     pub fs: fs::Fs,
-    /// The window is only absent when running in headless mode.
+    /// The window is only absent when running in headless mode, and even then
+    /// one is created if something needs it (see [Self::window_mut]).
     pub window: Option<window::Window>,
     pub mem: mem::Mem,
     /// Loaded binaries. Index `0` is always the app binary, other entries are
@@ -93,8 +110,24 @@ pub struct Environment {
     pub libc_state: libc::State,
     pub framework_state: frameworks::State,
     pub mutex_state: mutex::MutexState,
+    pub cond_state: cond::CondState,
+    pub rwlock_state: rwlock::RwLockState,
     pub options: options::Options,
+    /// Present when input is being recorded or replayed.
+    pub input_recording: Option<input_recording::InputRecording>,
+    /// Present when host function calls are being traced.
+    pub call_tracer: Option<call_trace::CallTracer>,
+    /// Present when missing functions and methods should be stubbed rather
+    /// than panicking.
+    pub stubs: Option<stubs::Stubs>,
     gdb_server: Option<gdb::GdbServer>,
+    /// Set when execution should stop in the debugger before the next guest
+    /// instruction is executed, e.g. because of a breakpoint set with
+    /// `monitor break-objc`.
+    debugger_stop_pending: bool,
+    /// Number of host-to-guest function calls (see [Self::run_call]) that
+    /// haven't returned yet.
+    host_calls_in_progress: u32,
 }
 
 /// What to do next when executing this thread.
@@ -118,12 +151,24 @@ enum ThreadBlock {
     Sleeping(Instant),
     // Thread is waiting for a mutex to unlock.
     Mutex(MutexId),
+    // Thread is waiting for a condition variable to be signalled, then will
+    // relock the mutex. (until Instant, if any)
+    Cond(CondId, MutexId, Option<Instant>),
+    // Thread has finished waiting for a condition variable and is waiting for
+    // the mutex to unlock. The i32 is the return value it should see.
+    CondRelock(MutexId, i32),
+    // Thread is waiting to take a read/write lock. (for writing if bool is
+    // true)
+    RwLock(RwLockId, bool),
     // Thread is waiting on a semaphore.
     Semaphore(MutPtr<sem_t>),
     // Thread is waiting for another thread to finish (joining).
     Joining(ThreadId, MutPtr<MutVoidPtr>),
     // Deferred guest-to-host return
     DeferredReturn,
+    // Main thread has exited and the app will exit once the other threads
+    // have finished.
+    ExitingApp,
 }
 
 impl Environment {
@@ -141,7 +186,15 @@ impl Environment {
         options: options::Options,
         env_for_salvage: Option<Environment>,
     ) -> Result<Environment, String> {
-        let startup_time = Instant::now();
+        let input_recording = input_recording::InputRecording::from_options(&options)?;
+        let clock = match input_recording {
+            Some(ref recording) => {
+                clock::Clock::new_virtual(recording.start_time(), recording.is_recording())
+            }
+            None => clock::Clock::new_real(),
+        };
+        let call_tracer = call_trace::CallTracer::from_options(&options)?;
+        let stubs = stubs::Stubs::from_options(&options)?;
 
         // Extract things to salvage from the old environment, and then drop it.
         // This needs to be done before creating a new window, because SDL2 only
@@ -192,37 +245,18 @@ impl Environment {
         } else {
             mem::Mem::new()
         };
-
-        let executable = mach_o::MachO::load_from_file(bundle.executable_path(), &fs, &mut mem)
-            .map_err(|e| format!("Could not load executable: {}", e))?;
-
-        let mut dylibs = Vec::new();
-        for dylib in &executable.dynamic_libraries {
-            if dylib == "/usr/lib/libSystem.B.dylib" || dylib == "/usr/lib/libobjc.A.dylib" {
-                // We have host implementations of these
-                continue;
-            }
-
-            // There are some Free Software libraries bundled with touchHLE and
-            // exposed via the guest file system (see Fs::new()).
-            if fs.is_file(fs::GuestPath::new(dylib)) {
-                let dylib = mach_o::MachO::load_from_file(fs::GuestPath::new(dylib), &fs, &mut mem)
-                    .map_err(|e| format!("Could not load bundled dylib: {}", e))?;
-                dylibs.push(dylib);
-            } else {
-                // System frameworks will have host implementations.
-                // TODO: warn about unimplemented frameworks?
-                if !dylib.starts_with("/System/Library/Frameworks/") {
-                    log!(
-                        "Warning: app binary depends on unexpected dylib \"{}\"",
-                        dylib
-                    );
-                }
-                continue;
-            };
+        if options.debug_heap {
+            mem.enable_debug_heap();
         }
 
-        let entry_point_addr = executable.entry_point_pc.ok_or_else(|| {
+        let bins = dyld::load_bins(
+            &bundle.executable_path(),
+            &fs,
+            &mut mem,
+            options.preferred_arch,
+        )?;
+
+        let entry_point_addr = bins[0].entry_point_pc.ok_or_else(|| {
             "Mach-O file does not specify an entry point PC, perhaps it is not an executable?"
                 .to_string()
         })?;
@@ -230,18 +264,23 @@ impl Environment {
 
         log_dbg!("Address of start function: {:?}", entry_point_addr);
 
-        let mut bins = dylibs;
-        bins.insert(0, executable);
-
         let mut objc = objc::ObjC::new();
 
         let mut dyld = dyld::Dyld::new();
         dyld.do_initial_linking(&bins, &mut mem, &mut objc);
 
-        let cpu = cpu::Cpu::new(match options.direct_memory_access {
-            true => Some(&mut mem),
-            false => None,
-        });
+        // A bundled dylib could conceivably need a newer architecture than the
+        // app itself.
+        let arch = bins.iter().map(|bin| bin.arch).max().unwrap();
+        log_dbg!("Using {} CPU emulation", arch.short_name());
+
+        let cpu = cpu::Cpu::new(
+            arch,
+            match options.direct_memory_access {
+                true => Some(&mut mem),
+                false => None,
+            },
+        );
 
         let main_thread = Thread {
             active: true,
@@ -254,7 +293,7 @@ impl Environment {
         };
 
         let mut env = Environment {
-            startup_time,
+            clock,
             bundle,
             fs,
             window,
@@ -267,9 +306,16 @@ impl Environment {
             threads: vec![main_thread],
             libc_state: Default::default(),
             mutex_state: Default::default(),
+            cond_state: Default::default(),
+            rwlock_state: Default::default(),
             framework_state: Default::default(),
             options,
+            input_recording,
+            call_tracer,
+            stubs,
             gdb_server: None,
+            debugger_stop_pending: false,
+            host_calls_in_progress: 0,
         };
 
         dyld::Dyld::do_late_linking(&mut env);
@@ -286,6 +332,15 @@ impl Environment {
 
         env.cpu.set_cpsr(cpu::Cpu::CPSR_USER_MODE);
 
+        // A save state replaces everything set up above that the app can see,
+        // and the app has already run its static initializers.
+        let state_loaded = if let Some(path) = env.options.load_state.take() {
+            env.load_state(&path)?;
+            true
+        } else {
+            false
+        };
+
         if let Some(addrs) = env.options.gdb_listen_addrs.take() {
             let listener = TcpListener::bind(addrs.as_slice())
                 .map_err(|e| format!("Could not bind to {:?}: {}", addrs, e))?;
@@ -301,35 +356,36 @@ impl Environment {
                 .accept()
                 .map_err(|e| format!("Could not accept connection: {}", e))?;
             echo!("Debugger client connected on {}.", client_addr);
-            let mut gdb_server = gdb::GdbServer::new(client);
-            gdb_server.wait_for_debugger(None, &mut env.cpu, &mut env.mem);
-            env.gdb_server = Some(gdb_server);
+            let arch = env.cpu.arch();
+            let libraries = env
+                .bins
+                .iter()
+                .filter_map(|bin| {
+                    Some(gdb::DebugLibrary {
+                        name: bin.name.clone(),
+                        header_addr: bin.header_addr?,
+                    })
+                })
+                .collect();
+            env.gdb_server = Some(gdb::GdbServer::new(client, arch, libraries));
+            env.wait_for_debugger(None);
         }
 
         echo!("CPU emulation begins now.");
 
+        if state_loaded {
+            return Ok(env);
+        }
+
         // Static initializers for libraries must be run before the initializer
         // in the app binary.
         // TODO: once we support more libraries, replace this hard-coded order
         //       with e.g. a topological sort.
         assert!(env.bins.len() <= 3);
         for bin_idx in [1, 2, 0] {
-            let Some(bin) = env.bins.get(bin_idx) else {
-                continue;
-            };
-            let Some(section) = bin.get_section(mach_o::SectionType::ModInitFuncPointers) else {
-                continue;
-            };
-
-            log_dbg!("Calling static initializers for {:?}", bin.name);
-            assert!(section.size % 4 == 0);
-            let base: mem::ConstPtr<abi::GuestFunction> = mem::Ptr::from_bits(section.addr);
-            let count = section.size / 4;
-            for i in 0..count {
-                let func = env.mem.read(base + i);
-                func.call(&mut env);
+            if bin_idx < env.bins.len() {
+                env.run_static_initializers(bin_idx);
             }
-            log_dbg!("Static initialization done");
         }
 
         env.cpu.branch(entry_point_addr);
@@ -337,6 +393,134 @@ impl Environment {
         Ok(env)
     }
 
+    fn run_static_initializers(&mut self, bin_idx: usize) {
+        let bin = &self.bins[bin_idx];
+        let Some(section) = bin.get_section(mach_o::SectionType::ModInitFuncPointers) else {
+            return;
+        };
+
+        log_dbg!("Calling static initializers for {:?}", bin.name);
+        assert!(section.size % 4 == 0);
+        let base: mem::ConstPtr<abi::GuestFunction> = mem::Ptr::from_bits(section.addr);
+        let count = section.size / 4;
+        for i in 0..count {
+            let func = self.mem.read(base + i);
+            func.call(self);
+        }
+        log_dbg!("Static initialization done");
+    }
+
+    /// Load a dylib after startup, for `dlopen()`: load it and any bundled
+    /// dylibs it depends on that aren't loaded yet, link them and run their
+    /// static initializers. Returns the index of the dylib in [Self::bins].
+    pub fn load_dylib(&mut self, path: &fs::GuestPath) -> Result<usize, String> {
+        // Everything is loaded before anything is linked, so that dylibs that
+        // depend on each other can use each other's symbols, and so that
+        // nothing is left half-loaded if one of them can't be loaded.
+        let mut dylibs = Vec::new();
+        if let Err(e) = self.load_dylib_and_dependencies(path, &mut Vec::new(), &mut dylibs) {
+            for dylib in dylibs {
+                dylib.unload(&mut self.mem);
+            }
+            return Err(e);
+        }
+
+        let first_new_bin = self.bins.len();
+        for dylib in dylibs {
+            log!("Loaded {:?} at runtime", dylib.name);
+            if let (Some(ref mut gdb_server), Some(header_addr)) =
+                (&mut self.gdb_server, dylib.header_addr)
+            {
+                gdb_server.add_library(
+                    gdb::DebugLibrary {
+                        name: dylib.name.clone(),
+                        header_addr,
+                    },
+                    &mut self.mem,
+                );
+            }
+            self.bins.push(dylib);
+        }
+        // Stop so the debugger can see the new libraries before their static
+        // initializers or anything else in them runs. A monitor command's
+        // guest code can't stop, but the next stop will still report them.
+        if self
+            .gdb_server
+            .as_ref()
+            .is_some_and(|gdb_server| !gdb_server.monitor_command_running())
+        {
+            self.debugger_stop_pending = true;
+        }
+        for bin_idx in first_new_bin..self.bins.len() {
+            self.dyld
+                .do_linking_for_new_bin(&self.bins, bin_idx, &mut self.mem, &mut self.objc);
+        }
+        dyld::Dyld::do_late_linking(self);
+
+        // Dependencies come first, so their initializers run first.
+        for bin_idx in first_new_bin..self.bins.len() {
+            self.run_static_initializers(bin_idx);
+        }
+        Ok(self.bins.len() - 1)
+    }
+
+    /// Load a dylib and then its bundled dependencies that aren't loaded yet,
+    /// adding them to `loaded` in the order they should be initialized, i.e.
+    /// the dylib itself comes last. `loading` is the chain of dylibs whose
+    /// dependencies are being loaded.
+    fn load_dylib_and_dependencies(
+        &mut self,
+        path: &fs::GuestPath,
+        loading: &mut Vec<String>,
+        loaded: &mut Vec<mach_o::MachO>,
+    ) -> Result<(), String> {
+        // Fat dylibs should use the same architecture as the CPU. A thin one
+        // for a newer architecture can't run, since the CPU was set up for the
+        // binaries loaded at startup.
+        let arch = self.cpu.arch();
+        let dylib = mach_o::MachO::load_from_file(path, &self.fs, &mut self.mem, Some(arch))
+            .map_err(|e| format!("{}: {}", path.as_str(), e))?;
+        if dylib.arch > arch {
+            let error = format!(
+                "{}: needs {} but the CPU emulates {}",
+                path.as_str(),
+                dylib.arch.short_name(),
+                arch.short_name()
+            );
+            dylib.unload(&mut self.mem);
+            return Err(error);
+        }
+
+        // A dependency might depend on this dylib in turn, which mustn't
+        // load it again.
+        loading.push(dylib.name.clone());
+        for dependency in dylib.dynamic_libraries.clone() {
+            if !dyld::is_bundled_dylib(&dependency, &self.fs) {
+                continue;
+            }
+            let name = fs::GuestPath::new(&dependency).file_name().unwrap();
+            if self
+                .bins
+                .iter()
+                .chain(loaded.iter())
+                .any(|bin| bin.name == name)
+                || loading.iter().any(|other| other == name)
+            {
+                continue;
+            }
+            if let Err(e) =
+                self.load_dylib_and_dependencies(fs::GuestPath::new(&dependency), loading, loaded)
+            {
+                dylib.unload(&mut self.mem);
+                return Err(e);
+            }
+        }
+        loading.pop();
+
+        loaded.push(dylib);
+        Ok(())
+    }
+
     /// Set up the emulator environment without loading an app binary.
     ///
     /// This is a special mode that only exists to support the app picker, which
@@ -348,7 +532,7 @@ impl Environment {
         let bundle = bundle::Bundle::new_fake_bundle();
         let fs = fs::Fs::new_fake_fs();
 
-        let startup_time = Instant::now();
+        let clock = clock::Clock::new_real();
 
         let icon = None;
         let launch_image = None;
@@ -370,10 +554,14 @@ impl Environment {
         let mut dyld = dyld::Dyld::new();
         dyld.do_initial_linking_with_no_bins(&mut mem, &mut objc);
 
-        let cpu = cpu::Cpu::new(match options.direct_memory_access {
-            true => Some(&mut mem),
-            false => None,
-        });
+        // There's no app, so the choice doesn't matter much.
+        let cpu = cpu::Cpu::new(
+            cpu::SUPPORTED_ARCHS[0],
+            match options.direct_memory_access {
+                true => Some(&mut mem),
+                false => None,
+            },
+        );
 
         let main_thread = Thread {
             active: true,
@@ -386,7 +574,7 @@ impl Environment {
         };
 
         let mut env = Environment {
-            startup_time,
+            clock,
             bundle,
             fs,
             window,
@@ -399,9 +587,16 @@ impl Environment {
             threads: vec![main_thread],
             libc_state: Default::default(),
             mutex_state: Default::default(),
+            cond_state: Default::default(),
+            rwlock_state: Default::default(),
             framework_state: Default::default(),
             options,
+            input_recording: None,
+            call_tracer: None,
+            stubs: None,
             gdb_server: None,
+            debugger_stop_pending: false,
+            host_calls_in_progress: 0,
         };
 
         // Dyld::do_late_linking() would be called here, but it doesn't do
@@ -425,36 +620,112 @@ impl Environment {
     }
 
     /// Get a shared reference to the window. Panics if touchHLE is running in
-    /// headless mode.
+    /// headless mode and [Self::window_mut] hasn't created a window yet.
     pub fn window(&self) -> &window::Window {
         self.window.as_ref().expect(
             "Tried to do something that needs a window, but touchHLE is running in headless mode!",
         )
     }
 
-    /// Get a mutable reference to the window. Panics if touchHLE is running
-    /// in headless mode.
+    /// Get a mutable reference to the window. In headless mode, an invisible
+    /// window is created the first time this is called, so that apps using
+    /// OpenGL ES and so on can still run, e.g. to replay a recording.
     pub fn window_mut(&mut self) -> &mut window::Window {
-        self.window.as_mut().expect(
-            "Tried to do something that needs a window, but touchHLE is running in headless mode!",
-        )
+        self.window.get_or_insert_with(|| {
+            log!("Creating an invisible window, since headless mode needs one.");
+            window::Window::new_hidden(&self.options)
+        })
+    }
+
+    /// Describe a guest code address in terms of the nearest known symbol,
+    /// e.g. `0x1234 (-[Foo bar] + 0x10)`. This considers symbols from all the
+    /// loaded binaries, Objective-C methods, and host functions called via
+    /// stubs. If nothing is found, just the address is returned.
+    fn symbolicate(&self, addr: u32) -> String {
+        let lookup_addr = addr & !1; // ignore the Thumb bit
+        if let Some(name) = self.dyld.host_function_at(&self.mem, lookup_addr) {
+            return format!("{:#x} ({} [host function])", addr, name);
+        }
+        let mut best: Option<(u32, String)> = None;
+        for bin in &self.bins {
+            if let Some(name) = bin.stub_symbol_at(lookup_addr) {
+                return format!("{:#x} ({} [stub])", addr, name);
+            }
+            if let Some((start, name)) = bin.symbol_before(lookup_addr) {
+                if Some(start) > best.as_ref().map(|&(best, _)| best) {
+                    best = Some((start, name.to_string()));
+                }
+            }
+        }
+        // A method only extends up to the next symbol or method, and never
+        // beyond the code section it's in.
+        let text_section = self.bins.iter().find_map(|bin| {
+            bin.get_section("__text")
+                .map(|section| section.addr..section.addr + section.size)
+                .filter(|range| range.contains(&lookup_addr))
+        });
+        if let Some(text_section) = text_section {
+            if let Some((start, name)) =
+                self.objc
+                    .guest_method_before(lookup_addr, text_section, &self.mem)
+            {
+                // Prefer the binary's own symbol if both start at the same
+                // address.
+                if Some(start) > best.as_ref().map(|&(best, _)| best) {
+                    best = Some((start, name));
+                }
+            }
+        }
+        match best {
+            Some((start, name)) if start == lookup_addr => format!("{:#x} ({})", addr, name),
+            Some((start, name)) => {
+                format!("{:#x} ({} + {:#x})", addr, name, lookup_addr - start)
+            }
+            None => format!("{:#x}", addr),
+        }
+    }
+
+    /// Report the problems found by the debug heap (see `--debug-heap`), if
+    /// any, and panic if any of them mean the heap is corrupt.
+    fn report_debug_heap_problems(&mut self) {
+        let mut fatal_message = None;
+        for problem in self.mem.take_debug_heap_problems() {
+            let message = problem.message(|site| self.symbolicate(site));
+            if problem.fatal {
+                fatal_message.get_or_insert(message);
+            } else {
+                log!("Warning: Debug heap: {}", message);
+            }
+        }
+        if let Some(message) = fatal_message {
+            panic!("Debug heap: {}", message);
+        }
+    }
+
+    /// Print a summary of allocations made by the app that haven't been freed,
+    /// if the debug heap is enabled (see [mem::Mem::report_debug_heap_leaks]).
+    pub fn report_debug_heap_leaks(&self) {
+        self.mem
+            .report_debug_heap_leaks(|site| self.symbolicate(site));
     }
 
     fn stack_trace(&self) {
-        if self.current_thread == 0 {
+        self.stack_trace_for_thread(self.current_thread, self.cpu.regs(), self.cpu.cpsr());
+    }
+
+    /// Like [Self::stack_trace], but for any thread, given its registers.
+    fn stack_trace_for_thread(&self, thread: ThreadId, regs: &[u32; 16], cpsr: u32) {
+        if thread == 0 {
             echo!("Attempting to produce stack trace for main thread:");
         } else {
-            echo!(
-                "Attempting to produce stack trace for thread {}:",
-                self.current_thread
-            );
+            echo!("Attempting to produce stack trace for thread {}:", thread);
         }
-        let stack_range = self.threads[self.current_thread].stack.clone().unwrap();
-        echo!(
-            " 0. {:#x} (PC)",
-            self.cpu.pc_with_thumb_bit().addr_with_thumb_bit()
+        let stack_range = self.threads[thread].stack.clone().unwrap();
+        let pc = abi::GuestFunction::from_addr_and_thumb_flag(
+            regs[cpu::Cpu::PC],
+            (cpsr & cpu::Cpu::CPSR_THUMB) != 0,
         );
-        let regs = self.cpu.regs();
+        echo!(" 0. {} (PC)", self.symbolicate(pc.addr_with_thumb_bit()));
         let mut lr = regs[cpu::Cpu::LR];
         let return_to_host_routine_addr = self.dyld.return_to_host_routine().addr_with_thumb_bit();
         let thread_exit_routine_addr = self.dyld.thread_exit_routine().addr_with_thumb_bit();
@@ -464,7 +735,7 @@ impl Environment {
             echo!(" 1. [thread exit] (LR)");
             return;
         } else {
-            echo!(" 1. {:#x} (LR)", lr);
+            echo!(" 1. {} (LR)", self.symbolicate(lr));
         }
         let mut i = 2;
         let mut fp: mem::ConstPtr<u8> = mem::Ptr::from_bits(regs[abi::FRAME_POINTER]);
@@ -481,7 +752,7 @@ impl Environment {
                 echo!("{:2}. [thread exit]", i);
                 return;
             } else {
-                echo!("{:2}. {:#x}", i, lr);
+                echo!("{:2}. {}", i, self.symbolicate(lr));
             }
             i += 1;
         }
@@ -489,13 +760,14 @@ impl Environment {
 
     /// Create a new thread and return its ID. The `start_routine` and
     /// `user_data` arguments have the same meaning as the last two arguments to
-    /// `pthread_create`.
+    /// `pthread_create`. The usual `stack_size` is
+    /// [mem::Mem::SECONDARY_THREAD_STACK_SIZE].
     pub fn new_thread(
         &mut self,
         start_routine: abi::GuestFunction,
         user_data: mem::MutVoidPtr,
+        stack_size: mem::GuestUSize,
     ) -> ThreadId {
-        let stack_size = mem::Mem::SECONDARY_THREAD_STACK_SIZE;
         let stack_alloc = self.mem.alloc(stack_size);
         let stack_high_addr = stack_alloc.to_bits() + stack_size;
         assert!(stack_high_addr % 4 == 0);
@@ -544,7 +816,7 @@ impl Environment {
             self.current_thread,
             duration
         );
-        let until = Instant::now().checked_add(duration).unwrap();
+        let until = self.clock.now().checked_add(duration).unwrap();
         self.threads[self.current_thread].blocked_by = ThreadBlock::Sleeping(until);
         // For non tail-call sleeps (such as in NSRunLoop), we want to poll
         // other threads but can't return back to the run loop, since it would
@@ -577,6 +849,7 @@ impl Environment {
             mutex_id
         );
         self.threads[self.current_thread].blocked_by = ThreadBlock::Mutex(mutex_id);
+        self.check_for_deadlock();
     }
 
     /// Locks a semaphore (decrements value of a semaphore and blocks if necessary)
@@ -663,6 +936,36 @@ impl Environment {
             joinee_thread
         );
         self.threads[self.current_thread].blocked_by = ThreadBlock::Joining(joinee_thread, ptr);
+        self.check_for_deadlock();
+    }
+
+    /// Make the current thread finish as though its start routine had returned
+    /// `return_value`. Similar to `pthread_exit`, but for host code.
+    ///
+    /// This only takes effect once the calling host function returns, and only
+    /// works if it was called directly by guest code.
+    pub fn exit_current_thread(&mut self, return_value: MutVoidPtr) {
+        if !self.threads[self.current_thread].in_start_routine {
+            // The main thread can't return to anything, so it just never runs
+            // again. The app exits once there are no other threads left.
+            log!(
+                "Main thread {} exited, the app will exit once the other threads finish.",
+                self.current_thread
+            );
+            self.threads[self.current_thread].blocked_by = ThreadBlock::ExitingApp;
+            return;
+        }
+        log_dbg!(
+            "Thread {} is exiting with return value {:?}.",
+            self.current_thread,
+            return_value
+        );
+        // Host functions return to LR, so this "returns" straight to the
+        // routine that a start routine would have returned to.
+        let thread_exit_routine = self.dyld.thread_exit_routine();
+        let regs = self.cpu.regs_mut();
+        regs[0] = return_value.to_bits();
+        regs[cpu::Cpu::LR] = thread_exit_routine.addr_with_thumb_bit();
     }
 
     /// Run the emulator. This is the main loop and won't return until app exit.
@@ -672,9 +975,8 @@ impl Environment {
         // the emulator will crash anyway, maybe this is okay.
         let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.run_inner(true)));
         if let Err(e) = res {
-            echo!("Register state immediately after panic:");
-            self.cpu.dump_regs();
-            self.stack_trace();
+            echo!("Thread state immediately after panic:");
+            self.dump_threads();
             std::panic::resume_unwind(e);
         }
     }
@@ -688,7 +990,9 @@ impl Environment {
         let was_in_host_function = self.threads[self.current_thread].in_host_function;
         let old_thread = self.current_thread;
         self.threads[self.current_thread].in_host_function = false;
+        self.host_calls_in_progress += 1;
         self.run_inner(false);
+        self.host_calls_in_progress -= 1;
         assert!(self.current_thread == old_thread);
         self.threads[self.current_thread].in_host_function = was_in_host_function;
     }
@@ -737,11 +1041,69 @@ impl Environment {
         // GDB doesn't seem to manage to produce a useful stack trace, so
         // let's print our own.
         self.stack_trace();
-        self.gdb_server.as_mut().unwrap().wait_for_debugger(
-            Some(error),
-            &mut self.cpu,
-            &mut self.mem,
-        )
+        self.wait_for_debugger(Some(error))
+    }
+
+    /// Check whether the PC is at a breakpoint inserted by the debugger with
+    /// `Z1` (see [gdb::GdbServer::hardware_breakpoint_at]).
+    fn hardware_breakpoint_hit(&self) -> bool {
+        let pc = self.cpu.regs()[cpu::Cpu::PC];
+        self.gdb_server
+            .as_ref()
+            .is_some_and(|gdb_server| gdb_server.hardware_breakpoint_at(pc))
+    }
+
+    /// Communicate with the connected debugger until it requests execution
+    /// should continue, letting it inspect all the active threads. See
+    /// [gdb::GdbServer::wait_for_debugger] for the return value.
+    fn wait_for_debugger(&mut self, mut stop_reason: Option<cpu::CpuError>) -> bool {
+        loop {
+            let descriptions: Vec<String> = (0..self.threads.len())
+                .map(|thread| self.describe_thread_state(thread))
+                .collect();
+            let mut threads: Vec<gdb::DebugThread> = self
+                .threads
+                .iter_mut()
+                .zip(descriptions)
+                .enumerate()
+                .filter(|(_, (thread, _))| thread.active)
+                .map(|(id, (thread, description))| gdb::DebugThread {
+                    id,
+                    context: thread.context.as_mut(),
+                    description,
+                })
+                .collect();
+            let action = self.gdb_server.as_mut().unwrap().wait_for_debugger(
+                stop_reason.take(),
+                &mut self.cpu,
+                &mut self.mem,
+                &mut threads,
+            );
+            match action {
+                gdb::DebuggerAction::Continue => return false,
+                gdb::DebuggerAction::Step => return true,
+                gdb::DebuggerAction::Monitor(command) => {
+                    // The command may run guest code (e.g. `monitor po`), which
+                    // mustn't stop at the debugger's breakpoints and
+                    // watchpoints while the debugger is waiting for the reply.
+                    self.gdb_server.as_mut().unwrap().set_breakpoints_suspended(
+                        true,
+                        &mut self.cpu,
+                        &mut self.mem,
+                    );
+                    self.mem.set_watchpoints_suspended(true);
+                    let output = self.run_monitor_command(&command);
+                    self.mem.set_watchpoints_suspended(false);
+                    let gdb_server = self.gdb_server.as_mut().unwrap();
+                    gdb_server.set_breakpoints_suspended(false, &mut self.cpu, &mut self.mem);
+                    // If the command's guest code stopped, the reply has
+                    // already been sent.
+                    if gdb_server.monitor_command_running() {
+                        gdb_server.send_monitor_output(&output);
+                    }
+                }
+            }
+        }
     }
 
     #[inline(always)]
@@ -810,19 +1172,30 @@ impl Environment {
                         }
                     }
                     dyld::Dyld::SVC_LAZY_LINK | dyld::Dyld::SVC_LINKED_FUNCTIONS_BASE.. => {
-                        if let Some(f) = self.dyld.get_svc_handler(
+                        if let Some((symbol, f)) = self.dyld.get_svc_handler(
                             &self.bins,
                             &mut self.mem,
                             &mut self.cpu,
+                            self.stubs.as_mut(),
                             svc_pc,
                             svc,
                         ) {
                             let was_in_host_function =
                                 self.threads[self.current_thread].in_host_function;
                             self.threads[self.current_thread].in_host_function = true;
+                            if let Some(ref mut tracer) = self.call_tracer {
+                                if tracer.should_trace(symbol) {
+                                    tracer.set_pending(call_trace::PendingCall {
+                                        symbol,
+                                        thread: self.current_thread,
+                                        return_addr: self.cpu.regs()[cpu::Cpu::LR],
+                                    });
+                                }
+                            }
                             f.call_from_guest(self);
                             self.threads[self.current_thread].in_host_function =
                                 was_in_host_function;
+                            self.report_debug_heap_problems();
                             // Host function might have put the thread to sleep.
                             if let ThreadBlock::NotBlocked =
                                 self.threads[self.current_thread].blocked_by
@@ -863,22 +1236,41 @@ impl Environment {
             };
             let mut step_and_debug = false;
             while ticks > 0 {
+                if self.debugger_stop_pending {
+                    self.debugger_stop_pending = false;
+                    step_and_debug = self.wait_for_debugger(None);
+                }
+                // "Hardware" breakpoints can be anywhere in a block of
+                // translated code, so the PC has to be checked after every
+                // instruction while there are any.
+                let check_pc = self
+                    .gdb_server
+                    .as_ref()
+                    .is_some_and(|gdb_server| gdb_server.has_hardware_breakpoints());
+                let ticks_before = ticks;
                 let state = self.cpu.run_or_step(
                     &mut self.mem,
-                    if step_and_debug {
+                    if step_and_debug || check_pc {
                         None
                     } else {
                         Some(&mut ticks)
                     },
                 );
+                self.clock.add_ticks(if step_and_debug || check_pc {
+                    1
+                } else {
+                    ticks_before - ticks
+                });
+                if check_pc && !step_and_debug {
+                    ticks -= 1;
+                }
                 match self.handle_cpu_state(state, initial_thread, root) {
                     ThreadNextAction::Continue => {
                         if step_and_debug {
-                            step_and_debug = self.gdb_server.as_mut().unwrap().wait_for_debugger(
-                                None,
-                                &mut self.cpu,
-                                &mut self.mem,
-                            );
+                            step_and_debug = self.wait_for_debugger(None);
+                        } else if check_pc && self.hardware_breakpoint_hit() {
+                            step_and_debug =
+                                self.wait_for_debugger(Some(cpu::CpuError::Breakpoint));
                         }
                     }
                     ThreadNextAction::Yield => break,
@@ -905,6 +1297,7 @@ impl Environment {
                 let mut suitable_thread: Option<ThreadId> = None;
                 let mut next_awakening: Option<Instant> = None;
                 let mut mutex_to_relock: Option<MutexId> = None;
+                let mut cond_wait_result: Option<(MutexId, i32)> = None;
                 for i in 0..self.threads.len() {
                     let i = (self.current_thread + 1 + i) % self.threads.len();
                     let candidate = &mut self.threads[i];
@@ -914,7 +1307,7 @@ impl Environment {
                     }
                     match candidate.blocked_by {
                         ThreadBlock::Sleeping(sleeping_until) => {
-                            if sleeping_until <= Instant::now() {
+                            if sleeping_until <= self.clock.now() {
                                 log_dbg!("Thread {} finished sleeping.", i);
                                 candidate.blocked_by = ThreadBlock::NotBlocked;
                                 suitable_thread = Some(i);
@@ -935,6 +1328,56 @@ impl Environment {
                                 break;
                             }
                         }
+                        ThreadBlock::Cond(cond_id, mutex_id, until) => {
+                            // Signalled threads have already been moved on
+                            // to ThreadBlock::CondRelock.
+                            let result = if until.is_some_and(|until| until <= self.clock.now()) {
+                                log_dbg!(
+                                    "Thread {} timed out waiting on condition variable #{}.",
+                                    i,
+                                    cond_id
+                                );
+                                self.cond_state.remove_waiting_thread(cond_id, i);
+                                libc::errno::ETIMEDOUT
+                            } else {
+                                if let Some(until) = until {
+                                    next_awakening = match next_awakening {
+                                        None => Some(until),
+                                        Some(other) => Some(other.min(until)),
+                                    };
+                                }
+                                continue;
+                            };
+                            self.threads[i].blocked_by = ThreadBlock::CondRelock(mutex_id, result);
+                            if !self.mutex_state.mutex_is_locked(mutex_id) {
+                                self.threads[i].blocked_by = ThreadBlock::NotBlocked;
+                                suitable_thread = Some(i);
+                                cond_wait_result = Some((mutex_id, result));
+                                break;
+                            }
+                        }
+                        ThreadBlock::CondRelock(mutex_id, result) => {
+                            if !self.mutex_state.mutex_is_locked(mutex_id) {
+                                log_dbg!("Thread {} can now relock mutex #{} after waiting on a condition variable.", i, mutex_id);
+                                self.threads[i].blocked_by = ThreadBlock::NotBlocked;
+                                suitable_thread = Some(i);
+                                cond_wait_result = Some((mutex_id, result));
+                                break;
+                            }
+                        }
+                        ThreadBlock::RwLock(rwlock_id, write) => {
+                            if self.rwlock_state.lock_unblocked_rwlock(rwlock_id, i, write) {
+                                log_dbg!(
+                                    "Thread {} was unblocked and took read/write lock #{} (write: {}).",
+                                    i,
+                                    rwlock_id,
+                                    write
+                                );
+                                self.threads[i].blocked_by = ThreadBlock::NotBlocked;
+                                suitable_thread = Some(i);
+                                break;
+                            }
+                        }
                         ThreadBlock::Semaphore(sem) => {
                             let host_sem_rc: &mut _ = self
                                 .libc_state
@@ -975,6 +1418,18 @@ impl Environment {
                                 break;
                             }
                         }
+                        ThreadBlock::ExitingApp => {
+                            if self
+                                .threads
+                                .iter()
+                                .enumerate()
+                                .all(|(j, thread)| j == i || !thread.active)
+                            {
+                                echo!("All threads have exited, exiting.");
+                                self.report_debug_heap_leaks();
+                                std::process::exit(0);
+                            }
+                        }
                         ThreadBlock::DeferredReturn => {
                             if i == initial_thread {
                                 log_dbg!("Thread {} is now able to return, returning", i);
@@ -999,13 +1454,21 @@ impl Environment {
                     if let Some(mutex_id) = mutex_to_relock {
                         self.relock_unblocked_mutex(mutex_id);
                     }
+                    if let Some((mutex_id, result)) = cond_wait_result {
+                        self.lock_mutex(mutex_id).unwrap();
+                        // Overwrite the value returned by the host function
+                        // that started the wait.
+                        self.cpu.regs_mut()[0] = result as u32;
+                    }
                     break;
                 // All suitable threads are blocked and at least one is asleep.
                 // Sleep until one of them wakes up.
                 } else if let Some(next_awakening) = next_awakening {
-                    let duration = next_awakening.duration_since(Instant::now());
-                    log_dbg!("All threads blocked/asleep, sleeping for {:?}.", duration);
-                    std::thread::sleep(duration);
+                    log_dbg!(
+                        "All threads blocked/asleep, sleeping for {:?}.",
+                        next_awakening.saturating_duration_since(self.clock.now())
+                    );
+                    self.clock.sleep_until(next_awakening);
                     // Try again, there should be some thread awake now (or
                     // there will be soon, since timing is approximate).
                     continue;
@@ -0,0 +1,167 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Internal condition variable interface.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use super::{Environment, MutexId, ThreadBlock, ThreadId};
+use crate::libc::errno::{EBUSY, EPERM};
+
+/// Stores and manages condition variables. Like with [super::MutexState], the
+/// methods for waiting on and signalling condition variables are on
+/// [Environment] instead, because they interact with threads.
+#[derive(Default)]
+pub struct CondState {
+    conds: HashMap<CondId, Cond>,
+    cond_count: u64,
+}
+
+/// Unique identifier for condition variables.
+pub type CondId = u64;
+
+struct Cond {
+    /// Threads waiting to be signalled, in the order they started waiting.
+    /// Signalling a thread removes it from here and moves it on to
+    /// [ThreadBlock::CondRelock], so the scheduler never needs to look up a
+    /// condition variable that may since have been destroyed.
+    waiting: VecDeque<ThreadId>,
+}
+
+impl CondState {
+    /// Initializes a condition variable and returns a handle to it. Similar to
+    /// `pthread_cond_init`, but for host code.
+    pub fn init_cond(&mut self) -> CondId {
+        let cond_id = self.cond_count;
+        self.cond_count = self.cond_count.checked_add(1).unwrap();
+        self.conds.insert(
+            cond_id,
+            Cond {
+                waiting: VecDeque::new(),
+            },
+        );
+        log_dbg!("Created condition variable #{}", cond_id);
+        cond_id
+    }
+
+    /// Destroys a condition variable and returns an error on failure (as
+    /// errno). Similar to `pthread_cond_destroy`, but for host code. Note that
+    /// the condition variable is not destroyed on an Err return.
+    pub fn destroy_cond(&mut self, cond_id: CondId) -> Result<(), i32> {
+        let cond = self.conds.get(&cond_id).unwrap();
+        if !cond.waiting.is_empty() {
+            log_dbg!(
+                "Attempted to destroy condition variable #{} with waiting threads, returning EBUSY!",
+                cond_id
+            );
+            return Err(EBUSY);
+        }
+        self.conds.remove(&cond_id);
+        Ok(())
+    }
+
+    /// Stop `thread` waiting, e.g. because it timed out. A thread that is
+    /// still waiting keeps its condition variable from being destroyed.
+    pub fn remove_waiting_thread(&mut self, cond_id: CondId, thread: ThreadId) {
+        self.conds
+            .get_mut(&cond_id)
+            .unwrap()
+            .waiting
+            .retain(|&waiting| waiting != thread);
+    }
+}
+
+impl Environment {
+    /// Unlock a mutex and block the current thread until the condition
+    /// variable is signalled or the time `until` (if any) is reached, then
+    /// relock the mutex. Returns an error (as errno) if the mutex could not be
+    /// unlocked. Similar to `pthread_cond_timedwait`, but for host code.
+    ///
+    /// The mutex must be locked exactly once by the current thread.
+    ///
+    /// Like [Self::block_on_mutex], this only takes effect after the host
+    /// function returns to the main run loop ([Environment::run]). Once the
+    /// wait is over, the guest will see either `0` or `ETIMEDOUT` as the
+    /// return value, in place of whatever the host function returned.
+    pub fn wait_on_cond(
+        &mut self,
+        cond_id: CondId,
+        mutex_id: MutexId,
+        until: Option<Instant>,
+    ) -> Result<(), i32> {
+        assert!(matches!(
+            self.threads[self.current_thread].blocked_by,
+            ThreadBlock::NotBlocked
+        ));
+        match self.unlock_mutex(mutex_id)? {
+            0 => (),
+            _ => {
+                // The mutex would still be locked while waiting, so nobody
+                // could ever signal us.
+                log!(
+                    "Warning: Attempted to wait on condition variable #{} with recursive mutex #{} locked more than once, returning EPERM.",
+                    cond_id,
+                    mutex_id
+                );
+                self.lock_mutex(mutex_id).unwrap();
+                return Err(EPERM);
+            }
+        }
+        log_dbg!(
+            "Thread {} waiting on condition variable #{} (mutex #{}, until {:?}).",
+            self.current_thread,
+            cond_id,
+            mutex_id,
+            until
+        );
+        self.cond_state
+            .conds
+            .get_mut(&cond_id)
+            .unwrap()
+            .waiting
+            .push_back(self.current_thread);
+        self.threads[self.current_thread].blocked_by = ThreadBlock::Cond(cond_id, mutex_id, until);
+        Ok(())
+    }
+
+    /// Wake up one thread waiting on a condition variable, if there are any.
+    /// Similar to `pthread_cond_signal`, but for host code.
+    pub fn signal_cond(&mut self, cond_id: CondId) {
+        let cond = self.cond_state.conds.get_mut(&cond_id).unwrap();
+        if let Some(thread) = cond.waiting.pop_front() {
+            log_dbg!(
+                "Signalled condition variable #{}, waking thread {}.",
+                cond_id,
+                thread
+            );
+            self.wake_cond_waiter(thread);
+        }
+    }
+
+    /// Wake up all threads waiting on a condition variable. Similar to
+    /// `pthread_cond_broadcast`, but for host code.
+    pub fn broadcast_cond(&mut self, cond_id: CondId) {
+        let cond = self.cond_state.conds.get_mut(&cond_id).unwrap();
+        log_dbg!(
+            "Broadcast condition variable #{}, waking threads {:?}.",
+            cond_id,
+            cond.waiting
+        );
+        let threads = std::mem::take(&mut cond.waiting);
+        for thread in threads {
+            self.wake_cond_waiter(thread);
+        }
+    }
+
+    /// Move a thread that was waiting on a condition variable on to relocking
+    /// its mutex.
+    fn wake_cond_waiter(&mut self, thread: ThreadId) {
+        let ThreadBlock::Cond(_cond_id, mutex_id, _until) = self.threads[thread].blocked_by else {
+            panic!("Thread {} is not waiting on a condition variable", thread);
+        };
+        self.threads[thread].blocked_by = ThreadBlock::CondRelock(mutex_id, 0);
+    }
+}
@@ -0,0 +1,177 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! touchHLE-specific debugger commands, sent with `monitor` in GDB (see
+//! [crate::gdb]). These can use the whole emulator's state, so unlike the rest
+//! of the debugger protocol, they're handled here rather than in the GDB
+//! server.
+
+use super::Environment;
+use crate::frameworks::foundation::ns_string;
+use crate::mem::Ptr;
+use crate::objc::{id, msg, MessageBreakpoint, ObjC};
+use std::fmt::Write;
+
+const HELP: &str = "\
+touchHLE monitor commands:
+  po <address>               Print an Objective-C object's class and description
+  classes                    List the Objective-C classes loaded so far
+  selectors [<pattern>]      List selectors containing <pattern>
+  threads                    List the threads and what they're waiting for
+  heap                       Show heap allocator statistics
+  break-objc [<method>]      Stop when a method is called, e.g.
+                             break-objc -[UIView addSubview:]
+                             (with no method, list these breakpoints)
+  delete-objc <method>       Remove a breakpoint set with break-objc
+  save-state <path>          Save the emulator state to a file on the host,
+                             to be loaded with --load-state= (only for
+                             programs that just use simple parts of libc)
+";
+
+/// Parse an address in hexadecimal (with `0x`) or decimal.
+fn parse_address(arg: &str) -> Option<u32> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+impl Environment {
+    /// Run a command requested by the debugger, returning its output.
+    pub(super) fn run_monitor_command(&mut self, command: &str) -> String {
+        let command = command.trim();
+        let (name, arg) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(name, arg)| (name, arg.trim()));
+        match name {
+            "" | "help" => HELP.to_string(),
+            "po" => self.monitor_po(arg),
+            "classes" => {
+                let classes = self.objc.describe_classes();
+                let mut output = format!("{} classes loaded:\n", classes.len());
+                for class in classes {
+                    writeln!(output, "{}", class).unwrap();
+                }
+                output
+            }
+            "selectors" => {
+                let mut output = String::new();
+                for selector in self.objc.selectors_matching(arg) {
+                    writeln!(output, "{}", selector).unwrap();
+                }
+                if output.is_empty() {
+                    output = format!("No selectors contain {:?}.\n", arg);
+                }
+                output
+            }
+            "threads" => {
+                let mut output = String::new();
+                for line in self.thread_summary() {
+                    writeln!(output, "{}", line).unwrap();
+                }
+                output
+            }
+            "heap" => {
+                let stats = self.mem.allocator_stats();
+                format!(
+                    "In use: {} bytes in {} chunks (including reserved regions, \
+                     e.g. the app binary and thread stacks)\n\
+                     Free: {} bytes in {} chunks, the largest being {} bytes\n",
+                    stats.used_bytes,
+                    stats.used_chunks,
+                    stats.free_bytes,
+                    stats.free_chunks,
+                    stats.largest_free_chunk
+                )
+            }
+            "break-objc" if arg.is_empty() => {
+                let mut output = String::new();
+                for breakpoint in self.objc.message_breakpoints() {
+                    writeln!(output, "{}", breakpoint).unwrap();
+                }
+                if output.is_empty() {
+                    output = "No Objective-C breakpoints.\n".to_string();
+                }
+                output
+            }
+            "break-objc" | "delete-objc" => {
+                let Some(breakpoint) = MessageBreakpoint::parse(arg) else {
+                    return format!(
+                        "Couldn't parse {:?}, expected something like -[UIView addSubview:]\n",
+                        arg
+                    );
+                };
+                let description = breakpoint.to_string();
+                if name == "break-objc" {
+                    if self.objc.add_message_breakpoint(breakpoint) {
+                        format!("Breakpoint set on {}.\n", description)
+                    } else {
+                        format!("There's already a breakpoint on {}.\n", description)
+                    }
+                } else if self.objc.remove_message_breakpoint(&breakpoint) {
+                    format!("Breakpoint on {} removed.\n", description)
+                } else {
+                    format!("There's no breakpoint on {}.\n", description)
+                }
+            }
+            "save-state" if arg.is_empty() => "Expected a path to save to.\n".to_string(),
+            "save-state" => match self.save_state(arg) {
+                Ok(()) => format!("Saved state to {:?}.\n", arg),
+                Err(e) => format!("Couldn't save state: {}.\n", e),
+            },
+            _ => format!("Unknown command {:?}. Try \"monitor help\".\n", name),
+        }
+    }
+
+    fn monitor_po(&mut self, arg: &str) -> String {
+        let Some(addr) = parse_address(arg) else {
+            return "Usage: monitor po <address>\n".to_string();
+        };
+        let object: id = Ptr::from_bits(addr);
+        if !self.objc.object_exists(object) {
+            return format!("{:?} is not an Objective-C object.\n", object);
+        }
+        let class = ObjC::read_isa(object, &self.mem);
+        let summary = format!("<{}: {:?}>", self.objc.get_class_name(class), object);
+        if !self.objc.class_is_implemented(class) {
+            return format!("{} (class is not implemented)\n", summary);
+        }
+        if !self
+            .objc
+            .object_has_method_named(&self.mem, object, "description")
+        {
+            return format!("{} (no description method)\n", summary);
+        }
+        // This may run guest code, if the app overrides the method.
+        let description: id = msg![self; object description];
+        let description = ns_string::to_rust_string(self, description);
+        format!("{}\n{}\n", summary, description)
+    }
+
+    /// Called by `objc_msgSend` when a message send resolves to a method with
+    /// a breakpoint set by `monitor break-objc`. For a method in the guest app,
+    /// execution stops at the method's first instruction. touchHLE's own
+    /// methods don't have any instructions, so execution stops before the
+    /// call instead, and stepping skips over the whole method.
+    pub fn hit_message_breakpoint(&mut self, breakpoint: &str, receiver: id, is_host_method: bool) {
+        let Some(ref gdb_server) = self.gdb_server else {
+            return;
+        };
+        if gdb_server.monitor_command_running() {
+            // e.g. `monitor po` called the method
+            log!(
+                "Ignoring breakpoint on {} while running a monitor command.",
+                breakpoint
+            );
+            return;
+        }
+        echo!("Hit breakpoint on {}, receiver {:?}.", breakpoint, receiver);
+        if is_host_method {
+            self.debugger_stop_pending = self.wait_for_debugger(None);
+        } else {
+            self.debugger_stop_pending = true;
+        }
+    }
+}
@@ -102,6 +102,14 @@ impl MutexState {
             .get(&mutex_id)
             .map_or(false, |mutex| mutex.locked.is_some())
     }
+
+    /// Which thread holds the mutex, if any. This is only meant for debugging.
+    pub fn mutex_owner(&self, mutex_id: MutexId) -> Option<ThreadId> {
+        self.mutexes
+            .get(&mutex_id)
+            .and_then(|mutex| mutex.locked)
+            .map(|(thread, _)| thread)
+    }
 }
 
 impl Environment {
@@ -166,6 +174,29 @@ impl Environment {
         Ok(1)
     }
 
+    /// Locks a mutex if that can be done without blocking, and returns the
+    /// lock count or an error (as errno). Similar to `pthread_mutex_trylock`,
+    /// but for host code.
+    pub fn try_lock_mutex(&mut self, mutex_id: MutexId) -> Result<u32, i32> {
+        let current_thread = self.current_thread;
+        let mutex = self.mutex_state.mutexes.get(&mutex_id).unwrap();
+        match mutex.locked {
+            Some((locking_thread, _))
+                if locking_thread != current_thread
+                    || mutex.type_ != MutexType::PTHREAD_MUTEX_RECURSIVE =>
+            {
+                log_dbg!(
+                    "Attempted to try-lock mutex #{} for thread {}, already locked by thread {}! Returning EBUSY.",
+                    mutex_id,
+                    current_thread,
+                    locking_thread,
+                );
+                Err(EBUSY)
+            }
+            _ => self.lock_mutex(mutex_id),
+        }
+    }
+
     /// Unlocks a mutex and returns the lock count or an error (as errno).
     /// Similar to `pthread_mutex_unlock`, but for host code.
     pub fn unlock_mutex(&mut self, mutex_id: MutexId) -> Result<u32, i32> {
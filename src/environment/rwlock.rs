@@ -0,0 +1,212 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Internal read/write lock interface.
+
+use std::collections::HashMap;
+
+use super::{Environment, ThreadBlock, ThreadId};
+use crate::libc::errno::{EBUSY, EDEADLK, EPERM};
+
+/// Stores and manages read/write locks. Like with [super::MutexState], the
+/// methods for locking and unlocking are on [Environment] instead, because
+/// they interact with threads.
+#[derive(Default)]
+pub struct RwLockState {
+    rwlocks: HashMap<RwLockId, RwLock>,
+    rwlock_count: u64,
+}
+
+/// Unique identifier for read/write locks.
+pub type RwLockId = u64;
+
+#[derive(Default)]
+struct RwLock {
+    /// Threads holding a read lock, with their lock counts. A thread can hold
+    /// more than one read lock at a time.
+    readers: HashMap<ThreadId, u32>,
+    writer: Option<ThreadId>,
+    /// Number of threads blocked waiting for a lock. Used to prevent
+    /// destruction of a lock that is in use.
+    waiting_count: u32,
+}
+
+impl RwLock {
+    /// Whether `thread` could take the lock right now. This doesn't check for
+    /// deadlocks.
+    fn can_lock(&self, thread: ThreadId, write: bool) -> bool {
+        if write {
+            self.writer.is_none() && self.readers.keys().all(|&reader| reader == thread)
+        } else {
+            self.writer.is_none()
+        }
+    }
+
+    fn lock(&mut self, thread: ThreadId, write: bool) {
+        if write {
+            self.writer = Some(thread);
+        } else {
+            *self.readers.entry(thread).or_default() += 1;
+        }
+    }
+}
+
+impl RwLockState {
+    /// Initializes a read/write lock and returns a handle to it. Similar to
+    /// `pthread_rwlock_init`, but for host code.
+    pub fn init_rwlock(&mut self) -> RwLockId {
+        let rwlock_id = self.rwlock_count;
+        self.rwlock_count = self.rwlock_count.checked_add(1).unwrap();
+        self.rwlocks.insert(rwlock_id, Default::default());
+        log_dbg!("Created read/write lock #{}", rwlock_id);
+        rwlock_id
+    }
+
+    /// Destroys a read/write lock and returns an error on failure (as errno).
+    /// Similar to `pthread_rwlock_destroy`, but for host code. Note that the
+    /// lock is not destroyed on an Err return.
+    pub fn destroy_rwlock(&mut self, rwlock_id: RwLockId) -> Result<(), i32> {
+        let rwlock = self.rwlocks.get(&rwlock_id).unwrap();
+        if rwlock.writer.is_some() || !rwlock.readers.is_empty() || rwlock.waiting_count != 0 {
+            log_dbg!(
+                "Attempted to destroy read/write lock #{} while in use, returning EBUSY!",
+                rwlock_id
+            );
+            return Err(EBUSY);
+        }
+        self.rwlocks.remove(&rwlock_id);
+        Ok(())
+    }
+
+    /// Which threads are preventing `thread` from taking the lock. This is only
+    /// meant for debugging.
+    pub fn rwlock_blockers(
+        &self,
+        rwlock_id: RwLockId,
+        thread: ThreadId,
+        write: bool,
+    ) -> Vec<ThreadId> {
+        let Some(rwlock) = self.rwlocks.get(&rwlock_id) else {
+            return Vec::new();
+        };
+        let mut blockers: Vec<ThreadId> = rwlock.writer.into_iter().collect();
+        if write {
+            blockers.extend(rwlock.readers.keys().filter(|&&reader| reader != thread));
+        }
+        blockers
+    }
+
+    /// Take the lock for a thread that was blocked on it, if it's now
+    /// available. Returns [true] on success. This should probably only be used
+    /// by the thread scheduler.
+    pub fn lock_unblocked_rwlock(
+        &mut self,
+        rwlock_id: RwLockId,
+        thread: ThreadId,
+        write: bool,
+    ) -> bool {
+        let rwlock = self.rwlocks.get_mut(&rwlock_id).unwrap();
+        if !rwlock.can_lock(thread, write) {
+            return false;
+        }
+        rwlock.lock(thread, write);
+        rwlock.waiting_count -= 1;
+        true
+    }
+}
+
+impl Environment {
+    /// Takes a read lock (if `write` is [false]) or a write lock (if `write`
+    /// is [true]) on a read/write lock, or returns an error (as errno).
+    /// Similar to `pthread_rwlock_rdlock`/`pthread_rwlock_wrlock` (or the
+    /// `try` variants if `wait` is [false]), but for host code.
+    ///
+    /// Like [Self::lock_mutex], if this has to block, it only takes effect
+    /// after the calling function returns to the host run loop
+    /// ([crate::Environment::run]).
+    pub fn lock_rwlock(&mut self, rwlock_id: RwLockId, write: bool, wait: bool) -> Result<(), i32> {
+        let current_thread = self.current_thread;
+        let rwlock = self.rwlock_state.rwlocks.get_mut(&rwlock_id).unwrap();
+
+        if rwlock.writer == Some(current_thread)
+            || (write && rwlock.readers.contains_key(&current_thread))
+        {
+            log_dbg!(
+                "Thread {} attempted to lock read/write lock #{} (write: {}) that it already holds, returning EDEADLK!",
+                current_thread,
+                rwlock_id,
+                write
+            );
+            return Err(EDEADLK);
+        }
+
+        if rwlock.can_lock(current_thread, write) {
+            log_dbg!(
+                "Locked read/write lock #{} (write: {}) for thread {}.",
+                rwlock_id,
+                write,
+                current_thread
+            );
+            rwlock.lock(current_thread, write);
+            return Ok(());
+        }
+
+        if !wait {
+            log_dbg!(
+                "Read/write lock #{} is busy, returning EBUSY to thread {}.",
+                rwlock_id,
+                current_thread
+            );
+            return Err(EBUSY);
+        }
+
+        // This is subtracted in lock_unblocked_rwlock.
+        rwlock.waiting_count += 1;
+
+        assert!(matches!(
+            self.threads[current_thread].blocked_by,
+            ThreadBlock::NotBlocked
+        ));
+        log_dbg!(
+            "Thread {} blocking on read/write lock #{} (write: {}).",
+            current_thread,
+            rwlock_id,
+            write
+        );
+        self.threads[current_thread].blocked_by = ThreadBlock::RwLock(rwlock_id, write);
+        self.check_for_deadlock();
+        Ok(())
+    }
+
+    /// Releases the current thread's lock on a read/write lock, or returns an
+    /// error (as errno). Similar to `pthread_rwlock_unlock`, but for host
+    /// code.
+    pub fn unlock_rwlock(&mut self, rwlock_id: RwLockId) -> Result<(), i32> {
+        let current_thread = self.current_thread;
+        let rwlock = self.rwlock_state.rwlocks.get_mut(&rwlock_id).unwrap();
+
+        if rwlock.writer == Some(current_thread) {
+            rwlock.writer = None;
+        } else if let Some(count) = rwlock.readers.get_mut(&current_thread) {
+            *count -= 1;
+            if *count == 0 {
+                rwlock.readers.remove(&current_thread);
+            }
+        } else {
+            log_dbg!(
+                "Thread {} attempted to unlock read/write lock #{} that it doesn't hold, returning EPERM!",
+                current_thread,
+                rwlock_id
+            );
+            return Err(EPERM);
+        }
+        log_dbg!(
+            "Unlocked read/write lock #{} for thread {}.",
+            rwlock_id,
+            current_thread
+        );
+        Ok(())
+    }
+}
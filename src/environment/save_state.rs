@@ -0,0 +1,190 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Saving and loading the whole emulator state (see [crate::save_state] for
+//! what's included).
+
+use super::{Environment, Thread, ThreadBlock};
+use crate::cpu::CpuContext;
+use crate::save_state::{Reader, Writer};
+use std::path::Path;
+
+/// Registers of a thread, in the form they're saved in.
+struct ThreadRegs {
+    regs: [u32; 16],
+    cpsr: u32,
+    ext_regs: [u32; 64],
+    fpscr: u32,
+}
+
+impl ThreadRegs {
+    fn save_state(&self, w: &mut Writer) {
+        for &reg in self.regs.iter().chain(&self.ext_regs) {
+            w.u32(reg);
+        }
+        w.u32(self.cpsr);
+        w.u32(self.fpscr);
+    }
+
+    fn load_state(r: &mut Reader) -> Result<ThreadRegs, String> {
+        let mut regs = [0; 16];
+        let mut ext_regs = [0; 64];
+        for reg in regs.iter_mut().chain(&mut ext_regs) {
+            *reg = r.u32()?;
+        }
+        Ok(ThreadRegs {
+            regs,
+            ext_regs,
+            cpsr: r.u32()?,
+            fpscr: r.u32()?,
+        })
+    }
+}
+
+impl Environment {
+    /// Save the emulator state to a file, so it can be loaded with
+    /// `--load-state=`. This is only possible when the app is stopped in guest
+    /// code, i.e. from the debugger, and when it hasn't used anything with
+    /// state that can't be saved.
+    pub fn save_state(&mut self, path: &str) -> Result<(), String> {
+        if self.host_calls_in_progress > 0 {
+            return Err("The app is running inside a call to a host function".to_string());
+        }
+        if let Some(id) = (0..self.threads.len()).find(|&id| {
+            let thread = &self.threads[id];
+            thread.active && (thread.is_blocked() || thread.in_host_function)
+        }) {
+            return Err(format!("Thread {} is waiting for something", id));
+        }
+
+        let mut w = Writer::new();
+        w.str(self.bundle.bundle_identifier());
+        w.u32(self.bins.len().try_into().unwrap());
+        for bin in &self.bins {
+            w.str(&bin.name);
+        }
+
+        w.u32(self.current_thread.try_into().unwrap());
+        w.u32(self.threads.len().try_into().unwrap());
+        for (id, thread) in self.threads.iter().enumerate() {
+            w.bool(thread.active);
+            w.bool(thread.in_start_routine);
+            w.option_u32(thread.return_value.map(|value| value.to_bits()));
+            w.bool(thread.stack.is_some());
+            if let Some(ref stack) = thread.stack {
+                w.u32(*stack.start());
+                w.u32(*stack.end());
+            }
+            let regs = if id == self.current_thread {
+                ThreadRegs {
+                    regs: *self.cpu.regs(),
+                    cpsr: self.cpu.cpsr(),
+                    ext_regs: *self.cpu.ext_regs(),
+                    fpscr: self.cpu.fpscr(),
+                }
+            } else {
+                let context = thread.context.as_ref().unwrap();
+                ThreadRegs {
+                    regs: *context.regs(),
+                    cpsr: context.cpsr(),
+                    ext_regs: *context.ext_regs(),
+                    fpscr: context.fpscr(),
+                }
+            };
+            regs.save_state(&mut w);
+        }
+
+        self.mem.save_state(&mut w)?;
+        self.dyld.save_state(&mut w, self.stubs.as_mut())?;
+        self.libc_state.save_state(&mut w);
+
+        std::fs::write(path, w.into_bytes())
+            .map_err(|e| format!("Couldn't write {:?}: {}", path, e))
+    }
+
+    /// Replace the state of a newly-created environment with one saved by
+    /// [Self::save_state], for the same app.
+    pub(super) fn load_state(&mut self, path: &Path) -> Result<(), String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Couldn't read {:?}: {}", path, e))?;
+        let mut r = Reader::new(&bytes)?;
+
+        let bundle_identifier = r.str()?;
+        if bundle_identifier != self.bundle.bundle_identifier() {
+            return Err(format!(
+                "Save state is for a different app, {}",
+                bundle_identifier
+            ));
+        }
+        let bin_count = r.u32()? as usize;
+        let mut bin_names = Vec::with_capacity(bin_count);
+        for _ in 0..bin_count {
+            bin_names.push(r.str()?);
+        }
+        if !bin_names
+            .iter()
+            .copied()
+            .eq(self.bins.iter().map(|bin| bin.name.as_str()))
+        {
+            return Err("Save state was made with different binaries loaded".to_string());
+        }
+
+        let current_thread = r.u32()? as usize;
+        let thread_count = r.u32()? as usize;
+        if current_thread >= thread_count {
+            return Err("Save state file is corrupt".to_string());
+        }
+        let mut threads = Vec::with_capacity(thread_count);
+        let mut current_regs = None;
+        for id in 0..thread_count {
+            let active = r.bool()?;
+            let in_start_routine = r.bool()?;
+            let return_value = r.option_u32()?.map(crate::mem::Ptr::from_bits);
+            let stack = if r.bool()? {
+                Some(r.u32()?..=r.u32()?)
+            } else {
+                None
+            };
+            let regs = ThreadRegs::load_state(&mut r)?;
+            let context = if id == current_thread {
+                current_regs = Some(regs);
+                None
+            } else {
+                let mut context = CpuContext::new();
+                *context.regs_mut() = regs.regs;
+                context.set_cpsr(regs.cpsr);
+                *context.ext_regs_mut() = regs.ext_regs;
+                context.set_fpscr(regs.fpscr);
+                Some(context)
+            };
+            threads.push(Thread {
+                active,
+                blocked_by: ThreadBlock::NotBlocked,
+                in_start_routine,
+                return_value,
+                in_host_function: false,
+                context,
+                stack,
+            });
+        }
+
+        self.mem.load_state(&mut r)?;
+        self.dyld.load_state(&mut r)?;
+        self.libc_state.load_state(&mut r)?;
+        r.finish()?;
+
+        let regs = current_regs.unwrap();
+        *self.cpu.regs_mut() = regs.regs;
+        self.cpu.set_cpsr(regs.cpsr);
+        *self.cpu.ext_regs_mut() = regs.ext_regs;
+        self.cpu.set_fpscr(regs.fpscr);
+        // Code may have been translated from what was in memory before.
+        self.cpu.invalidate_cache_range(0, u32::MAX);
+        self.threads = threads;
+        self.current_thread = current_thread;
+
+        log!("Loaded state from {:?}.", path);
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Diagnostics for threading problems: describing why threads are blocked,
+//! detecting deadlocks, and dumping the state of every thread.
+
+use super::{Environment, ThreadBlock, ThreadId};
+
+impl Environment {
+    /// Human-readable description of what a thread is currently doing.
+    pub(super) fn describe_thread_state(&self, thread: ThreadId) -> String {
+        let thread_info = &self.threads[thread];
+        if !thread_info.active {
+            return "finished".to_string();
+        }
+        let describe_mutex = |mutex_id| match self.mutex_state.mutex_owner(mutex_id) {
+            Some(owner) => format!("mutex #{} (held by thread {})", mutex_id, owner),
+            None => format!("mutex #{} (not held)", mutex_id),
+        };
+        match thread_info.blocked_by {
+            ThreadBlock::NotBlocked if thread == self.current_thread => "running".to_string(),
+            ThreadBlock::NotBlocked => "runnable".to_string(),
+            ThreadBlock::Sleeping(until) => format!(
+                "sleeping for {:?}",
+                until.saturating_duration_since(self.clock.now())
+            ),
+            ThreadBlock::Mutex(mutex_id) => format!("waiting for {}", describe_mutex(mutex_id)),
+            ThreadBlock::Cond(cond_id, mutex_id, until) => format!(
+                "waiting on condition variable #{} with mutex #{}{}",
+                cond_id,
+                mutex_id,
+                match until {
+                    Some(until) => format!(
+                        ", timing out in {:?}",
+                        until.saturating_duration_since(self.clock.now())
+                    ),
+                    None => String::new(),
+                }
+            ),
+            ThreadBlock::CondRelock(mutex_id, _) => {
+                format!("signalled, waiting to relock {}", describe_mutex(mutex_id))
+            }
+            ThreadBlock::RwLock(rwlock_id, write) => format!(
+                "waiting for {} lock on read/write lock #{} (blocked by threads {:?})",
+                if write { "write" } else { "read" },
+                rwlock_id,
+                self.rwlock_state.rwlock_blockers(rwlock_id, thread, write)
+            ),
+            ThreadBlock::Semaphore(sem) => {
+                match self.libc_state.semaphore.open_semaphores.get(&sem) {
+                    Some(host_sem) => format!(
+                        "waiting on semaphore {:?} (value {})",
+                        sem,
+                        host_sem.borrow().value
+                    ),
+                    None => format!("waiting on semaphore {:?} (not open)", sem),
+                }
+            }
+            ThreadBlock::Joining(joinee, _) => format!("joining thread {}", joinee),
+            ThreadBlock::DeferredReturn => "waiting to return to host".to_string(),
+            ThreadBlock::ExitingApp => "exited, waiting for other threads to finish".to_string(),
+        }
+    }
+
+    /// Threads that must make progress before `thread` can stop being
+    /// blocked. This only covers blocks with a definite owner (mutexes,
+    /// read/write locks, joins and app exit), so a thread waiting on a
+    /// semaphore or condition variable has no outgoing edges in this wait-for
+    /// graph.
+    fn threads_waited_for(&self, thread: ThreadId) -> Vec<ThreadId> {
+        match self.threads[thread].blocked_by {
+            ThreadBlock::Mutex(mutex_id) | ThreadBlock::CondRelock(mutex_id, _) => {
+                self.mutex_state.mutex_owner(mutex_id).into_iter().collect()
+            }
+            ThreadBlock::RwLock(rwlock_id, write) => {
+                self.rwlock_state.rwlock_blockers(rwlock_id, thread, write)
+            }
+            ThreadBlock::Joining(joinee, _) if self.threads[joinee].active => vec![joinee],
+            ThreadBlock::ExitingApp => (0..self.threads.len())
+                .filter(|&other| other != thread && self.threads[other].active)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Look for a cycle in the wait-for graph that passes through `start`.
+    /// If there is one, `start` can never be woken up. The result lists the
+    /// threads in the cycle, beginning with `start`.
+    fn find_wait_cycle(&self, start: ThreadId) -> Option<Vec<ThreadId>> {
+        let mut path = vec![start];
+        let mut visited = vec![false; self.threads.len()];
+        let mut stack = vec![self.threads_waited_for(start)];
+        while let Some(next) = stack.last_mut() {
+            let Some(thread) = next.pop() else {
+                stack.pop();
+                path.pop();
+                continue;
+            };
+            if thread == start {
+                return Some(path);
+            }
+            if visited[thread] {
+                continue;
+            }
+            visited[thread] = true;
+            path.push(thread);
+            stack.push(self.threads_waited_for(thread));
+        }
+        None
+    }
+
+    fn describe_wait_cycle(&self, cycle: &[ThreadId]) -> String {
+        cycle
+            .iter()
+            .map(|&thread| format!("thread {} ({})", thread, self.describe_thread_state(thread)))
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+
+    /// Check whether the current thread, which has just become blocked, is
+    /// now part of a deadlock, and warn about it if so. Catching this early
+    /// is useful because other threads can keep running for a long time
+    /// before the whole app deadlocks, if it ever does.
+    pub(super) fn check_for_deadlock(&self) {
+        if let Some(cycle) = self.find_wait_cycle(self.current_thread) {
+            log!(
+                "Warning: Deadlock detected! {}",
+                self.describe_wait_cycle(&cycle)
+            );
+        }
+    }
+
+    /// A line for each thread saying what it's doing, followed by a line for
+    /// each deadlock between them.
+    pub(super) fn thread_summary(&self) -> Vec<String> {
+        let mut lines: Vec<String> = (0..self.threads.len())
+            .map(|thread| format!("Thread {}: {}", thread, self.describe_thread_state(thread)))
+            .collect();
+
+        for thread in 0..self.threads.len() {
+            let Some(cycle) = self.find_wait_cycle(thread) else {
+                continue;
+            };
+            // Each cycle would otherwise be listed once per member.
+            if cycle.iter().all(|&other| other >= thread) {
+                lines.push(format!("Deadlock: {}", self.describe_wait_cycle(&cycle)));
+            }
+        }
+        lines
+    }
+
+    /// Print the state, registers and a stack trace for every thread, plus any
+    /// deadlocks between them. Used when the emulator panics.
+    pub(super) fn dump_threads(&self) {
+        echo!("State of all {} threads:", self.threads.len());
+        for line in self.thread_summary() {
+            echo!("{}", line);
+        }
+
+        for thread in 0..self.threads.len() {
+            if !self.threads[thread].active {
+                continue;
+            }
+            echo!("Registers for thread {}:", thread);
+            if thread == self.current_thread {
+                self.cpu.dump_regs();
+                self.stack_trace();
+                continue;
+            }
+            // The context could be missing if the panic happened while
+            // switching threads.
+            let Some(ref context) = self.threads[thread].context else {
+                echo!("(unavailable)");
+                continue;
+            };
+            context.dump_regs();
+            self.stack_trace_for_thread(thread, context.regs(), context.cpsr());
+        }
+    }
+}
@@ -37,6 +37,11 @@ pub(super) struct State {
 ///
 /// Returns the time a recomposite is due, if any.
 pub fn recomposite_if_necessary(env: &mut Environment) -> Option<Instant> {
+    if env.window.is_none() {
+        log_dbg!("Headless mode, skipping composition");
+        return None;
+    }
+
     // Assumes the last window in the list is the one on top.
     // TODO: this is not correct once we support zPosition.
     // TODO: can there be windows smaller than the screen? If so we need to draw
@@ -68,7 +73,7 @@ pub fn recomposite_if_necessary(env: &mut Environment) -> Option<Instant> {
             .count_frame(format_args!("Core Animation compositor"));
     }
 
-    let now = Instant::now();
+    let now = env.clock.now();
     let interval = 1.0 / 60.0; // 60Hz
     let new_recomposite_next = if let Some(recomposite_next) = env
         .framework_state
@@ -21,9 +21,11 @@ pub mod ns_date;
 pub mod ns_dictionary;
 pub mod ns_enumerator;
 pub mod ns_file_manager;
+pub mod ns_invocation;
 pub mod ns_keyed_unarchiver;
 pub mod ns_locale;
 pub mod ns_log;
+pub mod ns_method_signature;
 pub mod ns_notification;
 pub mod ns_notification_center;
 pub mod ns_null;
@@ -34,7 +34,7 @@ pub const CLASSES: ClassExports = objc_classes! {
 // - (id)objectAtIndex:(NSUInteger)index;
 // We can pick whichever subclass we want for the various alloc methods.
 // For the time being, that will always be _touchHLE_NSArray.
-@implementation NSArray: NSObject
+@implementation NSArray: NSObject <NSCopying, NSCoding>
 
 + (id)allocWithZone:(NSZonePtr)zone {
     // NSArray might be subclassed by something which needs allocWithZone:
@@ -23,7 +23,7 @@ pub const CLASSES: ClassExports = objc_classes! {
 
 + (id)date {
     let host_object = Box::new(NSDateHostObject {
-        instant: Instant::now()
+        instant: env.clock.now()
     });
     let new = env.objc.alloc_object(this, host_object, &mut env.mem);
 
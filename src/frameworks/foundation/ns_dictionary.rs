@@ -123,7 +123,7 @@ pub const CLASSES: ClassExports = objc_classes! {
 // - (NSEnumerator*)keyEnumerator
 // We can pick whichever subclass we want for the various alloc methods.
 // For the time being, that will always be _touchHLE_NSDictionary.
-@implementation NSDictionary: NSObject
+@implementation NSDictionary: NSObject <NSCopying>
 
 + (id)allocWithZone:(NSZonePtr)zone {
     // NSDictionary might be subclassed by something which needs allocWithZone:
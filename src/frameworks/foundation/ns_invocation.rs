@@ -0,0 +1,350 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! `NSInvocation`.
+//!
+//! The arguments are stored in a "frame" in guest memory that has the same
+//! layout as the arguments would have when passed to `objc_msgSend`, so
+//! invoking the message just means copying the frame into the registers and
+//! the stack.
+//!
+//! Message forwarding (`forwardInvocation:`) is the main reason apps encounter
+//! this class. The runtime side of that is in `objc_msgSend`.
+
+use super::ns_method_signature::NSMethodSignatureHostObject;
+use super::NSInteger;
+use crate::abi::{extend_stack_for_args, write_next_arg};
+use crate::cpu::Cpu;
+use crate::mem::{ConstPtr, ConstVoidPtr, GuestUSize, MutPtr, MutVoidPtr};
+use crate::objc::{
+    autorelease, id, msg, msg_class, msg_send_from_registers, nil, objc_classes, release, retain,
+    ClassExports, HostObject, SEL,
+};
+use crate::stubs::exception_not_raised;
+use crate::Environment;
+
+struct NSInvocationHostObject {
+    signature: id,
+    /// Guest memory for the arguments. See the module documentation.
+    frame: MutPtr<u8>,
+    /// Guest memory for the return value.
+    return_value: MutPtr<u8>,
+    arguments_retained: bool,
+}
+impl HostObject for NSInvocationHostObject {}
+
+/// Allocate zero-initialized guest memory. The size may be zero.
+fn alloc_zeroed(env: &mut Environment, size: GuestUSize) -> MutPtr<u8> {
+    let ptr: MutPtr<u8> = env.mem.alloc(size.max(1)).cast();
+    env.mem.bytes_at_mut(ptr, size).fill(0);
+    ptr
+}
+
+/// Get a pointer to an argument in the frame and its size, or [None] if the
+/// index is out of range.
+fn argument_ptr(
+    env: &Environment,
+    invocation: id,
+    index: NSInteger,
+) -> Option<(MutPtr<u8>, GuestUSize)> {
+    let host_object = env.objc.borrow::<NSInvocationHostObject>(invocation);
+    let signature = env
+        .objc
+        .borrow::<NSMethodSignatureHostObject>(host_object.signature);
+    let Some(index) = GuestUSize::try_from(index)
+        .ok()
+        .filter(|&index| index < signature.number_of_arguments())
+    else {
+        let message = format!("NSInvocation: argument index {} out of range", index);
+        // TODO: raise NSInvalidArgumentException
+        exception_not_raised(env, &message);
+        return None;
+    };
+    Some((
+        host_object.frame + signature.argument_frame_offset(index),
+        signature.argument_size(index),
+    ))
+}
+
+fn return_value_ptr(env: &Environment, invocation: id) -> (MutPtr<u8>, GuestUSize) {
+    let host_object = env.objc.borrow::<NSInvocationHostObject>(invocation);
+    let signature = env
+        .objc
+        .borrow::<NSMethodSignatureHostObject>(host_object.signature);
+    (host_object.return_value, signature.return_length())
+}
+
+/// Write the first (up to) 8 bytes of a return value to r0 and r1.
+fn return_value_to_regs(env: &mut Environment, return_value: ConstPtr<u8>, size: GuestUSize) {
+    let mut bytes = [0u8; 8];
+    let size = size.min(8);
+    bytes[..size as usize].copy_from_slice(env.mem.bytes_at(return_value, size));
+    let regs = env.cpu.regs_mut();
+    regs[0] = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+    regs[1] = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+}
+
+/// Inverse of [return_value_to_regs].
+fn return_value_from_regs(env: &mut Environment, return_value: MutPtr<u8>, size: GuestUSize) {
+    let regs = env.cpu.regs();
+    let mut bytes = [0u8; 8];
+    bytes[0..4].copy_from_slice(&regs[0].to_le_bytes());
+    bytes[4..8].copy_from_slice(&regs[1].to_le_bytes());
+    let size = size.min(8);
+    env.mem
+        .bytes_at_mut(return_value, size)
+        .copy_from_slice(&bytes[..size as usize]);
+}
+
+/// For use by `objc_msgSend`: create an invocation for a message that is being
+/// forwarded, with the arguments taken from where `objc_msgSend` (or
+/// `objc_msgSend_stret`, if `stret` is [true]) received them. `regs` is the
+/// original contents of r0-r3. The caller owns the result.
+pub fn invocation_for_forwarded_message(
+    env: &mut Environment,
+    signature: id,
+    regs: &[u32; 4],
+    stack_args: ConstPtr<u32>,
+    stret: bool,
+) -> id {
+    let invocation: id = msg_class![env; NSInvocation invocationWithMethodSignature:signature];
+    retain(env, invocation);
+
+    let frame = env.objc.borrow::<NSInvocationHostObject>(invocation).frame;
+    let frame_words = env
+        .objc
+        .borrow::<NSMethodSignatureHostObject>(signature)
+        .frame_length()
+        / 4;
+    let frame: MutPtr<u32> = frame.cast();
+    for i in 0..frame_words {
+        let reg_offset = i + stret as GuestUSize;
+        let word = if reg_offset < 4 {
+            regs[reg_offset as usize]
+        } else {
+            env.mem.read(stack_args + (reg_offset - 4))
+        };
+        env.mem.write(frame + i, word);
+    }
+
+    invocation
+}
+
+/// For use by `objc_msgSend`: return the return value of an invocation created
+/// by [invocation_for_forwarded_message] to the original caller. `stret_ptr`
+/// is the struct return pointer if `objc_msgSend_stret` was used.
+pub fn return_forwarded_message(
+    env: &mut Environment,
+    invocation: id,
+    stret_ptr: Option<MutVoidPtr>,
+) {
+    let (return_value, size) = return_value_ptr(env, invocation);
+    if let Some(stret_ptr) = stret_ptr {
+        env.mem
+            .memmove(stret_ptr, return_value.cast_void().cast_const(), size);
+    } else {
+        return_value_to_regs(env, return_value.cast_const(), size);
+    }
+}
+
+pub const CLASSES: ClassExports = objc_classes! {
+
+(env, this, _cmd);
+
+@implementation NSInvocation: NSObject
+
++ (id)invocationWithMethodSignature:(id)signature {
+    if signature == nil {
+        // TODO: raise NSInvalidArgumentException
+        exception_not_raised(env, "+[NSInvocation invocationWithMethodSignature:]: nil signature");
+        return nil;
+    }
+    let signature_host_object = env.objc.borrow::<NSMethodSignatureHostObject>(signature);
+    let frame_length = signature_host_object.frame_length();
+    let return_length = signature_host_object.return_length();
+
+    retain(env, signature);
+    let host_object = Box::new(NSInvocationHostObject {
+        signature,
+        frame: alloc_zeroed(env, frame_length),
+        return_value: alloc_zeroed(env, return_length),
+        arguments_retained: false,
+    });
+    let new = env.objc.alloc_object(this, host_object, &mut env.mem);
+    autorelease(env, new)
+}
+
+- (())dealloc {
+    let &NSInvocationHostObject {
+        signature,
+        frame,
+        return_value,
+        arguments_retained,
+    } = env.objc.borrow(this);
+
+    if arguments_retained {
+        let count = env
+            .objc
+            .borrow::<NSMethodSignatureHostObject>(signature)
+            .number_of_arguments();
+        for index in 0..count {
+            let signature_host_object = env.objc.borrow::<NSMethodSignatureHostObject>(signature);
+            if signature_host_object.argument_is_object(index) {
+                let offset = signature_host_object.argument_frame_offset(index);
+                let object: id = env.mem.read((frame + offset).cast());
+                release(env, object);
+            }
+        }
+    }
+
+    env.mem.free(frame.cast());
+    env.mem.free(return_value.cast());
+    release(env, signature);
+    env.objc.dealloc_object(this, &mut env.mem)
+}
+
+- (id)methodSignature {
+    env.objc.borrow::<NSInvocationHostObject>(this).signature
+}
+
+- (())retainArguments {
+    let &NSInvocationHostObject {
+        signature,
+        frame,
+        arguments_retained,
+        ..
+    } = env.objc.borrow(this);
+    if arguments_retained {
+        return;
+    }
+    env.objc.borrow_mut::<NSInvocationHostObject>(this).arguments_retained = true;
+
+    // TODO: Apple's implementation also copies C string arguments.
+    let count = env
+        .objc
+        .borrow::<NSMethodSignatureHostObject>(signature)
+        .number_of_arguments();
+    for index in 0..count {
+        let signature_host_object = env.objc.borrow::<NSMethodSignatureHostObject>(signature);
+        if signature_host_object.argument_is_object(index) {
+            let offset = signature_host_object.argument_frame_offset(index);
+            let object: id = env.mem.read((frame + offset).cast());
+            retain(env, object);
+        }
+    }
+}
+
+- (bool)argumentsRetained {
+    env.objc.borrow::<NSInvocationHostObject>(this).arguments_retained
+}
+
+- (id)target {
+    let (ptr, _size) = argument_ptr(env, this, 0).unwrap();
+    env.mem.read(ptr.cast())
+}
+- (())setTarget:(id)target {
+    let (ptr, _size) = argument_ptr(env, this, 0).unwrap();
+    let ptr: MutPtr<id> = ptr.cast();
+    if env.objc.borrow::<NSInvocationHostObject>(this).arguments_retained {
+        retain(env, target);
+        let old_target = env.mem.read(ptr);
+        release(env, old_target);
+    }
+    env.mem.write(ptr, target);
+}
+
+- (SEL)selector {
+    let (ptr, _size) = argument_ptr(env, this, 1).unwrap();
+    env.mem.read(ptr.cast())
+}
+- (())setSelector:(SEL)selector {
+    let (ptr, _size) = argument_ptr(env, this, 1).unwrap();
+    env.mem.write(ptr.cast(), selector);
+}
+
+- (())getArgument:(MutVoidPtr)buffer
+          atIndex:(NSInteger)index {
+    let Some((ptr, size)) = argument_ptr(env, this, index) else {
+        return;
+    };
+    env.mem.memmove(buffer, ptr.cast_void().cast_const(), size);
+}
+- (())setArgument:(ConstVoidPtr)buffer
+          atIndex:(NSInteger)index {
+    let Some((ptr, size)) = argument_ptr(env, this, index) else {
+        return;
+    };
+    let retains = env.objc.borrow::<NSInvocationHostObject>(this).arguments_retained
+        && {
+            let signature = env.objc.borrow::<NSInvocationHostObject>(this).signature;
+            env.objc
+                .borrow::<NSMethodSignatureHostObject>(signature)
+                .argument_is_object(index as GuestUSize)
+        };
+    if retains {
+        let new_object: id = env.mem.read(buffer.cast());
+        retain(env, new_object);
+        let old_object: id = env.mem.read(ptr.cast());
+        release(env, old_object);
+    }
+    env.mem.memmove(ptr.cast_void(), buffer, size);
+}
+
+- (())getReturnValue:(MutVoidPtr)buffer {
+    let (ptr, size) = return_value_ptr(env, this);
+    env.mem.memmove(buffer, ptr.cast_void().cast_const(), size);
+}
+- (())setReturnValue:(ConstVoidPtr)buffer {
+    let (ptr, size) = return_value_ptr(env, this);
+    env.mem.memmove(ptr.cast_void(), buffer, size);
+}
+
+- (())invoke {
+    let target: id = msg![env; this target];
+    msg![env; this invokeWithTarget:target]
+}
+
+- (())invokeWithTarget:(id)target {
+    () = msg![env; this setTarget:target];
+
+    let &NSInvocationHostObject {
+        signature,
+        frame,
+        return_value,
+        ..
+    } = env.objc.borrow(this);
+    let signature_host_object = env.objc.borrow::<NSMethodSignatureHostObject>(signature);
+    let frame_words = signature_host_object.frame_length() / 4;
+    let return_length = signature_host_object.return_length();
+    let stret = signature_host_object.returns_in_memory();
+
+    if target == nil {
+        env.mem.bytes_at_mut(return_value, return_length).fill(0);
+        return;
+    }
+
+    let regs = env.cpu.regs_mut();
+    let old_sp = extend_stack_for_args(frame_words as usize + stret as usize, regs);
+    let mut reg_offset = 0;
+    if stret {
+        write_next_arg(&mut reg_offset, regs, &mut env.mem, return_value);
+    }
+    let frame: ConstPtr<u32> = frame.cast().cast_const();
+    for i in 0..frame_words {
+        let word = env.mem.read(frame + i);
+        write_next_arg(&mut reg_offset, regs, &mut env.mem, word);
+    }
+
+    msg_send_from_registers(env, stret);
+
+    env.cpu.regs_mut()[Cpu::SP] = old_sp;
+    if !stret {
+        return_value_from_regs(env, return_value, return_length);
+    }
+}
+
+@end
+
+};
@@ -0,0 +1,329 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! `NSMethodSignature`.
+//!
+//! Resources:
+//! - Apple's [Type Encodings](https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/ObjCRuntimeGuide/Articles/ocrtTypeEncodings.html)
+//!   lists the characters used in Objective-C type encodings.
+
+use super::NSUInteger;
+use crate::mem::{ConstPtr, GuestUSize, MutPtr};
+use crate::objc::{autorelease, id, nil, objc_classes, ClassExports, HostObject};
+use crate::stubs::exception_not_raised;
+
+/// Type qualifiers, e.g. `r` for `const` and `V` for `oneway`. These can
+/// precede a type and don't affect its size.
+const QUALIFIERS: &[u8] = b"rnNoORV";
+
+/// The type of a single argument or of the return value of a method.
+struct ObjCType {
+    /// The type's first character after any qualifiers, e.g. `@` for objects.
+    kind: u8,
+    /// The type encoding of just this type, e.g. `r*` or `{CGPoint=ff}`, as a
+    /// guest C string.
+    encoding: MutPtr<u8>,
+    size: GuestUSize,
+}
+
+pub(super) struct NSMethodSignatureHostObject {
+    return_type: ObjCType,
+    /// The arguments, including the implicit `self` and `_cmd`.
+    arg_types: Vec<ObjCType>,
+}
+impl HostObject for NSMethodSignatureHostObject {}
+
+impl NSMethodSignatureHostObject {
+    pub(super) fn number_of_arguments(&self) -> GuestUSize {
+        self.arg_types.len().try_into().unwrap()
+    }
+
+    pub(super) fn argument_size(&self, index: GuestUSize) -> GuestUSize {
+        self.arg_types[index as usize].size
+    }
+
+    pub(super) fn argument_is_object(&self, index: GuestUSize) -> bool {
+        self.arg_types[index as usize].kind == b'@'
+    }
+
+    /// Arguments are stored in an `NSInvocation`'s frame the same way they
+    /// would be passed to a function: each one takes up a whole number of
+    /// 32-bit words, the first four of which would be passed in registers.
+    pub(super) fn argument_frame_offset(&self, index: GuestUSize) -> GuestUSize {
+        self.arg_types[..index as usize]
+            .iter()
+            .map(|arg_type| frame_size(arg_type.size))
+            .sum()
+    }
+
+    pub(super) fn frame_length(&self) -> GuestUSize {
+        self.argument_frame_offset(self.number_of_arguments())
+    }
+
+    pub(super) fn return_length(&self) -> GuestUSize {
+        self.return_type.size
+    }
+
+    /// Whether the return value is written to memory via a pointer passed as
+    /// an implicit first argument (see `objc_msgSend_stret`), rather than being
+    /// returned in registers.
+    pub(super) fn returns_in_memory(&self) -> bool {
+        matches!(self.return_type.kind, b'{' | b'(' | b'[') && self.return_type.size > 4
+    }
+}
+
+fn frame_size(size: GuestUSize) -> GuestUSize {
+    size.div_ceil(4) * 4
+}
+
+fn parse_number(types: &[u8]) -> Option<(GuestUSize, &[u8])> {
+    let digits = types.iter().take_while(|c| c.is_ascii_digit()).count();
+    let number = std::str::from_utf8(&types[..digits]).ok()?.parse().ok()?;
+    Some((number, &types[digits..]))
+}
+
+/// Skip the frame offset that may follow a type in a method's type encoding.
+/// These are meaningless on modern systems, so they are ignored.
+fn skip_offset(types: &[u8]) -> &[u8] {
+    let types = types.strip_prefix(b"-").unwrap_or(types);
+    let digits = types.iter().take_while(|c| c.is_ascii_digit()).count();
+    &types[digits..]
+}
+
+/// Skip a quoted class or field name, given the string after the opening
+/// quote.
+fn skip_quoted(types: &[u8]) -> Option<&[u8]> {
+    let end = types.iter().position(|&c| c == b'"')?;
+    Some(&types[end + 1..])
+}
+
+/// Parse a single type from the start of a type encoding. Returns the size and
+/// alignment of the type, and the rest of the type encoding.
+fn parse_type(types: &[u8]) -> Option<(GuestUSize, GuestUSize, &[u8])> {
+    let (&kind, rest) = types.split_first()?;
+    Some(match kind {
+        _ if QUALIFIERS.contains(&kind) => return parse_type(rest),
+        b'c' | b'C' | b'B' => (1, 1, rest),
+        b's' | b'S' => (2, 2, rest),
+        b'i' | b'I' | b'l' | b'L' | b'f' | b'*' | b'#' | b':' | b'?' => (4, 4, rest),
+        // 64-bit types only have 4-byte alignment in Apple's 32-bit Arm ABI.
+        b'q' | b'Q' | b'd' => (8, 4, rest),
+        b'v' => (0, 1, rest),
+        b'@' => {
+            let rest = match rest {
+                // Block
+                [b'?', rest @ ..] => rest,
+                // Class name, e.g. @"NSString"
+                [b'"', rest @ ..] => skip_quoted(rest)?,
+                _ => rest,
+            };
+            (4, 4, rest)
+        }
+        b'^' => {
+            let (_size, _align, rest) = parse_type(rest)?;
+            (4, 4, rest)
+        }
+        b'[' => {
+            let (count, rest) = parse_number(rest)?;
+            let (size, align, rest) = parse_type(rest)?;
+            let rest = rest.strip_prefix(b"]")?;
+            (count * size, align, rest)
+        }
+        b'{' | b'(' => {
+            let is_struct = kind == b'{';
+            let close = if is_struct { b'}' } else { b')' };
+            let name_len = rest.iter().position(|&c| c == b'=' || c == close)?;
+            let mut rest = &rest[name_len..];
+            let (mut size, mut align): (GuestUSize, GuestUSize) = (0, 1);
+            // The fields are omitted for structs that are only pointed to.
+            if let [b'=', fields @ ..] = rest {
+                rest = fields;
+                while *rest.first()? != close {
+                    if let [b'"', after_quote @ ..] = rest {
+                        rest = skip_quoted(after_quote)?;
+                    }
+                    let (field_size, field_align, after_field) = parse_type(rest)?;
+                    rest = after_field;
+                    align = align.max(field_align);
+                    size = if is_struct {
+                        size.next_multiple_of(field_align) + field_size
+                    } else {
+                        size.max(field_size)
+                    };
+                }
+            }
+            (size.next_multiple_of(align), align, &rest[1..])
+        }
+        // Bitfield
+        b'b' => {
+            let (bits, rest) = parse_number(rest)?;
+            (bits.div_ceil(8), 1, rest)
+        }
+        _ => return None,
+    })
+}
+
+/// Parse a method's type encoding, e.g. `v12@0:4i8`, into the encodings and
+/// sizes of the return type followed by those of the arguments.
+fn parse_method_types(types: &[u8]) -> Option<Vec<(&[u8], GuestUSize)>> {
+    let mut parsed = Vec::new();
+    let mut rest = types;
+    while !rest.is_empty() {
+        let (size, _align, after_type) = parse_type(rest)?;
+        parsed.push((&rest[..rest.len() - after_type.len()], size));
+        rest = skip_offset(after_type);
+    }
+    (!parsed.is_empty()).then_some(parsed)
+}
+
+pub const CLASSES: ClassExports = objc_classes! {
+
+(env, this, _cmd);
+
+@implementation NSMethodSignature: NSObject
+
++ (id)signatureWithObjCTypes:(ConstPtr<u8>)types {
+    let types_bytes = env.mem.cstr_at(types);
+    // The return type, self and _cmd are needed, since NSInvocation assumes
+    // the latter two exist.
+    let Some(parsed) = parse_method_types(types_bytes).filter(|parsed| parsed.len() >= 3) else {
+        log!(
+            "Warning: couldn't parse type encoding {:?}, returning nil",
+            String::from_utf8_lossy(types_bytes)
+        );
+        return nil;
+    };
+    let parsed: Vec<(Vec<u8>, GuestUSize)> = parsed
+        .into_iter()
+        .map(|(encoding, size)| (encoding.to_vec(), size))
+        .collect();
+
+    let mut parsed = parsed.into_iter().map(|(encoding, size)| {
+        let kind = *encoding
+            .iter()
+            .find(|&c| !QUALIFIERS.contains(c))
+            .unwrap();
+        ObjCType {
+            kind,
+            encoding: env.mem.alloc_and_write_cstr(&encoding),
+            size,
+        }
+    });
+    let host_object = NSMethodSignatureHostObject {
+        return_type: parsed.next().unwrap(),
+        arg_types: parsed.collect(),
+    };
+    let new = env.objc.alloc_object(this, Box::new(host_object), &mut env.mem);
+    autorelease(env, new)
+}
+
+- (())dealloc {
+    let host_object = env.objc.borrow::<NSMethodSignatureHostObject>(this);
+    let encodings: Vec<MutPtr<u8>> = std::iter::once(&host_object.return_type)
+        .chain(&host_object.arg_types)
+        .map(|objc_type| objc_type.encoding)
+        .collect();
+    for encoding in encodings {
+        env.mem.free(encoding.cast());
+    }
+    env.objc.dealloc_object(this, &mut env.mem)
+}
+
+- (NSUInteger)numberOfArguments {
+    env.objc.borrow::<NSMethodSignatureHostObject>(this).number_of_arguments()
+}
+
+- (ConstPtr<u8>)getArgumentTypeAtIndex:(NSUInteger)index {
+    let host_object = env.objc.borrow::<NSMethodSignatureHostObject>(this);
+    if index >= host_object.number_of_arguments() {
+        let message = format!(
+            "-[NSMethodSignature getArgumentTypeAtIndex:]: index {} out of range",
+            index
+        );
+        // TODO: raise NSInvalidArgumentException
+        exception_not_raised(env, &message);
+        return ConstPtr::null();
+    }
+    host_object.arg_types[index as usize].encoding.cast_const()
+}
+
+- (ConstPtr<u8>)methodReturnType {
+    env.objc.borrow::<NSMethodSignatureHostObject>(this).return_type.encoding.cast_const()
+}
+
+- (NSUInteger)methodReturnLength {
+    env.objc.borrow::<NSMethodSignatureHostObject>(this).return_length()
+}
+
+- (NSUInteger)frameLength {
+    env.objc.borrow::<NSMethodSignatureHostObject>(this).frame_length()
+}
+
+- (bool)isOneway {
+    let encoding = env.objc.borrow::<NSMethodSignatureHostObject>(this).return_type.encoding;
+    env.mem
+        .cstr_at(encoding)
+        .iter()
+        .take_while(|&c| QUALIFIERS.contains(c))
+        .any(|&c| c == b'V')
+}
+
+@end
+
+};
+
+#[cfg(test)]
+mod tests {
+    use super::parse_method_types;
+
+    #[test]
+    fn test_parse_method_types() {
+        let parse = |types: &'static str| {
+            parse_method_types(types.as_bytes()).map(|parsed| {
+                parsed
+                    .into_iter()
+                    .map(|(encoding, size)| (std::str::from_utf8(encoding).unwrap(), size))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(
+            parse("v12@0:4i8"),
+            Some(vec![("v", 0), ("@", 4), (":", 4), ("i", 4)])
+        );
+        assert_eq!(
+            parse("{CGRect={CGPoint=ff}{CGSize=ff}}8@0:4"),
+            Some(vec![
+                ("{CGRect={CGPoint=ff}{CGSize=ff}}", 16),
+                ("@", 4),
+                (":", 4)
+            ])
+        );
+        assert_eq!(
+            parse("Vv@:r*^{__CFString}d@\"NSString\"c[3s]"),
+            Some(vec![
+                ("Vv", 0),
+                ("@", 4),
+                (":", 4),
+                ("r*", 4),
+                ("^{__CFString}", 4),
+                ("d", 8),
+                ("@\"NSString\"", 4),
+                ("c", 1),
+                ("[3s]", 6)
+            ])
+        );
+        assert_eq!(
+            parse("{?=ci}@:"),
+            Some(vec![("{?=ci}", 8), ("@", 4), (":", 4)])
+        );
+        assert_eq!(
+            parse("(?=cd)@:"),
+            Some(vec![("(?=cd)", 8), ("@", 4), (":", 4)])
+        );
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("{unterminated=i"), None);
+    }
+}
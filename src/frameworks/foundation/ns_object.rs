@@ -11,6 +11,9 @@
 //!   it calls "manual retain-release", not ARC.
 //! - Apple's [Key-Value Coding Programming Guide](https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/KeyValueCoding/SearchImplementation.html)
 //!   explains the algorithm `setValue:forKey:` should follow.
+//! - Apple's [Message Forwarding](https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/ObjCRuntimeGuide/Articles/ocrtForwarding.html)
+//!   explains `forwardInvocation:` and friends. `objc_msgSend` is what calls
+//!   them.
 //!
 //! See also: [crate::objc], especially the `objects` module.
 
@@ -18,15 +21,49 @@ use super::ns_string::to_rust_string;
 use super::NSUInteger;
 use crate::mem::MutVoidPtr;
 use crate::objc::{
-    id, msg, msg_class, msg_send, objc_classes, Class, ClassExports, NSZonePtr, ObjC,
+    id, msg, msg_class, msg_send, nil, objc_classes, Class, ClassExports, NSZonePtr, ObjC,
     TrivialHostObject, SEL,
 };
+use crate::stubs::exception_not_raised;
+use crate::Environment;
+
+/// Get an `NSMethodSignature` for the method a class has for a selector, or
+/// nil if there's no such method.
+fn method_signature(env: &mut Environment, class: Class, selector: SEL) -> id {
+    if let Some(types) = env.objc.method_type_encoding(class, selector, &mut env.mem) {
+        return msg_class![env; NSMethodSignature signatureWithObjCTypes:types];
+    }
+    if env.objc.class_has_method(class, selector) {
+        log!(
+            "Warning: no type encoding for method \"{}\" of {:?} ({}), returning nil",
+            selector.as_str(&env.mem),
+            class,
+            env.objc.get_class_name(class),
+        );
+    }
+    nil
+}
+
+fn does_not_recognize_selector(env: &mut Environment, object: id, selector: SEL) {
+    let class = ObjC::read_isa(object, &env.mem);
+    let is_metaclass = env.objc.class_is_metaclass(class);
+    let message = format!(
+        "{}[{} {}]: unrecognized selector sent to {} {:?}",
+        if is_metaclass { '+' } else { '-' },
+        env.objc.get_class_name(class),
+        selector.as_str(&env.mem),
+        if is_metaclass { "class" } else { "instance" },
+        object,
+    );
+    // TODO: raise NSInvalidArgumentException
+    exception_not_raised(env, &message);
+}
 
 pub const CLASSES: ClassExports = objc_classes! {
 
 (env, this, _cmd);
 
-@implementation NSObject
+@implementation NSObject <NSObject>
 
 + (id)alloc {
     msg![env; this allocWithZone:(MutVoidPtr::null())]
@@ -60,6 +97,36 @@ pub const CLASSES: ClassExports = objc_classes! {
     env.objc.class_has_method(this, selector)
 }
 
++ (bool)conformsToProtocol:(id)protocol {
+    env.objc.class_conforms_to_protocol(this, protocol, &env.mem)
+}
+
+// Dynamic method resolution and message forwarding. The instance method
+// section has the normal versions of the last few of these.
++ (bool)resolveClassMethod:(SEL)_selector {
+    false
+}
++ (bool)resolveInstanceMethod:(SEL)_selector {
+    false
+}
++ (id)forwardingTargetForSelector:(SEL)_selector {
+    nil
+}
++ (id)methodSignatureForSelector:(SEL)selector {
+    let metaclass = ObjC::read_isa(this, &env.mem);
+    method_signature(env, metaclass, selector)
+}
++ (id)instanceMethodSignatureForSelector:(SEL)selector {
+    method_signature(env, this, selector)
+}
++ (())forwardInvocation:(id)invocation {
+    let selector: SEL = msg![env; invocation selector];
+    msg![env; this doesNotRecognizeSelector:selector]
+}
++ (())doesNotRecognizeSelector:(SEL)selector {
+    does_not_recognize_selector(env, this, selector)
+}
+
 - (id)init {
     this
 }
@@ -153,6 +220,35 @@ pub const CLASSES: ClassExports = objc_classes! {
     env.objc.class_has_method(class, selector)
 }
 
+- (bool)conformsToProtocol:(id)protocol {
+    let class = msg![env; this class];
+    env.objc.class_conforms_to_protocol(class, protocol, &env.mem)
+}
+
+- (id)forwardingTargetForSelector:(SEL)_selector {
+    nil
+}
+- (id)methodSignatureForSelector:(SEL)selector {
+    let class = msg![env; this class];
+    method_signature(env, class, selector)
+}
+- (())forwardInvocation:(id)invocation {
+    let selector: SEL = msg![env; invocation selector];
+    msg![env; this doesNotRecognizeSelector:selector]
+}
+- (())doesNotRecognizeSelector:(SEL)selector {
+    does_not_recognize_selector(env, this, selector)
+}
+
+@end
+
+// Protocol objects (see crate::objc's protocols module) are instances of this.
+// They live as long as the app does.
+@implementation Protocol: NSObject
+
+- (id)retain { this }
+- (())release {}
+- (id)autorelease { this }
 
 @end
 
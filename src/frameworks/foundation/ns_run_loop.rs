@@ -181,10 +181,11 @@ fn run_run_loop(env: &mut Environment, run_loop: id, single_iteration: bool) {
     loop {
         let mut sleep_until = None;
 
-        env.window
-            .as_mut()
-            .expect("NSRunLoop not supported in headless mode")
-            .poll_for_events(&env.options);
+        // In headless mode, there's nothing to poll, but input may still be
+        // replayed (see crate::input_recording).
+        if let Some(ref mut window) = env.window {
+            window.poll_for_events(&env.options);
+        }
 
         let next_due = uikit::handle_events(env);
         limit_sleep_time(&mut sleep_until, next_due);
@@ -229,7 +230,7 @@ fn run_run_loop(env: &mut Environment, run_loop: id, single_iteration: bool) {
         // apps can't do more than 60fps so this should be fine.
         let limit = Duration::from_millis(1000 / 60);
         env.sleep(
-            sleep_until.map_or(limit, |i| i.duration_since(Instant::now()).min(limit)),
+            sleep_until.map_or(limit, |i| i.duration_since(env.clock.now()).min(limit)),
             false,
         );
 
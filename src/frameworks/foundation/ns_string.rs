@@ -244,7 +244,7 @@ pub const CLASSES: ClassExports = objc_classes! {
 // - (unichar)characterAtIndex:(NSUInteger)index;
 // We can pick whichever subclass we want for the various alloc methods.
 // For the time being, that will always be _touchHLE_NSString.
-@implementation NSString: NSObject
+@implementation NSString: NSObject <NSCopying>
 
 + (id)allocWithZone:(NSZonePtr)zone {
     // NSString might be subclassed by something which needs allocWithZone:
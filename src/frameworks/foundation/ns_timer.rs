@@ -56,7 +56,7 @@ pub const CLASSES: ClassExports = objc_classes! {
         selector,
         user_info,
         repeats,
-        due_by: Some(Instant::now().checked_add(rust_interval).unwrap()),
+        due_by: Some(env.clock.now().checked_add(rust_interval).unwrap()),
         run_loop: nil,
     });
     let new = env.objc.alloc_object(this, host_object, &mut env.mem);
@@ -159,7 +159,7 @@ pub(super) fn handle_timer(env: &mut Environment, timer: id) -> Option<Instant>
     // invalidated timers should have already been removed from the run loop
     let due_by = due_by.unwrap();
 
-    let now = Instant::now();
+    let now = env.clock.now();
 
     if due_by > now {
         return Some(due_by);
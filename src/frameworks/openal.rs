@@ -164,10 +164,12 @@ fn alcGetProcAddress(
     let mangled_func_name = format!("_{}", env.mem.cstr_at_utf8(func_name).unwrap());
     assert!(mangled_func_name.starts_with("_al"));
 
-    if let Ok(ptr) = env
-        .dyld
-        .create_proc_address(&mut env.mem, &mut env.cpu, &mangled_func_name)
-    {
+    if let Ok(ptr) = env.dyld.create_proc_address(
+        &mut env.mem,
+        &mut env.cpu,
+        env.stubs.as_mut(),
+        &mangled_func_name,
+    ) {
         Ptr::from_bits(ptr.addr_with_thumb_bit())
     } else {
         panic!(
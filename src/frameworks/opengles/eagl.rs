@@ -113,7 +113,9 @@ pub const CLASSES: ClassExports = objc_classes! {
 - (id)initWithAPI:(EAGLRenderingAPI)api {
     assert!(api == kEAGLRenderingAPIOpenGLES1);
 
-    let window = env.window.as_mut().expect("OpenGL ES is not supported in headless mode");
+    // In headless mode, this creates an invisible window to render to.
+    env.window_mut();
+    let window = env.window.as_mut().unwrap();
     let gles1_ctx = create_gles1_ctx(window, &env.options);
 
     // Make the context current so we can get driver info from it.
@@ -165,7 +167,8 @@ pub const CLASSES: ClassExports = objc_classes! {
     }
     let internalformat = gles11::RGBA8_OES;
 
-    let window = env.window.as_mut().expect("OpenGL ES is not supported in headless mode");
+    env.window_mut();
+    let window = env.window.as_mut().unwrap();
 
     // FIXME: get width and height from the layer!
     let (width, height) = window.size_unrotated_scalehacked();
@@ -197,7 +200,8 @@ pub const CLASSES: ClassExports = objc_classes! {
 
     // The presented frame should be displayed ASAP, but the next one must be
     // delayed, so this needs to be checked before returning.
-    let sleep_for = limit_framerate(&mut env.objc.borrow_mut::<EAGLContextHostObject>(this).next_frame_due, &env.options);
+    let now = env.clock.now();
+    let sleep_for = limit_framerate(&mut env.objc.borrow_mut::<EAGLContextHostObject>(this).next_frame_due, now, &env.options);
 
     if env.options.print_fps {
         env
@@ -212,7 +216,8 @@ pub const CLASSES: ClassExports = objc_classes! {
 
     // Unclear from documentation if this method requires the context to be
     // current, but it would be weird if it didn't?
-    let window = env.window.as_mut().expect("OpenGL ES is not supported in headless mode");
+    env.window_mut();
+    let window = env.window.as_mut().unwrap();
     let gles = super::sync_context(&mut env.framework_state.opengles, &mut env.objc, window, env.current_thread);
 
     let renderbuffer: GLuint = unsafe {
@@ -307,7 +312,11 @@ pub const CLASSES: ClassExports = objc_classes! {
 /// an interval's worth of accumulated slop. Allowing infinite accumulation of
 /// slop is not desirable, because if the game is running slowly for a long time
 /// and suddenly speeds back up, it will then run too fast for a long time.
-fn limit_framerate(next_frame_due: &mut Option<Instant>, options: &Options) -> Option<Duration> {
+fn limit_framerate(
+    next_frame_due: &mut Option<Instant>,
+    now: Instant,
+    options: &Options,
+) -> Option<Duration> {
     let interval = if let Some(fps) = options.fps_limit {
         1.0 / fps
     } else {
@@ -317,11 +326,10 @@ fn limit_framerate(next_frame_due: &mut Option<Instant>, options: &Options) -> O
 
     let &mut Some(current_frame_due) = next_frame_due else {
         // First frame presented: no delay yet.
-        *next_frame_due = Some(Instant::now() + interval_rust);
+        *next_frame_due = Some(now + interval_rust);
         return None;
     };
 
-    let now = Instant::now();
     *next_frame_due = if now > current_frame_due + interval_rust {
         // Too much slop has accumulated. Make the next frame wait for the next
         // interval.
@@ -32,12 +32,12 @@ fn with_ctx_and_mem<T, U>(env: &mut Environment, f: T) -> U
 where
     T: FnOnce(&mut dyn GLES, &mut Mem) -> U,
 {
+    // In headless mode, this creates an invisible window to render to.
+    env.window_mut();
     let gles = super::sync_context(
         &mut env.framework_state.opengles,
         &mut env.objc,
-        env.window
-            .as_mut()
-            .expect("OpenGL ES is not supported in headless mode"),
+        env.window.as_mut().unwrap(),
         env.current_thread,
     );
 
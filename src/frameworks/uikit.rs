@@ -49,9 +49,19 @@ pub struct State {
 pub fn handle_events(env: &mut Environment) -> Option<Instant> {
     use crate::window::Event;
 
+    if let Some(ref mut recording) = env.input_recording {
+        recording.begin_poll();
+    }
+
     loop {
-        // NSRunLoop will never call this function in headless mode.
-        let Some(event) = env.window.as_mut().unwrap().pop_event() else {
+        // In headless mode, the only input is from a replay.
+        let window = env.window.as_mut();
+        let event = if let Some(ref mut recording) = env.input_recording {
+            recording.pop_event(window, env.clock.elapsed())
+        } else {
+            window.and_then(|window| window.pop_event())
+        };
+        let Some(event) = event else {
             break;
         };
 
@@ -71,7 +71,9 @@ pub const CLASSES: ClassExports = objc_classes! {
         env.framework_state.uikit.ui_accelerometer.delegate = None;
     } else {
         env.framework_state.uikit.ui_accelerometer.delegate = Some(delegate);
-        env.window().print_accelerometer_notice();
+        if let Some(ref window) = env.window {
+            window.print_accelerometer_notice();
+        }
     }
 }
 
@@ -132,7 +134,7 @@ pub(super) fn handle_accelerometer(env: &mut Environment) -> Option<Instant> {
     let ns_interval = state.update_interval.unwrap();
     let rust_interval = Duration::from_secs_f64(ns_interval);
 
-    let now = Instant::now();
+    let now = env.clock.now();
     let new_due_by = if let Some(due_by) = state.due_by {
         if due_by > now {
             return Some(due_by);
@@ -159,7 +161,15 @@ pub(super) fn handle_accelerometer(env: &mut Environment) -> Option<Instant> {
     // UIKit creates and drains autorelease pools when handling events.
     let pool: id = msg_class![env; NSAutoreleasePool new];
 
-    let (x, y, z) = env.window().get_acceleration(&env.options);
+    let (x, y, z) = if let Some(ref mut recording) = env.input_recording {
+        let now = env.clock.elapsed();
+        recording.get_acceleration(env.window.as_ref(), &env.options, now)
+    } else if let Some(ref window) = env.window {
+        window.get_acceleration(&env.options)
+    } else {
+        // Headless mode, and nothing to replay: lying flat on its back.
+        (0.0, 0.0, -1.0)
+    };
     let timestamp: NSTimeInterval = msg_class![env; NSProcessInfo systemUptime];
     let acceleration: id = msg_class![env; UIAcceleration alloc];
     *env.objc.borrow_mut(acceleration) = UIAccelerationHostObject {
@@ -101,7 +101,7 @@ pub const CLASSES: ClassExports = objc_classes! {
 }
 
 - (bool)idleTimerDisabled {
-    !env.window().is_screen_saver_enabled()
+    !env.window_mut().is_screen_saver_enabled()
 }
 - (())setIdleTimerDisabled:(bool)disabled {
     env.window_mut().set_screen_saver_enabled(!disabled);
@@ -266,6 +266,7 @@ pub(super) fn exit(env: &mut Environment) {
         let _: () = msg![env; pool drain];
     };
 
+    env.report_debug_heap_leaks();
     std::process::exit(0);
 }
 
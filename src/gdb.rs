@@ -12,30 +12,461 @@
 //!   - `include/gdb/signals.def` for the meanings of signal numbers
 //!   - `gdb/arch/arm.h` for ARMv6 register numbers
 
-use crate::cpu::{Cpu, CpuError};
-use crate::mem::{GuestUSize, Mem, Ptr};
+use crate::cpu::{Arch, Cpu, CpuContext, CpuError};
+use crate::mem::{GuestUSize, Mem, MutPtr, Protection, Ptr};
+use crate::ThreadId;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write as _;
 use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
 use std::net::TcpStream;
 use std::time::Duration;
 
-/// GDB target description XML.
-const TARGET_XML: &str = r#"
-<target version="1.0">
-    <architecture>armv6</architecture>
-    <osabi>Darwin</osabi>
+/// GDB target description XML, following the architecture (see
+/// [GdbServer::target_xml]). The registers are numbered in order, starting
+/// from 0, and the numbers must match [ThreadRegs::read_register].
+const TARGET_XML_FEATURES: &str = r#"    <osabi>Darwin</osabi>
+    <feature name="org.gnu.gdb.arm.core">
+        <reg name="r0" bitsize="32" type="uint32"/>
+        <reg name="r1" bitsize="32" type="uint32"/>
+        <reg name="r2" bitsize="32" type="uint32"/>
+        <reg name="r3" bitsize="32" type="uint32"/>
+        <reg name="r4" bitsize="32" type="uint32"/>
+        <reg name="r5" bitsize="32" type="uint32"/>
+        <reg name="r6" bitsize="32" type="uint32"/>
+        <reg name="r7" bitsize="32" type="uint32"/>
+        <reg name="r8" bitsize="32" type="uint32"/>
+        <reg name="r9" bitsize="32" type="uint32"/>
+        <reg name="r10" bitsize="32" type="uint32"/>
+        <reg name="r11" bitsize="32" type="uint32"/>
+        <reg name="r12" bitsize="32" type="uint32"/>
+        <reg name="sp" bitsize="32" type="data_ptr"/>
+        <reg name="lr" bitsize="32"/>
+        <reg name="pc" bitsize="32" type="code_ptr"/>
+        <reg name="cpsr" bitsize="32"/>
+    </feature>
+    <feature name="org.gnu.gdb.arm.vfp">
+        <reg name="d0" bitsize="64" type="ieee_double"/>
+        <reg name="d1" bitsize="64" type="ieee_double"/>
+        <reg name="d2" bitsize="64" type="ieee_double"/>
+        <reg name="d3" bitsize="64" type="ieee_double"/>
+        <reg name="d4" bitsize="64" type="ieee_double"/>
+        <reg name="d5" bitsize="64" type="ieee_double"/>
+        <reg name="d6" bitsize="64" type="ieee_double"/>
+        <reg name="d7" bitsize="64" type="ieee_double"/>
+        <reg name="d8" bitsize="64" type="ieee_double"/>
+        <reg name="d9" bitsize="64" type="ieee_double"/>
+        <reg name="d10" bitsize="64" type="ieee_double"/>
+        <reg name="d11" bitsize="64" type="ieee_double"/>
+        <reg name="d12" bitsize="64" type="ieee_double"/>
+        <reg name="d13" bitsize="64" type="ieee_double"/>
+        <reg name="d14" bitsize="64" type="ieee_double"/>
+        <reg name="d15" bitsize="64" type="ieee_double"/>
+        <reg name="fpscr" bitsize="32" type="int" group="float"/>
+    </feature>
 </target>
 "#;
 
+/// Register numbers `0..16` are the general-purpose registers.
+const REG_CPSR: usize = 16;
+/// `d0`–`d15` are numbered from here.
+const REG_D0: usize = REG_CPSR + 1;
+const REG_FPSCR: usize = REG_D0 + 16;
+/// `s0`–`s31` are numbered from here. These aren't in [TARGET_XML_FEATURES], because
+/// GDB provides them itself based on `d0`–`d15`, but LLDB wants them (see
+/// [register_info]).
+const REG_S0: usize = REG_FPSCR + 1;
+
+/// The registers in a `g` or `G` packet, in order.
+fn g_packet_regs() -> impl Iterator<Item = usize> {
+    0..=REG_FPSCR
+}
+
+/// Size of a register in bytes, or [None] if there's no such register.
+fn register_size(num: usize) -> Option<usize> {
+    match num {
+        0..=REG_CPSR | REG_FPSCR => Some(4),
+        _ if (REG_D0..REG_FPSCR).contains(&num) => Some(8),
+        _ if (REG_S0..REG_S0 + 32).contains(&num) => Some(4),
+        _ => None,
+    }
+}
+
+/// Split the data of a `G` packet into the values of the registers it sets.
+/// The debugger may send fewer registers than we'd send it, e.g. if it doesn't
+/// know about the VFP registers, but it must not send part of one. Returns
+/// [None] if the size doesn't match.
+fn split_g_packet(mut data: &[u8]) -> Option<Vec<(usize, &[u8])>> {
+    let mut values = Vec::new();
+    for num in g_packet_regs() {
+        if data.is_empty() {
+            break;
+        }
+        let size = register_size(num).unwrap();
+        if data.len() < size {
+            return None;
+        }
+        let (value, rest) = data.split_at(size);
+        values.push((num, value));
+        data = rest;
+    }
+    data.is_empty().then_some(values)
+}
+
+/// Offset of a register's value within a `g` packet, in bytes. The single
+/// precision registers overlap the double precision ones.
+fn g_packet_offset(num: usize) -> usize {
+    if num >= REG_S0 {
+        let s = num - REG_S0;
+        return g_packet_offset(REG_D0 + s / 2) + (s % 2) * 4;
+    }
+    (0..num).map(|num| register_size(num).unwrap()).sum()
+}
+
+/// Reply to LLDB's `qRegisterInfo` packet, which it uses instead of the target
+/// description to learn the register layout. [None] means there's no such
+/// register.
+fn register_info(num: usize) -> Option<String> {
+    let size = register_size(num)?;
+    let mut info = String::new();
+    match num {
+        0..=15 => {
+            let name = match num {
+                13 => "sp".to_string(),
+                14 => "lr".to_string(),
+                15 => "pc".to_string(),
+                _ => format!("r{}", num),
+            };
+            write!(info, "name:{};", name).unwrap();
+            if num >= 13 {
+                write!(info, "alt-name:r{};", num).unwrap();
+            } else if num == 7 {
+                // iPhone OS uses r7 as the frame pointer, even in Arm code.
+                info.push_str("alt-name:fp;");
+            }
+            info.push_str("encoding:uint;format:hex;set:General Purpose Registers;");
+            write!(info, "ehframe:{};dwarf:{};", num, num).unwrap();
+            match num {
+                0..=3 => write!(info, "generic:arg{};", num + 1).unwrap(),
+                7 => info.push_str("generic:fp;"),
+                13 => info.push_str("generic:sp;"),
+                14 => info.push_str("generic:ra;"),
+                15 => info.push_str("generic:pc;"),
+                _ => (),
+            }
+        }
+        REG_CPSR => info.push_str(
+            "name:cpsr;alt-name:flags;encoding:uint;format:hex;\
+             set:General Purpose Registers;generic:flags;",
+        ),
+        REG_FPSCR => {
+            info.push_str("name:fpscr;encoding:uint;format:hex;set:Floating Point Registers;")
+        }
+        _ if num < REG_FPSCR => {
+            let d = num - REG_D0;
+            write!(
+                info,
+                "name:d{};encoding:ieee754;format:float;set:Floating Point Registers;\
+                 dwarf:{};invalidate-regs:{:x},{:x};",
+                d,
+                256 + d,
+                REG_S0 + d * 2,
+                REG_S0 + d * 2 + 1
+            )
+            .unwrap();
+        }
+        _ => {
+            let s = num - REG_S0;
+            let d = REG_D0 + s / 2;
+            write!(
+                info,
+                "name:s{};encoding:ieee754;format:float;set:Floating Point Registers;\
+                 dwarf:{};container-regs:{:x};invalidate-regs:{:x};",
+                s,
+                64 + s,
+                d,
+                d
+            )
+            .unwrap();
+        }
+    }
+    write!(
+        info,
+        "bitsize:{};offset:{};",
+        size * 8,
+        g_packet_offset(num)
+    )
+    .unwrap();
+    Some(info)
+}
+
+/// Escape a reply that contains binary data, or in practice, text that might
+/// contain characters with special meaning in the protocol (e.g. JSON).
+fn escape_binary(data: &str) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for c in data.chars() {
+        if matches!(c, '#' | '$' | '}' | '*') {
+            escaped.push('}');
+            escaped.push(((c as u8) ^ 0x20) as char);
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Parse the parameters of a `qXfer:object:read:annex:offset,length` packet
+/// (everything after `qXfer:`), returning the object, annex, offset and length.
+fn parse_qxfer_read(params: &str) -> Option<(&str, &str, usize, usize)> {
+    let (object, params) = params.split_once(":read:")?;
+    let (annex, params) = params.split_once(':')?;
+    let (offset, length) = params.split_once(',')?;
+    let offset = usize::from_str_radix(offset, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    Some((object, annex, offset, length))
+}
+
+fn encode_hex(bytes: &[u8], out: &mut String) {
+    for byte in bytes {
+        write!(out, "{:02x}", byte).unwrap();
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Instruction GDB uses for software breakpoints in Arm code (an undefined
+/// instruction). Inserting a breakpoint of kind 4 replaces the code with this.
+const ARM_BREAKPOINT: [u8; 4] = 0xe7ffdefeu32.to_le_bytes();
+/// Instruction GDB uses for software breakpoints in 16-bit Thumb code
+/// (`bkpt #0xbe`). Used for breakpoints of kind 2.
+const THUMB_BREAKPOINT: [u8; 2] = 0xbebeu16.to_le_bytes();
+/// Instruction GDB uses for software breakpoints in place of 32-bit Thumb-2
+/// instructions (a permanently undefined instruction). Used for breakpoints
+/// of kind 3.
+const THUMB2_BREAKPOINT: [u8; 4] = [0xf0, 0xf7, 0x00, 0xa0];
+
+/// A breakpoint inserted by the emulator on the debugger's behalf.
+struct Breakpoint {
+    /// The breakpoint instruction, one of the constants above.
+    instruction: &'static [u8],
+    /// The bytes of code that were replaced by the breakpoint instruction.
+    original: Vec<u8>,
+}
+
+/// A thread that the debugger can inspect, provided by the caller of
+/// [GdbServer::wait_for_debugger].
+///
+/// GDB thread IDs must be positive, so the ID the debugger sees is one higher.
+pub struct DebugThread<'a> {
+    pub id: ThreadId,
+    /// The saved CPU state of the thread, or [None] if it's the thread
+    /// currently using the CPU.
+    pub context: Option<&'a mut CpuContext>,
+    /// Human-readable description of what the thread is doing, for
+    /// `qThreadExtraInfo`.
+    pub description: String,
+}
+
+/// A binary loaded into guest memory (the app or a dylib), for the debugger's
+/// list of shared libraries.
+pub struct DebugLibrary {
+    pub name: String,
+    /// Address of the Mach-O header.
+    pub header_addr: GuestUSize,
+}
+
+/// Guest copy of the list of libraries for `qShlibInfoAddr`, see
+/// [GdbServer::all_image_infos].
+struct AllImageInfos {
+    /// The `dyld_all_image_infos` structure. This never moves, since the
+    /// debugger only asks for its address once.
+    header: MutPtr<u32>,
+    /// The array of `dyld_image_info` structures it points to, which is
+    /// replaced whenever a library is added.
+    info_array: MutPtr<u32>,
+    /// Paths of the libraries, in the same order as [GdbServer::libraries].
+    paths: Vec<MutPtr<u8>>,
+}
+
+/// Registers of either the thread currently using the CPU or a suspended one.
+enum ThreadRegs<'a> {
+    Cpu(&'a mut Cpu),
+    Context(&'a mut CpuContext),
+}
+
+impl ThreadRegs<'_> {
+    fn regs(&self) -> &[u32; 16] {
+        match self {
+            ThreadRegs::Cpu(cpu) => cpu.regs(),
+            ThreadRegs::Context(context) => context.regs(),
+        }
+    }
+    fn regs_mut(&mut self) -> &mut [u32; 16] {
+        match self {
+            ThreadRegs::Cpu(cpu) => cpu.regs_mut(),
+            ThreadRegs::Context(context) => context.regs_mut(),
+        }
+    }
+    fn cpsr(&self) -> u32 {
+        match self {
+            ThreadRegs::Cpu(cpu) => cpu.cpsr(),
+            ThreadRegs::Context(context) => context.cpsr(),
+        }
+    }
+    fn set_cpsr(&mut self, cpsr: u32) {
+        match self {
+            ThreadRegs::Cpu(cpu) => cpu.set_cpsr(cpsr),
+            ThreadRegs::Context(context) => context.set_cpsr(cpsr),
+        }
+    }
+    fn ext_regs(&self) -> &[u32; 64] {
+        match self {
+            ThreadRegs::Cpu(cpu) => cpu.ext_regs(),
+            ThreadRegs::Context(context) => context.ext_regs(),
+        }
+    }
+    fn ext_regs_mut(&mut self) -> &mut [u32; 64] {
+        match self {
+            ThreadRegs::Cpu(cpu) => cpu.ext_regs_mut(),
+            ThreadRegs::Context(context) => context.ext_regs_mut(),
+        }
+    }
+    fn fpscr(&self) -> u32 {
+        match self {
+            ThreadRegs::Cpu(cpu) => cpu.fpscr(),
+            ThreadRegs::Context(context) => context.fpscr(),
+        }
+    }
+    fn set_fpscr(&mut self, fpscr: u32) {
+        match self {
+            ThreadRegs::Cpu(cpu) => cpu.set_fpscr(fpscr),
+            ThreadRegs::Context(context) => context.set_fpscr(fpscr),
+        }
+    }
+
+    /// Get the value of a register in the target's byte order (little-endian),
+    /// or [None] if there's no such register.
+    fn read_register(&self, num: usize) -> Option<Vec<u8>> {
+        let words = match num {
+            0..=15 => vec![self.regs()[num]],
+            REG_CPSR => vec![self.cpsr()],
+            REG_FPSCR => vec![self.fpscr()],
+            _ if (REG_D0..REG_FPSCR).contains(&num) => {
+                let idx = (num - REG_D0) * 2;
+                self.ext_regs()[idx..][..2].to_vec()
+            }
+            _ if (REG_S0..REG_S0 + 32).contains(&num) => vec![self.ext_regs()[num - REG_S0]],
+            _ => return None,
+        };
+        Some(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+    }
+
+    /// Set the value of a register from bytes in the target's byte order.
+    /// Returns [false] if there's no such register or the size is wrong.
+    fn write_register(&mut self, num: usize, value: &[u8]) -> bool {
+        if register_size(num) != Some(value.len()) {
+            return false;
+        }
+        let mut words = value
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()));
+        let word = words.next().unwrap();
+        match num {
+            0..=15 => self.regs_mut()[num] = word,
+            REG_CPSR => self.set_cpsr(word),
+            REG_FPSCR => self.set_fpscr(word),
+            _ if (REG_D0..REG_FPSCR).contains(&num) => {
+                let idx = (num - REG_D0) * 2;
+                self.ext_regs_mut()[idx] = word;
+                self.ext_regs_mut()[idx + 1] = words.next().unwrap();
+            }
+            _ => self.ext_regs_mut()[num - REG_S0] = word,
+        }
+        true
+    }
+}
+
+/// Get the registers of a thread, or [None] if there's no such thread.
+fn thread_regs<'a>(
+    cpu: &'a mut Cpu,
+    threads: &'a mut [DebugThread],
+    thread: ThreadId,
+) -> Option<ThreadRegs<'a>> {
+    let thread = threads.iter_mut().find(|t| t.id == thread)?;
+    Some(match thread.context.as_deref_mut() {
+        Some(context) => ThreadRegs::Context(context),
+        None => ThreadRegs::Cpu(cpu),
+    })
+}
+
+/// Parse a GDB thread ID. Returns [None] for the special IDs `0` (any thread)
+/// and `-1` (all threads), or [Err] if it's not a valid ID.
+fn parse_thread_id(id: &str) -> Result<Option<ThreadId>, ()> {
+    match i64::from_str_radix(id, 16) {
+        Ok(-1 | 0) => Ok(None),
+        Ok(id) if id > 0 => Ok(Some((id - 1) as ThreadId)),
+        _ => Err(()),
+    }
+}
+
+/// What the debugger wants to happen once [GdbServer::wait_for_debugger]
+/// returns.
+pub enum DebuggerAction {
+    /// Resume normal execution.
+    Continue,
+    /// Execute one instruction, then call [GdbServer::wait_for_debugger]
+    /// again.
+    Step,
+    /// Run a touchHLE-specific command sent with `monitor`, then send its
+    /// output with [GdbServer::send_monitor_output] and call
+    /// [GdbServer::wait_for_debugger] again.
+    Monitor(String),
+}
+
 /// GDB Remote Serial Protocol handler, implementing a server.
 pub struct GdbServer {
     reader: BufReader<TcpStream>,
     first_halt: bool,
+    /// Software breakpoints inserted with `Z0`, by address. The emulator
+    /// writes a breakpoint instruction to memory, but hides this from the
+    /// debugger's memory reads.
+    breakpoints: HashMap<GuestUSize, Breakpoint>,
+    /// "Hardware" breakpoints inserted with `Z1`. These leave memory alone;
+    /// instead, the emulator checks the PC against them after every
+    /// instruction (see [Self::hardware_breakpoint_at]).
+    hardware_breakpoints: HashSet<GuestUSize>,
+    /// Set while breakpoints are suspended with
+    /// [Self::set_breakpoints_suspended].
+    breakpoints_suspended: bool,
+    /// Set once the debugger asks to stop using acknowledgments (`+`), which
+    /// LLDB does.
+    no_ack_mode: bool,
+    arch: Arch,
+    libraries: Vec<DebugLibrary>,
+    /// Guest copy of the list of libraries for `qShlibInfoAddr`, created when
+    /// first requested.
+    all_image_infos: Option<AllImageInfos>,
+    /// Set when a library has been added since the last stop reply, so the
+    /// next one can tell the debugger to fetch the list again.
+    libraries_changed: bool,
+    /// The signal sent in the last stop reply.
+    stop_signal: u8,
+    /// Set while the emulator runs a monitor command, during which the
+    /// debugger is still waiting for the reply to its `qRcmd` packet.
+    monitor_command_running: bool,
 }
 
 impl GdbServer {
-    /// Create the handler from a TCP connection.
-    pub fn new(mut connection: TcpStream) -> GdbServer {
+    /// Create the handler from a TCP connection. `arch` is the architecture of
+    /// the emulated CPU, and `libraries` should list the app binary first.
+    pub fn new(mut connection: TcpStream, arch: Arch, libraries: Vec<DebugLibrary>) -> GdbServer {
         connection
             .set_read_timeout(Some(Duration::from_secs(3)))
             .unwrap();
@@ -54,6 +485,308 @@ impl GdbServer {
         GdbServer {
             reader: BufReader::with_capacity(4096, connection),
             first_halt: true,
+            breakpoints: HashMap::new(),
+            hardware_breakpoints: HashSet::new(),
+            breakpoints_suspended: false,
+            no_ack_mode: false,
+            arch,
+            libraries,
+            all_image_infos: None,
+            libraries_changed: false,
+            stop_signal: 0,
+            monitor_command_running: false,
+        }
+    }
+
+    /// Key-value pairs describing the emulated CPU and OS, shared by the
+    /// replies to `qHostInfo` and `qProcessInfo`.
+    fn target_info(&self) -> String {
+        let cpusubtype = match self.arch {
+            Arch::ARMv6 => 6,
+            Arch::ARMv7 => 9,
+        };
+        // CPU_TYPE_ARM is 12
+        format!(
+            "cputype:c;cpusubtype:{:x};ostype:ios;vendor:apple;endian:little;ptrsize:4;",
+            cpusubtype
+        )
+    }
+
+    /// Target description XML for `qXfer:features:read`, which tells the
+    /// debugger which instructions the emulated CPU supports.
+    fn target_xml(&self) -> String {
+        format!(
+            "<?xml version=\"1.0\"?>\n\
+             <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+             <target version=\"1.0\">\n    \
+             <architecture>{}</architecture>\n{}",
+            self.arch.short_name(),
+            TARGET_XML_FEATURES
+        )
+    }
+
+    /// Library list XML for `qXfer:libraries:read`. LLDB takes the address
+    /// of a library's first "section" to be where its Mach-O header is loaded.
+    fn libraries_xml(&self) -> String {
+        let mut xml = String::from("<library-list>");
+        for library in &self.libraries {
+            let name = library
+                .name
+                .replace('&', "&amp;")
+                .replace('"', "&quot;")
+                .replace('<', "&lt;");
+            write!(
+                xml,
+                "<library name=\"{}\"><section address=\"{:#x}\"/></library>",
+                name, library.header_addr
+            )
+            .unwrap();
+        }
+        xml.push_str("</library-list>");
+        xml
+    }
+
+    /// Get the address of a `dyld_all_image_infos` structure describing the
+    /// loaded binaries, as the real dyld would provide. LLDB looks for this
+    /// with `qShlibInfoAddr` to find the app and its libraries.
+    ///
+    /// There's no dyld in guest memory for the debugger to find, so this is
+    /// the oldest version of the structure, without a dyld load address or a
+    /// notification function that works. It's kept up to date in place by
+    /// [Self::add_library].
+    fn all_image_infos(&mut self, mem: &mut Mem) -> GuestUSize {
+        if let Some(ref infos) = self.all_image_infos {
+            return infos.header.to_bits();
+        }
+        // struct dyld_all_image_infos {
+        //     uint32_t version;
+        //     uint32_t infoArrayCount;
+        //     const struct dyld_image_info* infoArray;
+        //     dyld_image_notifier notification;
+        //     bool processDetachedFromSharedRegion;
+        // };
+        // The array is filled in by update_all_image_infos().
+        let header = [1, 0, 0, 0, 0];
+        let header_ptr: MutPtr<u32> = mem.alloc(header.len() as GuestUSize * 4).cast();
+        for (i, &word) in header.iter().enumerate() {
+            mem.write(header_ptr + i as GuestUSize, word);
+        }
+        self.all_image_infos = Some(AllImageInfos {
+            header: header_ptr,
+            info_array: Ptr::null(),
+            paths: Vec::new(),
+        });
+        self.update_all_image_infos(mem);
+        header_ptr.to_bits()
+    }
+
+    /// Bring the guest copy of the list of libraries up to date with
+    /// [Self::libraries], if it's been created. The old array is freed, but the
+    /// paths of libraries that were already listed are reused.
+    fn update_all_image_infos(&mut self, mem: &mut Mem) {
+        let Some(ref mut infos) = self.all_image_infos else {
+            return;
+        };
+        for library in &self.libraries[infos.paths.len()..] {
+            infos
+                .paths
+                .push(mem.alloc_and_write_cstr(library.name.as_bytes()));
+        }
+        // struct dyld_image_info {
+        //     const struct mach_header* imageLoadAddress;
+        //     const char* imageFilePath;
+        //     uintptr_t imageFileModDate;
+        // };
+        let mut image_infos: Vec<u32> = Vec::new();
+        for (library, path) in self.libraries.iter().zip(&infos.paths) {
+            image_infos.extend([library.header_addr, path.to_bits(), 0]);
+        }
+        if !infos.info_array.is_null() {
+            mem.free(infos.info_array.cast());
+        }
+        infos.info_array = mem.alloc(image_infos.len() as GuestUSize * 4).cast();
+        for (i, &word) in image_infos.iter().enumerate() {
+            mem.write(infos.info_array + i as GuestUSize, word);
+        }
+        mem.write(infos.header + 1, self.libraries.len() as u32);
+        mem.write(infos.header + 2, infos.info_array.to_bits());
+    }
+
+    /// Handle a `Z` or `z` packet (`params` excludes the first character),
+    /// returning the reply.
+    fn insert_or_remove_point(
+        &mut self,
+        insert: bool,
+        params: &str,
+        cpu: &mut Cpu,
+        mem: &mut Mem,
+    ) -> &'static str {
+        // Conditions and commands to be evaluated by the target can follow
+        // the kind, but we don't claim to support those.
+        let params = params.split(';').next().unwrap();
+        let mut params = params.split(',');
+        let (Some(type_), Some(addr), Some(kind), None) =
+            (params.next(), params.next(), params.next(), params.next())
+        else {
+            return "E00";
+        };
+        let (Ok(addr), Ok(kind)) = (
+            GuestUSize::from_str_radix(addr, 16),
+            GuestUSize::from_str_radix(kind, 16),
+        ) else {
+            return "E00";
+        };
+
+        let watch_kind = match type_ {
+            // Software breakpoint
+            "0" => {
+                return if insert {
+                    self.insert_breakpoint(addr, kind, cpu, mem)
+                } else {
+                    self.remove_breakpoint(addr, cpu, mem)
+                };
+            }
+            // "Hardware" breakpoint. Inserting the same one twice must have no
+            // effect, like for software breakpoints.
+            "1" => {
+                if insert {
+                    self.hardware_breakpoints.insert(addr);
+                    return "OK";
+                }
+                return if self.hardware_breakpoints.remove(&addr) {
+                    "OK"
+                } else {
+                    "E00"
+                };
+            }
+            // Write watchpoint
+            "2" => Protection::WRITE,
+            // Read watchpoint
+            "3" => Protection::READ,
+            // Access watchpoint
+            "4" => Protection::READ | Protection::WRITE,
+            // Unsupported type
+            _ => return "",
+        };
+        // For watchpoints, the kind is the number of bytes to watch.
+        if kind == 0 || addr.checked_add(kind - 1).is_none() {
+            "E00"
+        } else if insert {
+            mem.add_watchpoint(addr, kind, watch_kind);
+            "OK"
+        } else if mem.remove_watchpoint(addr, kind, watch_kind) {
+            "OK"
+        } else {
+            "E00"
+        }
+    }
+
+    fn insert_breakpoint(
+        &mut self,
+        addr: GuestUSize,
+        kind: GuestUSize,
+        cpu: &mut Cpu,
+        mem: &mut Mem,
+    ) -> &'static str {
+        let instruction: &'static [u8] = match kind {
+            2 => &THUMB_BREAKPOINT,
+            3 => &THUMB2_BREAKPOINT,
+            4 => &ARM_BREAKPOINT,
+            _ => return "E00",
+        };
+        // Inserting the same breakpoint twice must have no effect.
+        if self.breakpoints.contains_key(&addr) {
+            return "OK";
+        }
+        let len = instruction.len() as GuestUSize;
+        let Some(code) = mem.get_bytes_fallible_mut(Ptr::from_bits(addr), len) else {
+            return "E00";
+        };
+        let original = code.to_vec();
+        code.copy_from_slice(instruction);
+        cpu.invalidate_cache_range(addr, len);
+        self.breakpoints.insert(
+            addr,
+            Breakpoint {
+                instruction,
+                original,
+            },
+        );
+        "OK"
+    }
+
+    fn remove_breakpoint(
+        &mut self,
+        addr: GuestUSize,
+        cpu: &mut Cpu,
+        mem: &mut Mem,
+    ) -> &'static str {
+        let Some(Breakpoint { original, .. }) = self.breakpoints.remove(&addr) else {
+            return "E00";
+        };
+        let len = original.len() as GuestUSize;
+        mem.get_bytes_fallible_mut(Ptr::from_bits(addr), len)
+            .unwrap()
+            .copy_from_slice(&original);
+        cpu.invalidate_cache_range(addr, len);
+        "OK"
+    }
+
+    /// Put the original code back at every inserted breakpoint if `suspended`
+    /// is [true], or the breakpoint instructions if it's [false]. The
+    /// debugger's view of memory is the same either way. "Hardware"
+    /// breakpoints are ignored while suspended.
+    pub fn set_breakpoints_suspended(&mut self, suspended: bool, cpu: &mut Cpu, mem: &mut Mem) {
+        self.breakpoints_suspended = suspended;
+        for (&addr, breakpoint) in &self.breakpoints {
+            let code = if suspended {
+                &breakpoint.original[..]
+            } else {
+                breakpoint.instruction
+            };
+            let len = code.len() as GuestUSize;
+            mem.get_bytes_fallible_mut(Ptr::from_bits(addr), len)
+                .unwrap()
+                .copy_from_slice(code);
+            cpu.invalidate_cache_range(addr, len);
+        }
+    }
+
+    /// Check whether execution should stop at `pc` because of a breakpoint
+    /// inserted with `Z1`. Since this has to be checked for every instruction,
+    /// the CPU is single-stepped while there are any of these breakpoints (see
+    /// [Self::has_hardware_breakpoints]).
+    pub fn hardware_breakpoint_at(&self, pc: GuestUSize) -> bool {
+        !self.breakpoints_suspended && self.hardware_breakpoints.contains(&pc)
+    }
+
+    pub fn has_hardware_breakpoints(&self) -> bool {
+        !self.breakpoints_suspended && !self.hardware_breakpoints.is_empty()
+    }
+
+    /// For each inserted breakpoint overlapping the memory range of `length`
+    /// bytes at `addr`, call `f` with the offset into that range where the
+    /// overlap begins, and the overlapping parts of the original code and of
+    /// the breakpoint instruction.
+    fn for_each_breakpoint_in(
+        &mut self,
+        addr: GuestUSize,
+        length: GuestUSize,
+        mut f: impl FnMut(usize, &mut [u8], &[u8]),
+    ) {
+        let range_end = u64::from(addr) + u64::from(length);
+        for (&bp_addr, bp) in self.breakpoints.iter_mut() {
+            let start = bp_addr.max(addr);
+            let end = (u64::from(bp_addr) + bp.original.len() as u64).min(range_end);
+            if u64::from(start) >= end {
+                continue;
+            }
+            let part = (start - bp_addr) as usize..(end - u64::from(bp_addr)) as usize;
+            f(
+                (start - addr) as usize,
+                &mut bp.original[part.clone()],
+                &bp.instruction[part],
+            );
         }
     }
 
@@ -112,10 +845,12 @@ impl GdbServer {
         log_dbg!("Got packet: {:?}", body);
 
         // Send acknowledgment
-        self.reader
-            .get_mut()
-            .write_all(b"+")
-            .expect("Couldn't send ACK");
+        if !self.no_ack_mode {
+            self.reader
+                .get_mut()
+                .write_all(b"+")
+                .expect("Couldn't send ACK");
+        }
 
         Some(body)
     }
@@ -126,43 +861,169 @@ impl GdbServer {
         log_dbg!("Sent packet: {:?}", body);
     }
 
+    /// Send a stop reply packet saying that the current thread stopped due to
+    /// `signal`. `extra` is for further `name:value;` pairs. If libraries have
+    /// been added since the last stop reply, this also tells the debugger.
+    fn send_stop_reply(&mut self, signal: u8, current_thread: ThreadId, extra: &str) {
+        let library = if std::mem::take(&mut self.libraries_changed) {
+            "library:;"
+        } else {
+            ""
+        };
+        self.send_packet(&format!(
+            "T{:02x}thread:{:x};{}{}",
+            signal,
+            current_thread + 1,
+            extra,
+            library
+        ));
+    }
+
+    /// Build the JSON reply to `jThreadsInfo`, which includes the registers
+    /// LLDB needs for a backtrace, so it doesn't have to ask for them.
+    fn threads_info(
+        cpu: &mut Cpu,
+        threads: &mut [DebugThread],
+        current_thread: ThreadId,
+        stop_signal: u8,
+    ) -> String {
+        let ids: Vec<ThreadId> = threads.iter().map(|t| t.id).collect();
+        let mut json = String::from("[");
+        for (i, id) in ids.into_iter().enumerate() {
+            if i != 0 {
+                json.push(',');
+            }
+            write!(json, "{{\"tid\":{}", id + 1).unwrap();
+            if id == current_thread {
+                write!(json, ",\"reason\":\"signal\",\"signal\":{}", stop_signal).unwrap();
+            }
+            json.push_str(",\"registers\":{");
+            let regs = thread_regs(cpu, threads, id).unwrap();
+            for (j, num) in [7, 13, 14, 15, REG_CPSR].into_iter().enumerate() {
+                if j != 0 {
+                    json.push(',');
+                }
+                write!(json, "\"{}\":\"", num).unwrap();
+                encode_hex(&regs.read_register(num).unwrap(), &mut json);
+                json.push('"');
+            }
+            json.push_str("}}");
+        }
+        json.push(']');
+        json
+    }
+
+    /// Add a library loaded after startup (by `dlopen()`). The debugger is
+    /// told about it in the next stop reply (with the `library` stop reason,
+    /// which makes it fetch the list again), so the caller should make
+    /// execution stop before the library's code runs.
+    pub fn add_library(&mut self, library: DebugLibrary, mem: &mut Mem) {
+        self.libraries.push(library);
+        self.update_all_image_infos(mem);
+        self.libraries_changed = true;
+    }
+
+    /// Whether a command requested with [DebuggerAction::Monitor] is running.
+    pub fn monitor_command_running(&self) -> bool {
+        self.monitor_command_running
+    }
+
+    /// Send the output of a command requested with [DebuggerAction::Monitor].
+    pub fn send_monitor_output(&mut self, output: &str) {
+        assert!(self.monitor_command_running);
+        // The output is sent as console output packets, which must be kept
+        // within the debugger's buffer size.
+        for chunk in output.as_bytes().chunks(512) {
+            let mut packet = String::from("O");
+            encode_hex(chunk, &mut packet);
+            self.send_packet(&packet);
+        }
+    }
+
     /// Communciates with the debugger, returning only once it requests
-    /// execution should continue. Returns [true] if the CPU should step and
-    /// then resume debugging, or [false] if it should resume normal execution.
+    /// execution should continue or a monitor command should be run.
+    ///
+    /// `threads` should contain every active thread, exactly one of which is
+    /// the thread currently using the CPU. Only that thread can be stepped.
     pub fn wait_for_debugger(
         &mut self,
         stop_reason: Option<CpuError>,
         cpu: &mut Cpu,
         mem: &mut Mem,
-    ) -> bool {
-        echo!("Waiting for debugger to continue.");
+        threads: &mut [DebugThread],
+    ) -> DebuggerAction {
+        let current_thread = threads.iter().find(|t| t.context.is_none()).unwrap().id;
+        // Thread selected with the `Hg` packet, for register access.
+        let mut selected_thread = current_thread;
 
         // Send reply to continue/step packet that gdb sent earlier, so it knows
-        // why execution was stopped.
-        match stop_reason {
-            None => {
-                if self.first_halt {
-                    // The debugger has just connected, it hasn't sent anything yet.
-                    self.first_halt = false;
-                } else {
-                    // The debugger previously requested stepping and no errors
-                    // occurred.
-                    self.send_packet("S05"); // SIGTRAP
-                }
-            }
-            // GDB uses an undefined instruction for software breakpoints in
-            // normal Arm code, and the BKPT instruction in Thumb code.
-            // It apparently expects SIGTRAP instead of SIGILL even in the
-            // former case.
-            Some(CpuError::UndefinedInstruction) | Some(CpuError::Breakpoint) => {
-                self.send_packet("S05"); // SIGTRAP
+        // why execution was stopped. The signal is also needed for
+        // `jThreadsInfo`.
+        let stop_signal = if self.monitor_command_running {
+            if let Some(error) = stop_reason {
+                // Guest code run by the command stopped, e.g. it crashed. The
+                // debugger is still waiting for the command's reply, so this
+                // can only be reported as a failure of the command. Execution
+                // stays stopped inside the command.
+                echo!("Error during monitor command: {:?}.", error);
+                self.send_monitor_output(&format!(
+                    "Stopped by {:?} while running the command. Continuing \
+                     will resume the command.\n",
+                    error
+                ));
+                self.monitor_command_running = false;
+                self.send_packet("E01");
+            } else {
+                // Execution didn't resume, so there's nothing new to report,
+                // but the monitor command needs its final reply.
+                self.monitor_command_running = false;
+                self.send_packet("OK");
             }
-            Some(CpuError::MemoryError) => {
-                self.send_packet("S0b"); // SIGSEGV
+            self.stop_signal
+        } else {
+            echo!("Waiting for debugger to continue.");
+            match stop_reason {
+                None => {
+                    if self.first_halt {
+                        // The debugger has just connected, it hasn't sent anything yet.
+                        self.first_halt = false;
+                        0x00 // no signal
+                    } else {
+                        // The debugger previously requested stepping and no errors
+                        // occurred.
+                        self.send_stop_reply(0x05, current_thread, ""); // SIGTRAP
+                        0x05
+                    }
+                }
+                // GDB uses an undefined instruction for software breakpoints in
+                // normal Arm code, and the BKPT instruction in Thumb code.
+                // It apparently expects SIGTRAP instead of SIGILL even in the
+                // former case.
+                Some(CpuError::UndefinedInstruction) | Some(CpuError::Breakpoint) => {
+                    self.send_stop_reply(0x05, current_thread, ""); // SIGTRAP
+                    0x05
+                }
+                Some(CpuError::MemoryError) | Some(CpuError::ProtectionFault(_)) => {
+                    self.send_stop_reply(0x0b, current_thread, ""); // SIGSEGV
+                    0x0b
+                }
+                Some(CpuError::Watchpoint(ref hit)) => {
+                    // SIGTRAP, and the address lets the debugger tell which
+                    // watchpoint was hit.
+                    let watch = match hit.kind {
+                        Protection::WRITE => "watch",
+                        Protection::READ => "rwatch",
+                        _ => "awatch",
+                    };
+                    let extra = format!("{}:{:x};", watch, hit.addr);
+                    self.send_stop_reply(0x05, current_thread, &extra);
+                    0x05
+                }
             }
-        }
+        };
+        self.stop_signal = stop_signal;
 
-        let do_step = loop {
+        let action = loop {
             let Some(p) = self.read_packet() else {
                 continue;
             };
@@ -174,51 +1035,52 @@ impl GdbServer {
             match p.as_bytes()[0] {
                 // Query for target halt reason when first connecting
                 b'?' => {
-                    assert!(stop_reason.is_none());
-                    self.send_packet("S00"); // no signal
+                    self.send_stop_reply(stop_signal, current_thread, "");
                 }
                 // Read general registers
                 b'g' => {
-                    let mut packet = String::with_capacity(16 * 4 * 2);
-                    for reg in cpu.regs() {
-                        // Rust always prints in big-endian, but GDB expects
-                        // little-endian.
-                        let reg = u32::from_be_bytes(reg.to_le_bytes());
-                        write!(packet, "{:08x}", reg).unwrap();
+                    let Some(regs) = thread_regs(cpu, threads, selected_thread) else {
+                        // Error 0
+                        self.send_packet("E00");
+                        continue;
+                    };
+                    let mut packet = String::new();
+                    for num in g_packet_regs() {
+                        encode_hex(&regs.read_register(num).unwrap(), &mut packet);
                     }
                     self.send_packet(&packet);
                 }
                 // Write general registers
                 b'G' => {
-                    let data = &p[1..];
-                    let regs = cpu.regs_mut();
-                    assert!(data.len() == regs.len() * 4 * 2);
-                    for (i, reg) in regs.iter_mut().enumerate() {
-                        let word = &data[i * 4 * 2..][..4 * 2];
-                        let word = u32::from_str_radix(word, 16).unwrap();
-                        // Rust decodes in big-endian, but GDB supplies
-                        // little-endian.
-                        let word = u32::from_le_bytes(word.to_be_bytes());
-                        *reg = word;
+                    let data = decode_hex(&p[1..]);
+                    let Some(values) = data.as_deref().and_then(split_g_packet) else {
+                        // Error 0
+                        self.send_packet("E00");
+                        continue;
+                    };
+                    let Some(mut regs) = thread_regs(cpu, threads, selected_thread) else {
+                        // Error 0
+                        self.send_packet("E00");
+                        continue;
+                    };
+                    let mut ok = true;
+                    for (num, value) in values {
+                        ok &= regs.write_register(num, value);
                     }
-                    self.send_packet("OK");
+                    // Error 0
+                    self.send_packet(if ok { "OK" } else { "E00" });
                 }
                 // Read single register by number
                 b'p' => {
-                    let num = usize::from_str_radix(&p[1..], 16).unwrap();
-                    let reg = if num < 16 {
-                        Some(cpu.regs()[num])
-                    } else if num == 25 {
-                        Some(cpu.cpsr())
-                    // TODO: FPSCR, VFP registers
-                    } else {
-                        None
-                    };
-                    if let Some(reg) = reg {
-                        // Rust always prints in big-endian, but GDB expects
-                        // little-endian.
-                        let reg = u32::from_be_bytes(reg.to_le_bytes());
-                        self.send_packet(&format!("{:08x}", reg));
+                    let regs = thread_regs(cpu, threads, selected_thread);
+                    let value = usize::from_str_radix(&p[1..], 16)
+                        .ok()
+                        .zip(regs)
+                        .and_then(|(num, regs)| regs.read_register(num));
+                    if let Some(value) = value {
+                        let mut packet = String::new();
+                        encode_hex(&value, &mut packet);
+                        self.send_packet(&packet);
                     } else {
                         // Error 0
                         self.send_packet("E00");
@@ -226,19 +1088,15 @@ impl GdbServer {
                 }
                 // Write single register by number
                 b'P' => {
-                    let (num, word) = p[1..].split_once('=').unwrap();
-                    let num = usize::from_str_radix(num, 16).unwrap();
-                    let word = u32::from_str_radix(word, 16).unwrap();
-                    // Rust decodes in big-endian, but GDB supplies
-                    // little-endian.
-                    let word = u32::from_le_bytes(word.to_be_bytes());
-                    if num < 16 {
-                        cpu.regs_mut()[num] = word;
+                    let parsed = p[1..].split_once('=').and_then(|(num, value)| {
+                        Some((usize::from_str_radix(num, 16).ok()?, decode_hex(value)?))
+                    });
+                    let regs = thread_regs(cpu, threads, selected_thread);
+                    if parsed
+                        .zip(regs)
+                        .is_some_and(|((num, value), mut regs)| regs.write_register(num, &value))
+                    {
                         self.send_packet("OK");
-                    } else if num == 25 {
-                        cpu.set_cpsr(word);
-                        self.send_packet("OK");
-                    // TODO: FPSCR, VFP registers
                     } else {
                         // Error 0
                         self.send_packet("E00");
@@ -252,6 +1110,11 @@ impl GdbServer {
                     let mut packet = String::with_capacity(length as usize * 2);
                     match mem.get_bytes_fallible(Ptr::from_bits(addr), length) {
                         Some(data) => {
+                            // The debugger shouldn't see our breakpoints.
+                            let mut data = data.to_vec();
+                            self.for_each_breakpoint_in(addr, length, |offset, original, _| {
+                                data[offset..][..original.len()].copy_from_slice(original);
+                            });
                             for byte in data {
                                 write!(packet, "{:02x}", byte).unwrap();
                             }
@@ -278,6 +1141,18 @@ impl GdbServer {
                                 let byte = u8::from_str_radix(byte, 16).unwrap();
                                 dest[i] = byte;
                             }
+                            // If this overwrote a breakpoint, the new code is
+                            // what should be restored when it's removed, and
+                            // the breakpoint instruction must be kept.
+                            self.for_each_breakpoint_in(
+                                addr,
+                                length,
+                                |offset, original, instruction| {
+                                    let dest = &mut dest[offset..][..original.len()];
+                                    original.copy_from_slice(dest);
+                                    dest.copy_from_slice(instruction);
+                                },
+                            );
                             // Important for e.g. software breakpoints.
                             cpu.invalidate_cache_range(addr, length);
                             self.send_packet("OK");
@@ -294,7 +1169,11 @@ impl GdbServer {
                     if !addr.is_empty() {
                         todo!("TODO: Resume at {}", addr);
                     }
-                    break p.as_bytes()[0] == b's';
+                    break if p.as_bytes()[0] == b's' {
+                        DebuggerAction::Step
+                    } else {
+                        DebuggerAction::Continue
+                    };
                 }
                 // "Continue with signal" or "Step with signal".
                 // Presumably "with" means "ignoring"?
@@ -303,7 +1182,49 @@ impl GdbServer {
                     if let Some((_signal, addr)) = p[1..].split_once(';') {
                         todo!("TODO: Resume at {}", addr);
                     }
-                    break p.as_bytes()[0] == b'S';
+                    break if p.as_bytes()[0] == b'S' {
+                        DebuggerAction::Step
+                    } else {
+                        DebuggerAction::Continue
+                    };
+                }
+                // Insert or remove breakpoint or watchpoint
+                b'Z' | b'z' => {
+                    let insert = p.as_bytes()[0] == b'Z';
+                    let reply = self.insert_or_remove_point(insert, &p[1..], cpu, mem);
+                    self.send_packet(reply);
+                }
+                // Set thread for subsequent operations
+                b'H' if p.len() > 2 => {
+                    let thread = parse_thread_id(&p[2..]);
+                    let exists = |thread| threads.iter().any(|t| t.id == thread);
+                    let reply = match (p.as_bytes()[1], thread) {
+                        // Register access
+                        (b'g', Ok(None)) => {
+                            selected_thread = current_thread;
+                            "OK"
+                        }
+                        (b'g', Ok(Some(thread))) if exists(thread) => {
+                            selected_thread = thread;
+                            "OK"
+                        }
+                        // Continuing and stepping. All threads are always
+                        // resumed together, and only the current thread can
+                        // be stepped, so the debugger can't pick another one.
+                        (b'c', Ok(None)) => "OK",
+                        (b'c', Ok(Some(thread))) if thread == current_thread => "OK",
+                        _ => "E00",
+                    };
+                    self.send_packet(reply);
+                }
+                // Query whether a thread is alive
+                b'T' => {
+                    match parse_thread_id(&p[1..]) {
+                        Ok(Some(thread)) if threads.iter().any(|t| t.id == thread) => {
+                            self.send_packet("OK")
+                        }
+                        _ => self.send_packet("E00"),
+                    };
                 }
                 // Kill
                 b'k' => {
@@ -314,55 +1235,139 @@ impl GdbServer {
                     if p == "qAttached" {
                         // New process
                         self.send_packet("0");
+                    // Query for the list of active threads. All of them fit
+                    // in the first reply.
+                    } else if p == "qfThreadInfo" {
+                        let ids: Vec<String> =
+                            threads.iter().map(|t| format!("{:x}", t.id + 1)).collect();
+                        self.send_packet(&format!("m{}", ids.join(",")));
+                    } else if p == "qsThreadInfo" {
+                        // End of list
+                        self.send_packet("l");
+                    // Query for the current thread
+                    } else if p == "qC" {
+                        self.send_packet(&format!("QC{:x}", current_thread + 1));
+                    // Query for a description of a thread
+                    } else if let Some(thread) = p.strip_prefix("qThreadExtraInfo,") {
+                        let thread = parse_thread_id(thread).ok().flatten();
+                        if let Some(t) = threads.iter().find(|t| Some(t.id) == thread) {
+                            let mut packet = String::with_capacity(t.description.len() * 2);
+                            encode_hex(t.description.as_bytes(), &mut packet);
+                            self.send_packet(&packet);
+                        } else {
+                            self.send_packet("E00");
+                        }
                     // Query for supported features
                     } else if p == "qSupported" || p.starts_with("qSupported:") {
-                        // Tell GDB we can send it an XML target description.
-                        self.send_packet("qXfer:features:read+");
-                    // Read XML target description
-                    } else if let Some(params) = p.strip_prefix("qXfer:features:read:") {
-                        let (annex, params) = params.split_once(':').unwrap();
-                        let (offset, length) = params.split_once(',').unwrap();
-                        let offset = usize::from_str_radix(offset, 16).unwrap();
-                        let length = usize::from_str_radix(length, 16).unwrap();
-                        let bytes = TARGET_XML.as_bytes();
-                        if annex == "target.xml" && offset <= bytes.len() {
-                            let bytes = &bytes[offset..];
-                            let length_read = length.min(bytes.len());
-                            let mut packet = String::with_capacity(1 + length_read);
-                            if length_read < length {
-                                // Read data, more remains
-                                packet.push('l');
-                            } else {
-                                // Read data, none left
-                                packet.push('m');
+                        // Tell GDB we can send it an XML target description
+                        // and a list of the loaded binaries.
+                        self.send_packet(
+                            "qXfer:features:read+;qXfer:libraries:read+;QStartNoAckMode+",
+                        );
+                    // Stop sending and expecting acknowledgments. This
+                    // packet itself is still acknowledged.
+                    } else if p == "QStartNoAckMode" {
+                        self.send_packet("OK");
+                        self.no_ack_mode = true;
+                    // Read XML target description or library list
+                    } else if let Some(params) = p.strip_prefix("qXfer:") {
+                        let Some((object, annex, offset, length)) = parse_qxfer_read(params) else {
+                            // Error 0
+                            self.send_packet("E00");
+                            continue;
+                        };
+                        let xml = match (object, annex) {
+                            ("features", "target.xml") => Some(self.target_xml()),
+                            ("libraries", "") => Some(self.libraries_xml()),
+                            _ => None,
+                        };
+                        match xml {
+                            Some(xml) if offset <= xml.len() => {
+                                let bytes = &xml.as_bytes()[offset..];
+                                let length_read = length.min(bytes.len());
+                                let mut packet = String::with_capacity(1 + length_read);
+                                if length_read < length {
+                                    // Read data, none left
+                                    packet.push('l');
+                                } else {
+                                    // Read data, more may remain
+                                    packet.push('m');
+                                }
+                                // This packet uses the modern style of binary
+                                // data where most bytes are unescaped. The
+                                // library names could in principle contain
+                                // bytes that need escaping, and they might
+                                // not be ASCII.
+                                let data = String::from_utf8_lossy(&bytes[..length_read]);
+                                packet.push_str(&escape_binary(&data));
+                                self.send_packet(&packet);
                             }
-                            // This packet uses the modern style of binary
-                            // data where most bytes are unescaped.
-                            // We happen to know none of the bytes in the XML
-                            // need escaping, and that they're all ASCII.
-                            packet.push_str(std::str::from_utf8(&bytes[..length_read]).unwrap());
-                            self.send_packet(&packet);
-                        } else {
-                            // Unsupported annex or invalid offset
+                            _ => {
+                                // Unsupported object or annex, or invalid
+                                // offset
+                                self.send_packet("E00");
+                            }
+                        }
+                    // LLDB: query for information about the host and the
+                    // process. These are the same thing as far as the debugger
+                    // is concerned.
+                    } else if p == "qHostInfo" {
+                        let info = self.target_info() + "watchpoint_exceptions_received:before;";
+                        self.send_packet(&info);
+                    } else if p == "qProcessInfo" {
+                        let info = format!("pid:1;parent-pid:1;{}", self.target_info());
+                        self.send_packet(&info);
+                    // LLDB: query for the details of a register
+                    } else if let Some(num) = p.strip_prefix("qRegisterInfo") {
+                        let Ok(num) = usize::from_str_radix(num, 16) else {
+                            // Error 0
                             self.send_packet("E00");
+                            continue;
+                        };
+                        match register_info(num) {
+                            Some(info) => self.send_packet(&info),
+                            // No more registers
+                            None => self.send_packet("E45"),
                         }
+                    // LLDB: query for the address of the list of binaries
+                    } else if p == "qShlibInfoAddr" {
+                        let addr = self.all_image_infos(mem);
+                        self.send_packet(&format!("{:x}", addr));
+                    // LLDB: query for the state of all threads at once
+                    } else if p == "jThreadsInfo" {
+                        let json = Self::threads_info(cpu, threads, current_thread, stop_signal);
+                        self.send_packet(&escape_binary(&json));
+                    // Run a touchHLE-specific command ("monitor" in GDB and
+                    // "process plugin packet monitor" in LLDB)
+                    } else if let Some(command) = p.strip_prefix("qRcmd,") {
+                        let Some(command) = decode_hex(command) else {
+                            // Error 0
+                            self.send_packet("E00");
+                            continue;
+                        };
+                        let command = String::from_utf8_lossy(&command).into_owned();
+                        self.monitor_command_running = true;
+                        break DebuggerAction::Monitor(command);
                     } else {
                         log_dbg!("Unhandled packet.");
                         // Tell GDB we don't understand this packet.
-                        // In some cases this causes convenient fallbacks:
-                        // Since we don't support 'Z', GDB will implement
-                        // software breakpoints for us with trap instructions.
                         self.send_packet("");
                     }
                 }
             }
         };
 
-        if do_step {
-            echo!("Debugger requested step, resuming execution for one instruction only.");
-        } else {
-            echo!("Debugger requested continue, resuming execution.");
+        match action {
+            DebuggerAction::Continue => {
+                echo!("Debugger requested continue, resuming execution.")
+            }
+            DebuggerAction::Step => {
+                echo!("Debugger requested step, resuming execution for one instruction only.")
+            }
+            DebuggerAction::Monitor(ref command) => {
+                echo!("Debugger requested monitor command: {}", command)
+            }
         }
-        do_step
+        action
     }
 }
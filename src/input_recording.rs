@@ -0,0 +1,400 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Recording and replaying of input (see `--record-input=` and
+//! `--replay-input=`), useful for reproducing bugs.
+//!
+//! Input is consumed by the app when UIKit handles events (see
+//! [crate::frameworks::uikit::handle_events]), so that's where it's recorded.
+//! Each input is tagged with how many times events had been handled at that
+//! point, and with the virtual time (see [crate::clock]). Since the app sees
+//! the same times on replay, it should handle events at exactly the same
+//! points, and the inputs can be fed back in at those points.
+//!
+//! The file format is plain text with one input per line, so it can be
+//! inspected or edited by hand if need be.
+
+use crate::options::{Button, Options};
+use crate::window::{Coords, Event, FingerId, Window};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const MAGIC: &str = "touchHLE input recording v1";
+
+enum Input {
+    Event(Event),
+    Acceleration((f32, f32, f32)),
+}
+
+struct Entry {
+    /// Value of [InputRecording::poll_count] when this was consumed.
+    poll: u64,
+    /// Virtual time when this was consumed.
+    time: Duration,
+    input: Input,
+}
+
+enum Mode {
+    Recording(File),
+    Replaying {
+        entries: VecDeque<Entry>,
+        warned_about_divergence: bool,
+    },
+}
+
+pub struct InputRecording {
+    mode: Mode,
+    /// Wall-clock time the app saw at startup. This is part of the recording
+    /// so that the replay sees the same dates.
+    start_time: SystemTime,
+    /// Number of times events have been handled so far.
+    poll_count: u64,
+}
+
+impl InputRecording {
+    /// Start recording or replaying, if the options ask for it.
+    pub fn from_options(options: &Options) -> Result<Option<InputRecording>, String> {
+        match (&options.record_input, &options.replay_input) {
+            (None, None) => Ok(None),
+            (Some(_), Some(_)) => {
+                Err("--record-input= and --replay-input= can't be used together".to_string())
+            }
+            (Some(path), None) => Self::start_recording(path).map(Some),
+            (None, Some(path)) => Self::start_replaying(path).map(Some),
+        }
+    }
+
+    fn start_recording(path: &Path) -> Result<InputRecording, String> {
+        let start_time = SystemTime::now();
+        let start_nanos = start_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let mut file = File::create(path)
+            .map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+        writeln!(file, "{}\nstart {}", MAGIC, start_nanos)
+            .map_err(|e| format!("Could not write to {}: {}", path.display(), e))?;
+        log!("Recording input to {}.", path.display());
+        Ok(InputRecording {
+            mode: Mode::Recording(file),
+            start_time,
+            poll_count: 0,
+        })
+    }
+
+    fn start_replaying(path: &Path) -> Result<InputRecording, String> {
+        let file =
+            File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        let mut lines = BufReader::new(file).lines().enumerate();
+        let mut next_line = || -> Option<Result<(usize, String), String>> {
+            let (line_no, line) = lines.next()?;
+            // Line numbering usually starts from 1
+            let line_no = line_no + 1;
+            Some(
+                line.map(|line| (line_no, line))
+                    .map_err(|e| format!("Error while reading line {}: {}", line_no, e)),
+            )
+        };
+
+        let bad_header = || format!("{} is not a touchHLE input recording", path.display());
+        let (_, magic) = next_line().ok_or_else(bad_header)??;
+        if magic != MAGIC {
+            return Err(bad_header());
+        }
+        let (_, start) = next_line().ok_or_else(bad_header)??;
+        let start_nanos: u64 = start
+            .strip_prefix("start ")
+            .and_then(|nanos| nanos.parse().ok())
+            .ok_or_else(bad_header)?;
+        let start_time = SystemTime::UNIX_EPOCH + Duration::from_nanos(start_nanos);
+
+        let mut entries = VecDeque::new();
+        while let Some(line) = next_line() {
+            let (line_no, line) = line?;
+            let entry = parse_entry(&line)
+                .ok_or_else(|| format!("Line {} of {} is invalid", line_no, path.display()))?;
+            entries.push_back(entry);
+        }
+
+        log!(
+            "Replaying {} inputs from {}.",
+            entries.len(),
+            path.display()
+        );
+        Ok(InputRecording {
+            mode: Mode::Replaying {
+                entries,
+                warned_about_divergence: false,
+            },
+            start_time,
+            poll_count: 0,
+        })
+    }
+
+    pub fn start_time(&self) -> SystemTime {
+        self.start_time
+    }
+
+    /// Whether the clock should keep pace with real time, because a human is
+    /// providing the input.
+    pub fn is_recording(&self) -> bool {
+        matches!(self.mode, Mode::Recording(_))
+    }
+
+    /// Must be called each time events are about to be handled.
+    pub fn begin_poll(&mut self) {
+        self.poll_count += 1;
+    }
+
+    /// Replacement for [Window::pop_event]. The window is absent in headless
+    /// mode, where there's no input to record, but a recording still lets the
+    /// run be replayed exactly.
+    pub fn pop_event(&mut self, window: Option<&mut Window>, now: Duration) -> Option<Event> {
+        match self.mode {
+            Mode::Recording(_) => {
+                let event = window?.pop_event()?;
+                self.record(now, format_event(&event));
+                Some(event)
+            }
+            Mode::Replaying { .. } => {
+                // Real input is ignored, but the user should still be able to
+                // close the window.
+                if let Some(window) = window {
+                    while let Some(event) = window.pop_event() {
+                        if let Event::Quit = event {
+                            return Some(event);
+                        }
+                    }
+                }
+                match self.take_replayed(now, |input| matches!(input, Input::Event(_)))? {
+                    Input::Event(event) => Some(event),
+                    Input::Acceleration(_) => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// Replacement for [Window::get_acceleration]. The window is absent in
+    /// headless mode.
+    pub fn get_acceleration(
+        &mut self,
+        window: Option<&Window>,
+        options: &Options,
+        now: Duration,
+    ) -> (f32, f32, f32) {
+        match self.mode {
+            Mode::Recording(_) => {
+                let (x, y, z) = match window {
+                    Some(window) => window.get_acceleration(options),
+                    // Lying flat on its back.
+                    None => (0.0, 0.0, -1.0),
+                };
+                self.record(now, format!("accel {} {} {}", x, y, z));
+                (x, y, z)
+            }
+            Mode::Replaying { .. } => {
+                match self.take_replayed(now, |input| matches!(input, Input::Acceleration(_))) {
+                    Some(Input::Acceleration(acceleration)) => acceleration,
+                    Some(Input::Event(_)) => unreachable!(),
+                    // Lying flat on its back.
+                    None => (0.0, 0.0, -1.0),
+                }
+            }
+        }
+    }
+
+    fn record(&mut self, now: Duration, input: String) {
+        let Mode::Recording(ref mut file) = self.mode else {
+            unreachable!();
+        };
+        // This is written immediately rather than buffered, because the app
+        // may exit without giving us the chance to flush anything.
+        let line = format!("{} {} {}\n", self.poll_count, now.as_nanos(), input);
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log!("Warning: Could not write input recording: {}", e);
+        }
+    }
+
+    fn take_replayed(
+        &mut self,
+        now: Duration,
+        is_wanted: impl Fn(&Input) -> bool,
+    ) -> Option<Input> {
+        let Mode::Replaying {
+            ref mut entries,
+            ref mut warned_about_divergence,
+        } = self.mode
+        else {
+            unreachable!();
+        };
+
+        let entry = entries.front()?;
+        if entry.poll > self.poll_count
+            || (entry.poll == self.poll_count && !is_wanted(&entry.input))
+        {
+            return None;
+        }
+        let entry = entries.pop_front().unwrap();
+
+        if (entry.poll != self.poll_count || entry.time != now || !is_wanted(&entry.input))
+            && !*warned_about_divergence
+        {
+            *warned_about_divergence = true;
+            log!(
+                "Warning: Replay has diverged from the recording (expected input at event poll {}, time {:?}; now at event poll {}, time {:?}). The rest of the replay may not be accurate.",
+                entry.poll,
+                entry.time,
+                self.poll_count,
+                now
+            );
+        }
+        if entries.is_empty() {
+            log!("Input replay finished.");
+        }
+
+        if is_wanted(&entry.input) {
+            Some(entry.input)
+        } else {
+            None
+        }
+    }
+}
+
+fn format_finger_id(finger_id: FingerId) -> String {
+    match finger_id {
+        FingerId::Mouse => "mouse".to_string(),
+        FingerId::Touch(id) => format!("touch:{}", id),
+        FingerId::VirtualCursor => "cursor".to_string(),
+        FingerId::ButtonToTouch(button) => format!("button:{:?}", button),
+    }
+}
+
+fn parse_finger_id(finger_id: &str) -> Option<FingerId> {
+    match finger_id {
+        "mouse" => Some(FingerId::Mouse),
+        "cursor" => Some(FingerId::VirtualCursor),
+        _ => {
+            if let Some(id) = finger_id.strip_prefix("touch:") {
+                Some(FingerId::Touch(id.parse().ok()?))
+            } else {
+                let button = finger_id.strip_prefix("button:")?;
+                Some(FingerId::ButtonToTouch(Button::from_name(button).ok()?))
+            }
+        }
+    }
+}
+
+fn format_event(event: &Event) -> String {
+    let (kind, touches) = match event {
+        Event::Quit => return "quit".to_string(),
+        Event::AppWillResignActive => return "resign_active".to_string(),
+        Event::AppWillTerminate => return "terminate".to_string(),
+        Event::TouchesDown(touches) => ("touches_down", touches),
+        Event::TouchesMove(touches) => ("touches_move", touches),
+        Event::TouchesUp(touches) => ("touches_up", touches),
+    };
+    let mut line = kind.to_string();
+    for (&finger_id, &(x, y)) in touches {
+        line += &format!(" {}={},{}", format_finger_id(finger_id), x, y);
+    }
+    line
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut parts = line.split(' ');
+    let poll: u64 = parts.next()?.parse().ok()?;
+    let time = Duration::from_nanos(parts.next()?.parse().ok()?);
+    let kind = parts.next()?;
+
+    fn parse_touches<'a>(
+        parts: impl Iterator<Item = &'a str>,
+    ) -> Option<HashMap<FingerId, Coords>> {
+        let mut touches = HashMap::new();
+        for touch in parts {
+            let (finger_id, coords) = touch.split_once('=')?;
+            let (x, y) = coords.split_once(',')?;
+            touches.insert(
+                parse_finger_id(finger_id)?,
+                (x.parse().ok()?, y.parse().ok()?),
+            );
+        }
+        Some(touches)
+    }
+    let input = match kind {
+        "quit" => Input::Event(Event::Quit),
+        "resign_active" => Input::Event(Event::AppWillResignActive),
+        "terminate" => Input::Event(Event::AppWillTerminate),
+        "touches_down" => Input::Event(Event::TouchesDown(parse_touches(parts)?)),
+        "touches_move" => Input::Event(Event::TouchesMove(parse_touches(parts)?)),
+        "touches_up" => Input::Event(Event::TouchesUp(parse_touches(parts)?)),
+        "accel" => {
+            let x = parts.next()?.parse().ok()?;
+            let y = parts.next()?.parse().ok()?;
+            let z = parts.next()?.parse().ok()?;
+            Input::Acceleration((x, y, z))
+        }
+        _ => return None,
+    };
+    Some(Entry { poll, time, input })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &str) -> Entry {
+        let entry = parse_entry(&format!("12 3456 {}", input)).unwrap();
+        assert_eq!(entry.poll, 12);
+        assert_eq!(entry.time, Duration::from_nanos(3456));
+        entry
+    }
+
+    #[test]
+    fn test_event_round_trip() {
+        let events = [
+            Event::Quit,
+            Event::AppWillResignActive,
+            Event::AppWillTerminate,
+            Event::TouchesDown(HashMap::from([(FingerId::Mouse, (1.5, 2.0))])),
+            Event::TouchesMove(HashMap::from([
+                (FingerId::Touch(3), (-4.25, 100.0)),
+                (FingerId::VirtualCursor, (0.0, 0.0)),
+            ])),
+            Event::TouchesUp(HashMap::from([(
+                FingerId::ButtonToTouch(Button::DPadLeft),
+                (160.0, 240.0),
+            )])),
+        ];
+        for event in events {
+            match round_trip(&format_event(&event)).input {
+                Input::Event(parsed) => assert_eq!(parsed, event),
+                Input::Acceleration(_) => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_acceleration_round_trip() {
+        let acceleration = (0.1, -0.5, -1.0);
+        let (x, y, z) = acceleration;
+        match round_trip(&format!("accel {} {} {}", x, y, z)).input {
+            Input::Acceleration(parsed) => assert_eq!(parsed, acceleration),
+            Input::Event(_) => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        assert!(parse_entry("").is_none());
+        assert!(parse_entry("1 2").is_none());
+        assert!(parse_entry("1 2 bogus").is_none());
+        assert!(parse_entry("x 2 quit").is_none());
+        assert!(parse_entry("1 2 touches_down mouse=1").is_none());
+        assert!(parse_entry("1 2 touches_down nobody=1,2").is_none());
+    }
+}
@@ -31,6 +31,9 @@ mod abi;
 mod app_picker;
 mod audio;
 mod bundle;
+mod call_trace;
+mod clock;
+mod compat_report;
 mod cpu;
 mod dyld;
 mod environment;
@@ -40,6 +43,7 @@ mod fs;
 mod gdb;
 mod gles;
 mod image;
+mod input_recording;
 mod libc;
 mod licenses;
 mod mach_o;
@@ -48,7 +52,9 @@ mod mem;
 mod objc;
 mod options;
 mod paths;
+mod save_state;
 mod stack;
+mod stubs;
 mod window;
 
 // Environment is used very frequently used and used to be in this module, so
@@ -56,7 +62,9 @@ mod window;
 // probably shouldn't be, but they need a new home (TODO).
 // Unlike its siblings, this module should be considered private and only used
 // via re-exports.
-use environment::{Environment, MutexId, MutexType, ThreadId, PTHREAD_MUTEX_DEFAULT};
+use environment::{
+    CondId, Environment, MutexId, MutexType, RwLockId, ThreadId, PTHREAD_MUTEX_DEFAULT,
+};
 
 use std::path::PathBuf;
 
@@ -113,6 +121,13 @@ Special options:
 
     --info
         Print basic information about the app bundle without running the app.
+
+    --compat-report
+    --compat-report=path/to/report.json
+        Print basic information about the app bundle, then list the functions,
+        constants, classes and selectors it imports that touchHLE has no
+        implementation of, without running the app. If a path is given, the
+        report is also written there as JSON.
 ";
 
 pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
@@ -131,6 +146,8 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
 
     let mut bundle_path: Option<PathBuf> = None;
     let mut just_info = false;
+    let mut compat_report = false;
+    let mut compat_report_json: Option<PathBuf> = None;
     let mut option_args = Vec::new();
 
     for arg in args {
@@ -143,6 +160,11 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
             return Ok(());
         } else if arg == "--info" {
             just_info = true;
+        } else if arg == "--compat-report" {
+            compat_report = true;
+        } else if let Some(path) = arg.strip_prefix("--compat-report=") {
+            compat_report = true;
+            compat_report_json = Some(PathBuf::from(path));
         // Parse an option but discard the value, to test whether it's valid.
         // We don't want to apply it immediately, because then options loaded
         // from a file would take precedence over options from the command line.
@@ -171,6 +193,7 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
                 "No app specified. Use the --help flag to see command-line usage.".to_string(),
             );
         }
+        log::configure(&options)?;
         echo!(
             "No app specified, opening app picker. Use the --help flag to see command-line usage."
         );
@@ -214,6 +237,21 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
         "- Minimum OS version: {}",
         minimum_os_version.unwrap_or("(not specified)")
     );
+    let slices = fs
+        .read(bundle.executable_path())
+        .map_err(|_| "Could not read executable file")
+        .and_then(|bytes| mach_o::MachO::list_slices(&bytes));
+    match slices {
+        Ok(ref slices) => echo!(
+            "- Executable architectures: {}",
+            slices
+                .iter()
+                .map(mach_o::Slice::name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Err(e) => echo!("- Executable architectures: (unknown: {})", e),
+    }
     echo!();
 
     if let Some(version) = minimum_os_version {
@@ -224,7 +262,30 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
         }
     }
 
-    if just_info {
+    if just_info || compat_report {
+        // Options files aren't consulted in these modes, so only a
+        // --preferred-arch= on the command line is taken into account.
+        let mut options = options::Options::default();
+        for option_arg in &option_args {
+            let parse_result = options.parse_argument(option_arg);
+            assert!(parse_result == Ok(true));
+        }
+        if just_info {
+            if let Ok(slices) = slices {
+                match mach_o::MachO::select_slice(&slices, options.preferred_arch) {
+                    Some(slice) => echo!("Architecture that would be used: {}", slice.name()),
+                    None => echo!("Warning: no architecture in the executable can be used!"),
+                }
+            }
+        }
+        if compat_report {
+            compat_report::compat_report(
+                &bundle,
+                &fs,
+                options.preferred_arch,
+                compat_report_json.as_deref(),
+            )?;
+        }
         return Ok(());
     }
 
@@ -285,6 +346,8 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
         assert!(parse_result == Ok(true));
     }
 
+    log::configure(&options)?;
+
     let mut env = Environment::new(bundle, fs, options, env_for_salvage)?;
     env.run();
     Ok(())
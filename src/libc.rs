@@ -11,6 +11,8 @@
 
 mod generic_char;
 
+use crate::save_state::{Reader, Writer};
+
 pub mod ctype;
 pub mod cxxabi;
 pub mod dlfcn;
@@ -37,6 +39,7 @@ pub mod wchar;
 /// Container for state of various child modules
 #[derive(Default)]
 pub struct State {
+    dlfcn: dlfcn::State,
     keymgr: keymgr::State,
     posix_io: posix_io::State,
     pthread: pthread::State,
@@ -46,3 +49,22 @@ pub struct State {
     time: time::State,
     errno: errno::State,
 }
+
+impl State {
+    /// Write the state of the modules whose functions an app may have used
+    /// before a save state is made (see [crate::dyld::Dyld::save_state]).
+    pub fn save_state(&self, w: &mut Writer) {
+        self.stdlib.save_state(w);
+        self.string.save_state(w);
+        self.time.save_state(w);
+        self.errno.save_state(w);
+    }
+
+    /// Read back what [Self::save_state] wrote.
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.stdlib.load_state(r)?;
+        self.string.load_state(r)?;
+        self.time.load_state(r)?;
+        self.errno.load_state(r)
+    }
+}
@@ -4,47 +4,282 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 //! `dlfcn.h` (`dlopen()` and friends)
+//!
+//! Dylibs in the guest file system (e.g. plugins in the app bundle) are really
+//! loaded and linked. System libraries touchHLE has host implementations of
+//! can also be opened, and their symbols are looked up in those.
 
-use crate::dyld::{export_c_func, FunctionExports};
-use crate::mem::{ConstPtr, MutVoidPtr, Ptr};
+use crate::cpu::Cpu;
+use crate::dyld::{export_c_func, Dyld, FunctionExports, HOST_LIBRARIES};
+use crate::fs::GuestPath;
+use crate::mem::{ConstPtr, MutPtr, MutVoidPtr, Ptr};
 use crate::Environment;
 
-const ALLOWED_LIBRARIES: [Result<&str, &[u8]>; 2] = [
-    Ok("/usr/lib/libSystem.B.dylib"),
-    Ok("/System/Library/Frameworks/OpenAL.framework/OpenAL"),
+/// Frameworks touchHLE has host implementations of (at least in part).
+const HOST_FRAMEWORKS: &[&str] = &[
+    "AudioToolbox",
+    "CoreAudio",
+    "CoreFoundation",
+    "CoreGraphics",
+    "Foundation",
+    "MediaPlayer",
+    "OpenAL",
+    "OpenGLES",
+    "QuartzCore",
+    "UIKit",
 ];
 
-fn dlopen(env: &mut Environment, path: ConstPtr<u8>, _mode: i32) -> MutVoidPtr {
-    // TODO: dlopen() support for real dynamic libraries, and support for all
-    // libraries with host implementations.
-    assert!(ALLOWED_LIBRARIES.contains(&env.mem.cstr_at_utf8(path)));
-    // For convenience, use the path as the handle.
-    // TODO: Find out whether the handle is truly opaque on iPhone OS, and if
-    // not, where it points.
-    path.cast_mut().cast()
+/// `dlopen()` mode flag: only return a handle if the library is already
+/// loaded, as defined in Apple's dlfcn.h.
+const RTLD_NOLOAD: i32 = 0x10;
+
+// Special handles for dlsym(), as defined in Apple's dlfcn.h.
+const RTLD_NEXT: u32 = -1i32 as u32;
+const RTLD_DEFAULT: u32 = -2i32 as u32;
+const RTLD_SELF: u32 = -3i32 as u32;
+const RTLD_MAIN_ONLY: u32 = -5i32 as u32;
+
+#[derive(Clone, PartialEq, Eq)]
+enum Library {
+    /// Returned by `dlopen(NULL, ...)`: symbols are searched for everywhere.
+    Global,
+    /// A library touchHLE has host implementations of, identified by its path.
+    Host(String),
+    /// A binary that's been loaded, identified by its index in
+    /// [Environment::bins].
+    Image(usize),
+}
+
+struct Handle {
+    /// Opaque value given to the app. This is a small allocation, so that it
+    /// can't be confused with anything else, but nothing is stored there.
+    handle: MutVoidPtr,
+    library: Library,
+    open_count: u32,
+}
+
+#[derive(Default)]
+pub struct State {
+    handles: Vec<Handle>,
+    /// Error for the next `dlerror()` call.
+    error: Option<String>,
+    /// Storage for the string returned by `dlerror()`, which only has to last
+    /// until the next call.
+    error_string: Option<MutPtr<u8>>,
+}
+
+fn set_error(env: &mut Environment, error: String) {
+    log_dbg!("dlfcn error: {}", error);
+    env.libc_state.dlfcn.error = Some(error);
+}
+
+fn is_host_library(path: &str) -> bool {
+    if HOST_LIBRARIES.contains(&path) {
+        return true;
+    }
+    let Some(framework) = path.strip_prefix("/System/Library/Frameworks/") else {
+        return false;
+    };
+    HOST_FRAMEWORKS.iter().any(|&name| {
+        framework
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix(".framework/"))
+            == Some(name)
+    })
+}
+
+/// Find the library at `path`, loading it if it's a bundled dylib that isn't
+/// loaded yet, unless `no_load` is [true].
+fn find_library(env: &mut Environment, path: &str, no_load: bool) -> Result<Library, String> {
+    if is_host_library(path) {
+        return Ok(Library::Host(path.to_string()));
+    }
+    if !crate::dyld::is_bundled_dylib(path, &env.fs) {
+        return Err(format!("dlopen({}): image not found", path));
+    }
+    let name = GuestPath::new(path).file_name().unwrap();
+    if let Some(bin_idx) = env.bins.iter().position(|bin| bin.name == name) {
+        return Ok(Library::Image(bin_idx));
+    }
+    if no_load {
+        return Err(format!("dlopen({}): image not already loaded", path));
+    }
+    env.load_dylib(GuestPath::new(path))
+        .map(Library::Image)
+        .map_err(|e| format!("dlopen({}): {}", path, e))
+}
+
+fn dlopen(env: &mut Environment, path: ConstPtr<u8>, mode: i32) -> MutVoidPtr {
+    let library = if path.is_null() {
+        Library::Global
+    } else {
+        let path = env.mem.cstr_at_utf8(path).unwrap().to_string();
+        match find_library(env, &path, (mode & RTLD_NOLOAD) != 0) {
+            Ok(library) => library,
+            Err(error) => {
+                set_error(env, error);
+                return Ptr::null();
+            }
+        }
+    };
+
+    if let Some(handle) = env
+        .libc_state
+        .dlfcn
+        .handles
+        .iter_mut()
+        .find(|handle| handle.library == library)
+    {
+        handle.open_count += 1;
+        return handle.handle;
+    }
+    let handle = env.mem.alloc(4);
+    env.libc_state.dlfcn.handles.push(Handle {
+        handle,
+        library,
+        open_count: 1,
+    });
+    handle
+}
+
+/// Find the binary containing the code that called the current host function.
+fn caller_image(env: &Environment) -> Option<usize> {
+    let return_addr = env.cpu.regs()[Cpu::LR] & !1;
+    env.bins.iter().position(|bin| {
+        bin.sections
+            .iter()
+            .any(|section| (section.addr..section.addr + section.size).contains(&return_addr))
+    })
+}
+
+/// The loaded image `bin_idx` followed by the loaded images it depends on,
+/// directly or indirectly, in breadth-first order. This is what `dlsym()`
+/// searches for a handle from `dlopen()`.
+fn image_and_dependencies(env: &Environment, bin_idx: usize) -> Vec<usize> {
+    let mut images = vec![bin_idx];
+    let mut i = 0;
+    while i < images.len() {
+        for dependency in &env.bins[images[i]].dynamic_libraries {
+            let name = GuestPath::new(dependency).file_name().unwrap();
+            if let Some(dependency_idx) = env.bins.iter().position(|bin| bin.name == name) {
+                if !images.contains(&dependency_idx) {
+                    images.push(dependency_idx);
+                }
+            }
+        }
+        i += 1;
+    }
+    images
 }
 
 fn dlsym(env: &mut Environment, handle: MutVoidPtr, symbol: ConstPtr<u8>) -> MutVoidPtr {
-    assert!(ALLOWED_LIBRARIES.contains(&env.mem.cstr_at_utf8(handle.cast())));
+    let symbol_name = env.mem.cstr_at_utf8(symbol).unwrap().to_string();
     // For some reason, the symbols passed to dlsym() don't have the leading _.
-    let symbol = format!("_{}", env.mem.cstr_at_utf8(symbol).unwrap());
-    // TODO: error handling. dlsym() should just return NULL in this case, but
-    // currently it's probably more useful to have the emulator crash if there's
-    // no symbol found, since it most likely indicates a missing host function.
-    let addr = env
-        .dyld
-        .create_proc_address(&mut env.mem, &mut env.cpu, &symbol)
-        .unwrap_or_else(|_| panic!("dlsym() for unimplemented function {}", symbol));
-    Ptr::from_bits(addr.addr_with_thumb_bit())
+    let symbol = format!("_{}", symbol_name);
+
+    // Which binaries to search, in order, and whether to search host
+    // implementations afterwards.
+    let all_bins: Vec<usize> = (0..env.bins.len()).collect();
+    let (bins, search_host) = match handle.to_bits() {
+        RTLD_DEFAULT => (all_bins, true),
+        RTLD_NEXT => match caller_image(env) {
+            Some(bin_idx) => ((bin_idx + 1..env.bins.len()).collect(), true),
+            None => (all_bins, true),
+        },
+        RTLD_SELF => match caller_image(env) {
+            Some(bin_idx) => ((bin_idx..env.bins.len()).collect(), true),
+            None => (all_bins, true),
+        },
+        RTLD_MAIN_ONLY => (vec![0], false),
+        _ => {
+            let library = env
+                .libc_state
+                .dlfcn
+                .handles
+                .iter()
+                .find(|other| other.handle == handle)
+                .map(|other| &other.library);
+            match library {
+                Some(Library::Global) => (all_bins, true),
+                Some(Library::Host(_)) => (Vec::new(), true),
+                // Only the image and the bundled dylibs it uses, not
+                // everything touchHLE implements.
+                Some(&Library::Image(bin_idx)) => (image_and_dependencies(env, bin_idx), false),
+                None => {
+                    set_error(
+                        env,
+                        format!("dlsym({:?}, {}): invalid handle", handle, symbol_name),
+                    );
+                    return Ptr::null();
+                }
+            }
+        }
+    };
+
+    for bin_idx in bins {
+        if let Some(&addr) = env.bins[bin_idx].exported_symbols.get(&symbol) {
+            return Ptr::from_bits(addr);
+        }
+    }
+
+    if search_host {
+        if let Ok(f) = env
+            .dyld
+            .create_proc_address(&mut env.mem, &mut env.cpu, None, &symbol)
+        {
+            return Ptr::from_bits(f.addr_with_thumb_bit());
+        }
+        if let Some(addr) = Dyld::create_constant_address(env, &symbol) {
+            return addr.cast_mut();
+        }
+        // With --lenient, a stub is better than a crash if the app doesn't
+        // check the result.
+        if let Ok(f) =
+            env.dyld
+                .create_proc_address(&mut env.mem, &mut env.cpu, env.stubs.as_mut(), &symbol)
+        {
+            return Ptr::from_bits(f.addr_with_thumb_bit());
+        }
+    }
+
+    set_error(
+        env,
+        format!("dlsym({:?}, {}): symbol not found", handle, symbol_name),
+    );
+    Ptr::null()
 }
 
 fn dlclose(env: &mut Environment, handle: MutVoidPtr) -> i32 {
-    assert!(ALLOWED_LIBRARIES.contains(&env.mem.cstr_at_utf8(handle.cast())));
+    let handles = &mut env.libc_state.dlfcn.handles;
+    let Some(idx) = handles.iter().position(|other| other.handle == handle) else {
+        set_error(env, format!("dlclose({:?}): invalid handle", handle));
+        return -1;
+    };
+    handles[idx].open_count -= 1;
+    if handles[idx].open_count == 0 {
+        // Unloading a binary isn't supported, so it stays loaded, but the
+        // handle can be freed.
+        handles.remove(idx);
+        env.mem.free(handle);
+    }
     0 // success
 }
 
+fn dlerror(env: &mut Environment) -> ConstPtr<u8> {
+    if let Some(old_string) = env.libc_state.dlfcn.error_string.take() {
+        env.mem.free(old_string.cast());
+    }
+    let Some(error) = env.libc_state.dlfcn.error.take() else {
+        return Ptr::null();
+    };
+    let error_string = env.mem.alloc_and_write_cstr(error.as_bytes());
+    env.libc_state.dlfcn.error_string = Some(error_string);
+    error_string.cast_const()
+}
+
 pub const FUNCTIONS: FunctionExports = &[
     export_c_func!(dlopen(_, _)),
     export_c_func!(dlsym(_, _)),
     export_c_func!(dlclose(_)),
+    export_c_func!(dlerror()),
 ];
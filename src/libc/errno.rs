@@ -7,14 +7,19 @@
 
 use crate::dyld::FunctionExports;
 use crate::export_c_func;
-use crate::mem::{ConstPtr, MutPtr};
+use crate::mem::{ConstPtr, MutPtr, Ptr};
+use crate::save_state::{Reader, Writer};
 use crate::Environment;
 use std::io::Write;
 
 pub const EPERM: i32 = 1;
+pub const ESRCH: i32 = 3;
 pub const EDEADLK: i32 = 11;
+pub const ENOMEM: i32 = 12;
+pub const EACCES: i32 = 13;
 pub const EBUSY: i32 = 16;
 pub const EINVAL: i32 = 22;
+pub const ETIMEDOUT: i32 = 60;
 
 #[derive(Default)]
 pub struct State {
@@ -28,12 +33,38 @@ impl State {
     ) -> MutPtr<i32> {
         *self.errnos.entry(thread).or_insert_with(|| {
             log!(
-                "TODO: errno accessed on thread {} (will always be 0)",
+                "TODO: errno accessed on thread {} (only some functions set it)",
                 thread
             );
             mem.alloc_and_write(0i32)
         })
     }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        let mut errnos: Vec<_> = self.errnos.iter().collect();
+        errnos.sort_by_key(|&(&thread, _)| thread);
+        w.u32(errnos.len().try_into().unwrap());
+        for (&thread, ptr) in errnos {
+            w.u32(thread.try_into().unwrap());
+            w.u32(ptr.to_bits());
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.errnos.clear();
+        for _ in 0..r.u32()? {
+            let thread = r.u32()? as crate::ThreadId;
+            self.errnos.insert(thread, Ptr::from_bits(r.u32()?));
+        }
+        Ok(())
+    }
+}
+
+/// Set the current thread's `errno`, for host functions that report errors
+/// that way.
+pub fn set_errno(env: &mut Environment, errno: i32) {
+    let ptr = __error(env);
+    env.mem.write(ptr, errno);
 }
 
 fn __error(env: &mut Environment) -> MutPtr<i32> {
@@ -7,20 +7,23 @@
 use crate::dyld::FunctionExports;
 use crate::environment::Environment;
 use crate::export_c_func;
+use crate::libc::errno::{set_errno, EACCES, EINVAL, ENOMEM};
 use crate::libc::posix_io;
 use crate::libc::posix_io::{off_t, FileDescriptor, SEEK_SET};
-use crate::mem::{GuestUSize, MutVoidPtr};
+use crate::mem::{GuestUSize, Mem, MutVoidPtr, Protection, ProtectionError, Ptr};
 
 #[allow(dead_code)]
 const MAP_FILE: i32 = 0x0000;
 const MAP_ANON: i32 = 0x1000;
 
+const MAP_FAILED: MutVoidPtr = Ptr::from_bits(!0);
+
 /// Our implementation of mmap is really simple: it's just load entirety of file in memory!
 fn mmap(
     env: &mut Environment,
     addr: MutVoidPtr,
     len: GuestUSize,
-    _prot: i32,
+    prot: i32,
     flags: i32,
     fd: FileDescriptor,
     offset: off_t,
@@ -30,10 +33,82 @@ fn mmap(
     assert_eq!((flags & MAP_ANON), 0);
     let new_offset = posix_io::lseek(env, fd, offset, SEEK_SET);
     assert_eq!(new_offset, offset);
-    let ptr = env.mem.alloc(len);
+
+    // Like the kernel, we map whole pages, so that the protection applies to
+    // the entire mapping without affecting anything else. The memory is
+    // reserved rather than allocated because it never gets freed.
+    let Some(size) = len
+        .checked_next_multiple_of(Mem::PAGE_SIZE)
+        .filter(|&size| size > 0)
+    else {
+        log!("Warning: mmap() called with invalid length {:#x}", len);
+        set_errno(env, EINVAL);
+        return MAP_FAILED;
+    };
+    let Some(base) = env.mem.find_reservable(size, Mem::PAGE_SIZE) else {
+        log!(
+            "Warning: mmap() couldn't find {:#x} bytes of free memory",
+            size
+        );
+        set_errno(env, ENOMEM);
+        return MAP_FAILED;
+    };
+    env.mem.reserve(base, size);
+    // The memory may have been used and freed before.
+    env.mem.bytes_at_mut(Ptr::from_bits(base), size).fill(0);
+
+    let ptr = Ptr::from_bits(base);
     let read = posix_io::read(env, fd, ptr, len);
     assert_eq!(read as u32, len);
+
+    // This can't fail, since the reserved memory is in the address space.
+    env.mem
+        .set_protection(
+            base,
+            size,
+            Protection::from_bits_truncate(prot),
+            Protection::ALL,
+        )
+        .unwrap();
+
     ptr
 }
 
-pub const FUNCTIONS: FunctionExports = &[export_c_func!(mmap(_, _, _, _, _, _))];
+fn mprotect(env: &mut Environment, addr: MutVoidPtr, len: GuestUSize, prot: i32) -> i32 {
+    if addr.to_bits() % Mem::PAGE_SIZE != 0 {
+        log!(
+            "Warning: mprotect() called with unaligned address {:?}, returning EINVAL",
+            addr
+        );
+        set_errno(env, EINVAL);
+        return -1;
+    }
+    match env
+        .mem
+        .protect(addr.to_bits(), len, Protection::from_bits_truncate(prot))
+    {
+        Ok(()) => 0,
+        Err(e) => {
+            log!(
+                "Warning: mprotect({:?}, {:#x}, {:#x}) failed: {:?}",
+                addr,
+                len,
+                prot,
+                e
+            );
+            set_errno(
+                env,
+                match e {
+                    ProtectionError::OutOfRange => ENOMEM,
+                    ProtectionError::ExceedsMaximum => EACCES,
+                },
+            );
+            -1
+        }
+    }
+}
+
+pub const FUNCTIONS: FunctionExports = &[
+    export_c_func!(mmap(_, _, _, _, _, _)),
+    export_c_func!(mprotect(_, _, _)),
+];
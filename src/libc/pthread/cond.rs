@@ -0,0 +1,185 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Guest condition variable interface.
+//!
+//! See [crate::environment::cond] for the internal implementation.
+
+use super::mutex::{mutex_id_for, pthread_mutex_t};
+use crate::dyld::{export_c_func, FunctionExports};
+use crate::libc::errno::EINVAL;
+use crate::libc::time::timespec;
+use crate::mem::{ConstPtr, MutPtr, Ptr, SafeRead};
+use crate::{CondId, Environment};
+use std::time::{Duration, SystemTime};
+
+/// Apple's implementation is a 4-byte magic number followed by a 4-byte opaque
+/// region. We only have to match the size theirs has.
+#[repr(C, packed)]
+struct pthread_condattr_t {
+    /// Magic number (must be [MAGIC_CONDATTR])
+    magic: u32,
+    _unused: u32,
+}
+unsafe impl SafeRead for pthread_condattr_t {}
+
+/// Apple's implementation is a 4-byte magic number followed by a 24-byte opaque
+/// region. We will store the actual data on the host, determined by a condition
+/// variable identifier.
+#[repr(C, packed)]
+struct pthread_cond_t {
+    /// Magic number (must be [MAGIC_COND])
+    magic: u32,
+    /// Unique condition variable identifier, used in matching the condition
+    /// variable to its host object.
+    cond_id: CondId,
+}
+unsafe impl SafeRead for pthread_cond_t {}
+
+/// Arbitrarily-chosen magic number for `pthread_condattr_t` (not Apple's).
+const MAGIC_CONDATTR: u32 = u32::from_be_bytes(*b"CoAt");
+/// Arbitrarily-chosen magic number for `pthread_cond_t` (not Apple's).
+const MAGIC_COND: u32 = u32::from_be_bytes(*b"COND");
+/// Magic number used by `PTHREAD_COND_INITIALIZER`. This is part of the ABI!
+const MAGIC_COND_STATIC: u32 = 0x3CB0B1BB;
+
+fn pthread_condattr_init(env: &mut Environment, attr: MutPtr<pthread_condattr_t>) -> i32 {
+    env.mem.write(
+        attr,
+        pthread_condattr_t {
+            magic: MAGIC_CONDATTR,
+            _unused: 0,
+        },
+    );
+    0 // success
+}
+fn pthread_condattr_destroy(env: &mut Environment, attr: MutPtr<pthread_condattr_t>) -> i32 {
+    check_magic!(env, attr, MAGIC_CONDATTR);
+    env.mem.write(
+        attr,
+        pthread_condattr_t {
+            magic: 0,
+            _unused: 0,
+        },
+    );
+    0 // success
+}
+
+fn pthread_cond_init(
+    env: &mut Environment,
+    cond: MutPtr<pthread_cond_t>,
+    attr: ConstPtr<pthread_condattr_t>,
+) -> i32 {
+    if !attr.is_null() {
+        check_magic!(env, attr, MAGIC_CONDATTR);
+    }
+    let cond_id = env.cond_state.init_cond();
+    log_dbg!(
+        "Condition variable #{} created from pthread_cond_init ({:#x})",
+        cond_id,
+        cond.to_bits()
+    );
+    env.mem.write(
+        cond,
+        pthread_cond_t {
+            magic: MAGIC_COND,
+            cond_id,
+        },
+    );
+
+    0 // success
+}
+
+/// Get the host condition variable for a guest one, registering it first if
+/// it was statically-initialized.
+fn cond_id_for(env: &mut Environment, cond: MutPtr<pthread_cond_t>) -> CondId {
+    let magic: u32 = env.mem.read(cond.cast());
+    if magic == MAGIC_COND_STATIC {
+        log_dbg!(
+            "Detected statically-initialized condition variable at {:?}, registering.",
+            cond
+        );
+        pthread_cond_init(env, cond, Ptr::null());
+    } else {
+        // See check_or_register_mutex
+        assert_eq!(magic, MAGIC_COND);
+    }
+    env.mem.read(cond).cond_id
+}
+
+fn pthread_cond_wait(
+    env: &mut Environment,
+    cond: MutPtr<pthread_cond_t>,
+    mutex: MutPtr<pthread_mutex_t>,
+) -> i32 {
+    let cond_id = cond_id_for(env, cond);
+    let mutex_id = mutex_id_for(env, mutex);
+    env.wait_on_cond(cond_id, mutex_id, None).err().unwrap_or(0)
+}
+
+fn pthread_cond_timedwait(
+    env: &mut Environment,
+    cond: MutPtr<pthread_cond_t>,
+    mutex: MutPtr<pthread_mutex_t>,
+    abstime: ConstPtr<timespec>,
+) -> i32 {
+    let timespec { tv_sec, tv_nsec } = env.mem.read(abstime);
+    if tv_sec < 0 || !(0..1_000_000_000).contains(&tv_nsec) {
+        return EINVAL;
+    }
+    // The timeout is an absolute wall-clock time, but the scheduler uses
+    // monotonic time.
+    let abstime = Duration::new(tv_sec as u64, tv_nsec as u32);
+    let now = env
+        .clock
+        .system_time()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let until = env.clock.now() + abstime.saturating_sub(now);
+
+    let cond_id = cond_id_for(env, cond);
+    let mutex_id = mutex_id_for(env, mutex);
+    env.wait_on_cond(cond_id, mutex_id, Some(until))
+        .err()
+        .unwrap_or(0)
+}
+
+fn pthread_cond_signal(env: &mut Environment, cond: MutPtr<pthread_cond_t>) -> i32 {
+    let cond_id = cond_id_for(env, cond);
+    env.signal_cond(cond_id);
+    0 // success
+}
+
+fn pthread_cond_broadcast(env: &mut Environment, cond: MutPtr<pthread_cond_t>) -> i32 {
+    let cond_id = cond_id_for(env, cond);
+    env.broadcast_cond(cond_id);
+    0 // success
+}
+
+fn pthread_cond_destroy(env: &mut Environment, cond: MutPtr<pthread_cond_t>) -> i32 {
+    let cond_id = cond_id_for(env, cond);
+    if let Err(e) = env.cond_state.destroy_cond(cond_id) {
+        return e;
+    }
+    env.mem.write(
+        cond,
+        pthread_cond_t {
+            magic: 0,
+            cond_id: 0xFFFFFFFFFFFFFFFF,
+        },
+    );
+    0 // success
+}
+
+pub const FUNCTIONS: FunctionExports = &[
+    export_c_func!(pthread_condattr_init(_)),
+    export_c_func!(pthread_condattr_destroy(_)),
+    export_c_func!(pthread_cond_init(_, _)),
+    export_c_func!(pthread_cond_wait(_, _)),
+    export_c_func!(pthread_cond_timedwait(_, _, _)),
+    export_c_func!(pthread_cond_signal(_)),
+    export_c_func!(pthread_cond_broadcast(_)),
+    export_c_func!(pthread_cond_destroy(_)),
+];
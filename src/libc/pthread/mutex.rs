@@ -8,8 +8,9 @@
 //! See [crate::environment::mutex] for the internal implementation.
 
 use crate::dyld::{export_c_func, FunctionExports};
+use crate::libc::errno::EINVAL;
 use crate::mem::{ConstPtr, MutPtr, Ptr, SafeRead};
-use crate::{Environment, MutexId, PTHREAD_MUTEX_DEFAULT};
+use crate::{Environment, MutexId, MutexType, PTHREAD_MUTEX_DEFAULT};
 
 /// Apple's implementation is a 4-byte magic number followed by an 8-byte opaque
 /// region. We only have to match the size theirs has.
@@ -26,7 +27,7 @@ unsafe impl SafeRead for pthread_mutexattr_t {}
 /// Apple's implementation is a 4-byte magic number followed by a 56-byte opaque
 /// region. We will store the actual data on the host, determined by a mutex identifier.
 #[repr(C, packed)]
-struct pthread_mutex_t {
+pub(super) struct pthread_mutex_t {
     /// Magic number (must be [MAGIC_MUTEX])
     magic: u32,
     /// Unique mutex identifier, used in matching the mutex to it's host object.
@@ -58,11 +59,24 @@ fn pthread_mutexattr_settype(
     type_: i32,
 ) -> i32 {
     check_magic!(env, attr, MAGIC_MUTEXATTR);
+    if MutexType::try_from(type_).is_err() {
+        return EINVAL;
+    }
     let mut attr_copy = env.mem.read(attr);
     attr_copy.type_ = type_;
     env.mem.write(attr, attr_copy);
     0 // success
 }
+fn pthread_mutexattr_gettype(
+    env: &mut Environment,
+    attr: ConstPtr<pthread_mutexattr_t>,
+    type_: MutPtr<i32>,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_MUTEXATTR);
+    let pthread_mutexattr_t { type_: value, .. } = env.mem.read(attr);
+    env.mem.write(type_, value);
+    0 // success
+}
 fn pthread_mutexattr_destroy(env: &mut Environment, attr: MutPtr<pthread_mutexattr_t>) -> i32 {
     check_magic!(env, attr, MAGIC_MUTEXATTR);
     env.mem.write(
@@ -123,16 +137,25 @@ fn check_or_register_mutex(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>
     }
 }
 
-fn pthread_mutex_lock(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>) -> i32 {
-    let mutex_data = env.mem.read(mutex);
+/// Get the host mutex for a guest mutex, for use by the other pthread modules.
+pub(super) fn mutex_id_for(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>) -> MutexId {
     check_or_register_mutex(env, mutex);
-    env.lock_mutex(mutex_data.mutex_id).err().unwrap_or(0)
+    env.mem.read(mutex).mutex_id
+}
+
+fn pthread_mutex_lock(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>) -> i32 {
+    let mutex_id = mutex_id_for(env, mutex);
+    env.lock_mutex(mutex_id).err().unwrap_or(0)
+}
+
+fn pthread_mutex_trylock(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>) -> i32 {
+    let mutex_id = mutex_id_for(env, mutex);
+    env.try_lock_mutex(mutex_id).err().unwrap_or(0)
 }
 
 fn pthread_mutex_unlock(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>) -> i32 {
-    let mutex_data = env.mem.read(mutex);
-    check_or_register_mutex(env, mutex);
-    env.unlock_mutex(mutex_data.mutex_id).err().unwrap_or(0)
+    let mutex_id = mutex_id_for(env, mutex);
+    env.unlock_mutex(mutex_id).err().unwrap_or(0)
 }
 
 fn pthread_mutex_destroy(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>) -> i32 {
@@ -151,9 +174,11 @@ fn pthread_mutex_destroy(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>)
 pub const FUNCTIONS: FunctionExports = &[
     export_c_func!(pthread_mutexattr_init(_)),
     export_c_func!(pthread_mutexattr_settype(_, _)),
+    export_c_func!(pthread_mutexattr_gettype(_, _)),
     export_c_func!(pthread_mutexattr_destroy(_)),
     export_c_func!(pthread_mutex_init(_, _)),
     export_c_func!(pthread_mutex_lock(_)),
+    export_c_func!(pthread_mutex_trylock(_)),
     export_c_func!(pthread_mutex_unlock(_)),
     export_c_func!(pthread_mutex_destroy(_)),
 ];
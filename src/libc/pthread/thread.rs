@@ -7,8 +7,8 @@
 
 use crate::abi::GuestFunction;
 use crate::dyld::{export_c_func, FunctionExports};
-use crate::libc::errno::{EDEADLK, EINVAL};
-use crate::mem::{ConstPtr, MutPtr, MutVoidPtr, SafeRead};
+use crate::libc::errno::{EDEADLK, EINVAL, ESRCH};
+use crate::mem::{ConstPtr, GuestUSize, Mem, MutPtr, MutVoidPtr, SafeRead};
 use crate::{Environment, ThreadId};
 use std::collections::HashMap;
 
@@ -31,16 +31,29 @@ struct pthread_attr_t {
     /// Magic number (must be [MAGIC_ATTR])
     magic: u32,
     detachstate: i32,
-    _unused: [u32; 8],
+    stacksize: GuestUSize,
+    schedpolicy: i32,
+    sched_priority: i32,
+    _unused: [u32; 5],
 }
 unsafe impl SafeRead for pthread_attr_t {}
 
 const DEFAULT_ATTR: pthread_attr_t = pthread_attr_t {
     magic: MAGIC_ATTR,
     detachstate: PTHREAD_CREATE_JOINABLE,
-    _unused: [0; 8],
+    stacksize: Mem::SECONDARY_THREAD_STACK_SIZE,
+    schedpolicy: SCHED_OTHER,
+    sched_priority: DEFAULT_PRIORITY,
+    _unused: [0; 5],
 };
 
+#[repr(C, packed)]
+struct sched_param {
+    sched_priority: i32,
+    _opaque: [u8; 4],
+}
+unsafe impl SafeRead for sched_param {}
+
 /// Apple's implementation is a 4-byte magic number followed by a massive
 /// (>4KiB) opaque region. We will store the actual data on the host instead.
 #[repr(C, packed)]
@@ -55,7 +68,8 @@ type pthread_t = MutPtr<OpaqueThread>;
 struct ThreadHostObject {
     thread_id: ThreadId,
     joined_by: Option<ThreadId>,
-    _attr: pthread_attr_t,
+    /// Kept up to date by `pthread_detach` and `pthread_setschedparam`.
+    attr: pthread_attr_t,
 }
 
 /// Arbitrarily-chosen magic number for `pthread_attr_t` (not Apple's).
@@ -68,6 +82,18 @@ type DetachState = i32;
 const PTHREAD_CREATE_JOINABLE: DetachState = 1;
 const PTHREAD_CREATE_DETACHED: DetachState = 2;
 
+/// Minimum stack size for a thread, from Apple's `<limits.h>`.
+const PTHREAD_STACK_MIN: GuestUSize = 16 * 1024;
+
+const SCHED_OTHER: i32 = 1;
+const SCHED_RR: i32 = 2;
+const SCHED_FIFO: i32 = 4;
+/// Priority range for all the scheduling policies. Priorities are recorded but
+/// have no effect, since touchHLE's scheduler doesn't have a concept of them.
+const MIN_PRIORITY: i32 = 15;
+const MAX_PRIORITY: i32 = 47;
+const DEFAULT_PRIORITY: i32 = 31;
+
 fn pthread_attr_init(env: &mut Environment, attr: MutPtr<pthread_attr_t>) -> i32 {
     env.mem.write(attr, DEFAULT_ATTR);
     0 // success
@@ -78,12 +104,107 @@ fn pthread_attr_setdetachstate(
     detachstate: DetachState,
 ) -> i32 {
     check_magic!(env, attr, MAGIC_ATTR);
-    assert!(detachstate == PTHREAD_CREATE_JOINABLE || detachstate == PTHREAD_CREATE_DETACHED); // should be EINVAL
+    if detachstate != PTHREAD_CREATE_JOINABLE && detachstate != PTHREAD_CREATE_DETACHED {
+        return EINVAL;
+    }
     let mut attr_copy = env.mem.read(attr);
     attr_copy.detachstate = detachstate;
     env.mem.write(attr, attr_copy);
     0 // success
 }
+fn pthread_attr_getdetachstate(
+    env: &mut Environment,
+    attr: ConstPtr<pthread_attr_t>,
+    detachstate: MutPtr<DetachState>,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    let pthread_attr_t {
+        detachstate: value, ..
+    } = env.mem.read(attr);
+    env.mem.write(detachstate, value);
+    0 // success
+}
+fn pthread_attr_setstacksize(
+    env: &mut Environment,
+    attr: MutPtr<pthread_attr_t>,
+    stacksize: GuestUSize,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    if stacksize < PTHREAD_STACK_MIN || stacksize % Mem::PAGE_SIZE != 0 {
+        return EINVAL;
+    }
+    let mut attr_copy = env.mem.read(attr);
+    attr_copy.stacksize = stacksize;
+    env.mem.write(attr, attr_copy);
+    0 // success
+}
+fn pthread_attr_getstacksize(
+    env: &mut Environment,
+    attr: ConstPtr<pthread_attr_t>,
+    stacksize: MutPtr<GuestUSize>,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    let pthread_attr_t {
+        stacksize: value, ..
+    } = env.mem.read(attr);
+    env.mem.write(stacksize, value);
+    0 // success
+}
+fn pthread_attr_setschedpolicy(
+    env: &mut Environment,
+    attr: MutPtr<pthread_attr_t>,
+    policy: i32,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    if ![SCHED_OTHER, SCHED_RR, SCHED_FIFO].contains(&policy) {
+        return EINVAL;
+    }
+    let mut attr_copy = env.mem.read(attr);
+    attr_copy.schedpolicy = policy;
+    env.mem.write(attr, attr_copy);
+    0 // success
+}
+fn pthread_attr_getschedpolicy(
+    env: &mut Environment,
+    attr: ConstPtr<pthread_attr_t>,
+    policy: MutPtr<i32>,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    let pthread_attr_t { schedpolicy, .. } = env.mem.read(attr);
+    env.mem.write(policy, schedpolicy);
+    0 // success
+}
+fn pthread_attr_setschedparam(
+    env: &mut Environment,
+    attr: MutPtr<pthread_attr_t>,
+    param: ConstPtr<sched_param>,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    let sched_param { sched_priority, .. } = env.mem.read(param);
+    if !(MIN_PRIORITY..=MAX_PRIORITY).contains(&sched_priority) {
+        return EINVAL;
+    }
+    let mut attr_copy = env.mem.read(attr);
+    attr_copy.sched_priority = sched_priority;
+    env.mem.write(attr, attr_copy);
+    0 // success
+}
+fn pthread_attr_getschedparam(
+    env: &mut Environment,
+    attr: ConstPtr<pthread_attr_t>,
+    param: MutPtr<sched_param>,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    let pthread_attr_t { sched_priority, .. } = env.mem.read(attr);
+    env.mem.write(
+        param,
+        sched_param {
+            sched_priority,
+            _opaque: [0; 4],
+        },
+    );
+    0 // success
+}
 fn pthread_attr_destroy(env: &mut Environment, attr: MutPtr<pthread_attr_t>) -> i32 {
     check_magic!(env, attr, MAGIC_ATTR);
     env.mem.write(
@@ -91,6 +212,9 @@ fn pthread_attr_destroy(env: &mut Environment, attr: MutPtr<pthread_attr_t>) ->
         pthread_attr_t {
             magic: 0,
             detachstate: 0,
+            stacksize: 0,
+            schedpolicy: 0,
+            sched_priority: 0,
             _unused: Default::default(),
         },
     );
@@ -111,7 +235,7 @@ fn pthread_create(
         DEFAULT_ATTR
     };
 
-    let thread_id = env.new_thread(start_routine, user_data);
+    let thread_id = env.new_thread(start_routine, user_data, attr.stacksize);
 
     let opaque = env.mem.alloc_and_write(OpaqueThread {
         magic: MAGIC_THREAD,
@@ -124,7 +248,7 @@ fn pthread_create(
         ThreadHostObject {
             thread_id,
             joined_by: None,
-            _attr: attr,
+            attr,
         },
     );
 
@@ -151,7 +275,7 @@ fn pthread_self(env: &mut Environment) -> pthread_t {
             ThreadHostObject {
                 thread_id: 0,
                 joined_by: None,
-                _attr: DEFAULT_ATTR,
+                attr: DEFAULT_ATTR,
             },
         );
         log_dbg!(
@@ -172,7 +296,14 @@ fn pthread_join(env: &mut Environment, thread: pthread_t, retval: MutPtr<MutVoid
     let current_thread = env.current_thread;
     let curr_pthread_t = pthread_self(env);
     // The joinee is the thread that is being waited on.
-    let joinee_thread = State::get(env).threads.get_mut(&thread).unwrap().thread_id;
+    let Some(host_obj_joinee) = State::get(env).threads.get(&thread) else {
+        log_dbg!(
+            "Thread attempted join with unknown thread {:?}, returning ESRCH!",
+            thread
+        );
+        return ESRCH;
+    };
+    let joinee_thread = host_obj_joinee.thread_id;
 
     // FIXME?: Blocking on the main thread is technically allowed, but effectively useless (as the
     // main thread exiting means the whole application exits). It complicates some handling and is
@@ -203,7 +334,7 @@ fn pthread_join(env: &mut Environment, thread: pthread_t, retval: MutPtr<MutVoid
 
     // Deattached threads cannot be joined with.
     let host_obj_joinee = State::get(env).threads.get_mut(&thread).unwrap();
-    if host_obj_joinee._attr.detachstate == PTHREAD_CREATE_DETACHED {
+    if host_obj_joinee.attr.detachstate == PTHREAD_CREATE_DETACHED {
         log_dbg!("Thread attempted join with deattached thread, returning EINVAL!");
         return EINVAL;
     }
@@ -213,6 +344,85 @@ fn pthread_join(env: &mut Environment, thread: pthread_t, retval: MutPtr<MutVoid
     env.join_with_thread(joinee_thread, retval);
     0
 }
+fn pthread_detach(env: &mut Environment, thread: pthread_t) -> i32 {
+    let Some(host_obj) = State::get(env).threads.get_mut(&thread) else {
+        log_dbg!(
+            "Attempted to detach unknown thread {:?}, returning ESRCH!",
+            thread
+        );
+        return ESRCH;
+    };
+    if host_obj.attr.detachstate == PTHREAD_CREATE_DETACHED || host_obj.joined_by.is_some() {
+        log_dbg!("Attempted to detach thread {:?} that is already detached or being joined, returning EINVAL!", thread);
+        return EINVAL;
+    }
+    host_obj.attr.detachstate = PTHREAD_CREATE_DETACHED;
+    0 // success
+}
+
+fn pthread_exit(env: &mut Environment, value: MutVoidPtr) {
+    env.exit_current_thread(value);
+}
+
+fn pthread_equal(_env: &mut Environment, thread1: pthread_t, thread2: pthread_t) -> i32 {
+    (thread1 == thread2).into()
+}
+
+fn pthread_getschedparam(
+    env: &mut Environment,
+    thread: pthread_t,
+    policy: MutPtr<i32>,
+    param: MutPtr<sched_param>,
+) -> i32 {
+    let Some(host_obj) = State::get(env).threads.get(&thread) else {
+        return ESRCH;
+    };
+    let pthread_attr_t {
+        schedpolicy,
+        sched_priority,
+        ..
+    } = host_obj.attr;
+    if !policy.is_null() {
+        env.mem.write(policy, schedpolicy);
+    }
+    if !param.is_null() {
+        env.mem.write(
+            param,
+            sched_param {
+                sched_priority,
+                _opaque: [0; 4],
+            },
+        );
+    }
+    0 // success
+}
+
+fn pthread_setschedparam(
+    env: &mut Environment,
+    thread: pthread_t,
+    policy: i32,
+    param: ConstPtr<sched_param>,
+) -> i32 {
+    let sched_param { sched_priority, .. } = env.mem.read(param);
+    if ![SCHED_OTHER, SCHED_RR, SCHED_FIFO].contains(&policy)
+        || !(MIN_PRIORITY..=MAX_PRIORITY).contains(&sched_priority)
+    {
+        return EINVAL;
+    }
+    let Some(host_obj) = State::get(env).threads.get_mut(&thread) else {
+        return ESRCH;
+    };
+    log_dbg!(
+        "Thread {} policy set to {}, priority set to {} (ignored)",
+        host_obj.thread_id,
+        policy,
+        sched_priority
+    );
+    host_obj.attr.schedpolicy = policy;
+    host_obj.attr.sched_priority = sched_priority;
+    0 // success
+}
+
 fn pthread_setcanceltype(_env: &mut Environment, _type: i32, _oldtype: MutPtr<i32>) -> i32 {
     // TODO
     0
@@ -227,13 +437,44 @@ fn pthread_mach_thread_np(env: &mut Environment, thread: pthread_t) -> mach_port
     host_object.thread_id.try_into().unwrap()
 }
 
+// sched.h
+
+fn sched_get_priority_min(_env: &mut Environment, policy: i32) -> i32 {
+    if [SCHED_OTHER, SCHED_RR, SCHED_FIFO].contains(&policy) {
+        MIN_PRIORITY
+    } else {
+        -1
+    }
+}
+fn sched_get_priority_max(_env: &mut Environment, policy: i32) -> i32 {
+    if [SCHED_OTHER, SCHED_RR, SCHED_FIFO].contains(&policy) {
+        MAX_PRIORITY
+    } else {
+        -1
+    }
+}
+
 pub const FUNCTIONS: FunctionExports = &[
     export_c_func!(pthread_attr_init(_)),
     export_c_func!(pthread_attr_setdetachstate(_, _)),
+    export_c_func!(pthread_attr_getdetachstate(_, _)),
+    export_c_func!(pthread_attr_setstacksize(_, _)),
+    export_c_func!(pthread_attr_getstacksize(_, _)),
+    export_c_func!(pthread_attr_setschedpolicy(_, _)),
+    export_c_func!(pthread_attr_getschedpolicy(_, _)),
+    export_c_func!(pthread_attr_setschedparam(_, _)),
+    export_c_func!(pthread_attr_getschedparam(_, _)),
     export_c_func!(pthread_attr_destroy(_)),
     export_c_func!(pthread_create(_, _, _, _)),
     export_c_func!(pthread_self()),
     export_c_func!(pthread_join(_, _)),
+    export_c_func!(pthread_detach(_)),
+    export_c_func!(pthread_exit(_)),
+    export_c_func!(pthread_equal(_, _)),
+    export_c_func!(pthread_getschedparam(_, _, _)),
+    export_c_func!(pthread_setschedparam(_, _, _)),
     export_c_func!(pthread_setcanceltype(_, _)),
     export_c_func!(pthread_mach_thread_np(_)),
+    export_c_func!(sched_get_priority_min(_)),
+    export_c_func!(sched_get_priority_max(_)),
 ];
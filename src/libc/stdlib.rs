@@ -6,8 +6,10 @@
 //! `stdlib.h`
 
 use crate::abi::{CallFromHost, GuestFunction};
+use crate::cpu::Cpu;
 use crate::dyld::{export_c_func, FunctionExports};
 use crate::mem::{ConstPtr, ConstVoidPtr, GuestUSize, MutPtr, MutVoidPtr, Ptr};
+use crate::save_state::{Reader, Writer};
 use crate::Environment;
 use std::collections::HashMap;
 
@@ -21,24 +23,54 @@ pub struct State {
     env: HashMap<Vec<u8>, MutPtr<u8>>,
 }
 
+impl State {
+    pub fn save_state(&self, w: &mut Writer) {
+        w.u32(self.rand);
+        w.u32(self.random);
+        w.u32(self.arc4random);
+        let mut env: Vec<_> = self.env.iter().collect();
+        env.sort_by(|(a, _), (b, _)| a.cmp(b));
+        w.u32(env.len().try_into().unwrap());
+        for (name, value) in env {
+            w.bytes(name);
+            w.u32(value.to_bits());
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.rand = r.u32()?;
+        self.random = r.u32()?;
+        self.arc4random = r.u32()?;
+        self.env.clear();
+        for _ in 0..r.u32()? {
+            let name = r.bytes()?.to_vec();
+            self.env.insert(name, Ptr::from_bits(r.u32()?));
+        }
+        Ok(())
+    }
+}
+
 // Sizes of zero are implementation-defined. macOS will happily give you back
 // an allocation for any of these, so presumably iPhone OS does too.
 // (touchHLE's allocator will round up allocations to at least 16 bytes.)
 
 fn malloc(env: &mut Environment, size: GuestUSize) -> MutVoidPtr {
-    env.mem.alloc(size)
+    let caller = env.cpu.regs()[Cpu::LR];
+    env.mem.alloc_from_guest(size, caller)
 }
 
 fn calloc(env: &mut Environment, count: GuestUSize, size: GuestUSize) -> MutVoidPtr {
     let total = size.checked_mul(count).unwrap();
-    env.mem.alloc(total)
+    let caller = env.cpu.regs()[Cpu::LR];
+    env.mem.alloc_from_guest(total, caller)
 }
 
 fn realloc(env: &mut Environment, ptr: MutVoidPtr, size: GuestUSize) -> MutVoidPtr {
     if ptr.is_null() {
         return malloc(env, size);
     }
-    env.mem.realloc(ptr, size)
+    let caller = env.cpu.regs()[Cpu::LR];
+    env.mem.realloc_from_guest(ptr, size, caller)
 }
 
 fn free(env: &mut Environment, ptr: MutVoidPtr) {
@@ -206,8 +238,9 @@ fn setenv(env: &mut Environment, name: ConstPtr<u8>, value: ConstPtr<u8>, overwr
     0 // success
 }
 
-fn exit(_env: &mut Environment, exit_code: i32) {
+fn exit(env: &mut Environment, exit_code: i32) {
     echo!("App called exit(), exiting.");
+    env.report_debug_heap_leaks();
     std::process::exit(exit_code);
 }
 
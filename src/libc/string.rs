@@ -7,6 +7,7 @@
 
 use crate::dyld::{export_c_func, FunctionExports};
 use crate::mem::{ConstPtr, ConstVoidPtr, GuestUSize, MutPtr, MutVoidPtr, Ptr};
+use crate::save_state::{Reader, Writer};
 use crate::Environment;
 use std::cmp::Ordering;
 
@@ -17,6 +18,17 @@ pub struct State {
     strtok: Option<MutPtr<u8>>,
 }
 
+impl State {
+    pub fn save_state(&self, w: &mut Writer) {
+        w.option_u32(self.strtok.map(|ptr| ptr.to_bits()));
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.strtok = r.option_u32()?.map(Ptr::from_bits);
+        Ok(())
+    }
+}
+
 fn strtok(env: &mut Environment, s: MutPtr<u8>, sep: ConstPtr<u8>) -> MutPtr<u8> {
     let s = if s.is_null() {
         let state = env.libc_state.string.strtok.unwrap();
@@ -7,6 +7,7 @@
 
 use crate::dyld::{export_c_func, FunctionExports};
 use crate::mem::{guest_size_of, ConstPtr, MutPtr, Ptr, SafeRead};
+use crate::save_state::{Reader, Writer};
 use crate::Environment;
 use std::time::SystemTime;
 
@@ -18,6 +19,19 @@ pub struct State {
     gmtime_tmp: Option<MutPtr<tm>>,
 }
 
+impl State {
+    pub fn save_state(&self, w: &mut Writer) {
+        w.bool(self.y2k38_warned);
+        w.option_u32(self.gmtime_tmp.map(|ptr| ptr.to_bits()));
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.y2k38_warned = r.bool()?;
+        self.gmtime_tmp = r.option_u32()?.map(Ptr::from_bits);
+        Ok(())
+    }
+}
+
 // time.h (C)
 
 #[allow(non_camel_case_types)]
@@ -25,7 +39,9 @@ pub struct State {
 pub type time_t = i32;
 
 fn time(env: &mut Environment, out: MutPtr<time_t>) -> time_t {
-    let time64 = SystemTime::now()
+    let time64 = env
+        .clock
+        .system_time()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_secs();
@@ -255,6 +271,16 @@ fn localtime(env: &mut Environment, timestamp: ConstPtr<time_t>) -> MutPtr<tm> {
     gmtime(env, timestamp)
 }
 
+// time.h (POSIX)
+
+#[allow(non_camel_case_types)]
+#[repr(C, packed)]
+pub struct timespec {
+    pub tv_sec: time_t,
+    pub tv_nsec: i32,
+}
+unsafe impl SafeRead for timespec {}
+
 // sys/time.h (POSIX)
 
 #[allow(non_camel_case_types)]
@@ -295,7 +321,9 @@ fn gettimeofday(
         return 0; // success
     }
 
-    let time = SystemTime::now()
+    let time = env
+        .clock
+        .system_time()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap();
 
@@ -4,6 +4,13 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 //! Logging and terminal output macros.
+//!
+//! What gets logged can be configured at runtime with the `--log=` and
+//! `--log-file=` options (see [configure]).
+
+use crate::options::Options;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
 
 /// Accessing log output on Android is more difficult than on other platforms;
 /// logcat requires a separate device. As an alternative, let's write to a file
@@ -27,13 +34,16 @@ pub fn get_log_file() -> &'static std::fs::File {
     unsafe { LOG_FILE.as_ref().unwrap() }
 }
 
-/// Prints a log message unconditionally. Use this for errors or warnings.
+/// Prints a log message, unless logging has been turned off for the module
+/// where it is used. Use this for errors or warnings.
 ///
 /// The message is prefixed with the module path, so it is clear where it comes
 /// from.
 macro_rules! log {
     ($($arg:tt)+) => {
-        echo!("{}: {}", module_path!(), format_args!($($arg)+));
+        if $crate::log::is_enabled(module_path!(), $crate::log::LogLevel::Normal) {
+            echo!("{}: {}", module_path!(), format_args!($($arg)+));
+        }
     }
 }
 
@@ -42,8 +52,8 @@ macro_rules! log {
 /// when debugging.
 macro_rules! log_dbg {
     ($($arg:tt)+) => {
-        if $crate::log::ENABLED_MODULES.contains(&module_path!()) {
-            log!($($arg)*);
+        if $crate::log::is_enabled(module_path!(), $crate::log::LogLevel::Debug) {
+            echo!("{}: {}", module_path!(), format_args!($($arg)+));
         }
     }
 }
@@ -65,7 +75,11 @@ macro_rules! echo {
                 let _ = log_file.write_all(b"\n");
             }
             #[cfg(not(target_os = "android"))]
-            eprintln!($($arg)+);
+            {
+                let formatted_str = format!($($arg)+);
+                eprintln!("{}", formatted_str);
+                $crate::log::write_to_log_file(&formatted_str);
+            }
         }
     };
     () => {
@@ -77,11 +91,125 @@ macro_rules! echo {
                 let _ = $crate::log::get_log_file().write_all(b"\n");
             }
             #[cfg(not(target_os = "android"))]
-            eprintln!("");
+            {
+                eprintln!("");
+                $crate::log::write_to_log_file("");
+            }
         }
     }
 }
 
 /// Put modules to enable [log_dbg] for here, e.g. "touchHLE::mem" to see when
-/// memory is allocated and freed.
+/// memory is allocated and freed. This is the same as passing `--log=` with
+/// these modules on every run.
 pub const ENABLED_MODULES: &[&str] = &[];
+
+/// How much to log for a module. See `--log=`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Print nothing.
+    Quiet,
+    /// Print messages from [log], but not from [log_dbg]. This is the default.
+    Normal,
+    /// Print messages from both [log] and [log_dbg].
+    Debug,
+}
+impl LogLevel {
+    pub fn from_name(name: &str) -> Result<Self, ()> {
+        match name {
+            "quiet" => Ok(LogLevel::Quiet),
+            "normal" => Ok(LogLevel::Normal),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Module path prefixes and their log levels, from `--log=`.
+static MODULE_LEVELS: RwLock<Vec<(String, LogLevel)>> = RwLock::new(Vec::new());
+/// Whether [MODULE_LEVELS] is non-empty. This is checked first so that
+/// [log_dbg], which is used in some very hot code paths, stays cheap in the
+/// common case.
+static HAVE_MODULE_LEVELS: AtomicBool = AtomicBool::new(false);
+
+/// Only for internal use by the logging macros.
+pub fn is_enabled(module: &str, level: LogLevel) -> bool {
+    module_level(module) >= level
+}
+
+fn module_level(module: &str) -> LogLevel {
+    let default = if ENABLED_MODULES.contains(&module) {
+        LogLevel::Debug
+    } else {
+        LogLevel::Normal
+    };
+    if !HAVE_MODULE_LEVELS.load(Ordering::Relaxed) {
+        return default;
+    }
+    // The most specific prefix wins.
+    MODULE_LEVELS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(prefix, _)| {
+            module
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or(default, |&(_, level)| level)
+}
+
+/// The file from `--log-file=` and its path.
+#[cfg(not(target_os = "android"))]
+static LOG_FILE: std::sync::Mutex<Option<(std::path::PathBuf, std::fs::File)>> =
+    std::sync::Mutex::new(None);
+
+/// Only for internal use by the logging macros.
+#[cfg(not(target_os = "android"))]
+pub fn write_to_log_file(formatted_str: &str) {
+    use std::io::Write;
+    if let Some((_, ref mut log_file)) = *LOG_FILE.lock().unwrap() {
+        let _ = log_file.write_all(formatted_str.as_bytes());
+        let _ = log_file.write_all(b"\n");
+    }
+}
+
+/// Apply the logging-related options (`--log=` and `--log-file=`). These are
+/// global, so this should be called once the options are final, before the
+/// app is run. It may be called again if they change (e.g. once an app has been
+/// picked and its options are known), in which case a log file that is
+/// already open stays open rather than being truncated.
+pub fn configure(options: &Options) -> Result<(), String> {
+    let mut module_levels = MODULE_LEVELS.write().unwrap();
+    module_levels.clone_from(&options.log_levels);
+    HAVE_MODULE_LEVELS.store(!module_levels.is_empty(), Ordering::Relaxed);
+    drop(module_levels);
+
+    let Some(ref path) = options.log_file else {
+        return Ok(());
+    };
+    #[cfg(target_os = "android")]
+    {
+        log!(
+            "Warning: Ignoring --log-file={}, the log is always written to log.txt on Android.",
+            path.display()
+        );
+    }
+    #[cfg(not(target_os = "android"))]
+    {
+        let mut log_file = LOG_FILE.lock().unwrap();
+        if log_file
+            .as_ref()
+            .is_some_and(|(open_path, _)| open_path == path)
+        {
+            return Ok(());
+        }
+        let file = std::fs::File::create(path)
+            .map_err(|e| format!("Could not create log file {}: {}", path.display(), e))?;
+        *log_file = Some((path.clone(), file));
+        drop(log_file);
+        log!("Writing log to {}.", path.display());
+    }
+    Ok(())
+}
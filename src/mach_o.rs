@@ -4,7 +4,8 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 //! Reading of Mach-O files, the executable and library format on iPhone OS.
-//! Currently only handles executables.
+//! Currently only handles executables. Fat (universal) binaries are supported
+//! by picking a single ARM slice to load.
 //!
 //! Implemented using the mach_object crate. All usage of that crate should be
 //! confined to this module. The goal is to read the Mach-O binary exactly once,
@@ -16,15 +17,17 @@
 //! - Alex Drummond's [Inside a Hello World executable on OS X](https://adrummond.net/posts/macho) is about macOS circa 2017 rather than iPhone OS circa 2008, so not all of what it says applies, but the sections up to and including "9. The indirect symbol table" are helpful.
 //! - The LLVM functions [`RuntimeDyldMachO::populateIndirectSymbolPointersSection`](https://github.com/llvm/llvm-project/blob/2e999b7dd1934a44d38c3a753460f1e5a217e9a5/llvm/lib/ExecutionEngine/RuntimeDyld/RuntimeDyldMachO.cpp#L179-L220) and [`MachOObjectFile::getIndirectSymbolTableEntry`](https://github.com/llvm/llvm-project/blob/3c09ed006ab35dd8faac03311b14f0857b01949c/llvm/lib/Object/MachOObjectFile.cpp#L4803-L4808) are references for how to read the indirect symbol table.
 //! - `/usr/include/mach-o/reloc.h` in the macOS SDK was the reference for the format of relocation entries.
+//! - `/usr/include/mach-o/loader.h` in the macOS SDK describes the rebase and binding opcodes used by `LC_DYLD_INFO`.
 //! - The [source code of the mach_object crate](https://docs.rs/mach_object/latest/src/mach_object/commands.rs.html) has useful comments that don't show up in the generated documentation, e.g. around `DySymTab`.
 
 use crate::abi::GuestFunction;
+use crate::cpu::{self, Arch};
 use crate::fs::{Fs, GuestPath};
-use crate::mem::{Mem, Ptr};
+use crate::mem::{Mem, MutPtr, Protection, Ptr};
 use mach_object::{
-    vm_prot_t, DyLib, LoadCommand, MachCommand, OFile, Symbol, SymbolIter, ThreadState,
-    N_ARM_THUMB_DEF, S_LAZY_SYMBOL_POINTERS, S_MOD_INIT_FUNC_POINTERS, S_NON_LAZY_SYMBOL_POINTERS,
-    S_SYMBOL_STUBS,
+    cpu_subtype_t, cpu_type_t, vm_prot_t, BindSymbolType, DyLib, LoadCommand, MachCommand, OFile,
+    Rebase, Symbol, SymbolIter, ThreadState, N_ARM_THUMB_DEF, S_LAZY_SYMBOL_POINTERS,
+    S_MOD_INIT_FUNC_POINTERS, S_NON_LAZY_SYMBOL_POINTERS, S_SYMBOL_STUBS,
 };
 use std::collections::HashMap;
 use std::io::{Cursor, Seek, SeekFrom};
@@ -46,11 +49,111 @@ pub struct MachO {
     /// can look things up quickly. Thumb function symbols always have the Thumb
     /// bit set.
     pub exported_symbols: HashMap<String, u32>,
+    /// All defined symbols, including non-exported ones, as (address, name)
+    /// pairs sorted by address. The Thumb bit is not set. This is only used
+    /// for debugging.
+    pub symbols: Vec<(u32, String)>,
     /// List of addresses and names of external relocations for the dynamic
     /// linker to resolve.
     pub external_relocations: Vec<(u32, String)>,
+    /// Binding information from `LC_DYLD_INFO`/`LC_DYLD_INFO_ONLY`, if the
+    /// binary has it. Such binaries don't use external relocations, and don't
+    /// need the indirect symbol table for linking.
+    pub dyld_info: Option<DyldInfo>,
     /// Address/program counter value for the entry point.
     pub entry_point_pc: Option<u32>,
+    /// Architecture of the loaded code (the chosen slice, for a fat binary).
+    pub arch: Arch,
+    /// Address of the `__TEXT` segment, which begins with the Mach-O header.
+    /// This is only used for debugging.
+    pub header_addr: Option<u32>,
+    /// Base addresses of the regions of guest memory reserved for the
+    /// segments, so they can be freed again with [Self::unload].
+    reserved_regions: Vec<u32>,
+}
+
+/// Symbol bindings decoded from the opcode streams of an `LC_DYLD_INFO` or
+/// `LC_DYLD_INFO_ONLY` command. (Rebasing is done when loading, since it only
+/// depends on where the binary is loaded.)
+#[derive(Debug, Default)]
+pub struct DyldInfo {
+    /// Bindings to be done when linking, e.g. for non-lazy symbol pointers or
+    /// Objective-C superclass pointers.
+    pub bindings: Vec<Binding>,
+    /// Bindings for lazy symbol pointers, which are used by symbol stubs.
+    pub lazy_bindings: Vec<Binding>,
+    /// Locations that use a weak definition (e.g. of a C++ inline function),
+    /// which should be bound to the first definition of it in any binary, so
+    /// that all binaries agree on one.
+    pub weak_bindings: Vec<Binding>,
+}
+
+/// A location the dynamic linker should write a symbol's address to.
+#[derive(Debug)]
+pub struct Binding {
+    pub addr: u32,
+    pub symbol: String,
+    /// Value to add to the symbol's address.
+    pub addend: i32,
+    /// If [true], the binary can cope with the symbol being missing, and the
+    /// location should be set to null in that case.
+    pub weak_import: bool,
+}
+
+/// Description of the architecture-specific code in a Mach-O file. A fat
+/// binary has several of these, a normal ("thin") binary has exactly one.
+#[derive(Debug, Clone)]
+pub struct Slice {
+    pub cputype: cpu_type_t,
+    /// CPU subtype with the capability bits masked out.
+    pub cpusubtype: cpu_subtype_t,
+    /// [None] if this isn't an ARM architecture touchHLE knows about.
+    pub arch: Option<Arch>,
+    /// Offset of the slice's data within the file.
+    offset: usize,
+    /// Size of the slice's data in bytes.
+    size: usize,
+}
+impl Slice {
+    fn new(cputype: cpu_type_t, cpusubtype: cpu_subtype_t, offset: usize, size: usize) -> Self {
+        let cpusubtype = cpusubtype & !mach_object::CPU_SUBTYPE_MASK;
+        let arch = if cputype == mach_object::CPU_TYPE_ARM {
+            match cpusubtype {
+                // ARMv6 is backwards-compatible with these older variants.
+                mach_object::CPU_SUBTYPE_ARM_ALL
+                | mach_object::CPU_SUBTYPE_ARM_V4T
+                | mach_object::CPU_SUBTYPE_ARM_V5TEJ
+                | mach_object::CPU_SUBTYPE_ARM_XSCALE
+                | mach_object::CPU_SUBTYPE_ARM_V6 => Some(Arch::ARMv6),
+                mach_object::CPU_SUBTYPE_ARM_V7 | mach_object::CPU_SUBTYPE_ARM_V7F => {
+                    Some(Arch::ARMv7)
+                }
+                // armv7s and armv7k add instructions (e.g. integer division)
+                // that ARMv7-A doesn't have, and only appeared with iOS 6+.
+                _ => None,
+            }
+        } else {
+            None
+        };
+        Slice {
+            cputype,
+            cpusubtype,
+            arch,
+            offset,
+            size,
+        }
+    }
+
+    /// Human-readable name of the slice's architecture.
+    pub fn name(&self) -> String {
+        match self.arch {
+            Some(arch) => arch.short_name().to_string(),
+            None => format!(
+                "unknown (CPU type {}, subtype {})",
+                self.cputype, self.cpusubtype
+            ),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -111,6 +214,187 @@ impl SectionPredicate for SectionType {
     }
 }
 
+/// Round a segment's address range out to whole pages, returning the start
+/// and end addresses.
+fn segment_pages(vmaddr: u64, vmsize: u64) -> Result<(u32, u32), &'static str> {
+    let page_size = u64::from(Mem::PAGE_SIZE);
+    let start = vmaddr / page_size * page_size;
+    let end = vmaddr
+        .checked_add(vmsize)
+        .and_then(|end| end.checked_next_multiple_of(page_size))
+        .and_then(|end| u32::try_from(end).ok())
+        .ok_or("Segment extends past the end of the address space")?;
+    let start = u32::try_from(start).map_err(|_| "Segment address is out of range")?;
+    Ok((start, end))
+}
+
+// Binding opcodes and related constants, from `/usr/include/mach-o/loader.h`.
+const BIND_OPCODE_MASK: u8 = 0xF0;
+const BIND_IMMEDIATE_MASK: u8 = 0x0F;
+const BIND_OPCODE_DONE: u8 = 0x00;
+const BIND_OPCODE_SET_DYLIB_ORDINAL_IMM: u8 = 0x10;
+const BIND_OPCODE_SET_DYLIB_ORDINAL_ULEB: u8 = 0x20;
+const BIND_OPCODE_SET_DYLIB_SPECIAL_IMM: u8 = 0x30;
+const BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM: u8 = 0x40;
+const BIND_OPCODE_SET_TYPE_IMM: u8 = 0x50;
+const BIND_OPCODE_SET_ADDEND_SLEB: u8 = 0x60;
+const BIND_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB: u8 = 0x70;
+const BIND_OPCODE_ADD_ADDR_ULEB: u8 = 0x80;
+const BIND_OPCODE_DO_BIND: u8 = 0x90;
+const BIND_OPCODE_DO_BIND_ADD_ADDR_ULEB: u8 = 0xA0;
+const BIND_OPCODE_DO_BIND_ADD_ADDR_IMM_SCALED: u8 = 0xB0;
+const BIND_OPCODE_DO_BIND_ULEB_TIMES_SKIPPING_ULEB: u8 = 0xC0;
+const BIND_TYPE_POINTER: u8 = 1;
+const BIND_SYMBOL_FLAGS_WEAK_IMPORT: u8 = 0x1;
+
+/// A binding as decoded from a binding opcode stream, before its address is
+/// resolved.
+#[derive(Debug, PartialEq, Eq)]
+struct RawBinding {
+    segment_index: usize,
+    /// Offset within the segment. Adding a large offset is used to subtract,
+    /// so wrapping arithmetic is needed.
+    segment_offset: u32,
+    symbol_type: u8,
+    symbol: String,
+    addend: i32,
+    weak_import: bool,
+}
+
+/// Cursor for reading a binding opcode stream.
+struct OpcodeReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> OpcodeReader<'a> {
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        let (&byte, rest) = self
+            .bytes
+            .split_first()
+            .ok_or("Binding opcode stream is truncated")?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    /// Read an LEB128 number, sign-extending it if `signed` is [true].
+    /// Anything beyond 32 bits is discarded, since it can't matter for a 32-bit
+    /// address space.
+    fn leb128(&mut self, signed: bool) -> Result<u32, &'static str> {
+        let mut value: u32 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 32 {
+                value |= u32::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if signed && shift < 32 && byte & 0x40 != 0 {
+                    value |= u32::MAX << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+    fn uleb128(&mut self) -> Result<u32, &'static str> {
+        self.leb128(false)
+    }
+    fn sleb128(&mut self) -> Result<i32, &'static str> {
+        self.leb128(true).map(|value| value as i32)
+    }
+
+    fn cstr(&mut self) -> Result<String, &'static str> {
+        let len = self
+            .bytes
+            .iter()
+            .position(|&byte| byte == b'\0')
+            .ok_or("Binding opcode stream is truncated")?;
+        let string = std::str::from_utf8(&self.bytes[..len])
+            .map_err(|_| "Symbol name in binding opcode stream isn't UTF-8")?
+            .to_string();
+        self.bytes = &self.bytes[len + 1..];
+        Ok(string)
+    }
+}
+
+/// Decode the binding opcode stream of an `LC_DYLD_INFO` command (any of the
+/// normal, weak and lazy ones). The mach_object crate can do this too, but it
+/// reads `BIND_OPCODE_SET_ADDEND_SLEB` as unsigned, which breaks negative
+/// addends.
+fn parse_bindings(bytes: &[u8]) -> Result<Vec<RawBinding>, &'static str> {
+    const POINTER_SIZE: u32 = 4;
+
+    let mut r = OpcodeReader { bytes };
+    let mut bindings = Vec::new();
+
+    // State machine registers
+    let mut segment_index = 0;
+    let mut segment_offset: u32 = 0;
+    let mut symbol_type = BIND_TYPE_POINTER;
+    let mut symbol: Option<String> = None;
+    let mut addend = 0;
+    let mut weak_import = false;
+
+    // The stream of lazy bindings has a DONE opcode after each binding, so
+    // the end of the bytes is used as the end of the stream instead.
+    while !r.bytes.is_empty() {
+        let byte = r.u8()?;
+        let immediate = byte & BIND_IMMEDIATE_MASK;
+        // How many bindings to do, and how far to advance after each one.
+        let (count, skip) = match byte & BIND_OPCODE_MASK {
+            BIND_OPCODE_DONE => continue,
+            // The library is irrelevant, symbols are looked up everywhere.
+            BIND_OPCODE_SET_DYLIB_ORDINAL_IMM | BIND_OPCODE_SET_DYLIB_SPECIAL_IMM => continue,
+            BIND_OPCODE_SET_DYLIB_ORDINAL_ULEB => {
+                r.uleb128()?;
+                continue;
+            }
+            BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM => {
+                weak_import = immediate & BIND_SYMBOL_FLAGS_WEAK_IMPORT != 0;
+                symbol = Some(r.cstr()?);
+                continue;
+            }
+            BIND_OPCODE_SET_TYPE_IMM => {
+                symbol_type = immediate;
+                continue;
+            }
+            BIND_OPCODE_SET_ADDEND_SLEB => {
+                addend = r.sleb128()?;
+                continue;
+            }
+            BIND_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB => {
+                segment_index = usize::from(immediate);
+                segment_offset = r.uleb128()?;
+                continue;
+            }
+            BIND_OPCODE_ADD_ADDR_ULEB => {
+                segment_offset = segment_offset.wrapping_add(r.uleb128()?);
+                continue;
+            }
+            BIND_OPCODE_DO_BIND => (1, 0),
+            BIND_OPCODE_DO_BIND_ADD_ADDR_ULEB => (1, r.uleb128()?),
+            BIND_OPCODE_DO_BIND_ADD_ADDR_IMM_SCALED => (1, u32::from(immediate) * POINTER_SIZE),
+            BIND_OPCODE_DO_BIND_ULEB_TIMES_SKIPPING_ULEB => (r.uleb128()?, r.uleb128()?),
+            _ => return Err("Unknown binding opcode"),
+        };
+        let symbol = symbol
+            .as_ref()
+            .ok_or("Binding opcode stream binds without a symbol")?;
+        for _ in 0..count {
+            bindings.push(RawBinding {
+                segment_index,
+                segment_offset,
+                symbol_type,
+                symbol: symbol.clone(),
+                addend,
+                weak_import,
+            });
+            segment_offset = segment_offset.wrapping_add(POINTER_SIZE).wrapping_add(skip);
+        }
+    }
+    Ok(bindings)
+}
+
 fn get_sym_by_idx<'a>(
     idx: u32,
     (symoff, nsyms, stroff, strsize): (u32, u32, u32, u32),
@@ -214,16 +498,106 @@ impl Reloc {
 }
 
 impl MachO {
+    /// List the architecture-specific slices in a Mach-O binary (provided as
+    /// `bytes`).
+    pub fn list_slices(bytes: &[u8]) -> Result<Vec<Slice>, &'static str> {
+        let mut cursor = Cursor::new(bytes);
+
+        let file = OFile::parse(&mut cursor).map_err(|_| "Could not parse Mach-O file")?;
+
+        match file {
+            OFile::MachFile { header, .. } => Ok(vec![Slice::new(
+                header.cputype,
+                header.cpusubtype,
+                0,
+                bytes.len(),
+            )]),
+            OFile::FatFile { files, .. } => files
+                .iter()
+                .map(|(arch, _)| {
+                    let offset: usize = arch
+                        .offset
+                        .try_into()
+                        .map_err(|_| "Fat binary slice offset is out of range")?;
+                    let size: usize = arch
+                        .size
+                        .try_into()
+                        .map_err(|_| "Fat binary slice size is out of range")?;
+                    Ok(Slice::new(arch.cputype, arch.cpusubtype, offset, size))
+                })
+                .collect(),
+            OFile::ArFile { .. } | OFile::SymDef { .. } => {
+                Err("Unexpected Mach-O file kind: not an executable")
+            }
+        }
+    }
+
+    /// Pick the slice that should be loaded. If `preferred_arch` is provided
+    /// and there's a slice for it, that slice is used, otherwise the choice is
+    /// based on [cpu::SUPPORTED_ARCHS].
+    pub fn select_slice(slices: &[Slice], preferred_arch: Option<Arch>) -> Option<&Slice> {
+        if let Some(preferred_arch) = preferred_arch {
+            if let Some(slice) = slices.iter().find(|s| s.arch == Some(preferred_arch)) {
+                return Some(slice);
+            }
+        }
+        cpu::SUPPORTED_ARCHS
+            .iter()
+            .find_map(|&arch| slices.iter().find(|s| s.arch == Some(arch)))
+    }
+
     /// Load the all the sections from a Mach-O binary (provided as `bytes`)
     /// into the guest memory (`into_mem`), and return a struct containing
-    /// metadata (e.g. symbols).
+    /// metadata (e.g. symbols). If the binary is a fat binary, the slice to
+    /// load is chosen by [MachO::select_slice].
     pub fn load_from_bytes(
         bytes: &[u8],
         into_mem: &mut Mem,
         name: String,
+        preferred_arch: Option<Arch>,
     ) -> Result<MachO, &'static str> {
         log_dbg!("Reading {:?}", name);
 
+        let slices = Self::list_slices(bytes)?;
+        let Some(slice) = Self::select_slice(&slices, preferred_arch) else {
+            if slices
+                .iter()
+                .any(|s| s.cputype == mach_object::CPU_TYPE_ARM)
+            {
+                return Err("Executable is not for a supported ARM architecture!");
+            } else {
+                return Err("Executable is not for an ARM CPU!");
+            }
+        };
+        if slices.len() > 1 {
+            log!(
+                "{:?} is a fat binary with slices: {}. Using the {} slice.",
+                name,
+                slices
+                    .iter()
+                    .map(Slice::name)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                slice.name()
+            );
+        }
+        if let Some(preferred_arch) = preferred_arch {
+            if slice.arch != Some(preferred_arch) {
+                log!(
+                    "Warning: {:?} has no {} code, using {} instead.",
+                    name,
+                    preferred_arch.short_name(),
+                    slice.name()
+                );
+            }
+        }
+        let arch = slice.arch.unwrap();
+        // From this point on, all file offsets are relative to the slice.
+        let bytes = bytes
+            .get(slice.offset..)
+            .and_then(|bytes| bytes.get(..slice.size))
+            .ok_or("Mach-O slice extends past the end of the file")?;
+
         let mut cursor = Cursor::new(bytes);
 
         let file = OFile::parse(&mut cursor).map_err(|_| "Could not parse Mach-O file")?;
@@ -231,7 +605,7 @@ impl MachO {
         let (header, commands) = match file {
             OFile::MachFile { header, commands } => (header, commands),
             OFile::FatFile { .. } => {
-                unimplemented!("Fat binary support is not implemented yet");
+                return Err("Unexpected Mach-O file kind: nested fat binary");
             }
             OFile::ArFile { .. } | OFile::SymDef { .. } => {
                 return Err("Unexpected Mach-O file kind: not an executable");
@@ -249,7 +623,6 @@ impl MachO {
         if is_64bit {
             return Err("Executable is not 32-bit!");
         }
-        // TODO: Check cpusubtype (should be some flavour of ARMv6/ARMv7)
 
         let split_segs = (header.flags & mach_object::MH_SPLIT_SEGS) != 0;
 
@@ -258,14 +631,69 @@ impl MachO {
         let mut first_read_write_segment_base: Option<u32> = None;
         let mut text_segment_base: Option<u32> = None;
         let mut all_sections = Vec::new();
+        let mut reserved_regions = Vec::new();
         let mut sym_tab_info: Option<(u32, u32, u32, u32)> = None;
 
         // Info used for the result
         let mut dynamic_libraries = Vec::new();
         let mut exported_symbols = HashMap::new();
+        let mut all_symbols = Vec::new();
         let mut indirect_undef_symbols: Vec<Option<String>> = Vec::new();
         let mut external_relocations: Vec<(u32, String)> = Vec::new();
         let mut entry_point_pc: Option<u32> = None;
+        // Addresses of all segments (including ones that aren't loaded), which
+        // are referred to by index in the dyld info opcodes.
+        let mut segment_bases = Vec::new();
+        // (offset, size) pairs for the rebase, bind, weak bind and lazy bind
+        // opcode streams.
+        let mut dyld_info_streams: Option<[(u32, u32); 4]> = None;
+
+        // Check upfront whether the binary can be loaded at its preferred
+        // address, so that nothing is left half-loaded if it can't be, e.g.
+        // when a dylib is loaded by dlopen(). If it can't be, it must be moved
+        // ("slid") elsewhere, which is only possible if there is rebase
+        // information saying which pointers need adjusting.
+        let mut preferred_range: Option<(u32, u32)> = None;
+        let mut fits = true;
+        let mut has_rebase_info = false;
+        for MachCommand(command, _size) in &commands {
+            match command {
+                LoadCommand::Segment {
+                    segname,
+                    vmaddr,
+                    vmsize,
+                    ..
+                } => {
+                    if segname == "__LINKEDIT" || segname == "__PAGEZERO" {
+                        continue;
+                    }
+                    let (seg_start, seg_end) = segment_pages(*vmaddr as u64, *vmsize as u64)?;
+                    fits &= into_mem.can_reserve(seg_start, seg_end - seg_start);
+                    let (start, end) = preferred_range.unwrap_or((seg_start, seg_end));
+                    preferred_range = Some((start.min(seg_start), end.max(seg_end)));
+                }
+                LoadCommand::DyldInfo { .. } => has_rebase_info = true,
+                _ => (),
+            }
+        }
+        let slide: u32 = match preferred_range {
+            _ if fits => 0,
+            Some((start, end)) if has_rebase_info => {
+                let Some(new_start) = into_mem.find_reservable(end - start, Mem::PAGE_SIZE) else {
+                    return Err("Not enough free memory to load binary");
+                };
+                log_dbg!(
+                    "{} can't be loaded at {:#x}, loading it at {:#x} instead",
+                    name,
+                    start,
+                    new_start
+                );
+                new_start.wrapping_sub(start)
+            }
+            _ => {
+                return Err("Binary overlaps memory that is already in use, and can't be relocated")
+            }
+        };
 
         for MachCommand(command, _size) in commands {
             match command {
@@ -276,12 +704,27 @@ impl MachO {
                     fileoff,
                     filesize,
                     initprot,
+                    maxprot,
                     sections,
                     ..
                 } => {
-                    let vmaddr: u32 = vmaddr.try_into().unwrap();
-                    let vmsize: u32 = vmsize.try_into().unwrap();
-                    let filesize: u32 = filesize.try_into().unwrap();
+                    let vmaddr: u32 = vmaddr
+                        .try_into()
+                        .map_err(|_| "Segment address is out of range")?;
+                    let vmsize: u32 = vmsize
+                        .try_into()
+                        .map_err(|_| "Segment size is out of range")?;
+                    let filesize: u32 = filesize
+                        .try_into()
+                        .map_err(|_| "Segment file size is out of range")?;
+
+                    // The zero page stays where it is.
+                    let vmaddr = if segname == "__PAGEZERO" {
+                        vmaddr
+                    } else {
+                        vmaddr.wrapping_add(slide)
+                    };
+                    segment_bases.push(vmaddr);
 
                     if first_segment_base.is_none() {
                         first_segment_base = Some(vmaddr);
@@ -317,7 +760,20 @@ impl MachO {
                     };
 
                     if load_me {
-                        into_mem.reserve(vmaddr, vmsize);
+                        // Protection is page-granular, so like the kernel, we
+                        // round the segment out to whole pages. If the segment
+                        // doesn't start on a page boundary, its first page may
+                        // already belong to the previous segment.
+                        let (start, end) = segment_pages(vmaddr.into(), vmsize.into())?;
+                        let start = if into_mem.can_reserve(start, Mem::PAGE_SIZE) {
+                            start
+                        } else {
+                            start + Mem::PAGE_SIZE
+                        };
+                        if start < end {
+                            into_mem.reserve(start, end - start);
+                            reserved_regions.push(start);
+                        }
 
                         // If filesize is less than vmsize, the rest of the
                         // segment should be filled with zeroes. We are assuming
@@ -329,6 +785,17 @@ impl MachO {
                             let dst = into_mem.bytes_at_mut(Ptr::from_bits(vmaddr), filesize);
                             dst.copy_from_slice(src);
                         }
+
+                        // A page shared with the previous segment gets this
+                        // segment's protection, as it would on a real device.
+                        into_mem
+                            .set_protection(
+                                vmaddr,
+                                vmsize,
+                                Protection::from_bits_truncate(initprot),
+                                Protection::from_bits_truncate(maxprot),
+                            )
+                            .map_err(|_| "Segment extends past the end of the address space")?;
                     }
 
                     all_sections.extend_from_slice(&sections);
@@ -355,6 +822,16 @@ impl MachO {
                             if let Symbol::Debug { .. } = symbol {
                                 continue;
                             }
+                            if let Symbol::Defined {
+                                name: Some(name),
+                                section: Some(_),
+                                entry,
+                                ..
+                            } = symbol
+                            {
+                                let entry: u32 = entry.try_into().unwrap();
+                                all_symbols.push((entry.wrapping_add(slide), name.to_string()));
+                            }
                             if let Symbol::Defined {
                                 name: Some(name),
                                 external: true,
@@ -364,6 +841,7 @@ impl MachO {
                             } = symbol
                             {
                                 let entry: u32 = entry.try_into().unwrap();
+                                let entry = entry.wrapping_add(slide);
                                 let entry = if desc & N_ARM_THUMB_DEF != 0 {
                                     entry | GuestFunction::THUMB_BIT
                                 } else {
@@ -447,7 +925,7 @@ impl MachO {
                                 // Resolve them immediately, there is no value
                                 // in passing these on to Dyld.
                                 let addr = Ptr::from_bits(addr);
-                                let entry = entry as u32;
+                                let entry = (entry as u32).wrapping_add(slide);
                                 let entry = if desc & N_ARM_THUMB_DEF != 0 {
                                     entry | GuestFunction::THUMB_BIT
                                 } else {
@@ -483,7 +961,7 @@ impl MachO {
                     };
                     // There should only be a single initial thread state.
                     assert!(entry_point_pc.is_none());
-                    entry_point_pc = Some(pc);
+                    entry_point_pc = Some(pc.wrapping_add(slide));
                 }
                 // New-style entry point PC command
                 LoadCommand::EntryPoint {
@@ -499,15 +977,94 @@ impl MachO {
                     let entryoff: u32 = entryoff.try_into().unwrap();
                     entry_point_pc = Some(text_segment_base.unwrap() + entryoff);
                 }
-                // LoadCommand::DyldInfo is apparently a newer thing that 2008
-                // games don't have. Ignore for now? Unsure if/when iOS got it.
-                LoadCommand::DyldInfo { .. } => {
-                    log!("Warning! DyldInfo is not handled.");
+                // Newer replacement for relocations and the indirect symbol
+                // table, used by binaries built for iPhone OS 3.1 and later.
+                // This is read once all the segments are known.
+                LoadCommand::DyldInfo {
+                    rebase_off,
+                    rebase_size,
+                    bind_off,
+                    bind_size,
+                    weak_bind_off,
+                    weak_bind_size,
+                    lazy_bind_off,
+                    lazy_bind_size,
+                    ..
+                } => {
+                    dyld_info_streams = Some([
+                        (rebase_off, rebase_size),
+                        (bind_off, bind_size),
+                        (weak_bind_off, weak_bind_size),
+                        (lazy_bind_off, lazy_bind_size),
+                    ]);
                 }
                 _ => (),
             }
         }
 
+        let dyld_info = dyld_info_streams.map(|streams| -> Result<DyldInfo, &'static str> {
+            let mut slices: [&[u8]; 4] = Default::default();
+            for (slice, (offset, size)) in slices.iter_mut().zip(streams) {
+                *slice = bytes
+                    .get(offset as usize..)
+                    .and_then(|bytes| bytes.get(..size as usize))
+                    .ok_or("Dyld info extends past the end of the file")?;
+            }
+            let [rebases, bindings, weak_bindings, lazy_bindings] = slices;
+            // Offsets are encoded as unsigned numbers, but adding a large
+            // enough one is used to subtract, so wrapping arithmetic is needed.
+            let addr = |segment_index: usize, offset: isize| {
+                segment_bases
+                    .get(segment_index)
+                    .map(|base| base.wrapping_add(offset as u32))
+                    .ok_or("Dyld info refers to a nonexistent segment")
+            };
+
+            if slide != 0 {
+                for rebase in Rebase::parse(rebases, 4) {
+                    let ptr: MutPtr<u32> =
+                        Ptr::from_bits(addr(rebase.segment_index, rebase.symbol_offset)?);
+                    match rebase.symbol_type {
+                        BindSymbolType::Pointer | BindSymbolType::TextAbsolute32 => {
+                            let value: u32 = into_mem.read(ptr);
+                            into_mem.write(ptr, value.wrapping_add(slide));
+                        }
+                        // PC-relative values don't change when everything
+                        // moves together.
+                        BindSymbolType::TextRelative32 => (),
+                    }
+                }
+            }
+
+            let mut dyld_info = DyldInfo::default();
+            for (stream, kind, list) in [
+                (bindings, "", &mut dyld_info.bindings),
+                (weak_bindings, "weak ", &mut dyld_info.weak_bindings),
+                (lazy_bindings, "lazy ", &mut dyld_info.lazy_bindings),
+            ] {
+                for binding in parse_bindings(stream)? {
+                    if binding.symbol_type != BIND_TYPE_POINTER {
+                        log!(
+                            "Warning: Unhandled {}binding of type {} for {:?} in {:?}",
+                            kind,
+                            binding.symbol_type,
+                            binding.symbol,
+                            name
+                        );
+                        continue;
+                    }
+                    list.push(Binding {
+                        addr: addr(binding.segment_index, binding.segment_offset as isize)?,
+                        symbol: binding.symbol,
+                        addend: binding.addend,
+                        weak_import: binding.weak_import,
+                    });
+                }
+            }
+            Ok(dyld_info)
+        });
+        let dyld_info = dyld_info.transpose()?;
+
         let sections = all_sections
             .iter()
             .map(|section| {
@@ -515,6 +1072,7 @@ impl MachO {
 
                 let name = section.sectname.clone();
                 let addr: u32 = section.addr.try_into().unwrap();
+                let addr = addr.wrapping_add(slide);
                 let size: u32 = section.size.try_into().unwrap();
                 let type_ = section.flags.sect_type();
 
@@ -559,16 +1117,31 @@ impl MachO {
             })
             .collect();
 
+        all_symbols.sort();
+
         Ok(MachO {
             name,
             dynamic_libraries,
             sections,
             exported_symbols,
+            symbols: all_symbols,
             external_relocations,
+            dyld_info,
             entry_point_pc,
+            arch,
+            header_addr: text_segment_base,
+            reserved_regions,
         })
     }
 
+    /// Free the memory a binary was loaded into. This is only for when loading
+    /// a dylib fails partway, before anything could refer to it.
+    pub fn unload(self, mem: &mut Mem) {
+        for base in self.reserved_regions {
+            mem.free(Ptr::from_bits(base));
+        }
+    }
+
     /// Load the all the sections from a Mach-O binary (from `path`) into the
     /// guest memory (`into_mem`), and return a struct containing metadata
     /// (e.g. symbols).
@@ -576,6 +1149,7 @@ impl MachO {
         path: P,
         fs: &Fs,
         into_mem: &mut Mem,
+        preferred_arch: Option<Arch>,
     ) -> Result<MachO, &'static str> {
         let name = path.as_ref().file_name().unwrap().to_string();
         Self::load_from_bytes(
@@ -583,6 +1157,7 @@ impl MachO {
                 .map_err(|_| "Could not read executable file")?,
             into_mem,
             name,
+            preferred_arch,
         )
     }
 
@@ -590,4 +1165,106 @@ impl MachO {
     pub fn get_section<P: SectionPredicate>(&self, by: P) -> Option<&Section> {
         self.sections.iter().find(|section| by.test(section))
     }
+
+    /// Find the symbol `addr` is most likely to be part of, i.e. the closest
+    /// one at or before it in the same section. Returns the address and name
+    /// of the symbol. This is only meant for debugging.
+    pub fn symbol_before(&self, addr: u32) -> Option<(u32, &str)> {
+        let section = self
+            .sections
+            .iter()
+            .find(|section| (section.addr..section.addr + section.size).contains(&addr))?;
+        let idx = self
+            .symbols
+            .partition_point(|&(sym_addr, _)| sym_addr <= addr);
+        let (sym_addr, ref name) = *self.symbols.get(idx.checked_sub(1)?)?;
+        (sym_addr >= section.addr).then_some((sym_addr, name))
+    }
+
+    /// If `addr` is within a symbol stub, returns the name of the function the
+    /// stub is for.
+    pub fn stub_symbol_at(&self, addr: u32) -> Option<&str> {
+        let section = self.get_section(SectionType::SymbolStubs)?;
+        if !(section.addr..section.addr + section.size).contains(&addr) {
+            return None;
+        }
+        let info = section.dyld_indirect_symbol_info.as_ref()?;
+        let idx = (addr - section.addr) / info.entry_size;
+        info.indirect_undef_symbols.get(idx as usize)?.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mach_object::{
+        CPU_SUBTYPE_ARM_V6, CPU_SUBTYPE_ARM_V7, CPU_SUBTYPE_ARM_V7S, CPU_SUBTYPE_I386_ALL,
+        CPU_TYPE_ARM, CPU_TYPE_I386,
+    };
+
+    #[test]
+    fn test_select_slice() {
+        let armv6 = Slice::new(CPU_TYPE_ARM, CPU_SUBTYPE_ARM_V6, 0x1000, 0x1000);
+        let armv7 = Slice::new(CPU_TYPE_ARM, CPU_SUBTYPE_ARM_V7, 0x2000, 0x1000);
+        let armv7s = Slice::new(CPU_TYPE_ARM, CPU_SUBTYPE_ARM_V7S, 0x3000, 0x1000);
+        let i386 = Slice::new(CPU_TYPE_I386, CPU_SUBTYPE_I386_ALL, 0x4000, 0x1000);
+        let slices = [armv7s.clone(), armv7.clone(), armv6.clone()];
+
+        // Preferred architecture
+        let slice = MachO::select_slice(&slices, Some(Arch::ARMv7)).unwrap();
+        assert_eq!(slice.offset, armv7.offset);
+        let slice = MachO::select_slice(&slices, Some(Arch::ARMv6)).unwrap();
+        assert_eq!(slice.offset, armv6.offset);
+
+        // Fallback order
+        let slice = MachO::select_slice(&slices, None).unwrap();
+        assert_eq!(slice.offset, armv6.offset);
+        let slices = [armv7s.clone(), armv7.clone()];
+        let slice = MachO::select_slice(&slices, Some(Arch::ARMv6)).unwrap();
+        assert_eq!(slice.offset, armv7.offset);
+
+        // No usable ARM code
+        assert!(MachO::select_slice(std::slice::from_ref(&armv7s), None).is_none());
+        assert!(MachO::select_slice(&[armv7s, i386.clone()], Some(Arch::ARMv7)).is_none());
+        assert!(MachO::select_slice(&[i386], None).is_none());
+        assert!(MachO::select_slice(&[], None).is_none());
+    }
+
+    #[test]
+    fn test_parse_bindings() {
+        #[rustfmt::skip]
+        let stream = [
+            0x11, // library ordinal 1
+            0x40, b'_', b'f', b'o', b'o', 0, // symbol "_foo"
+            0x51, // pointer
+            0x60, 0x7c, // addend -4
+            0x72, 0x10, // segment 2, offset 0x10
+            0x90, // bind
+            0xa0, 0x08, // bind, skip 8 bytes
+            0x41, b'_', b'b', b'a', b'r', 0, // symbol "_bar", weak import
+            0x60, 0xc0, 0x00, // addend 64
+            0xc0, 0x02, 0x04, // bind twice, skipping 4 bytes
+            0x00, // done
+        ];
+        let binding = |segment_offset, symbol: &str, addend, weak_import| RawBinding {
+            segment_index: 2,
+            segment_offset,
+            symbol_type: BIND_TYPE_POINTER,
+            symbol: symbol.to_string(),
+            addend,
+            weak_import,
+        };
+        assert_eq!(
+            parse_bindings(&stream).unwrap(),
+            [
+                binding(0x10, "_foo", -4, false),
+                binding(0x14, "_foo", -4, false),
+                binding(0x20, "_bar", 64, true),
+                binding(0x28, "_bar", 64, true),
+            ]
+        );
+
+        assert!(parse_bindings(&stream[..4]).is_err());
+        assert!(parse_bindings(&[0x90]).is_err());
+    }
 }
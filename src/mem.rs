@@ -15,6 +15,16 @@
 //! * [Memory Usage Performance Guidelines](https://developer.apple.com/library/archive/documentation/Performance/Conceptual/ManagingMemory/ManagingMemory.html)
 
 mod allocator;
+mod debug_heap;
+mod protection;
+mod watchpoint;
+
+pub use allocator::AllocatorStats;
+pub use debug_heap::DebugHeapProblem;
+pub use protection::{MemoryFault, Protection, ProtectionError};
+pub use watchpoint::WatchpointHit;
+
+use crate::save_state::{Reader, Writer};
 
 /// Equivalent of `usize` for guest memory.
 pub type GuestUSize = u32;
@@ -225,6 +235,19 @@ pub struct Mem {
     bytes: *mut Bytes,
 
     allocator: allocator::Allocator,
+
+    protections: protection::PageProtections,
+    /// The most recent guest access denied by [Self::check_guest_access],
+    /// waiting to be picked up by the CPU.
+    guest_access_fault: Option<MemoryFault>,
+
+    watchpoints: watchpoint::Watchpoints,
+    /// The most recent guest access that triggered a watchpoint, waiting to be
+    /// picked up by the CPU.
+    watchpoint_hit: Option<WatchpointHit>,
+
+    /// Present if `--debug-heap` is in use.
+    debug_heap: Option<debug_heap::DebugHeap>,
 }
 
 impl Drop for Mem {
@@ -237,11 +260,14 @@ impl Drop for Mem {
 }
 
 impl Mem {
+    /// Granularity of memory protection. iPhone OS uses 4KiB pages.
+    pub const PAGE_SIZE: GuestUSize = protection::PAGE_SIZE;
+
     /// The first 4KiB of address space on iPhone OS is unused, so null pointer
     /// accesses can be trapped.
     ///
-    /// We don't have full memory protection, but we can check accesses in that
-    /// range.
+    /// Guest accesses in that range are denied by the page protections. Host
+    /// accesses are checked separately, see [Self::bytes_at].
     ///
     /// Note that there is also code in `src/cpu/dynarmic_wrapper/lib.cpp` which
     /// makes assumptions about the size of the null page, and it can't see this
@@ -269,7 +295,15 @@ impl Mem {
 
         let allocator = allocator::Allocator::new();
 
-        Mem { bytes, allocator }
+        Mem {
+            bytes,
+            allocator,
+            protections: protection::PageProtections::new(),
+            guest_access_fault: None,
+            watchpoints: Default::default(),
+            watchpoint_hit: None,
+            debug_heap: None,
+        }
     }
 
     /// Take an existing instance of [Mem], but free and zero all the
@@ -281,7 +315,17 @@ impl Mem {
         let Mem {
             bytes: _,
             ref mut allocator,
+            ref mut protections,
+            ref mut guest_access_fault,
+            ref mut watchpoints,
+            ref mut watchpoint_hit,
+            ref mut debug_heap,
         } = mem;
+        protections.reset();
+        *guest_access_fault = None;
+        *watchpoints = Default::default();
+        *watchpoint_hit = None;
+        *debug_heap = None;
         let used_chunks = allocator.reset_and_drain_used_chunks();
         for allocator::Chunk { base, size } in used_chunks {
             mem.bytes_mut()[base as usize..][..size.get() as usize].fill(0);
@@ -441,21 +485,135 @@ impl Mem {
             .copy_within(src..src.checked_add(size).unwrap(), dest)
     }
 
+    /// Turn on heap debugging (see `--debug-heap`). This only affects
+    /// allocations made after this point.
+    pub fn enable_debug_heap(&mut self) {
+        self.debug_heap.get_or_insert_with(Default::default);
+    }
+
+    /// Write the allocator's state, the contents of all memory in use and the
+    /// page protections to a save state. Watchpoints aren't included, since
+    /// they belong to the debugger.
+    pub fn save_state(&self, w: &mut Writer) -> Result<(), String> {
+        if self.debug_heap.is_some() {
+            return Err("Save states aren't supported with --debug-heap".to_string());
+        }
+        let (used, unused) = self.allocator.chunks();
+        for chunks in [&used, &unused] {
+            w.u32(chunks.len().try_into().unwrap());
+            for chunk in chunks {
+                w.u32(chunk.base);
+                w.u32(chunk.size.get());
+            }
+        }
+        // Unused memory is always zeroed (see Self::free), and so is a lot of
+        // used memory, so only non-zero pages are written.
+        for chunk in used {
+            let bytes = &self.bytes()[chunk.base as usize..][..chunk.size.get() as usize];
+            for page in bytes.chunks(Self::PAGE_SIZE as usize) {
+                let is_zero = page.iter().all(|&byte| byte == 0);
+                w.bool(!is_zero);
+                if !is_zero {
+                    w.bytes(page);
+                }
+            }
+        }
+        self.protections.save_state(w);
+        Ok(())
+    }
+
+    /// Replace the allocator's state, the contents of memory and the page
+    /// protections with what [Self::save_state] wrote.
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), String> {
+        let mut chunk_lists = [Vec::new(), Vec::new()];
+        for chunks in chunk_lists.iter_mut() {
+            for _ in 0..r.u32()? {
+                let base = r.u32()?;
+                let size = r.u32()?;
+                if size == 0 {
+                    return Err("Save state file is corrupt".to_string());
+                }
+                chunks.push(allocator::Chunk::new(base, size));
+            }
+        }
+        let [used, unused] = chunk_lists;
+        let allocator = allocator::Allocator::from_chunks(used.clone(), unused)
+            .map_err(|_| "Save state file is corrupt".to_string())?;
+
+        // Anything else in use is zeroed so that the memory matches too.
+        let (old_used, _) = self.allocator.chunks();
+        self.allocator = allocator;
+        for allocator::Chunk { base, size } in old_used {
+            self.bytes_mut()[base as usize..][..size.get() as usize].fill(0);
+        }
+        for chunk in used {
+            let bytes = &mut self.bytes_mut()[chunk.base as usize..][..chunk.size.get() as usize];
+            for page in bytes.chunks_mut(Self::PAGE_SIZE as usize) {
+                if r.bool()? {
+                    let saved = r.bytes()?;
+                    if saved.len() != page.len() {
+                        return Err("Save state file is corrupt".to_string());
+                    }
+                    page.copy_from_slice(saved);
+                }
+            }
+        }
+        self.protections.load_state(r)?;
+        self.guest_access_fault = None;
+        self.watchpoint_hit = None;
+        Ok(())
+    }
+
+    /// Get statistics about the heap, for debugging.
+    pub fn allocator_stats(&self) -> AllocatorStats {
+        self.allocator.stats()
+    }
+
     /// Allocate `size` bytes.
     pub fn alloc(&mut self, size: GuestUSize) -> MutVoidPtr {
-        let ptr = Ptr::from_bits(self.allocator.alloc(size));
+        self.alloc_inner(size, None)
+    }
+
+    /// Like [Self::alloc], but for allocations made by the app (e.g. via
+    /// `malloc()`), with the address of the guest code that made it. This is
+    /// only used for debugging.
+    pub fn alloc_from_guest(&mut self, size: GuestUSize, site: VAddr) -> MutVoidPtr {
+        self.alloc_inner(size, Some(site))
+    }
+
+    fn alloc_inner(&mut self, size: GuestUSize, site: Option<VAddr>) -> MutVoidPtr {
+        let ptr = Ptr::from_bits(if self.debug_heap.is_some() {
+            self.debug_heap_alloc(size, site)
+        } else {
+            self.allocator.alloc(size)
+        });
         log_dbg!("Allocated {:?} ({:#x} bytes)", ptr, size);
         ptr
     }
 
-    pub fn realloc(&mut self, old_ptr: MutVoidPtr, size: GuestUSize) -> MutVoidPtr {
+    /// Reallocate an allocation made by the app (e.g. via `realloc()`). See
+    /// [Self::alloc_from_guest].
+    pub fn realloc_from_guest(
+        &mut self,
+        old_ptr: MutVoidPtr,
+        size: GuestUSize,
+        site: VAddr,
+    ) -> MutVoidPtr {
+        if self
+            .debug_heap
+            .as_ref()
+            .is_some_and(|debug_heap| debug_heap.owns(old_ptr.to_bits()))
+        {
+            return Ptr::from_bits(self.debug_heap_realloc(old_ptr.to_bits(), size, site));
+        }
+
         // TODO: for a moment we always assume that we do not have enough size to realloc inplace
         let old_size = self.allocator.find_allocated_size(old_ptr.to_bits());
         if old_size == size {
             return old_ptr;
         }
         assert!(size > old_size);
-        let new_ptr = self.alloc(size);
+        let new_ptr = self.alloc_from_guest(size, site);
         self.memmove(new_ptr, old_ptr.cast_const(), old_size);
         self.free(old_ptr);
         new_ptr
@@ -463,8 +621,19 @@ impl Mem {
 
     /// Free an allocation made with one of the `alloc` methods on this type.
     pub fn free(&mut self, ptr: MutVoidPtr) {
+        if self
+            .debug_heap
+            .as_ref()
+            .is_some_and(|debug_heap| debug_heap.owns(ptr.to_bits()))
+        {
+            self.debug_heap_free(ptr.to_bits());
+            log_dbg!("Freed {:?}", ptr);
+            return;
+        }
+
         let size = self.allocator.free(ptr.to_bits());
         self.bytes_at_mut(ptr.cast(), size).fill(0);
+        self.protections.reset_range(ptr.to_bits(), size);
         log_dbg!("Freed {:?} ({:#x} bytes)", ptr, size);
     }
 
@@ -512,4 +681,156 @@ impl Mem {
     pub fn reserve(&mut self, base: VAddr, size: GuestUSize) {
         self.allocator.reserve(allocator::Chunk::new(base, size));
     }
+
+    /// Check whether a region of address space is entirely unused, so that
+    /// [Self::reserve] can be used on it.
+    pub fn can_reserve(&self, base: VAddr, size: GuestUSize) -> bool {
+        self.allocator
+            .can_reserve(allocator::Chunk::new(base, size))
+    }
+
+    /// Find a region of address space of `size` bytes, aligned to `align`
+    /// bytes, that is entirely unused, so that [Self::reserve] can be used on
+    /// it.
+    pub fn find_reservable(&self, size: GuestUSize, align: GuestUSize) -> Option<VAddr> {
+        self.allocator.find_reservable(size, align)
+    }
+
+    /// Set the protection for the pages overlapping with a range of memory,
+    /// and the maximum protection [Self::protect] can set for them later.
+    /// Note that this is page-granular, so make sure nothing else shares those
+    /// pages. Returns [Err] and changes nothing if the range extends beyond the
+    /// end of the address space.
+    pub fn set_protection(
+        &mut self,
+        base: VAddr,
+        size: GuestUSize,
+        current: Protection,
+        max: Protection,
+    ) -> Result<(), ProtectionError> {
+        log_dbg!(
+            "Setting protection of {:#x}–{:#x} to {:?} (max {:?})",
+            base,
+            base + size.saturating_sub(1),
+            current,
+            max
+        );
+        self.protections.set(base, size, current, Some(max))
+    }
+
+    /// Like `mprotect()`: change the protection for the pages overlapping with
+    /// a range of memory. Returns [Err] and changes nothing if this would
+    /// exceed the maximum protection of one of the pages, or if the range
+    /// extends beyond the end of the address space.
+    pub fn protect(
+        &mut self,
+        base: VAddr,
+        size: GuestUSize,
+        protection: Protection,
+    ) -> Result<(), ProtectionError> {
+        log_dbg!(
+            "Changing protection of {:#x}–{:#x} to {:?}",
+            base,
+            base + size.saturating_sub(1),
+            protection
+        );
+        self.protections.set(base, size, protection, None)
+    }
+
+    /// Get the current protection of the page containing `addr`.
+    pub fn protection_at(&self, addr: VAddr) -> Protection {
+        self.protections.get(addr)
+    }
+
+    /// Check whether guest code may access `size` bytes at `addr` in the way
+    /// described by `access`. If not, the details are recorded for
+    /// [Self::take_guest_access_fault]. An access that triggers a watchpoint
+    /// is also refused, so that execution stops before it happens, and is
+    /// recorded for [Self::take_watchpoint_hit]. Only for use by [crate::cpu].
+    #[inline(always)]
+    pub fn check_guest_access(
+        &mut self,
+        addr: VAddr,
+        size: GuestUSize,
+        access: Protection,
+    ) -> bool {
+        match self.protections.check(addr, size, access) {
+            Ok(()) => (),
+            Err(fault) => {
+                self.guest_access_fault = Some(fault);
+                return false;
+            }
+        }
+        if self.watchpoints.is_empty() {
+            return true;
+        }
+        match self.watchpoints.check(addr, size, access) {
+            None => true,
+            Some(hit) => {
+                self.watchpoint_hit = Some(hit);
+                false
+            }
+        }
+    }
+
+    /// Take the details of the last guest access denied by
+    /// [Self::check_guest_access], if any. Only for use by [crate::cpu].
+    pub fn take_guest_access_fault(&mut self) -> Option<MemoryFault> {
+        self.guest_access_fault.take()
+    }
+
+    /// Take the details of the last guest access that triggered a watchpoint,
+    /// if any. Only for use by [crate::cpu].
+    pub fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        self.watchpoint_hit.take()
+    }
+
+    /// Add a watchpoint that stops guest code before it accesses `size` bytes
+    /// at `addr` in a way included in `kind`, which must only contain
+    /// [Protection::READ] and/or [Protection::WRITE].
+    pub fn add_watchpoint(&mut self, addr: VAddr, size: GuestUSize, kind: Protection) {
+        log_dbg!(
+            "Adding {:?} watchpoint for {:#x}–{:#x}",
+            kind,
+            addr,
+            addr + size.saturating_sub(1)
+        );
+        let (first_page, count) = self.watchpoints.add(addr, size, kind);
+        self.protections.mark_changed(first_page, count);
+    }
+
+    /// Remove a watchpoint added by [Self::add_watchpoint] with the same
+    /// parameters. Returns [false] if there was no such watchpoint.
+    pub fn remove_watchpoint(&mut self, addr: VAddr, size: GuestUSize, kind: Protection) -> bool {
+        log_dbg!(
+            "Removing {:?} watchpoint for {:#x}–{:#x}",
+            kind,
+            addr,
+            addr + size.saturating_sub(1)
+        );
+        let Some((first_page, count)) = self.watchpoints.remove(addr, size, kind) else {
+            return false;
+        };
+        self.protections.mark_changed(first_page, count);
+        true
+    }
+
+    /// Stop or resume triggering watchpoints, e.g. while the debugger is
+    /// waiting for a command that runs guest code.
+    pub fn set_watchpoints_suspended(&mut self, suspended: bool) {
+        self.watchpoints.suspended = suspended;
+    }
+
+    /// Whether the page with number `page` contains a watchpoint, in which
+    /// case the CPU must not access it directly. Only for use by [crate::cpu].
+    pub fn page_is_watched(&self, page: u32) -> bool {
+        self.watchpoints.page_is_watched(page)
+    }
+
+    /// Take the list of page ranges (first page number, page count) whose
+    /// protection has changed since this was last called. Only for use by
+    /// [crate::cpu].
+    pub fn take_protection_changes(&mut self) -> Vec<(u32, u32)> {
+        self.protections.take_changes()
+    }
 }
@@ -134,6 +134,11 @@ mod collections {
         pub fn get_size_with_base(&self, base: VAddr) -> Option<NonZeroU32> {
             self.chunks.get(&base).copied()
         }
+        pub fn iter(&self) -> impl Iterator<Item = Chunk> + '_ {
+            self.chunks
+                .iter()
+                .map(|(&base, &size)| Chunk { base, size })
+        }
     }
 
     #[derive(Default, Debug)]
@@ -250,6 +255,19 @@ mod collections {
 }
 use collections::{ChunkMap, SizeBucketedChunkMap};
 
+/// Summary of the allocator's state, for debugging.
+#[derive(Debug)]
+pub struct AllocatorStats {
+    /// Number of chunks in use. This includes reserved regions, like the null
+    /// page and the app binary, not just allocations.
+    pub used_chunks: usize,
+    pub used_bytes: u64,
+    pub free_chunks: usize,
+    pub free_bytes: u64,
+    /// The largest allocation that could currently succeed.
+    pub largest_free_chunk: GuestUSize,
+}
+
 /// Tracks which memory is in use and makes allocations from it.
 #[derive(Debug)]
 pub struct Allocator {
@@ -280,6 +298,25 @@ impl Allocator {
         }
     }
 
+    /// Check whether [Self::reserve] would succeed.
+    pub fn can_reserve(&self, chunk: Chunk) -> bool {
+        self.unused_chunks
+            .iter()
+            .any(|unused_chunk| unused_chunk.trisect_by(chunk).is_some())
+    }
+
+    /// Find a region of `size` bytes, aligned to `align` bytes, that
+    /// [Self::reserve] would succeed for.
+    pub fn find_reservable(&self, size: GuestUSize, align: GuestUSize) -> Option<VAddr> {
+        self.unused_chunks.iter().find_map(|unused_chunk| {
+            let base = unused_chunk.base.checked_add(align - 1)? / align * align;
+            base.checked_add(size - 1)?;
+            unused_chunk
+                .trisect_by(Chunk::new(base, size))
+                .map(|_| base)
+        })
+    }
+
     pub fn reserve(&mut self, chunk: Chunk) {
         let mut to_trisect = None;
         for unused_chunk in self.unused_chunks.iter() {
@@ -355,6 +392,62 @@ impl Allocator {
         freed.size.get()
     }
 
+    pub fn stats(&self) -> AllocatorStats {
+        let size = |chunk: Chunk| u64::from(chunk.size.get());
+        AllocatorStats {
+            used_chunks: self.used_chunks.iter().count(),
+            used_bytes: self.used_chunks.iter().map(size).sum(),
+            free_chunks: self.unused_chunks.iter().count(),
+            free_bytes: self.unused_chunks.iter().map(size).sum(),
+            largest_free_chunk: self
+                .unused_chunks
+                .iter()
+                .map(|chunk| chunk.size.get())
+                .max()
+                .unwrap_or(0),
+        }
+    }
+
+    /// Get the used and unused chunks, for a save state. The unused chunks are
+    /// in the order [Self::from_chunks] needs to make the same allocation
+    /// decisions.
+    pub(super) fn chunks(&self) -> (Vec<Chunk>, Vec<Chunk>) {
+        (
+            self.used_chunks.iter().collect(),
+            self.unused_chunks.iter().collect(),
+        )
+    }
+
+    /// Recreate an allocator from the result of [Self::chunks]. Returns [Err]
+    /// if the chunks don't exactly cover the address space between them.
+    pub(super) fn from_chunks(used: Vec<Chunk>, unused: Vec<Chunk>) -> Result<Allocator, ()> {
+        let mut all: Vec<Chunk> = used.iter().chain(unused.iter()).copied().collect();
+        all.sort_by_key(|chunk| chunk.base);
+        let mut next_base = 0u64;
+        for chunk in all {
+            if u64::from(chunk.base) != next_base {
+                return Err(());
+            }
+            next_base += u64::from(chunk.size.get());
+        }
+        if next_base != 1 << 32 || unused.iter().any(|c| c.size.get() < MIN_CHUNK_SIZE) {
+            return Err(());
+        }
+
+        let mut used_chunks: ChunkMap = Default::default();
+        for chunk in used {
+            used_chunks.insert(chunk);
+        }
+        let mut unused_chunks: SizeBucketedChunkMap = Default::default();
+        for chunk in unused {
+            unused_chunks.insert(chunk);
+        }
+        Ok(Allocator {
+            used_chunks,
+            unused_chunks,
+        })
+    }
+
     pub(super) fn reset_and_drain_used_chunks(&mut self) -> impl Iterator<Item = Chunk> {
         let chunks = std::mem::take(&mut self.used_chunks);
         *self = Allocator::new();
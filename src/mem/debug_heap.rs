@@ -0,0 +1,260 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Heap debugging mode (see `--debug-heap`).
+//!
+//! When this is enabled, each allocation gets a red zone before and after it,
+//! filled with a known byte pattern. The red zones are checked when the
+//! allocation is freed or reallocated, which catches most buffer overflows and
+//! underflows. Freed memory is filled with another pattern and held in
+//! quarantine for a while before it can be reused, which makes use-after-free
+//! reads more obvious, and lets use-after-free writes be detected when it
+//! leaves quarantine.
+//!
+//! Allocations made by the app via `malloc()` and friends also record the
+//! address they were made from, so that leaks can be reported.
+//!
+//! Problems aren't reported straight away, since [Mem] can't describe the
+//! addresses they were allocated from in terms of symbols. Instead they're
+//! collected for [crate::Environment] to report once the host function that
+//! ran into them has returned (see [Mem::take_debug_heap_problems]).
+
+use super::{allocator, GuestUSize, Mem, Ptr, VAddr};
+use std::collections::{HashMap, VecDeque};
+
+/// This is a multiple of the allocator's alignment so that it is preserved.
+const RED_ZONE_SIZE: GuestUSize = allocator::MIN_CHUNK_SIZE;
+const RED_ZONE_BYTE: u8 = 0xfd;
+const FREED_BYTE: u8 = 0xdd;
+/// Freed memory stays in quarantine until this much newer freed memory is
+/// also in quarantine.
+const QUARANTINE_LIMIT: GuestUSize = 4 * 1024 * 1024;
+
+#[derive(Copy, Clone)]
+struct ChunkInfo {
+    /// Size requested, not including red zones.
+    size: GuestUSize,
+    /// Address of the guest code that made the allocation, if it was made by
+    /// the app.
+    site: Option<VAddr>,
+}
+
+#[derive(Default)]
+pub(super) struct DebugHeap {
+    /// Live allocations, indexed by the address given out (not the start of
+    /// the red zone).
+    chunks: HashMap<VAddr, ChunkInfo>,
+    /// Freed allocations, oldest first. The details are in
+    /// [Self::quarantined_chunks].
+    quarantine: VecDeque<VAddr>,
+    /// Freed allocations that haven't yet been released from quarantine,
+    /// indexed like [Self::chunks].
+    quarantined_chunks: HashMap<VAddr, ChunkInfo>,
+    quarantine_size: GuestUSize,
+    /// Problems that haven't been reported yet.
+    problems: Vec<DebugHeapProblem>,
+}
+
+/// A problem found by the debug heap, see [Mem::take_debug_heap_problems].
+pub struct DebugHeapProblem {
+    /// What happened, not including where the allocation was made.
+    description: String,
+    /// Address of the guest code that made the allocation, if it was made by
+    /// the app.
+    site: Option<VAddr>,
+    /// If [true], the app's heap is corrupt and it shouldn't continue.
+    /// Otherwise, this is only worth a warning.
+    pub fatal: bool,
+}
+
+impl DebugHeapProblem {
+    /// Get a complete message for the problem. `symbolicate` is used to
+    /// describe the allocation site.
+    pub fn message(&self, symbolicate: impl Fn(VAddr) -> String) -> String {
+        let site = match self.site {
+            Some(site) => symbolicate(site),
+            None => "an unknown site in touchHLE".to_string(),
+        };
+        format!("{}, allocated at {}", self.description, site)
+    }
+}
+
+impl DebugHeap {
+    pub fn owns(&self, addr: VAddr) -> bool {
+        self.chunks.contains_key(&addr) || self.quarantined_chunks.contains_key(&addr)
+    }
+}
+
+impl Mem {
+    pub(super) fn debug_heap_alloc(&mut self, size: GuestUSize, site: Option<VAddr>) -> VAddr {
+        let total_size = size.checked_add(RED_ZONE_SIZE * 2).unwrap();
+        let base = self.allocator.alloc(total_size);
+        let addr = base + RED_ZONE_SIZE;
+        self.bytes_mut()[base as usize..][..RED_ZONE_SIZE as usize].fill(RED_ZONE_BYTE);
+        self.bytes_mut()[(addr + size) as usize..][..RED_ZONE_SIZE as usize].fill(RED_ZONE_BYTE);
+        let debug_heap = self.debug_heap.as_mut().unwrap();
+        debug_heap.chunks.insert(addr, ChunkInfo { size, site });
+        addr
+    }
+
+    fn add_debug_heap_problem(&mut self, description: String, info: ChunkInfo, fatal: bool) {
+        let debug_heap = self.debug_heap.as_mut().unwrap();
+        debug_heap.problems.push(DebugHeapProblem {
+            description,
+            site: info.site,
+            fatal,
+        });
+    }
+
+    /// Take the problems the debug heap has found since this was last called,
+    /// if it's enabled. The caller is responsible for reporting them, and
+    /// should stop the app if any are fatal.
+    pub fn take_debug_heap_problems(&mut self) -> Vec<DebugHeapProblem> {
+        match self.debug_heap {
+            Some(ref mut debug_heap) => std::mem::take(&mut debug_heap.problems),
+            None => Vec::new(),
+        }
+    }
+
+    pub(super) fn debug_heap_free(&mut self, addr: VAddr) {
+        let debug_heap = self.debug_heap.as_mut().unwrap();
+        let Some(info) = debug_heap.chunks.remove(&addr) else {
+            let info = debug_heap.quarantined_chunks[&addr];
+            self.add_debug_heap_problem(format!("Double free of {:#x}", addr), info, true);
+            return;
+        };
+
+        self.check_red_zones(addr, info);
+
+        self.bytes_mut()[addr as usize..][..info.size as usize].fill(FREED_BYTE);
+        let debug_heap = self.debug_heap.as_mut().unwrap();
+        debug_heap.quarantine.push_back(addr);
+        debug_heap.quarantined_chunks.insert(addr, info);
+        debug_heap.quarantine_size += info.size;
+
+        let mut released = Vec::new();
+        while debug_heap.quarantine_size > QUARANTINE_LIMIT {
+            let addr = debug_heap.quarantine.pop_front().unwrap();
+            let info = debug_heap.quarantined_chunks.remove(&addr).unwrap();
+            debug_heap.quarantine_size -= info.size;
+            released.push((addr, info));
+        }
+        for (addr, info) in released {
+            self.release_from_quarantine(addr, info);
+        }
+    }
+
+    fn check_red_zones(&mut self, addr: VAddr, info: ChunkInfo) {
+        let before = &self.bytes()[(addr - RED_ZONE_SIZE) as usize..][..RED_ZONE_SIZE as usize];
+        let after = &self.bytes()[(addr + info.size) as usize..][..RED_ZONE_SIZE as usize];
+        if let Some(i) = before.iter().position(|&b| b != RED_ZONE_BYTE) {
+            let description = format!(
+                "Memory before allocation {:#x} ({:#x} bytes) was overwritten at {:#x}",
+                addr,
+                info.size,
+                addr - RED_ZONE_SIZE + i as GuestUSize
+            );
+            self.add_debug_heap_problem(description, info, true);
+        } else if let Some(i) = after.iter().position(|&b| b != RED_ZONE_BYTE) {
+            let description = format!(
+                "Memory after allocation {:#x} ({:#x} bytes) was overwritten at {:#x}",
+                addr,
+                info.size,
+                addr + info.size + i as GuestUSize
+            );
+            self.add_debug_heap_problem(description, info, true);
+        }
+    }
+
+    fn release_from_quarantine(&mut self, addr: VAddr, info: ChunkInfo) {
+        let freed = &self.bytes()[addr as usize..][..info.size as usize];
+        if let Some(i) = freed.iter().position(|&b| b != FREED_BYTE) {
+            let description = format!(
+                "Allocation {:#x} ({:#x} bytes) was written to at {:#x} after being freed",
+                addr,
+                info.size,
+                addr + i as GuestUSize
+            );
+            self.add_debug_heap_problem(description, info, false);
+        }
+        let base = addr - RED_ZONE_SIZE;
+        let size = self.allocator.free(base);
+        // The allocator expects freed memory to be zeroed.
+        self.bytes_mut()[base as usize..][..size as usize].fill(0);
+        self.protections.reset_range(base, size);
+    }
+
+    pub(super) fn debug_heap_realloc(
+        &mut self,
+        old_addr: VAddr,
+        size: GuestUSize,
+        site: VAddr,
+    ) -> VAddr {
+        let debug_heap = self.debug_heap.as_ref().unwrap();
+        let Some(&old_info) = debug_heap.chunks.get(&old_addr) else {
+            let info = debug_heap.quarantined_chunks[&old_addr];
+            let description = format!("Realloc of freed allocation {:#x}", old_addr);
+            self.add_debug_heap_problem(description, info, true);
+            // The contents can't be trusted, so they aren't copied.
+            return self.debug_heap_alloc(size, Some(site));
+        };
+        self.check_red_zones(old_addr, old_info);
+        // Always move the allocation, so that code holding on to the old
+        // address is caught.
+        let new_addr = self.debug_heap_alloc(size, Some(site));
+        self.memmove(
+            Ptr::from_bits(new_addr),
+            Ptr::from_bits(old_addr),
+            size.min(old_info.size),
+        );
+        self.debug_heap_free(old_addr);
+        new_addr
+    }
+
+    /// Print a summary of allocations made by the app that haven't been freed,
+    /// if the debug heap is enabled. `symbolicate` is used to describe the
+    /// allocation sites.
+    pub fn report_debug_heap_leaks(&self, symbolicate: impl Fn(VAddr) -> String) {
+        let Some(ref debug_heap) = self.debug_heap else {
+            return;
+        };
+
+        let mut by_site: HashMap<VAddr, (usize, GuestUSize)> = HashMap::new();
+        for info in debug_heap.chunks.values() {
+            let Some(site) = info.site else {
+                continue;
+            };
+            let (count, size) = by_site.entry(site).or_default();
+            *count += 1;
+            *size += info.size;
+        }
+        if by_site.is_empty() {
+            echo!("Debug heap: no leaks from the app's allocations.");
+            return;
+        }
+
+        let mut by_site: Vec<_> = by_site.into_iter().collect();
+        by_site.sort_by_key(|&(site, (_count, size))| (std::cmp::Reverse(size), site));
+        let total_count: usize = by_site.iter().map(|(_, (count, _))| count).sum();
+        let total_size: GuestUSize = by_site.iter().map(|(_, (_, size))| size).sum();
+        echo!(
+            "Debug heap: {} allocations ({:#x} bytes) made by the app were never freed. Biggest leaks by allocation site:",
+            total_count,
+            total_size
+        );
+        const MAX_SITES: usize = 20;
+        for &(site, (count, size)) in by_site.iter().take(MAX_SITES) {
+            echo!(
+                "- {}: {} allocations ({:#x} bytes)",
+                symbolicate(site),
+                count,
+                size
+            );
+        }
+        if by_site.len() > MAX_SITES {
+            echo!("- ({} more sites)", by_site.len() - MAX_SITES);
+        }
+    }
+}
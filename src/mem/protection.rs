@@ -0,0 +1,306 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Page-granular memory protection.
+//!
+//! Only accesses made by guest code (i.e. via the CPU's memory callbacks) are
+//! checked. Host code is trusted, and sometimes needs to bypass protections,
+//! e.g. the dynamic linker rewrites stubs in `__TEXT`.
+
+use super::{GuestUSize, VAddr};
+use crate::save_state::{Reader, Writer};
+
+/// Set of permitted access kinds. The bit values match `VM_PROT_*` from
+/// Mach and `PROT_*` from `mman.h`, which are the same on iPhone OS.
+///
+/// This is also used to describe a single kind of access.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Protection(u8);
+
+impl Protection {
+    pub const NONE: Self = Protection(0);
+    pub const READ: Self = Protection(1);
+    pub const WRITE: Self = Protection(2);
+    pub const EXECUTE: Self = Protection(4);
+    pub const ALL: Self = Protection(7);
+
+    /// Convert from a `vm_prot_t` or `PROT_*` value. Unknown bits are ignored.
+    pub fn from_bits_truncate(bits: i32) -> Self {
+        Protection((bits & 7) as u8)
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Protection {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Protection(self.0 | other.0)
+    }
+}
+
+impl std::fmt::Debug for Protection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flag = |p, c| if self.contains(p) { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(Self::READ, 'r'),
+            flag(Self::WRITE, 'w'),
+            flag(Self::EXECUTE, 'x')
+        )
+    }
+}
+
+/// Details of a guest memory access that was denied.
+pub struct MemoryFault {
+    pub addr: VAddr,
+    /// The kind of access attempted.
+    pub access: Protection,
+    /// The protection of the page that denied it.
+    pub protection: Protection,
+}
+
+impl std::fmt::Debug for MemoryFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let access = match self.access {
+            Protection::READ => "read",
+            Protection::WRITE => "write",
+            Protection::EXECUTE => "execute",
+            _ => unreachable!(),
+        };
+        write!(
+            f,
+            "{} access at {:#x} denied (page protection is {:?})",
+            access, self.addr, self.protection
+        )
+    }
+}
+
+/// Why a change of protection was refused.
+#[derive(Debug)]
+pub enum ProtectionError {
+    /// The range of memory extends beyond the end of the address space.
+    OutOfRange,
+    /// The new protection exceeds the maximum protection of a page.
+    ExceedsMaximum,
+}
+
+pub const PAGE_SIZE: GuestUSize = 0x1000;
+const PAGE_COUNT: usize = 1 << 20;
+
+/// Current and maximum protection of every page in the address space.
+pub(super) struct PageProtections {
+    /// The low nibble is the current protection, the high nibble is the
+    /// maximum protection (see [Self::set]).
+    pages: Box<[u8]>,
+    /// Ranges of pages (first page number, page count) whose protection has
+    /// changed since the last [Self::take_changes].
+    changes: Vec<(u32, u32)>,
+}
+
+impl PageProtections {
+    const DEFAULT: u8 = Protection::ALL.0 | (Protection::ALL.0 << 4);
+
+    /// Everything is accessible, except for the null page.
+    pub fn new() -> PageProtections {
+        let mut protections = PageProtections {
+            pages: vec![Self::DEFAULT; PAGE_COUNT].into_boxed_slice(),
+            changes: Vec::new(),
+        };
+        protections.reset();
+        // The CPU's page table starts out matching this.
+        protections.changes.clear();
+        protections
+    }
+
+    /// Restore the state of [Self::new].
+    pub fn reset(&mut self) {
+        self.pages.fill(Self::DEFAULT);
+        // See Mem::NULL_PAGE_SIZE
+        self.pages[0] = Protection::NONE.0 | (Protection::NONE.0 << 4);
+        self.changes.push((0, PAGE_COUNT as u32));
+    }
+
+    pub fn get(&self, addr: VAddr) -> Protection {
+        Protection(self.pages[(addr / PAGE_SIZE) as usize] & 0xf)
+    }
+
+    /// Set the protection of the pages overlapping with a range of memory.
+    /// `max` limits what later calls may set `current` to. If `max` is [None],
+    /// it is unchanged, and [Err] is returned if `current` would exceed it.
+    /// Nothing is changed on an [Err] return.
+    pub fn set(
+        &mut self,
+        base: VAddr,
+        size: GuestUSize,
+        current: Protection,
+        max: Option<Protection>,
+    ) -> Result<(), ProtectionError> {
+        if size == 0 {
+            return Ok(());
+        }
+        let last_addr = base
+            .checked_add(size - 1)
+            .ok_or(ProtectionError::OutOfRange)?;
+        let first_page = base / PAGE_SIZE;
+        let last_page = last_addr / PAGE_SIZE;
+        let pages = &mut self.pages[first_page as usize..=last_page as usize];
+        if max.is_none()
+            && pages
+                .iter()
+                .any(|&page| !Protection(page >> 4).contains(current))
+        {
+            return Err(ProtectionError::ExceedsMaximum);
+        }
+        for page in pages {
+            let max = max.map_or(*page >> 4, |max| max.0);
+            *page = current.0 | (max << 4);
+        }
+        self.changes.push((first_page, last_page - first_page + 1));
+        Ok(())
+    }
+
+    /// Restore the default protection for pages entirely within a range of
+    /// memory. Pages partially in the range are left alone, since they may be
+    /// shared with other allocations.
+    pub fn reset_range(&mut self, base: VAddr, size: GuestUSize) {
+        let first_page = base.div_ceil(PAGE_SIZE);
+        let end_page = (u64::from(base) + u64::from(size)) / u64::from(PAGE_SIZE);
+        let end_page = end_page as u32;
+        if first_page >= end_page {
+            return;
+        }
+        let pages = &mut self.pages[first_page as usize..end_page as usize];
+        if pages.iter().all(|&page| page == Self::DEFAULT) {
+            return;
+        }
+        pages.fill(Self::DEFAULT);
+        self.changes.push((first_page, end_page - first_page));
+    }
+
+    /// Check whether a guest access of `size` bytes at `addr` is allowed.
+    #[inline(always)]
+    pub fn check(
+        &self,
+        addr: VAddr,
+        size: GuestUSize,
+        access: Protection,
+    ) -> Result<(), MemoryFault> {
+        let last_addr = addr.wrapping_add(size - 1);
+        for addr in [addr, last_addr] {
+            let protection = self.get(addr);
+            if !protection.contains(access) {
+                return Err(MemoryFault {
+                    addr,
+                    access,
+                    protection,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Record that something other than the protection has changed for a
+    /// range of pages that affects how the CPU must access them.
+    pub fn mark_changed(&mut self, first_page: u32, count: u32) {
+        self.changes.push((first_page, count));
+    }
+
+    pub fn take_changes(&mut self) -> Vec<(u32, u32)> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Write the current and maximum protection of every page to a save state,
+    /// as runs of pages with the same protection.
+    pub fn save_state(&self, w: &mut Writer) {
+        let runs: Vec<&[u8]> = self.pages.chunk_by(|a, b| a == b).collect();
+        w.u32(runs.len().try_into().unwrap());
+        for run in runs {
+            w.u32(run.len().try_into().unwrap());
+            w.u8(run[0]);
+        }
+    }
+
+    /// Read back what [Self::save_state] wrote.
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), String> {
+        let mut pages = Vec::with_capacity(PAGE_COUNT);
+        for _ in 0..r.u32()? {
+            let count = r.u32()? as usize;
+            let page = r.u8()?;
+            if pages.len() + count > PAGE_COUNT {
+                return Err("Save state file is corrupt".to_string());
+            }
+            pages.resize(pages.len() + count, page);
+        }
+        if pages.len() != PAGE_COUNT {
+            return Err("Save state file is corrupt".to_string());
+        }
+        self.pages = pages.into_boxed_slice();
+        self.changes.push((0, PAGE_COUNT as u32));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_protections() {
+        let mut p = PageProtections::new();
+        assert!(p.check(0, 1, Protection::READ).is_err());
+        assert!(p.check(0x1000, 4, Protection::WRITE).is_ok());
+
+        let text = Protection::READ | Protection::EXECUTE;
+        p.set(0x1000, 0x1800, text, Some(Protection::ALL)).unwrap();
+        assert_eq!(p.get(0x1000), text);
+        assert_eq!(p.get(0x2fff), text);
+        assert_eq!(p.get(0x3000), Protection::ALL);
+        assert!(p.check(0x2ffe, 4, Protection::READ).is_ok());
+        // Straddles a writable and a non-writable page
+        let fault = p.check(0x2ffe, 4, Protection::WRITE).unwrap_err();
+        assert_eq!(fault.addr, 0x2ffe);
+        assert!(p.check(0xffe, 4, Protection::READ).is_err());
+
+        // Can't exceed the maximum protection
+        p.set(0x4000, 0x1000, text, Some(text)).unwrap();
+        assert!(matches!(
+            p.set(0x4000, 0x1000, Protection::ALL, None),
+            Err(ProtectionError::ExceedsMaximum)
+        ));
+        assert!(p.set(0x4000, 0x1000, Protection::READ, None).is_ok());
+        assert!(p.set(0x4000, 0x1000, text, None).is_ok());
+
+        // Can't go past the end of the address space
+        assert!(p.set(0xfffff000, 0x1000, text, None).is_ok());
+        assert!(matches!(
+            p.set(0xfffff000, 0x1001, text, None),
+            Err(ProtectionError::OutOfRange)
+        ));
+
+        // Only whole pages are reset
+        p.reset_range(0x1001, 0x2fff);
+        assert_eq!(p.get(0x1000), text);
+        assert_eq!(p.get(0x2000), Protection::ALL);
+
+        p.take_changes();
+        p.reset_range(0x2000, 0x1000);
+        assert!(p.take_changes().is_empty());
+
+        // Save states
+        let mut w = Writer::new();
+        p.save_state(&mut w);
+        let bytes = w.into_bytes();
+        let mut p2 = PageProtections::new();
+        let mut r = Reader::new(&bytes).unwrap();
+        p2.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert!(p2.pages == p.pages);
+    }
+}
@@ -0,0 +1,101 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Data watchpoints, for use by the debugger (see [crate::gdb]).
+//!
+//! Like page protections, these are only checked for accesses made by guest
+//! code. A page containing a watchpoint is taken out of the CPU's direct memory
+//! access page table so that every access to it goes through the checks.
+
+use super::{GuestUSize, Mem, Protection, VAddr};
+
+#[derive(Debug, PartialEq, Eq)]
+struct Watchpoint {
+    addr: VAddr,
+    size: GuestUSize,
+    /// [Protection::WRITE], [Protection::READ], or both for any access.
+    kind: Protection,
+}
+
+impl Watchpoint {
+    fn pages(&self) -> std::ops::RangeInclusive<u32> {
+        (self.addr / Mem::PAGE_SIZE)..=((self.addr + (self.size - 1)) / Mem::PAGE_SIZE)
+    }
+}
+
+/// Details of a guest memory access that triggered a watchpoint.
+#[derive(Debug)]
+pub struct WatchpointHit {
+    /// The address of the accessed data, limited to the watched range.
+    pub addr: VAddr,
+    /// The kind of watchpoint that was hit (see [Watchpoints::add]).
+    pub kind: Protection,
+}
+
+#[derive(Default)]
+pub(super) struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+    /// While this is set, no watchpoint is triggered. The pages stay out of
+    /// the CPU's page table, so this can be changed at any time.
+    pub suspended: bool,
+}
+
+impl Watchpoints {
+    /// Whether no watchpoint can currently be triggered, in which case
+    /// [Self::check] need not be called.
+    pub fn is_empty(&self) -> bool {
+        self.suspended || self.watchpoints.is_empty()
+    }
+
+    /// Add a watchpoint for accesses of the kind `kind` to `size` bytes at
+    /// `addr`. Returns the range of pages (first page number, page count)
+    /// affected.
+    pub fn add(&mut self, addr: VAddr, size: GuestUSize, kind: Protection) -> (u32, u32) {
+        assert!(size != 0);
+        let watchpoint = Watchpoint { addr, size, kind };
+        let pages = watchpoint.pages();
+        self.watchpoints.push(watchpoint);
+        (*pages.start(), pages.end() - pages.start() + 1)
+    }
+
+    /// Remove a watchpoint previously added with the same parameters. Returns
+    /// the range of pages affected, or [None] if there was no such watchpoint.
+    pub fn remove(
+        &mut self,
+        addr: VAddr,
+        size: GuestUSize,
+        kind: Protection,
+    ) -> Option<(u32, u32)> {
+        let watchpoint = Watchpoint { addr, size, kind };
+        let idx = self.watchpoints.iter().position(|w| *w == watchpoint)?;
+        let pages = self.watchpoints.remove(idx).pages();
+        Some((*pages.start(), pages.end() - pages.start() + 1))
+    }
+
+    pub fn page_is_watched(&self, page: u32) -> bool {
+        self.watchpoints.iter().any(|w| w.pages().contains(&page))
+    }
+
+    /// Check whether a guest access triggers any watchpoint.
+    pub fn check(
+        &self,
+        addr: VAddr,
+        size: GuestUSize,
+        access: Protection,
+    ) -> Option<WatchpointHit> {
+        let access_end = u64::from(addr) + u64::from(size);
+        self.watchpoints
+            .iter()
+            .find(|w| {
+                w.kind.contains(access)
+                    && u64::from(addr) < u64::from(w.addr) + u64::from(w.size)
+                    && u64::from(w.addr) < access_end
+            })
+            .map(|w| WatchpointHit {
+                addr: addr.max(w.addr),
+                kind: w.kind,
+            })
+    }
+}
@@ -19,20 +19,25 @@
 //! categories and dynamic class editing).
 
 use crate::dyld::{export_c_func, FunctionExports};
+use crate::mem::ConstPtr;
 use crate::MutexId;
 use std::collections::HashMap;
 
 mod classes;
+mod debugging;
 mod messages;
 mod methods;
 mod objects;
 mod properties;
+mod protocols;
 mod selectors;
 mod synchronization;
 
 pub use classes::{objc_classes, Class, ClassExports, ClassTemplate};
+pub use debugging::MessageBreakpoint;
 pub use messages::{
-    autorelease, msg, msg_class, msg_send, msg_send_super2, msg_super, objc_super, release, retain,
+    autorelease, msg, msg_class, msg_send, msg_send_from_registers, msg_send_super2, msg_super,
+    objc_super, release, retain,
 };
 pub use methods::{GuestIMP, HostIMP, IMP};
 pub use objects::{
@@ -40,13 +45,28 @@ pub use objects::{
 };
 pub use selectors::{selector, SEL};
 
-use classes::{ClassHostObject, FakeClass, UnimplementedClass, CLASS_LISTS};
+use classes::{
+    class_getName, class_getSuperclass, objc_getClass, ClassHostObject, FakeClass,
+    UnimplementedClass, CLASS_LISTS,
+};
 use messages::{
     objc_msgSend, objc_msgSendSuper2, objc_msgSend_stret, MsgSendSignature, MsgSendSuperSignature,
 };
-use methods::method_list_t;
-use objects::{objc_object, HostObjectEntry};
-use properties::{objc_copyStruct, objc_setProperty};
+use methods::{
+    bin_method_names, class_addMethod, class_copyMethodList, class_getClassMethod,
+    class_getInstanceMethod, class_respondsToSelector, method_exchangeImplementations,
+    method_getImplementation, method_getName, method_list_t, method_setImplementation, method_t,
+};
+use objects::{objc_object, object_getClass, HostObjectEntry};
+use properties::{
+    class_copyPropertyList, class_getInstanceVariable, ivar_getName, ivar_getOffset, ivar_list_t,
+    objc_copyStruct, objc_setProperty, object_getInstanceVariable, property_getAttributes,
+    property_getName, property_list_t,
+};
+use protocols::{
+    class_conformsToProtocol, objc_getProtocol, protocol_conformsToProtocol, protocol_getName,
+    protocol_list_t,
+};
 use selectors::sel_registerName;
 use synchronization::{objc_sync_enter, objc_sync_exit};
 
@@ -71,6 +91,10 @@ pub struct ObjC {
     /// Look at the `isa` to get the metaclass for a class.
     classes: HashMap<String, Class>,
 
+    /// Known protocols, by name. A protocol may be defined by several binaries,
+    /// but only one object is used for it (see protocols.rs).
+    protocols: HashMap<String, id>,
+
     /// Mutexes used in @synchronized blocks (objc_sync_enter/exit).
     sync_mutexes: HashMap<id, MutexId>,
 
@@ -78,6 +102,19 @@ pub struct ObjC {
     /// Type information isn't part of the `objc_msgSend` ABI, so an alternative
     /// channel is needed.
     message_type_info: Option<(std::any::TypeId, &'static str)>,
+
+    /// Breakpoints set by the debugger, checked by `objc_msgSend`.
+    message_breakpoints: Vec<MessageBreakpoint>,
+
+    /// Guest functions created for host method implementations, so the app can
+    /// be given them by the runtime API (see methods.rs).
+    host_imp_functions: Vec<(&'static dyn HostIMP, GuestIMP)>,
+    /// `Method`s the app has been given by the runtime API, by class and
+    /// selector, and vice-versa.
+    runtime_methods: HashMap<(Class, SEL), ConstPtr<method_t>>,
+    runtime_method_owners: HashMap<ConstPtr<method_t>, (Class, SEL)>,
+    /// Class names the app has been given by `class_getName`.
+    class_name_strings: HashMap<Class, ConstPtr<u8>>,
 }
 
 impl ObjC {
@@ -86,8 +123,14 @@ impl ObjC {
             selectors: HashMap::new(),
             objects: HashMap::new(),
             classes: HashMap::new(),
+            protocols: HashMap::new(),
             sync_mutexes: HashMap::new(),
             message_type_info: None,
+            message_breakpoints: Vec::new(),
+            host_imp_functions: Vec::new(),
+            runtime_methods: HashMap::new(),
+            runtime_method_owners: HashMap::new(),
+            class_name_strings: HashMap::new(),
         }
     }
 }
@@ -101,4 +144,28 @@ pub const FUNCTIONS: FunctionExports = &[
     export_c_func!(objc_sync_enter(_)),
     export_c_func!(objc_sync_exit(_)),
     export_c_func!(sel_registerName(_)),
+    export_c_func!(objc_getClass(_)),
+    export_c_func!(object_getClass(_)),
+    export_c_func!(class_getName(_)),
+    export_c_func!(class_getSuperclass(_)),
+    export_c_func!(class_getInstanceMethod(_, _)),
+    export_c_func!(class_getClassMethod(_, _)),
+    export_c_func!(class_addMethod(_, _, _, _)),
+    export_c_func!(class_respondsToSelector(_, _)),
+    export_c_func!(class_copyMethodList(_, _)),
+    export_c_func!(method_getName(_)),
+    export_c_func!(method_getImplementation(_)),
+    export_c_func!(method_setImplementation(_, _)),
+    export_c_func!(method_exchangeImplementations(_, _)),
+    export_c_func!(class_copyPropertyList(_, _)),
+    export_c_func!(property_getName(_)),
+    export_c_func!(property_getAttributes(_)),
+    export_c_func!(class_getInstanceVariable(_, _)),
+    export_c_func!(object_getInstanceVariable(_, _, _)),
+    export_c_func!(ivar_getName(_)),
+    export_c_func!(ivar_getOffset(_)),
+    export_c_func!(objc_getProtocol(_)),
+    export_c_func!(protocol_getName(_)),
+    export_c_func!(protocol_conformsToProtocol(_, _)),
+    export_c_func!(class_conformsToProtocol(_, _)),
 ];
@@ -14,11 +14,14 @@ mod class_lists;
 pub(super) use class_lists::CLASS_LISTS;
 
 use super::{
-    id, method_list_t, nil, objc_object, AnyHostObject, HostIMP, HostObject, ObjC, IMP, SEL,
+    bin_method_names, id, ivar_list_t, method_list_t, nil, objc_object, property_list_t,
+    protocol_list_t, AnyHostObject, HostIMP, HostObject, ObjC, IMP, SEL,
 };
 use crate::mach_o::MachO;
 use crate::mem::{guest_size_of, ConstPtr, ConstVoidPtr, GuestUSize, Mem, Ptr, SafeRead};
-use std::collections::HashMap;
+use crate::Environment;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 
 /// Generic pointer to an Objective-C class or metaclass.
 ///
@@ -38,6 +41,9 @@ pub(super) struct ClassHostObject {
     pub(super) is_metaclass: bool,
     pub(super) superclass: Class,
     pub(super) methods: HashMap<SEL, IMP>,
+    /// Objective-C type encodings of methods, where known. Host methods don't
+    /// have these.
+    pub(super) method_types: HashMap<SEL, ConstPtr<u8>>,
     /// Offset into the allocated memory for the object where the ivars of
     /// instances of this class or metaclass (respectively: normal objects or
     /// classes) should live. This is always >= the value in the superclass.
@@ -45,6 +51,15 @@ pub(super) struct ClassHostObject {
     /// Size of the allocated memory for instances of this class or metaclass.
     /// This is always >= the value in the superclass.
     pub(super) instance_size: GuestUSize,
+    /// The ivars declared by this class (not its superclasses) in the app
+    /// binary. Null if there are none, which is always true for host classes.
+    pub(super) ivars: ConstPtr<ivar_list_t>,
+    /// The properties declared by this class in the app binary. Null if there
+    /// are none, which is always true for host classes.
+    pub(super) properties: ConstPtr<property_list_t>,
+    /// The protocols adopted by this class (not its superclasses). Always
+    /// empty for metaclasses.
+    pub(super) protocols: Vec<id>,
 }
 impl HostObject for ClassHostObject {}
 
@@ -93,10 +108,10 @@ struct class_rw_t {
     _reserved: u32,
     name: ConstPtr<u8>,
     base_methods: ConstPtr<method_list_t>,
-    _base_protocols: ConstVoidPtr, // protocol list (TODO)
-    _ivars: ConstVoidPtr,          // ivar list (TODO)
+    base_protocols: ConstPtr<protocol_list_t>,
+    ivars: ConstPtr<ivar_list_t>,
     _weak_ivar_layout: u32,
-    _base_properties: ConstVoidPtr, // property list (TODO)
+    base_properties: ConstPtr<property_list_t>,
 }
 unsafe impl SafeRead for class_rw_t {}
 
@@ -109,7 +124,7 @@ struct category_t {
     class: Class,
     instance_methods: ConstPtr<method_list_t>,
     class_methods: ConstPtr<method_list_t>,
-    _protocols: ConstVoidPtr,     // protocol list (TODO)
+    protocols: ConstPtr<protocol_list_t>,
     _property_list: ConstVoidPtr, // property list (TODO)
 }
 unsafe impl SafeRead for category_t {}
@@ -122,6 +137,8 @@ unsafe impl SafeRead for category_t {}
 pub struct ClassTemplate {
     pub name: &'static str,
     pub superclass: Option<&'static str>,
+    /// Names of the protocols adopted by the class.
+    pub protocols: &'static [&'static str],
     pub class_methods: &'static [(&'static str, &'static dyn HostIMP)],
     pub instance_methods: &'static [(&'static str, &'static dyn HostIMP)],
 }
@@ -190,7 +207,7 @@ macro_rules! _objc_method {
 ///                    // The second one should be `self` to match Objective-C,
 ///                    // but that's reserved in Rust, hence `this`.
 ///
-/// @implementation MyClass: NSObject
+/// @implementation MyClass: NSObject <NSCopying>
 ///
 /// + (id)foo {
 ///     // ...
@@ -215,6 +232,7 @@ macro_rules! _objc_method {
 ///     ("MyClass", ClassTemplate {
 ///         name: "MyClass",
 ///         superclass: Some("NSObject"),
+///         protocols: &["NSCopying"],
 ///         class_methods: &[
 ///             ("foo", &(|env: &mut Environment, this: id, _cmd: SEL| -> id {
 ///                 // ...
@@ -232,7 +250,8 @@ macro_rules! _objc_method {
 /// ];
 /// ```
 ///
-/// Note that the instance methods must be preceded by the class methods.
+/// Note that the instance methods must be preceded by the class methods. The
+/// superclass and the list of adopted protocols are both optional.
 #[macro_export] // documentation comment links are annoying without this
 macro_rules! objc_classes {
     {
@@ -242,6 +261,7 @@ macro_rules! objc_classes {
         ($env:ident, $this:ident, $_cmd:ident);
         $(
             @implementation $class_name:ident $(: $superclass_name:ident)?
+                            $(<$($protocol_name:ident),+>)?
 
             $( + ($cm_type:ty) $cm_name:ident $(:($cm_type1:ty) $cm_arg1:ident)?
                               $($cm_namen:ident:($cm_typen:ty) $cm_argn:ident)*
@@ -268,6 +288,7 @@ macro_rules! objc_classes {
                 (_OBJC_CURRENT_CLASS, $crate::objc::ClassTemplate {
                     name: _OBJC_CURRENT_CLASS,
                     superclass: $crate::_objc_superclass!($(: $superclass_name)?),
+                    protocols: &[$($(stringify!($protocol_name)),+)?],
                     class_methods: &[
                         $(
                             (
@@ -346,9 +367,14 @@ impl ClassHostObject {
                     (objc.selectors[name], IMP::Host(host_imp))
                 }),
             ),
+            method_types: HashMap::new(),
             // maybe this should be 0 for NSObject? does it matter?
             _instance_start: size,
             instance_size: size,
+            ivars: Ptr::null(),
+            properties: Ptr::null(),
+            // Resolved once the class exists, see [ObjC::link_class_inner].
+            protocols: Vec::new(),
         }
     }
 
@@ -361,6 +387,9 @@ impl ClassHostObject {
             instance_size,
             name,
             base_methods,
+            ivars,
+            base_properties,
+            base_protocols,
             ..
         } = mem.read(data);
 
@@ -371,8 +400,16 @@ impl ClassHostObject {
             is_metaclass,
             superclass,
             methods: HashMap::new(),
+            method_types: HashMap::new(),
             _instance_start: instance_start,
             instance_size,
+            ivars,
+            properties: base_properties,
+            protocols: if is_metaclass {
+                Vec::new()
+            } else {
+                objc.canonical_protocols(base_protocols, mem)
+            },
         };
 
         if !base_methods.is_null() {
@@ -450,6 +487,12 @@ impl ObjC {
         crate::dyld::search_lists(CLASS_LISTS, name).map(|&(_name, ref template)| template)
     }
 
+    /// Check whether touchHLE has an implementation of a class, without
+    /// loading it. For static analysis (see [crate::compat_report]).
+    pub fn has_host_class(name: &str) -> bool {
+        Self::find_template(name).is_some()
+    }
+
     /// For use by [crate::dyld]: get the class or metaclass referenced by an
     /// external relocation in the app binary. If we don't have an
     /// implementation of the class, a placeholder is used.
@@ -457,6 +500,17 @@ impl ObjC {
         self.link_class_inner(name, is_metaclass, mem, true)
     }
 
+    /// Get a class by name if it exists, loading it if touchHLE has an
+    /// implementation of it. Unlike [Self::link_class], this never gives a
+    /// placeholder for an unimplemented class.
+    fn lookup_class(&mut self, name: &str, mem: &mut Mem) -> Option<Class> {
+        if let Some(class) = self.get_class(name, /* is_metaclass: */ false, mem) {
+            let host_object = self.get_host_object(class).unwrap();
+            return (!host_object.as_any().is::<UnimplementedClass>()).then_some(class);
+        }
+        Self::has_host_class(name).then(|| self.get_known_class(name, mem))
+    }
+
     /// For use by host functions: get a particular class. If we don't have an
     /// implementation of the class, panic.
     pub fn get_known_class(&mut self, name: &str, mem: &mut Mem) -> Class {
@@ -551,6 +605,18 @@ impl ObjC {
 
         self.classes.insert(name.to_string(), class);
 
+        // Protocols are objects whose class is a host class, so they can only
+        // be linked once this class is fully registered, otherwise linking
+        // NSObject would recurse forever.
+        if let Some(template) = Self::find_template(name) {
+            let protocols = template
+                .protocols
+                .iter()
+                .map(|&protocol_name| self.link_protocol(protocol_name, mem))
+                .collect();
+            self.borrow_mut::<ClassHostObject>(class).protocols = protocols;
+        }
+
         if is_metaclass {
             metaclass
         } else {
@@ -617,6 +683,19 @@ impl ObjC {
             let class = data.class;
             let metaclass = Self::read_isa(class, mem);
 
+            let protocols = self.canonical_protocols(data.protocols, mem);
+            if !protocols.is_empty() {
+                let any = self.get_host_object(class).unwrap().as_any();
+                if any.is::<ClassHostObject>() {
+                    let host_obj = self.borrow_mut::<ClassHostObject>(class);
+                    for protocol in protocols {
+                        if !host_obj.protocols.contains(&protocol) {
+                            host_obj.protocols.push(protocol);
+                        }
+                    }
+                }
+            }
+
             for (class, methods) in [
                 (class, data.instance_methods),
                 (metaclass, data.class_methods),
@@ -639,8 +718,12 @@ impl ObjC {
                         is_metaclass: Default::default(),
                         superclass: nil,
                         methods: Default::default(),
+                        method_types: Default::default(),
                         _instance_start: Default::default(),
                         instance_size: Default::default(),
+                        ivars: Ptr::null(),
+                        properties: Ptr::null(),
+                        protocols: Vec::new(),
                     },
                 );
                 log_dbg!(
@@ -666,6 +749,48 @@ impl ObjC {
         }
     }
 
+    /// Get the selectors of all the methods defined by classes and categories
+    /// in a binary, without registering anything. For static analysis (see
+    /// [crate::compat_report]).
+    pub fn bin_method_names<'a>(bin: &MachO, mem: &'a Mem) -> HashSet<&'a str> {
+        let mut names = HashSet::new();
+
+        if let Some(list) = bin.get_section("__objc_classlist") {
+            assert!(list.size % 4 == 0);
+            let base: ConstPtr<Class> = Ptr::from_bits(list.addr);
+            for i in 0..(list.size / 4) {
+                let class = mem.read(base + i);
+                let metaclass = Self::read_isa(class, mem);
+                for class in [class, metaclass] {
+                    let class_t { data, .. } = mem.read(class.cast());
+                    let class_rw_t { base_methods, .. } = mem.read(data);
+                    if !base_methods.is_null() {
+                        names.extend(bin_method_names(base_methods, mem));
+                    }
+                }
+            }
+        }
+
+        if let Some(list) = bin.get_section("__objc_catlist") {
+            assert!(list.size % 4 == 0);
+            let base: ConstPtr<ConstPtr<category_t>> = Ptr::from_bits(list.addr);
+            for i in 0..(list.size / 4) {
+                let category_t {
+                    instance_methods,
+                    class_methods,
+                    ..
+                } = mem.read(mem.read(base + i));
+                for methods in [instance_methods, class_methods] {
+                    if !methods.is_null() {
+                        names.extend(bin_method_names(methods, mem));
+                    }
+                }
+            }
+        }
+
+        names
+    }
+
     pub fn class_is_subclass_of(&self, class: Class, superclass: Class) -> bool {
         if class == superclass {
             return true;
@@ -686,6 +811,59 @@ impl ObjC {
         }
     }
 
+    /// Find the guest method implementation that starts nearest to, but not
+    /// after, `addr`, returning its start address and a name in the usual
+    /// `-[Class selector]` form. Only methods starting within `section` (the
+    /// code section containing `addr`) are considered, so that addresses
+    /// outside of any method aren't attributed to one in another binary. Used
+    /// for symbolicating stack traces.
+    pub fn guest_method_before(
+        &self,
+        addr: u32,
+        section: Range<u32>,
+        mem: &Mem,
+    ) -> Option<(u32, String)> {
+        let mut best: Option<(u32, String)> = None;
+        for &class in self.classes.values() {
+            for class in [class, Self::read_isa(class, mem)] {
+                let Some(host_object) = self.get_host_object(class) else {
+                    continue;
+                };
+                let Some(ClassHostObject {
+                    name,
+                    is_metaclass,
+                    methods,
+                    ..
+                }) = host_object.as_any().downcast_ref()
+                else {
+                    continue;
+                };
+                for (&sel, imp) in methods {
+                    let &IMP::Guest(imp) = imp else {
+                        continue;
+                    };
+                    let start = imp.addr_without_thumb_bit();
+                    if start > addr
+                        || !section.contains(&start)
+                        || best.as_ref().is_some_and(|&(best, _)| best >= start)
+                    {
+                        continue;
+                    }
+                    best = Some((
+                        start,
+                        format!(
+                            "{}[{} {}]",
+                            if *is_metaclass { '+' } else { '-' },
+                            name,
+                            sel.as_str(mem)
+                        ),
+                    ));
+                }
+            }
+        }
+        best
+    }
+
     pub fn get_class_name(&self, class: Class) -> &str {
         let host_object = self.get_host_object(class).unwrap();
         if let Some(ClassHostObject { name, .. }) = host_object.as_any().downcast_ref() {
@@ -698,4 +876,50 @@ impl ObjC {
             panic!();
         }
     }
+
+    pub fn class_is_metaclass(&self, class: Class) -> bool {
+        let host_object = self.get_host_object(class).unwrap();
+        if let Some(&ClassHostObject { is_metaclass, .. }) = host_object.as_any().downcast_ref() {
+            is_metaclass
+        } else if let Some(&UnimplementedClass { is_metaclass, .. }) =
+            host_object.as_any().downcast_ref()
+        {
+            is_metaclass
+        } else if let Some(&FakeClass { is_metaclass, .. }) = host_object.as_any().downcast_ref() {
+            is_metaclass
+        } else {
+            panic!();
+        }
+    }
+}
+
+pub(super) fn objc_getClass(env: &mut Environment, name: ConstPtr<u8>) -> Class {
+    let name = env.mem.cstr_at_utf8(name).unwrap().to_string();
+    env.objc.lookup_class(&name, &mut env.mem).unwrap_or(nil)
+}
+
+pub(super) fn class_getName(env: &mut Environment, class: Class) -> ConstPtr<u8> {
+    if let Some(&name) = env.objc.class_name_strings.get(&class) {
+        return name;
+    }
+    let name = if class == nil {
+        "nil"
+    } else {
+        env.objc.get_class_name(class)
+    };
+    let name = env.mem.alloc_and_write_cstr(name.as_bytes()).cast_const();
+    env.objc.class_name_strings.insert(class, name);
+    name
+}
+
+pub(super) fn class_getSuperclass(env: &mut Environment, class: Class) -> Class {
+    if class == nil {
+        return nil;
+    }
+    let host_object = env.objc.get_host_object(class).unwrap();
+    match host_object.as_any().downcast_ref::<ClassHostObject>() {
+        Some(&ClassHostObject { superclass, .. }) => superclass,
+        // Superclasses of unimplemented or fake classes are unknown.
+        None => nil,
+    }
 }
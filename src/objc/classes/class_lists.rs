@@ -27,8 +27,10 @@ pub const CLASS_LISTS: &[super::ClassExports] = &[
     foundation::ns_dictionary::CLASSES,
     foundation::ns_enumerator::CLASSES,
     foundation::ns_file_manager::CLASSES,
+    foundation::ns_invocation::CLASSES,
     foundation::ns_keyed_unarchiver::CLASSES,
     foundation::ns_locale::CLASSES,
+    foundation::ns_method_signature::CLASSES,
     foundation::ns_notification::CLASSES,
     foundation::ns_notification_center::CLASSES,
     foundation::ns_null::CLASSES,
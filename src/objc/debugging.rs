@@ -0,0 +1,158 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Inspection of the Objective-C runtime's state for the debugger's `monitor`
+//! commands, and breakpoints on methods.
+
+use super::{id, nil, Class, ClassHostObject, FakeClass, ObjC, UnimplementedClass, SEL};
+use crate::mem::Mem;
+
+/// A breakpoint on an Objective-C method, which stops execution when a
+/// message send resolves to that method. Written in the usual
+/// `-[Class selector]` or `+[Class selector]` form.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MessageBreakpoint {
+    class_name: String,
+    is_metaclass: bool,
+    selector: String,
+}
+
+impl MessageBreakpoint {
+    pub fn parse(method: &str) -> Option<MessageBreakpoint> {
+        let is_metaclass = match method.chars().next()? {
+            '-' => false,
+            '+' => true,
+            _ => return None,
+        };
+        let method = method[1..].strip_prefix('[')?.strip_suffix(']')?;
+        let (class_name, selector) = method.split_once(' ')?;
+        let selector = selector.trim();
+        if class_name.is_empty() || selector.is_empty() {
+            return None;
+        }
+        Some(MessageBreakpoint {
+            class_name: class_name.to_string(),
+            is_metaclass,
+            selector: selector.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for MessageBreakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}[{} {}]",
+            if self.is_metaclass { '+' } else { '-' },
+            self.class_name,
+            self.selector
+        )
+    }
+}
+
+impl ObjC {
+    /// Check whether `object` is a known object (including classes). Memory
+    /// that isn't an object can't safely have messages sent to it.
+    pub fn object_exists(&self, object: id) -> bool {
+        object != nil && self.objects.contains_key(&object)
+    }
+
+    /// Check whether messages can be sent to objects of a class, i.e. it's not
+    /// a placeholder for a class touchHLE doesn't implement.
+    pub fn class_is_implemented(&self, class: Class) -> bool {
+        self.get_host_object(class)
+            .is_some_and(|host_object| host_object.as_any().is::<ClassHostObject>())
+    }
+
+    /// Describe every class loaded so far, one per line, sorted by name.
+    /// Classes implemented by touchHLE are only loaded once something uses
+    /// them.
+    pub fn describe_classes(&self) -> Vec<String> {
+        let mut classes: Vec<(&str, Class)> = self
+            .classes
+            .iter()
+            .map(|(name, &class)| (name.as_str(), class))
+            .collect();
+        classes.sort_by_key(|&(name, _)| name);
+        classes
+            .into_iter()
+            .map(|(name, class)| {
+                let host_object = self.get_host_object(class).unwrap();
+                let kind = if let Some(&ClassHostObject { superclass, .. }) =
+                    host_object.as_any().downcast_ref()
+                {
+                    if superclass == nil {
+                        String::new()
+                    } else {
+                        format!(" : {}", self.get_class_name(superclass))
+                    }
+                } else if host_object.as_any().is::<UnimplementedClass>() {
+                    " (unimplemented)".to_string()
+                } else if host_object.as_any().is::<FakeClass>() {
+                    " (fake)".to_string()
+                } else {
+                    String::new()
+                };
+                format!("{:?} {}{}", class, name, kind)
+            })
+            .collect()
+    }
+
+    /// Names of all registered selectors containing `pattern`, sorted.
+    pub fn selectors_matching(&self, pattern: &str) -> Vec<&str> {
+        let mut selectors: Vec<&str> = self
+            .selectors
+            .keys()
+            .map(String::as_str)
+            .filter(|name| name.contains(pattern))
+            .collect();
+        selectors.sort();
+        selectors
+    }
+
+    /// Add a breakpoint. Returns [false] if it already exists.
+    pub fn add_message_breakpoint(&mut self, breakpoint: MessageBreakpoint) -> bool {
+        if self.message_breakpoints.contains(&breakpoint) {
+            return false;
+        }
+        self.message_breakpoints.push(breakpoint);
+        true
+    }
+
+    /// Remove a breakpoint. Returns [false] if it didn't exist.
+    pub fn remove_message_breakpoint(&mut self, breakpoint: &MessageBreakpoint) -> bool {
+        let old_len = self.message_breakpoints.len();
+        self.message_breakpoints.retain(|other| other != breakpoint);
+        self.message_breakpoints.len() != old_len
+    }
+
+    pub fn message_breakpoints(&self) -> &[MessageBreakpoint] {
+        &self.message_breakpoints
+    }
+
+    /// For use by `objc_msgSend`: find the breakpoint, if any, on the method
+    /// for `selector` that was found in `class`.
+    pub(super) fn message_breakpoint_hit(
+        &self,
+        class: Class,
+        selector: SEL,
+        mem: &Mem,
+    ) -> Option<&MessageBreakpoint> {
+        if self.message_breakpoints.is_empty() {
+            return None;
+        }
+        let &ClassHostObject {
+            ref name,
+            is_metaclass,
+            ..
+        } = self.borrow(class);
+        let selector = selector.as_str(mem);
+        self.message_breakpoints.iter().find(|breakpoint| {
+            breakpoint.is_metaclass == is_metaclass
+                && breakpoint.class_name == *name
+                && breakpoint.selector == selector
+        })
+    }
+}
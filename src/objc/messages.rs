@@ -10,12 +10,18 @@
 //! - [Apple's documentation of `objc_msgSend`](https://developer.apple.com/documentation/objectivec/1456712-objc_msgsend)
 //! - Mike Ash's [objc_msgSend's New Prototype](https://www.mikeash.com/pyblog/objc_msgsends-new-prototype.html)
 //! - Peter Steinberger's [Calling Super at Runtime in Swift](https://steipete.com/posts/calling-super-at-runtime/) explains `objc_msgSendSuper2`
+//! - Apple's [Message Forwarding](https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/ObjCRuntimeGuide/Articles/ocrtForwarding.html)
+//!   and [Dynamic Method Resolution](https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/ObjCRuntimeGuide/Articles/ocrtDynamicResolution.html)
+//!   explain what happens when an object doesn't have a method for a message.
 
 use super::{id, nil, Class, ObjC, IMP, SEL};
-use crate::abi::{CallFromHost, GuestRet};
-use crate::mem::{ConstPtr, MutVoidPtr, SafeRead};
+use crate::abi::{CallFromHost, GuestArg, GuestRet};
+use crate::cpu::Cpu;
+use crate::frameworks::foundation::ns_invocation;
+use crate::mem::{ConstPtr, MutVoidPtr, Ptr, SafeRead};
 use crate::Environment;
 use std::any::TypeId;
+use std::fmt::Write;
 
 /// The core implementation of `objc_msgSend`, the main function of Objective-C.
 ///
@@ -29,13 +35,25 @@ use std::any::TypeId;
 /// Similarly, the return value of `objc_msgSend` is whatever value is returned
 /// by the method implementation. We are relying on CallFromGuest not
 /// overwriting it.
+///
+/// `stret` is [true] for `objc_msgSend_stret`, where the arguments are shifted
+/// by the struct return pointer.
 #[allow(non_snake_case)]
-fn objc_msgSend_inner(env: &mut Environment, receiver: id, selector: SEL, super2: Option<Class>) {
+fn objc_msgSend_inner(
+    env: &mut Environment,
+    receiver: id,
+    selector: SEL,
+    super2: Option<Class>,
+    stret: bool,
+) {
     let message_type_info = env.objc.message_type_info.take();
 
     if receiver == nil {
         // https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/ObjectiveC/Chapters/ocObjectsClasses.html#//apple_ref/doc/uid/TP30001163-CH11-SW7
         log_dbg!("[nil {}]", selector.as_str(&env.mem));
+        if let Some(depth) = message_trace_depth(env) {
+            trace_message(env, depth, receiver, nil, selector, false, None);
+        }
         env.cpu.regs_mut()[0..2].fill(0);
         return;
     }
@@ -56,6 +74,31 @@ fn objc_msgSend_inner(env: &mut Environment, receiver: id, selector: SEL, super2
                 is_metaclass,
                 ..
             } = class_host_object.as_any().downcast_ref().unwrap();
+            let name = name.clone();
+
+            if forward_message(env, receiver, orig_class, selector, super2, stret) {
+                return;
+            }
+
+            let method = format!(
+                "{}[{} {}]",
+                if is_metaclass { '+' } else { '-' },
+                name,
+                selector.as_str(&env.mem)
+            );
+            if stub_missing_method(env, &method, receiver, orig_class, selector, super2) {
+                return;
+            }
+
+            // The default implementation of this in NSObject raises an error,
+            // but the app might have overridden it.
+            if let Some(dnrs_sel) = env.objc.lookup_selector("doesNotRecognizeSelector:") {
+                if env.objc.object_has_method(&env.mem, receiver, dnrs_sel) {
+                    () = msg![env; receiver doesNotRecognizeSelector:selector];
+                    env.cpu.regs_mut()[0..2].fill(0);
+                    return;
+                }
+            }
 
             panic!(
                 "{} {:?} ({}class \"{}\", {:?}){} does not respond to selector \"{}\"!",
@@ -88,7 +131,28 @@ fn objc_msgSend_inner(env: &mut Environment, receiver: id, selector: SEL, super2
                 continue;
             }
 
-            if let Some(imp) = methods.get(&selector) {
+            if let Some(&imp) = methods.get(&selector) {
+                let thread = env.current_thread;
+                let trace_depth = message_trace_depth(env);
+                if let Some(depth) = trace_depth {
+                    let found = Some((class, imp));
+                    trace_message(
+                        env,
+                        depth,
+                        receiver,
+                        orig_class,
+                        selector,
+                        super2.is_some(),
+                        found,
+                    );
+                    env.call_tracer.as_mut().unwrap().enter_message(thread);
+                }
+                if let Some(breakpoint) = env.objc.message_breakpoint_hit(class, selector, &env.mem)
+                {
+                    let breakpoint = breakpoint.to_string();
+                    let is_host_method = matches!(imp, IMP::Host(_));
+                    env.hit_message_breakpoint(&breakpoint, receiver, is_host_method);
+                }
                 match imp {
                     IMP::Host(host_imp) => {
                         // TODO: do type checks when calling GuestIMPs too.
@@ -118,6 +182,9 @@ Type mismatch when sending message {} to {:?}!
                     // interfere with pass-through of stack arguments.
                     IMP::Guest(guest_imp) => guest_imp.call_without_pushing_stack_frame(env),
                 }
+                if trace_depth.is_some() {
+                    env.call_tracer.as_mut().unwrap().exit_message(thread);
+                }
                 return;
             } else {
                 class = superclass;
@@ -127,6 +194,16 @@ Type mismatch when sending message {} to {:?}!
             is_metaclass,
         }) = host_object.as_any().downcast_ref()
         {
+            let name = name.clone();
+            let method = format!(
+                "{}[{} {}]",
+                if is_metaclass { '+' } else { '-' },
+                name,
+                selector.as_str(&env.mem)
+            );
+            if stub_missing_method(env, &method, receiver, orig_class, selector, super2) {
+                return;
+            }
             panic!(
                 "Class \"{}\" ({:?}) is unimplemented. Call to {} method \"{}\".",
                 name,
@@ -146,6 +223,10 @@ Type mismatch when sending message {} to {:?}!
                 if is_metaclass { "class" } else { "instance" },
                 selector.as_str(&env.mem),
             );
+            if let Some(depth) = message_trace_depth(env) {
+                let is_super = super2.is_some();
+                trace_message(env, depth, receiver, orig_class, selector, is_super, None);
+            }
             env.cpu.regs_mut()[0..2].fill(0);
             return;
         } else {
@@ -157,10 +238,217 @@ Type mismatch when sending message {} to {:?}!
     }
 }
 
+/// Try to handle a message for which `receiver` has no method, like Apple's
+/// runtime does:
+///
+/// 1. `+resolveInstanceMethod:` or `+resolveClassMethod:` may add a method, and
+///    then the message is sent again.
+/// 2. `forwardingTargetForSelector:` may provide another object to send the
+///    message to instead.
+/// 3. `methodSignatureForSelector:` may provide the types of the arguments,
+///    which lets them be packaged into an `NSInvocation` and given to
+///    `forwardInvocation:`.
+///
+/// Each step is skipped if the receiver doesn't respond to it, so this doesn't
+/// recurse. Returns [true] if the message was handled, in which case the return
+/// value has been written. The last resort, `doesNotRecognizeSelector:`, is up
+/// to the caller.
+fn forward_message(
+    env: &mut Environment,
+    receiver: id,
+    orig_class: Class,
+    selector: SEL,
+    super2: Option<Class>,
+    stret: bool,
+) -> bool {
+    // Sending the messages below will clobber the argument registers, but the
+    // stack arguments are left alone.
+    let saved_regs: [u32; 4] = env.cpu.regs()[0..4].try_into().unwrap();
+    let restore_regs = |env: &mut Environment| {
+        env.cpu.regs_mut()[0..4].copy_from_slice(&saved_regs);
+    };
+
+    let responds = |env: &Environment, object: id, sel_name: &str| {
+        env.objc
+            .lookup_selector(sel_name)
+            .is_some_and(|sel| env.objc.object_has_method(&env.mem, object, sel))
+    };
+
+    let is_metaclass = env.objc.class_is_metaclass(orig_class);
+    let (resolver, resolve_sel_name) = if is_metaclass {
+        (receiver, "resolveClassMethod:")
+    } else {
+        (ObjC::read_isa(receiver, &env.mem), "resolveInstanceMethod:")
+    };
+    if responds(env, resolver, resolve_sel_name) {
+        let resolved: bool = if is_metaclass {
+            msg![env; resolver resolveClassMethod:selector]
+        } else {
+            msg![env; resolver resolveInstanceMethod:selector]
+        };
+        let lookup_class = match super2 {
+            Some(class) => env.objc.borrow::<super::ClassHostObject>(class).superclass,
+            None => orig_class,
+        };
+        if resolved && env.objc.find_method(lookup_class, selector).is_some() {
+            log_dbg!(
+                "{:?} resolved method for selector \"{}\"",
+                resolver,
+                selector.as_str(&env.mem)
+            );
+            restore_regs(env);
+            objc_msgSend_inner(env, receiver, selector, super2, stret);
+            return true;
+        }
+    }
+
+    if responds(env, receiver, "forwardingTargetForSelector:") {
+        let target: id = msg![env; receiver forwardingTargetForSelector:selector];
+        if target != nil && target != receiver {
+            log_dbg!(
+                "Forwarding \"{}\" from {:?} to {:?}",
+                selector.as_str(&env.mem),
+                receiver,
+                target
+            );
+            restore_regs(env);
+            env.cpu.regs_mut()[stret as usize] = target.to_bits();
+            objc_msgSend_inner(env, target, selector, /* super2: */ None, stret);
+            return true;
+        }
+    }
+
+    if responds(env, receiver, "methodSignatureForSelector:")
+        && responds(env, receiver, "forwardInvocation:")
+    {
+        let signature: id = msg![env; receiver methodSignatureForSelector:selector];
+        if signature != nil {
+            log_dbg!(
+                "Forwarding \"{}\" to {:?} as an invocation",
+                selector.as_str(&env.mem),
+                receiver
+            );
+            let stack_args = Ptr::from_bits(env.cpu.regs()[Cpu::SP]);
+            let invocation = ns_invocation::invocation_for_forwarded_message(
+                env,
+                signature,
+                &saved_regs,
+                stack_args,
+                stret,
+            );
+            () = msg![env; receiver forwardInvocation:invocation];
+            let stret_ptr = stret.then(|| Ptr::from_bits(saved_regs[0]));
+            ns_invocation::return_forwarded_message(env, invocation, stret_ptr);
+            release(env, invocation);
+            return true;
+        }
+    }
+
+    restore_regs(env);
+    false
+}
+
+/// Part of `--lenient`/`--stub-values-file=` support (see [crate::stubs]): if
+/// `method` (in `-[Class selector]` form) is to be stubbed, return the stub's
+/// value and [true], otherwise [false].
+fn stub_missing_method(
+    env: &mut Environment,
+    method: &str,
+    receiver: id,
+    orig_class: Class,
+    selector: SEL,
+    super2: Option<Class>,
+) -> bool {
+    let Some(value) = env
+        .stubs
+        .as_mut()
+        .and_then(|stubs| stubs.return_value(method))
+    else {
+        return false;
+    };
+    if let Some(depth) = message_trace_depth(env) {
+        let is_super = super2.is_some();
+        trace_message(env, depth, receiver, orig_class, selector, is_super, None);
+    }
+    env.cpu.regs_mut()[0] = value;
+    env.cpu.regs_mut()[1] = 0;
+    true
+}
+
+/// Part of `--trace-messages=` support (see [crate::call_trace]): if messages
+/// are being traced, get the current thread's nesting depth.
+fn message_trace_depth(env: &Environment) -> Option<usize> {
+    let tracer = env.call_tracer.as_ref()?;
+    if !tracer.traces_messages() {
+        return None;
+    }
+    Some(tracer.message_depth(env.current_thread))
+}
+
+/// Log a message send if it matches the `--trace-messages=` patterns. `found`
+/// is the class the method was found in and the method, or [None] if the
+/// message won't be dispatched to a method (e.g. the receiver is nil).
+fn trace_message(
+    env: &mut Environment,
+    depth: usize,
+    receiver: id,
+    orig_class: Class,
+    selector: SEL,
+    is_super: bool,
+    found: Option<(Class, IMP)>,
+) {
+    let (class_name, is_metaclass) = if receiver == nil {
+        ("nil", false)
+    } else {
+        (
+            env.objc.get_class_name(orig_class),
+            env.objc.class_is_metaclass(orig_class),
+        )
+    };
+    let selector = selector.as_str(&env.mem);
+    let tracer = env.call_tracer.as_mut().unwrap();
+    if !tracer.should_trace_message(class_name, selector) {
+        return;
+    }
+
+    let mut line = format!(
+        "{}[{} {}]",
+        if is_metaclass { '+' } else { '-' },
+        class_name,
+        selector
+    );
+    if receiver != nil {
+        write!(line, " to {:?}", receiver).unwrap();
+    }
+    if is_super {
+        line.push_str(" (super)");
+    }
+    match found {
+        None => line.push_str(", not dispatched"),
+        Some((class, imp)) => {
+            match imp {
+                IMP::Host(_) => line.push_str(", host method"),
+                IMP::Guest(guest_imp) => write!(
+                    line,
+                    ", guest method at {:#x}",
+                    guest_imp.addr_without_thumb_bit()
+                )
+                .unwrap(),
+            }
+            if class != orig_class {
+                write!(line, " of {}", env.objc.get_class_name(class)).unwrap();
+            }
+        }
+    }
+    tracer.trace_message(env.current_thread, depth, &line);
+}
+
 /// Standard variant of `objc_msgSend`. See [objc_msgSend_inner].
 #[allow(non_snake_case)]
 pub(super) fn objc_msgSend(env: &mut Environment, receiver: id, selector: SEL) {
-    objc_msgSend_inner(env, receiver, selector, /* super2: */ None)
+    objc_msgSend_inner(
+        env, receiver, selector, /* super2: */ None, /* stret: */ false,
+    )
 }
 
 /// Variant of `objc_msgSend` for methods that return a struct via a pointer.
@@ -178,7 +466,9 @@ pub(super) fn objc_msgSend_stret(
     receiver: id,
     selector: SEL,
 ) {
-    objc_msgSend_inner(env, receiver, selector, /* super2: */ None)
+    objc_msgSend_inner(
+        env, receiver, selector, /* super2: */ None, /* stret: */ true,
+    )
 }
 
 #[repr(C, packed)]
@@ -212,7 +502,25 @@ pub(super) fn objc_msgSendSuper2(
     // Rewrite first argument to match the normal ABI.
     crate::abi::write_next_arg(&mut 0, env.cpu.regs_mut(), &mut env.mem, receiver);
 
-    objc_msgSend_inner(env, receiver, selector, /* super2: */ Some(class))
+    objc_msgSend_inner(
+        env,
+        receiver,
+        selector,
+        /* super2: */ Some(class),
+        /* stret: */ false,
+    )
+}
+
+/// Send a message whose receiver, selector and arguments have already been
+/// written to the registers and stack according to the calling convention, as
+/// if guest code had called `objc_msgSend`, or `objc_msgSend_stret` if `stret`
+/// is [true]. The return value is likewise left in the registers or memory.
+/// This is for `NSInvocation`, which only knows the argument types at runtime.
+pub fn msg_send_from_registers(env: &mut Environment, stret: bool) {
+    let regs = &env.cpu.regs()[stret as usize..];
+    let receiver: id = GuestArg::from_regs(&regs[0..1]);
+    let selector: SEL = GuestArg::from_regs(&regs[1..2]);
+    objc_msgSend_inner(env, receiver, selector, /* super2: */ None, stret)
 }
 
 /// Trait that assists with type-checking of [msg_send]'s arguments.
@@ -7,12 +7,15 @@
 //!
 //! Resources:
 //! - [Apple's documentation of `class_addMethod`](https://developer.apple.com/documentation/objectivec/1418901-class_addmethod?language=objc)
+//! - Apple's [Objective-C Runtime](https://developer.apple.com/documentation/objectivec/objective-c_runtime?language=objc)
+//!   documentation covers the other runtime API functions implemented here.
 
 use super::{
     id, nil, objc_super, Class, ClassHostObject, MsgSendSignature, MsgSendSuperSignature, ObjC, SEL,
 };
 use crate::abi::{CallFromGuest, DotDotDot, GuestArg, GuestFunction, GuestRet};
-use crate::mem::{guest_size_of, ConstPtr, GuestUSize, Mem, Ptr, SafeRead};
+use crate::dyld::HostFunction;
+use crate::mem::{guest_size_of, ConstPtr, GuestUSize, Mem, MutPtr, Ptr, SafeRead};
 use crate::Environment;
 use std::any::TypeId;
 
@@ -24,6 +27,7 @@ use std::any::TypeId;
 /// "guest methods" (functions in the guest app). Either way, the function needs
 /// to conform to the same ABI: [id] and [SEL] must be its first two parameters.
 #[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy)]
 pub enum IMP {
     Host(&'static dyn HostIMP),
     Guest(GuestIMP),
@@ -33,6 +37,12 @@ pub enum IMP {
 pub trait HostIMP: CallFromGuest {
     /// See [MsgSendSignature::type_info].
     fn type_info(&self) -> (TypeId, &'static str);
+    /// Get an Objective-C type encoding for this method, derived from the
+    /// Rust types of its return value and parameters.
+    fn type_encoding(&self) -> String;
+    /// Get this as a plain host function, so a guest function can be created
+    /// for it (see [IMP::to_guest]).
+    fn as_host_function(&'static self) -> HostFunction;
 }
 
 macro_rules! impl_HostIMP {
@@ -45,6 +55,15 @@ macro_rules! impl_HostIMP {
             fn type_info(&self) -> (TypeId, &'static str) {
                 <(R, (id, SEL, $($P,)*)) as MsgSendSignature>::type_info()
             }
+            fn type_encoding(&self) -> String {
+                let mut encoding = ret_type_encoding::<R>();
+                encoding += "@:";
+                $(encoding += &arg_type_encoding::<$P>();)*
+                encoding
+            }
+            fn as_host_function(&'static self) -> HostFunction {
+                self
+            }
         }
         impl<R, $($P,)*> HostIMP for fn(&mut Environment, id, SEL, $($P,)* DotDotDot) -> R
         where
@@ -54,6 +73,16 @@ macro_rules! impl_HostIMP {
             fn type_info(&self) -> (TypeId, &'static str) {
                 todo!("host-to-host message calls with var-args"); // TODO
             }
+            fn type_encoding(&self) -> String {
+                // Like in Apple's runtime, variadic arguments aren't encoded.
+                let mut encoding = ret_type_encoding::<R>();
+                encoding += "@:";
+                $(encoding += &arg_type_encoding::<$P>();)*
+                encoding
+            }
+            fn as_host_function(&'static self) -> HostFunction {
+                self
+            }
         }
 
         // Currently there is a one-to-one mapping between valid host IMP
@@ -76,6 +105,63 @@ macro_rules! impl_HostIMP {
     }
 }
 
+/// Get the Objective-C type encoding for a Rust type that has an obvious
+/// equivalent.
+fn known_type_encoding<T: 'static>() -> Option<&'static str> {
+    let type_id = TypeId::of::<T>();
+    [
+        (TypeId::of::<()>(), "v"),
+        (TypeId::of::<id>(), "@"),
+        (TypeId::of::<SEL>(), ":"),
+        // BOOL is a signed char.
+        (TypeId::of::<bool>(), "c"),
+        (TypeId::of::<i8>(), "c"),
+        (TypeId::of::<u8>(), "C"),
+        (TypeId::of::<i16>(), "s"),
+        (TypeId::of::<u16>(), "S"),
+        (TypeId::of::<i32>(), "i"),
+        (TypeId::of::<u32>(), "I"),
+        (TypeId::of::<i64>(), "q"),
+        (TypeId::of::<u64>(), "Q"),
+        (TypeId::of::<f32>(), "f"),
+        (TypeId::of::<f64>(), "d"),
+        (TypeId::of::<ConstPtr<u8>>(), "r*"),
+        (TypeId::of::<MutPtr<u8>>(), "*"),
+        (TypeId::of::<GuestFunction>(), "^?"),
+    ]
+    .into_iter()
+    .find(|&(known, _)| known == type_id)
+    .map(|(_, encoding)| encoding)
+}
+
+/// Type encoding for a type without a known encoding that is `size` bytes
+/// large: an anonymous struct. This at least has the right size, which is
+/// what `NSMethodSignature` and `NSInvocation` need.
+fn opaque_type_encoding(size: GuestUSize) -> String {
+    format!(
+        "{{?={}{}}}",
+        "I".repeat(size as usize / 4),
+        "C".repeat(size as usize % 4)
+    )
+}
+
+fn arg_type_encoding<T: GuestArg + 'static>() -> String {
+    match known_type_encoding::<T>() {
+        Some(encoding) => encoding.to_string(),
+        // Most other single-register types are pointers.
+        None if T::REG_COUNT == 1 => "^v".to_string(),
+        None => opaque_type_encoding((T::REG_COUNT * 4).try_into().unwrap()),
+    }
+}
+
+fn ret_type_encoding<T: GuestRet + 'static>() -> String {
+    match (known_type_encoding::<T>(), T::SIZE_IN_MEM) {
+        (Some(encoding), _) => encoding.to_string(),
+        (None, Some(size)) => opaque_type_encoding(size),
+        (None, None) => "^v".to_string(),
+    }
+}
+
 impl_HostIMP!();
 impl_HostIMP!(P1);
 impl_HostIMP!(P1, P2);
@@ -97,17 +183,47 @@ pub(super) struct method_list_t {
 }
 unsafe impl SafeRead for method_list_t {}
 
-/// The layout of a method in an app binary.
+/// The layout of a method in an app binary. This is also what a `Method` in
+/// the runtime API points to.
 ///
 /// The name, field names and field layout are based on what Ghidra outputs.
 #[repr(C, packed)]
-struct method_t {
+pub(super) struct method_t {
     name: ConstPtr<u8>,
     types: ConstPtr<u8>,
     imp: GuestIMP,
 }
 unsafe impl SafeRead for method_t {}
 
+/// Read the methods in a method list from an app binary.
+fn read_bin_methods(
+    method_list_ptr: ConstPtr<method_list_t>,
+    mem: &Mem,
+) -> impl Iterator<Item = method_t> + '_ {
+    let method_list_t { entsize, count } = mem.read(method_list_ptr);
+    assert!(entsize >= guest_size_of::<method_t>());
+
+    let methods_base_ptr: ConstPtr<method_t> = (method_list_ptr + 1).cast();
+
+    (0..count).map(move |i| {
+        let method_ptr: ConstPtr<method_t> =
+            Ptr::from_bits(methods_base_ptr.to_bits() + i * entsize);
+        mem.read(method_ptr)
+    })
+}
+
+/// Get the selector names of the methods in a method list from an app binary,
+/// without registering anything.
+pub(super) fn bin_method_names(
+    method_list_ptr: ConstPtr<method_list_t>,
+    mem: &Mem,
+) -> impl Iterator<Item = &str> {
+    read_bin_methods(method_list_ptr, mem).map(|method_t { name, .. }| {
+        // selectors are probably always UTF-8 but this hasn't been verified
+        mem.cstr_at_utf8(name).unwrap()
+    })
+}
+
 impl ClassHostObject {
     // See classes.rs for host method parsing
 
@@ -117,26 +233,14 @@ impl ClassHostObject {
         mem: &Mem,
         objc: &mut ObjC,
     ) {
-        let method_list_t { entsize, count } = mem.read(method_list_ptr);
-        assert!(entsize >= guest_size_of::<method_t>());
-
-        let methods_base_ptr: ConstPtr<method_t> = (method_list_ptr + 1).cast();
-
-        for i in 0..count {
-            let method_ptr: ConstPtr<method_t> =
-                Ptr::from_bits(methods_base_ptr.to_bits() + i * entsize);
-
-            // TODO: support type strings
-            let method_t {
-                name,
-                types: _,
-                imp,
-            } = mem.read(method_ptr);
-
+        for method_t { name, types, imp } in read_bin_methods(method_list_ptr, mem) {
             // There is no guarantee this string is unique or known.
             // We must deduplicate it like any other.
             let sel = objc.register_bin_selector(name, mem);
             self.methods.insert(sel, IMP::Guest(imp));
+            if !types.is_null() {
+                self.method_types.insert(sel, types);
+            }
         }
     }
 }
@@ -218,3 +322,318 @@ impl ObjC {
         }
     }
 }
+
+impl IMP {
+    /// Get a guest function for this method implementation, creating one if it
+    /// is a host implementation. `sel` is only used for debugging.
+    pub(super) fn to_guest(self, env: &mut Environment, sel: SEL) -> GuestIMP {
+        let host_imp = match self {
+            IMP::Guest(guest_imp) => return guest_imp,
+            IMP::Host(host_imp) => host_imp,
+        };
+        if let Some(&(_, guest_imp)) = env
+            .objc
+            .host_imp_functions
+            .iter()
+            .find(|&&(other, _)| is_same_host_imp(other, host_imp))
+        {
+            return guest_imp;
+        }
+        // This is leaked, but there's at most one per host method.
+        let name = format!("host implementation of {}", sel.as_str(&env.mem));
+        let name: &'static str = Box::leak(name.into_boxed_str());
+        let guest_imp = env.dyld.create_guest_function(
+            &mut env.mem,
+            &mut env.cpu,
+            name,
+            host_imp.as_host_function(),
+        );
+        env.objc.host_imp_functions.push((host_imp, guest_imp));
+        guest_imp
+    }
+
+    /// Inverse of [Self::to_guest]. Host implementations stay as such even if
+    /// the app moves them around, so that host code can still send messages
+    /// to methods it implements.
+    pub(super) fn from_guest(guest_imp: GuestIMP, objc: &ObjC) -> IMP {
+        let addr = guest_imp.addr_with_thumb_bit();
+        objc.host_imp_functions
+            .iter()
+            .find(|&&(_, other)| other.addr_with_thumb_bit() == addr)
+            .map_or(IMP::Guest(guest_imp), |&(host_imp, _)| IMP::Host(host_imp))
+    }
+}
+
+fn is_same_host_imp(a: &'static dyn HostIMP, b: &'static dyn HostIMP) -> bool {
+    // Vtable pointers aren't guaranteed to be unique, so only compare the data
+    // pointers.
+    std::ptr::eq(
+        a as *const dyn HostIMP as *const u8,
+        b as *const dyn HostIMP as *const u8,
+    )
+}
+
+impl ObjC {
+    /// Find the method for a selector in a class or its superclasses, returning
+    /// the class that has it and its implementation.
+    pub(super) fn find_method(&self, class: Class, sel: SEL) -> Option<(Class, IMP)> {
+        let mut class = class;
+        while class != nil {
+            let ClassHostObject {
+                superclass,
+                methods,
+                ..
+            } = self
+                .get_host_object(class)?
+                .as_any()
+                .downcast_ref::<ClassHostObject>()?;
+            if let Some(&imp) = methods.get(&sel) {
+                return Some((class, imp));
+            }
+            class = *superclass;
+        }
+        None
+    }
+
+    /// Get the Objective-C type encoding (a C string) of the method for a
+    /// selector in a class or its superclasses, if it has one. Guest methods
+    /// may lack one, but host methods get one derived from their signature
+    /// (see [HostIMP::type_encoding]).
+    pub fn method_type_encoding(
+        &mut self,
+        class: Class,
+        sel: SEL,
+        mem: &mut Mem,
+    ) -> Option<ConstPtr<u8>> {
+        let (class, imp) = self.find_method(class, sel)?;
+        let host_object = self.borrow_mut::<ClassHostObject>(class);
+        if let Some(&types) = host_object.method_types.get(&sel) {
+            return Some(types);
+        }
+        let IMP::Host(host_imp) = imp else {
+            return None;
+        };
+        let types = mem
+            .alloc_and_write_cstr(host_imp.type_encoding().as_bytes())
+            .cast_const();
+        host_object.method_types.insert(sel, types);
+        Some(types)
+    }
+}
+
+/// `Method` in the runtime API. touchHLE's own representation of methods is in
+/// [ClassHostObject], so these are created on demand for a particular class and
+/// selector, and the implementation in them is kept up-to-date when it changes
+/// via the runtime API.
+type Method = ConstPtr<method_t>;
+
+/// Get the [Method] for a method a class has (not inherited from a superclass).
+fn get_method(env: &mut Environment, class: Class, sel: SEL) -> Method {
+    let imp = env.objc.borrow::<ClassHostObject>(class).methods[&sel];
+    let types = env
+        .objc
+        .method_type_encoding(class, sel, &mut env.mem)
+        .unwrap_or_default();
+    let imp = imp.to_guest(env, sel);
+    let method = match env.objc.runtime_methods.get(&(class, sel)) {
+        Some(&method) => method,
+        None => {
+            let method: MutPtr<method_t> = env.mem.alloc(guest_size_of::<method_t>()).cast();
+            let method = method.cast_const();
+            env.objc.runtime_methods.insert((class, sel), method);
+            env.objc.runtime_method_owners.insert(method, (class, sel));
+            method
+        }
+    };
+    env.mem.write(
+        method.cast_mut(),
+        method_t {
+            name: sel.to_ptr(),
+            types,
+            imp,
+        },
+    );
+    method
+}
+
+/// Look up the class and selector of a [Method]. Returns [None] for a null or
+/// unknown method, which apps sometimes pass when a method they expected is
+/// missing.
+fn method_owner(env: &Environment, method: Method) -> Option<(Class, SEL)> {
+    if method.is_null() {
+        return None;
+    }
+    let owner = env.objc.runtime_method_owners.get(&method).copied();
+    if owner.is_none() {
+        log!("Warning: ignoring unknown Method {:?}", method);
+    }
+    owner
+}
+
+fn set_method_imp(env: &mut Environment, method: Method, imp: IMP) -> GuestIMP {
+    let Some((class, sel)) = method_owner(env, method) else {
+        return GuestFunction::from_addr_with_thumb_bit(0);
+    };
+    let old_imp = env
+        .objc
+        .borrow_mut::<ClassHostObject>(class)
+        .methods
+        .insert(sel, imp)
+        .unwrap();
+    log_dbg!(
+        "Changed implementation of {:?} ({}) for {:?}",
+        sel.as_str(&env.mem),
+        env.objc.get_class_name(class),
+        class
+    );
+    // Update the implementation in the method_t.
+    get_method(env, class, sel);
+    old_imp.to_guest(env, sel)
+}
+
+pub(super) fn class_getInstanceMethod(env: &mut Environment, class: Class, sel: SEL) -> Method {
+    if class == nil {
+        return Ptr::null();
+    }
+    match env.objc.find_method(class, sel) {
+        Some((class, _imp)) => get_method(env, class, sel),
+        None => Ptr::null(),
+    }
+}
+
+pub(super) fn class_getClassMethod(env: &mut Environment, class: Class, sel: SEL) -> Method {
+    if class == nil {
+        return Ptr::null();
+    }
+    let metaclass = ObjC::read_isa(class, &env.mem);
+    class_getInstanceMethod(env, metaclass, sel)
+}
+
+pub(super) fn method_getName(env: &mut Environment, method: Method) -> SEL {
+    match method_owner(env, method) {
+        Some((_class, sel)) => sel,
+        None => SEL::null(),
+    }
+}
+
+pub(super) fn method_getImplementation(env: &mut Environment, method: Method) -> GuestIMP {
+    let Some((class, sel)) = method_owner(env, method) else {
+        return GuestFunction::from_addr_with_thumb_bit(0);
+    };
+    let imp = env.objc.borrow::<ClassHostObject>(class).methods[&sel];
+    imp.to_guest(env, sel)
+}
+
+pub(super) fn method_setImplementation(
+    env: &mut Environment,
+    method: Method,
+    imp: GuestIMP,
+) -> GuestIMP {
+    let imp = IMP::from_guest(imp, &env.objc);
+    set_method_imp(env, method, imp)
+}
+
+pub(super) fn method_exchangeImplementations(env: &mut Environment, a: Method, b: Method) {
+    let (Some((class_a, sel_a)), Some((class_b, sel_b))) =
+        (method_owner(env, a), method_owner(env, b))
+    else {
+        return;
+    };
+    let imp_a = env.objc.borrow::<ClassHostObject>(class_a).methods[&sel_a];
+    let imp_b = env.objc.borrow::<ClassHostObject>(class_b).methods[&sel_b];
+    set_method_imp(env, a, imp_b);
+    set_method_imp(env, b, imp_a);
+}
+
+pub(super) fn class_addMethod(
+    env: &mut Environment,
+    class: Class,
+    sel: SEL,
+    imp: GuestIMP,
+    types: ConstPtr<u8>,
+) -> bool {
+    let imp = IMP::from_guest(imp, &env.objc);
+    let Some(host_object) = env.objc.get_host_object(class) else {
+        return false;
+    };
+    if !host_object.as_any().is::<ClassHostObject>() {
+        log!(
+            "Warning: can't add method {:?} to unimplemented or fake class {:?} ({})",
+            sel.as_str(&env.mem),
+            class,
+            env.objc.get_class_name(class)
+        );
+        return false;
+    }
+    let host_object = env.objc.borrow_mut::<ClassHostObject>(class);
+    if host_object.methods.contains_key(&sel) {
+        return false;
+    }
+    host_object.methods.insert(sel, imp);
+    if !types.is_null() {
+        host_object.method_types.insert(sel, types);
+    }
+    true
+}
+
+pub(super) fn class_respondsToSelector(env: &mut Environment, class: Class, sel: SEL) -> bool {
+    class != nil && env.objc.find_method(class, sel).is_some()
+}
+
+pub(super) fn class_copyMethodList(
+    env: &mut Environment,
+    class: Class,
+    out_count: MutPtr<u32>,
+) -> MutPtr<Method> {
+    let sels: Vec<SEL> = if class == nil {
+        Vec::new()
+    } else {
+        let host_object = env.objc.get_host_object(class).unwrap();
+        match host_object.as_any().downcast_ref::<ClassHostObject>() {
+            Some(ClassHostObject { methods, .. }) => {
+                let mut sels: Vec<SEL> = methods.keys().copied().collect();
+                // Keep the order stable between runs.
+                sels.sort_by_key(|sel| sel.to_ptr().to_bits());
+                sels
+            }
+            None => Vec::new(),
+        }
+    };
+
+    let count: u32 = sels.len().try_into().unwrap();
+    if !out_count.is_null() {
+        env.mem.write(out_count, count);
+    }
+    if count == 0 {
+        return Ptr::null();
+    }
+
+    // The caller is responsible for freeing this with free().
+    let list: MutPtr<Method> = env.mem.alloc(count * guest_size_of::<Method>()).cast();
+    for (i, sel) in sels.into_iter().enumerate() {
+        let method = get_method(env, class, sel);
+        env.mem.write(list + i.try_into().unwrap(), method);
+    }
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frameworks::core_graphics::CGRect;
+
+    #[test]
+    fn test_host_type_encoding() {
+        type BoolMethod = fn(&mut Environment, id, SEL, i32, id, f64, ConstPtr<u8>) -> bool;
+        let method: BoolMethod = |_, _, _, _, _, _, _| false;
+        assert_eq!(method.type_encoding(), "c@:i@dr*");
+
+        type RectMethod = fn(&mut Environment, id, SEL, CGRect, MutPtr<id>);
+        let method: RectMethod = |_, _, _, _, _| ();
+        assert_eq!(method.type_encoding(), "v@:{?=IIII}^v");
+
+        type StretMethod = fn(&mut Environment, id, SEL) -> CGRect;
+        let method: StretMethod = |_, _, _| CGRect::default();
+        assert_eq!(method.type_encoding(), "{?=IIII}@:");
+    }
+}
@@ -3,21 +3,217 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
-//! Handling of Objective-C properties.
+//! Handling of Objective-C properties and instance variables (ivars).
 //!
-//! Note that these are not the same as instance variables (ivars), though
-//! they're closely related, so maybe this file will end up being used for those
-//! too.
+//! Note that these are not the same thing, though they're closely related.
+//! touchHLE's host classes have neither (their data is in host objects), so
+//! the runtime API functions for them only find things from the app binary.
 //!
 //! Resources:
 //! - `objc_setProperty` and friends are not documented, so [reading the source code](https://opensource.apple.com/source/objc4/objc4-551.1/runtime/Accessors.subproj/objc-accessors.mm.auto.html) is useful.
 //!
 //! See also: [crate::frameworks::foundation::ns_object].
 
-use super::{id, msg, nil, release, retain, SEL};
-use crate::mem::{ConstVoidPtr, GuestISize, GuestUSize, MutPtr, MutVoidPtr, Ptr};
+use super::{id, msg, nil, release, retain, Class, ClassHostObject, ObjC, SEL};
+use crate::mem::{
+    guest_size_of, ConstPtr, ConstVoidPtr, GuestISize, GuestUSize, Mem, MutPtr, MutVoidPtr, Ptr,
+    SafeRead,
+};
 use crate::Environment;
 
+/// The layout of the header of an ivar list or property list in an app
+/// binary.
+///
+/// The name, field names and field layout are based on what Ghidra outputs.
+#[repr(C, packed)]
+pub(super) struct entsize_list_t {
+    entsize: GuestUSize,
+    count: GuestUSize,
+    // entries follow the struct
+}
+unsafe impl SafeRead for entsize_list_t {}
+
+#[allow(non_camel_case_types)]
+pub(super) type ivar_list_t = entsize_list_t;
+#[allow(non_camel_case_types)]
+pub(super) type property_list_t = entsize_list_t;
+
+/// The layout of an ivar in an app binary. This is also what an `Ivar` in the
+/// runtime API points to.
+///
+/// The name, field names and field layout are based on what Ghidra outputs.
+#[repr(C, packed)]
+pub(super) struct ivar_t {
+    offset: ConstPtr<GuestUSize>,
+    name: ConstPtr<u8>,
+    _type: ConstPtr<u8>,
+    _alignment: u32,
+    _size: u32,
+}
+unsafe impl SafeRead for ivar_t {}
+
+/// The layout of a property in an app binary. This is also what an
+/// `objc_property_t` in the runtime API points to.
+///
+/// The name, field names and field layout are based on what Ghidra outputs.
+#[repr(C, packed)]
+pub(super) struct property_t {
+    name: ConstPtr<u8>,
+    attributes: ConstPtr<u8>,
+}
+unsafe impl SafeRead for property_t {}
+
+type Ivar = ConstPtr<ivar_t>;
+type Property = ConstPtr<property_t>;
+
+/// Get pointers to the entries of an ivar or property list in an app binary,
+/// which may be null.
+fn list_entries<T: SafeRead>(list: ConstPtr<entsize_list_t>, mem: &Mem) -> Vec<ConstPtr<T>> {
+    if list.is_null() {
+        return Vec::new();
+    }
+    let entsize_list_t { entsize, count } = mem.read(list);
+    assert!(entsize >= guest_size_of::<T>());
+    let base: ConstPtr<T> = (list + 1).cast();
+    (0..count)
+        .map(|i| Ptr::from_bits(base.to_bits() + i * entsize))
+        .collect()
+}
+
+/// Allocate a list of pointers, as returned by functions like
+/// `class_copyPropertyList`, and write its length to `out_count` if that isn't
+/// null. The caller is responsible for freeing the list with `free()`.
+fn copy_list<T>(
+    env: &mut Environment,
+    items: &[ConstPtr<T>],
+    out_count: MutPtr<u32>,
+) -> MutPtr<ConstPtr<T>> {
+    let count: u32 = items.len().try_into().unwrap();
+    if !out_count.is_null() {
+        env.mem.write(out_count, count);
+    }
+    if count == 0 {
+        return Ptr::null();
+    }
+    let list: MutPtr<ConstPtr<T>> = env.mem.alloc(count * guest_size_of::<ConstPtr<T>>()).cast();
+    for (i, &item) in items.iter().enumerate() {
+        env.mem.write(list + i.try_into().unwrap(), item);
+    }
+    list
+}
+
+impl ObjC {
+    /// Find an ivar by name in a class or its superclasses.
+    fn find_ivar(&self, class: Class, name: &str, mem: &Mem) -> Option<Ivar> {
+        let mut class = class;
+        while class != nil {
+            let &ClassHostObject {
+                superclass, ivars, ..
+            } = self
+                .get_host_object(class)?
+                .as_any()
+                .downcast_ref::<ClassHostObject>()?;
+            let ivar = list_entries::<ivar_t>(ivars, mem)
+                .into_iter()
+                .find(|&ivar| {
+                    mem.cstr_at_utf8(mem.read(ivar).name)
+                        .is_ok_and(|ivar_name| ivar_name == name)
+                });
+            if ivar.is_some() {
+                return ivar;
+            }
+            class = superclass;
+        }
+        None
+    }
+}
+
+fn ivar_offset(ivar: Ivar, mem: &Mem) -> GuestUSize {
+    mem.read(mem.read(ivar).offset)
+}
+
+pub(super) fn class_getInstanceVariable(
+    env: &mut Environment,
+    class: Class,
+    name: ConstPtr<u8>,
+) -> Ivar {
+    if class == nil {
+        return Ptr::null();
+    }
+    let name = env.mem.cstr_at_utf8(name).unwrap();
+    env.objc
+        .find_ivar(class, name, &env.mem)
+        .unwrap_or_default()
+}
+
+pub(super) fn object_getInstanceVariable(
+    env: &mut Environment,
+    object: id,
+    name: ConstPtr<u8>,
+    out_value: MutPtr<MutVoidPtr>,
+) -> Ivar {
+    if object == nil {
+        return Ptr::null();
+    }
+    let class = ObjC::read_isa(object, &env.mem);
+    let name = env.mem.cstr_at_utf8(name).unwrap();
+    let Some(ivar) = env.objc.find_ivar(class, name, &env.mem) else {
+        return Ptr::null();
+    };
+    if !out_value.is_null() {
+        let value_ptr: MutPtr<MutVoidPtr> =
+            Ptr::from_bits(object.to_bits() + ivar_offset(ivar, &env.mem));
+        let value = env.mem.read(value_ptr);
+        env.mem.write(out_value, value);
+    }
+    ivar
+}
+
+pub(super) fn ivar_getName(env: &mut Environment, ivar: Ivar) -> ConstPtr<u8> {
+    if ivar.is_null() {
+        return Ptr::null();
+    }
+    env.mem.read(ivar).name
+}
+
+pub(super) fn ivar_getOffset(env: &mut Environment, ivar: Ivar) -> GuestISize {
+    if ivar.is_null() {
+        return 0;
+    }
+    ivar_offset(ivar, &env.mem) as GuestISize
+}
+
+pub(super) fn class_copyPropertyList(
+    env: &mut Environment,
+    class: Class,
+    out_count: MutPtr<u32>,
+) -> MutPtr<Property> {
+    let properties = if class == nil {
+        Vec::new()
+    } else {
+        let host_object = env.objc.get_host_object(class).unwrap();
+        match host_object.as_any().downcast_ref::<ClassHostObject>() {
+            Some(&ClassHostObject { properties, .. }) => list_entries(properties, &env.mem),
+            None => Vec::new(),
+        }
+    };
+    copy_list(env, &properties, out_count)
+}
+
+pub(super) fn property_getName(env: &mut Environment, property: Property) -> ConstPtr<u8> {
+    if property.is_null() {
+        return Ptr::null();
+    }
+    env.mem.read(property).name
+}
+
+pub(super) fn property_getAttributes(env: &mut Environment, property: Property) -> ConstPtr<u8> {
+    if property.is_null() {
+        return Ptr::null();
+    }
+    env.mem.read(property).attributes
+}
+
 /// Undocumented function (see link above) apparently used by auto-generated
 /// methods for properties to set an ivar and handle reference counting, copying
 /// and locking.
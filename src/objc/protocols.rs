@@ -0,0 +1,304 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Handling of Objective-C protocols.
+//!
+//! Protocols are objects (instances of `Protocol`) that the app can get with
+//! `@protocol(Foo)` or `objc_getProtocol()`. Each binary that uses a protocol
+//! contains its own copy of it, so the runtime picks one canonical object per
+//! protocol name and points the binary's references at it. Host classes
+//! declare the protocols they adopt by name (see [super::objc_classes]), and
+//! if no binary defines such a protocol, a minimal one is created for it.
+//!
+//! Only conformance is tracked. The method lists of protocols are ignored.
+
+use super::{id, nil, Class, ClassHostObject, HostObject, ObjC};
+use crate::mach_o::MachO;
+use crate::mem::{guest_size_of, ConstPtr, ConstVoidPtr, GuestUSize, Mem, MutPtr, Ptr, SafeRead};
+use crate::Environment;
+use std::collections::HashSet;
+
+/// The layout of a protocol in an app binary. This is also what a `Protocol *`
+/// in the runtime API points to.
+///
+/// The name, field names and field layout are based on what Ghidra outputs.
+#[repr(C, packed)]
+pub(super) struct protocol_t {
+    isa: Class, // note that this matches objc_object
+    name: ConstPtr<u8>,
+    protocols: ConstPtr<protocol_list_t>,
+    _instance_methods: ConstVoidPtr,          // method list (TODO)
+    _class_methods: ConstVoidPtr,             // method list (TODO)
+    _optional_instance_methods: ConstVoidPtr, // method list (TODO)
+    _optional_class_methods: ConstVoidPtr,    // method list (TODO)
+    _instance_properties: ConstVoidPtr,       // property list (TODO)
+    _size: GuestUSize,
+    _flags: u32,
+}
+unsafe impl SafeRead for protocol_t {}
+
+/// The layout of a protocol list in an app binary.
+///
+/// The name, field names and field layout are based on what Ghidra outputs.
+#[repr(C, packed)]
+pub(super) struct protocol_list_t {
+    count: GuestUSize,
+    // protocol_t pointers follow the struct
+}
+unsafe impl SafeRead for protocol_list_t {}
+
+/// Our internal representation of a protocol.
+pub(super) struct ProtocolHostObject {
+    /// Other protocols incorporated by this one. Conforming to this protocol
+    /// implies conforming to these.
+    pub(super) protocols: Vec<id>,
+}
+impl HostObject for ProtocolHostObject {}
+
+/// Read the entries of a protocol list from an app binary. The list may be
+/// null.
+pub(super) fn read_protocol_list(
+    list: ConstPtr<protocol_list_t>,
+    mem: &Mem,
+) -> Vec<ConstPtr<protocol_t>> {
+    if list.is_null() {
+        return Vec::new();
+    }
+    let protocol_list_t { count } = mem.read(list);
+    let entries: ConstPtr<ConstPtr<protocol_t>> = (list + 1).cast();
+    (0..count).map(|i| mem.read(entries + i)).collect()
+}
+
+fn protocol_name(protocol: ConstPtr<protocol_t>, mem: &Mem) -> &str {
+    let protocol_t { name, .. } = mem.read(protocol);
+    mem.cstr_at_utf8(name).unwrap()
+}
+
+impl ObjC {
+    /// Get the canonical protocol with a particular name, creating a minimal
+    /// one if no binary has defined it.
+    pub(super) fn link_protocol(&mut self, name: &str, mem: &mut Mem) -> id {
+        if let Some(&protocol) = self.protocols.get(name) {
+            return protocol;
+        }
+
+        let isa = self.link_class("Protocol", /* is_metaclass: */ false, mem);
+        let name_ptr = mem.alloc_and_write_cstr(name.as_bytes()).cast_const();
+        let protocol = mem.alloc_and_write(protocol_t {
+            isa,
+            name: name_ptr,
+            protocols: Ptr::null(),
+            _instance_methods: Ptr::null(),
+            _class_methods: Ptr::null(),
+            _optional_instance_methods: Ptr::null(),
+            _optional_class_methods: Ptr::null(),
+            _instance_properties: Ptr::null(),
+            _size: guest_size_of::<protocol_t>(),
+            _flags: 0,
+        });
+        let protocol: id = protocol.cast();
+        self.register_static_object(
+            protocol,
+            Box::new(ProtocolHostObject {
+                protocols: Vec::new(),
+            }),
+        );
+        self.protocols.insert(name.to_string(), protocol);
+        protocol
+    }
+
+    /// Get the canonical protocol for a protocol from an app binary, if one
+    /// with its name has been registered.
+    pub(super) fn canonical_protocol(
+        &self,
+        protocol: ConstPtr<protocol_t>,
+        mem: &Mem,
+    ) -> Option<id> {
+        if protocol.is_null() {
+            return None;
+        }
+        let protocol_id: id = protocol.cast().cast_mut();
+        if self
+            .get_host_object(protocol_id)
+            .is_some_and(|host_object| host_object.as_any().is::<ProtocolHostObject>())
+        {
+            return Some(protocol_id);
+        }
+        self.protocols.get(protocol_name(protocol, mem)).copied()
+    }
+
+    /// Get the canonical protocols for a protocol list from an app binary.
+    pub(super) fn canonical_protocols(
+        &self,
+        list: ConstPtr<protocol_list_t>,
+        mem: &Mem,
+    ) -> Vec<id> {
+        read_protocol_list(list, mem)
+            .into_iter()
+            .filter_map(|protocol| {
+                let canonical = self.canonical_protocol(protocol, mem);
+                if canonical.is_none() {
+                    log!(
+                        "Warning: ignoring unregistered protocol \"{}\" {:?}",
+                        protocol_name(protocol, mem),
+                        protocol
+                    );
+                }
+                canonical
+            })
+            .collect()
+    }
+
+    /// For use by [crate::dyld]: register all the protocols from a binary and
+    /// make its references to protocols point to the canonical ones. This must
+    /// happen before [Self::register_bin_classes].
+    pub fn register_bin_protocols(&mut self, bin: &MachO, mem: &mut Mem) {
+        let mut bin_protocols = Vec::new();
+        if let Some(list) = bin.get_section("__objc_protolist") {
+            assert!(list.size % 4 == 0);
+            let base: ConstPtr<ConstPtr<protocol_t>> = Ptr::from_bits(list.addr);
+            for i in 0..(list.size / 4) {
+                bin_protocols.push(mem.read(base + i));
+            }
+        }
+
+        // The first definition of a protocol becomes the canonical one.
+        for &protocol in &bin_protocols {
+            let name = protocol_name(protocol, mem).to_string();
+            if self.protocols.contains_key(&name) {
+                continue;
+            }
+            let isa = self.link_class("Protocol", /* is_metaclass: */ false, mem);
+            let protocol_id: id = protocol.cast().cast_mut();
+            mem.write(protocol_id.cast(), isa);
+            self.register_static_object(
+                protocol_id,
+                Box::new(ProtocolHostObject {
+                    protocols: Vec::new(),
+                }),
+            );
+            self.protocols.insert(name, protocol_id);
+        }
+
+        // Incorporated protocols can only be resolved once all the protocols
+        // have been registered. A protocol defined by several binaries gets
+        // the union of their lists.
+        for &protocol in &bin_protocols {
+            let protocol_t { protocols, .. } = mem.read(protocol);
+            let incorporated = self.canonical_protocols(protocols, mem);
+            let canonical = self.canonical_protocol(protocol, mem).unwrap();
+            let host_object = self.borrow_mut::<ProtocolHostObject>(canonical);
+            for incorporated in incorporated {
+                if !host_object.protocols.contains(&incorporated) {
+                    host_object.protocols.push(incorporated);
+                }
+            }
+        }
+
+        if let Some(refs) = bin.get_section("__objc_protorefs") {
+            assert!(refs.size % 4 == 0);
+            let base: MutPtr<ConstPtr<protocol_t>> = Ptr::from_bits(refs.addr);
+            for i in 0..(refs.size / 4) {
+                let protocol = mem.read(base + i);
+                if let Some(canonical) = self.canonical_protocol(protocol, mem) {
+                    mem.write(base + i, canonical.cast().cast_const());
+                }
+            }
+        }
+    }
+
+    /// Check whether a protocol is, or incorporates, another protocol.
+    fn protocol_conforms_to_protocol(&self, protocol: id, other: id) -> bool {
+        let mut visited = HashSet::new();
+        let mut to_visit = vec![protocol];
+        while let Some(protocol) = to_visit.pop() {
+            if protocol == other {
+                return true;
+            }
+            if visited.insert(protocol) {
+                let host_object = self.borrow::<ProtocolHostObject>(protocol);
+                to_visit.extend_from_slice(&host_object.protocols);
+            }
+        }
+        false
+    }
+
+    /// Check whether a class itself (not its superclasses) adopts a protocol,
+    /// like `class_conformsToProtocol()`.
+    fn class_adopts_protocol(&self, class: Class, protocol: id) -> bool {
+        let Some(host_object) = self.get_host_object(class) else {
+            return false;
+        };
+        let Some(ClassHostObject { protocols, .. }) =
+            host_object.as_any().downcast_ref::<ClassHostObject>()
+        else {
+            return false;
+        };
+        protocols
+            .iter()
+            .any(|&adopted| self.protocol_conforms_to_protocol(adopted, protocol))
+    }
+
+    /// Check whether a class or any of its superclasses adopts a protocol, like
+    /// `conformsToProtocol:`. The protocol may be one from the app binary
+    /// that isn't canonical.
+    pub fn class_conforms_to_protocol(&self, class: Class, protocol: id, mem: &Mem) -> bool {
+        let Some(protocol) = self.canonical_protocol(protocol.cast().cast_const(), mem) else {
+            return false;
+        };
+        let mut class = class;
+        while class != nil {
+            if self.class_adopts_protocol(class, protocol) {
+                return true;
+            }
+            class = match self
+                .get_host_object(class)
+                .and_then(|host_object| host_object.as_any().downcast_ref::<ClassHostObject>())
+            {
+                Some(&ClassHostObject { superclass, .. }) => superclass,
+                None => nil,
+            };
+        }
+        false
+    }
+}
+
+pub(super) fn objc_getProtocol(env: &mut Environment, name: ConstPtr<u8>) -> id {
+    let name = env.mem.cstr_at_utf8(name).unwrap();
+    env.objc.protocols.get(name).copied().unwrap_or(nil)
+}
+
+pub(super) fn protocol_getName(env: &mut Environment, protocol: id) -> ConstPtr<u8> {
+    if protocol == nil {
+        return Ptr::null();
+    }
+    let protocol_t { name, .. } = env.mem.read(protocol.cast::<protocol_t>().cast_const());
+    name
+}
+
+pub(super) fn protocol_conformsToProtocol(env: &mut Environment, protocol: id, other: id) -> bool {
+    let objc = &env.objc;
+    let mem = &env.mem;
+    match (
+        objc.canonical_protocol(protocol.cast().cast_const(), mem),
+        objc.canonical_protocol(other.cast().cast_const(), mem),
+    ) {
+        (Some(protocol), Some(other)) => objc.protocol_conforms_to_protocol(protocol, other),
+        _ => false,
+    }
+}
+
+pub(super) fn class_conformsToProtocol(env: &mut Environment, class: Class, protocol: id) -> bool {
+    if class == nil {
+        return false;
+    }
+    let Some(protocol) = env
+        .objc
+        .canonical_protocol(protocol.cast().cast_const(), &env.mem)
+    else {
+        return false;
+    };
+    env.objc.class_adopts_protocol(class, protocol)
+}
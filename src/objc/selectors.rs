@@ -17,8 +17,9 @@
 use super::ObjC;
 use crate::abi::{GuestArg, GuestRet};
 use crate::mach_o::MachO;
-use crate::mem::{ConstPtr, Mem, MutPtr, Ptr};
+use crate::mem::{ConstPtr, Mem, MutPtr, Ptr, SafeRead};
 use crate::Environment;
+use std::collections::HashSet;
 
 /// Create a string literal for a selector from Objective-C message syntax
 /// components. Useful for [super::objc_classes] and for [super::msg].
@@ -38,6 +39,7 @@ pub use crate::selector; // #[macro_export] is weird...
 #[repr(transparent)]
 #[allow(clippy::upper_case_acronyms)] // silly clippit, this isn't an acronym!
 pub struct SEL(ConstPtr<u8>);
+unsafe impl SafeRead for SEL {}
 
 impl GuestArg for SEL {
     const REG_COUNT: usize = <ConstPtr<u8> as GuestArg>::REG_COUNT;
@@ -62,6 +64,16 @@ impl SEL {
         // selectors are probably always UTF-8 but this hasn't been verified
         mem.cstr_at_utf8(self.0).unwrap()
     }
+
+    /// Get the pointer to the selector's name, which is what the guest sees.
+    pub(super) fn to_ptr(self) -> ConstPtr<u8> {
+        self.0
+    }
+
+    /// The null selector, which the runtime API returns on failure.
+    pub(super) fn null() -> Self {
+        SEL(Ptr::null())
+    }
 }
 
 impl ObjC {
@@ -104,6 +116,20 @@ impl ObjC {
         }
     }
 
+    /// Get the selectors of all the methods of host classes, without
+    /// registering anything. For static analysis (see [crate::compat_report]).
+    pub fn host_selectors() -> HashSet<&'static str> {
+        let mut selectors = HashSet::new();
+        for &class_list in super::CLASS_LISTS {
+            for (_name, template) in class_list {
+                for method_list in [template.class_methods, template.instance_methods] {
+                    selectors.extend(method_list.iter().map(|&(name, _imp)| name));
+                }
+            }
+        }
+        selectors
+    }
+
     /// Register a selector from the application binary. Must be a
     /// static-lifetime constant string.
     pub(super) fn register_bin_selector(&mut self, sel_cstr: ConstPtr<u8>, mem: &Mem) -> SEL {
@@ -135,6 +161,20 @@ impl ObjC {
             mem.write(selref, sel.0);
         }
     }
+
+    /// Get the names of the selectors referenced in a binary, without
+    /// registering anything. For static analysis (see [crate::compat_report]).
+    pub fn bin_selector_refs<'a>(bin: &MachO, mem: &'a Mem) -> Vec<&'a str> {
+        let Some(selrefs) = bin.get_section("__objc_selrefs") else {
+            return Vec::new();
+        };
+
+        assert!(selrefs.size % 4 == 0);
+        let base: ConstPtr<ConstPtr<u8>> = Ptr::from_bits(selrefs.addr);
+        (0..(selrefs.size / 4))
+            .map(|i| mem.cstr_at_utf8(mem.read(base + i)).unwrap())
+            .collect()
+    }
 }
 
 /// Standard Objective-C runtime function for selector registration.
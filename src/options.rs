@@ -5,12 +5,15 @@
  */
 //! Parsing and management of user-configurable options, e.g. for input methods.
 
+use crate::cpu::Arch;
 use crate::gles::GLESImplementation;
+use crate::log::LogLevel;
 use crate::window::DeviceOrientation;
 use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Read};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::num::NonZeroU32;
+use std::path::PathBuf;
 
 pub const OPTIONS_HELP: &str =
     include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/OPTIONS_HELP.txt"));
@@ -27,6 +30,22 @@ pub enum Button {
     X,
     Y,
 }
+impl Button {
+    /// Parse a button name. The names are the same as the variant names.
+    pub fn from_name(name: &str) -> Result<Self, ()> {
+        match name {
+            "DPadLeft" => Ok(Button::DPadLeft),
+            "DPadUp" => Ok(Button::DPadUp),
+            "DPadRight" => Ok(Button::DPadRight),
+            "DPadDown" => Ok(Button::DPadDown),
+            "A" => Ok(Button::A),
+            "B" => Ok(Button::B),
+            "X" => Ok(Button::X),
+            "Y" => Ok(Button::Y),
+            _ => Err(()),
+        }
+    }
+}
 
 /// Struct containing all user-configurable options.
 pub struct Options {
@@ -47,6 +66,18 @@ pub struct Options {
     pub headless: bool,
     pub print_fps: bool,
     pub fps_limit: Option<f64>,
+    pub preferred_arch: Option<Arch>,
+    pub record_input: Option<PathBuf>,
+    pub replay_input: Option<PathBuf>,
+    pub load_state: Option<PathBuf>,
+    pub trace_calls: Option<Vec<String>>,
+    pub trace_calls_file: Option<PathBuf>,
+    pub trace_messages: Option<Vec<String>>,
+    pub log_levels: Vec<(String, LogLevel)>,
+    pub log_file: Option<PathBuf>,
+    pub debug_heap: bool,
+    pub lenient: bool,
+    pub stub_values_file: Option<PathBuf>,
 }
 
 impl Default for Options {
@@ -69,6 +100,18 @@ impl Default for Options {
             headless: false,
             print_fps: false,
             fps_limit: Some(60.0), // Original iPhone is 60Hz and uses v-sync
+            preferred_arch: None,
+            record_input: None,
+            replay_input: None,
+            load_state: None,
+            trace_calls: None,
+            trace_calls_file: None,
+            trace_messages: None,
+            log_levels: Vec::new(),
+            log_file: None,
+            debug_heap: false,
+            lenient: false,
+            stub_values_file: None,
         }
     }
 }
@@ -115,17 +158,8 @@ impl Options {
             let (x, y) = coords
                 .split_once(',')
                 .ok_or_else(|| "--button-to-touch= requires three values".to_string())?;
-            let button = match button {
-                "DPadLeft" => Ok(Button::DPadLeft),
-                "DPadUp" => Ok(Button::DPadUp),
-                "DPadRight" => Ok(Button::DPadRight),
-                "DPadDown" => Ok(Button::DPadDown),
-                "A" => Ok(Button::A),
-                "B" => Ok(Button::B),
-                "X" => Ok(Button::X),
-                "Y" => Ok(Button::Y),
-                _ => Err("Invalid button for --button-to-touch=".to_string()),
-            }?;
+            let button = Button::from_name(button)
+                .map_err(|_| "Invalid button for --button-to-touch=".to_string())?;
             let x: f32 = x
                 .parse()
                 .map_err(|_| "Invalid X co-ordinate for --button-to-touch=".to_string())?;
@@ -182,6 +216,43 @@ impl Options {
                     .ok_or_else(|| "Invalid value for --fps-limit=".to_string())?;
                 self.fps_limit = Some(limit);
             }
+        } else if let Some(value) = arg.strip_prefix("--preferred-arch=") {
+            self.preferred_arch = Some(
+                Arch::from_short_name(value)
+                    .map_err(|_| "Unrecognized --preferred-arch= value".to_string())?,
+            );
+        } else if let Some(value) = arg.strip_prefix("--record-input=") {
+            self.record_input = Some(PathBuf::from(value));
+        } else if let Some(value) = arg.strip_prefix("--replay-input=") {
+            self.replay_input = Some(PathBuf::from(value));
+        } else if let Some(value) = arg.strip_prefix("--load-state=") {
+            self.load_state = Some(PathBuf::from(value));
+        } else if let Some(value) = arg.strip_prefix("--trace-calls=") {
+            self.trace_calls = Some(value.split(',').map(|s| s.to_string()).collect());
+        } else if let Some(value) = arg.strip_prefix("--trace-calls-file=") {
+            self.trace_calls_file = Some(PathBuf::from(value));
+        } else if let Some(value) = arg.strip_prefix("--trace-messages=") {
+            self.trace_messages = Some(value.split(',').map(|s| s.to_string()).collect());
+        } else if let Some(value) = arg.strip_prefix("--log=") {
+            for module in value.split(',') {
+                let (prefix, level) = match module.split_once('=') {
+                    Some((prefix, level)) => (
+                        prefix,
+                        LogLevel::from_name(level)
+                            .map_err(|_| format!("Unrecognized log level {:?}", level))?,
+                    ),
+                    None => (module, LogLevel::Debug),
+                };
+                self.log_levels.push((prefix.to_string(), level));
+            }
+        } else if let Some(value) = arg.strip_prefix("--log-file=") {
+            self.log_file = Some(PathBuf::from(value));
+        } else if arg == "--debug-heap" {
+            self.debug_heap = true;
+        } else if arg == "--lenient" {
+            self.lenient = true;
+        } else if let Some(value) = arg.strip_prefix("--stub-values-file=") {
+            self.stub_values_file = Some(PathBuf::from(value));
         } else {
             return Ok(false);
         };
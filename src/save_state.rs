@@ -0,0 +1,186 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Save state file format (see `monitor save-state` and `--load-state=`).
+//!
+//! Save states are currently only for programs that use nothing but simple
+//! parts of libc, which rules out any real app. See the TODO on
+//! [crate::Environment] for what full save states still need.
+//!
+//! A save state is a flat sequence of little-endian values, written and read
+//! back in the same order by each part of the emulator that has state to save
+//! (see [crate::Environment::save_state]). There are no field names or lengths
+//! for the parts, so the format version must be bumped whenever anything about
+//! what's written changes.
+//!
+//! Only state that can be recreated exactly from plain data is saved: guest
+//! memory, CPU and thread state, and the host-side bookkeeping for some simple
+//! parts of libc. A save state is loaded into a freshly started environment for
+//! the same app, so anything set up while linking at startup (e.g. Objective-C
+//! classes) is already as it was. Everything else, like Objective-C objects,
+//! threading primitives and framework state holding OpenGL contexts or audio
+//! sources, is only changed by host functions that aren't allowed when saving
+//! (see [crate::dyld::Dyld::save_state]), so saving refuses if the app has
+//! used any of them.
+
+const MAGIC: &[u8] = b"touchHLE save state v1\n";
+
+/// Accumulates the contents of a save state file.
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer {
+            bytes: MAGIC.to_vec(),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8)
+    }
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value)
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes())
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes())
+    }
+    pub fn option_u32(&mut self, value: Option<u32>) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            self.u32(value);
+        }
+    }
+    /// Write a length-prefixed byte string.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len().try_into().unwrap());
+        self.bytes.extend_from_slice(bytes)
+    }
+    pub fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes())
+    }
+}
+
+/// Reads back what was written by a [Writer]. Every method returns [Err] if
+/// the file ends too soon.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Reader<'a>, String> {
+        let bytes = bytes
+            .strip_prefix(MAGIC)
+            .ok_or("Not a save state file, or one from a different version of touchHLE")?;
+        Ok(Reader { bytes })
+    }
+
+    /// Check that everything has been read.
+    pub fn finish(self) -> Result<(), String> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err("Save state file has unexpected data at the end".to_string())
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let (value, rest) = self
+            .bytes
+            .split_first_chunk()
+            .ok_or("Save state file is truncated")?;
+        self.bytes = rest;
+        Ok(*value)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err("Save state file is corrupt".to_string()),
+        }
+    }
+    pub fn u8(&mut self) -> Result<u8, String> {
+        self.take::<1>().map(|[value]| value)
+    }
+    pub fn u32(&mut self) -> Result<u32, String> {
+        self.take().map(u32::from_le_bytes)
+    }
+    pub fn u64(&mut self) -> Result<u64, String> {
+        self.take().map(u64::from_le_bytes)
+    }
+    pub fn option_u32(&mut self) -> Result<Option<u32>, String> {
+        Ok(if self.bool()? {
+            Some(self.u32()?)
+        } else {
+            None
+        })
+    }
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        if self.bytes.len() < len {
+            return Err("Save state file is truncated".to_string());
+        }
+        let (value, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(value)
+    }
+    pub fn str(&mut self) -> Result<&'a str, String> {
+        std::str::from_utf8(self.bytes()?).map_err(|_| "Save state file is corrupt".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = Writer::new();
+        w.bool(true);
+        w.u8(0xab);
+        w.u32(0xdeadbeef);
+        w.u64(u64::MAX - 1);
+        w.option_u32(None);
+        w.option_u32(Some(7));
+        w.bytes(&[1, 2, 3]);
+        w.str("héllo");
+        let bytes = w.into_bytes();
+
+        let mut r = Reader::new(&bytes).unwrap();
+        assert!(r.bool().unwrap());
+        assert_eq!(r.u8().unwrap(), 0xab);
+        assert_eq!(r.u32().unwrap(), 0xdeadbeef);
+        assert_eq!(r.u64().unwrap(), u64::MAX - 1);
+        assert_eq!(r.option_u32().unwrap(), None);
+        assert_eq!(r.option_u32().unwrap(), Some(7));
+        assert_eq!(r.bytes().unwrap(), &[1, 2, 3]);
+        assert_eq!(r.str().unwrap(), "héllo");
+        r.finish().unwrap();
+
+        // Truncation and trailing data are detected
+        let mut r = Reader::new(&bytes[..bytes.len() - 1]).unwrap();
+        r.bool().unwrap();
+        r.u8().unwrap();
+        r.u32().unwrap();
+        r.u64().unwrap();
+        r.option_u32().unwrap();
+        r.option_u32().unwrap();
+        r.bytes().unwrap();
+        assert!(r.str().is_err());
+        assert!(Reader::new(b"not a save state").is_err());
+        let mut r = Reader::new(&bytes).unwrap();
+        r.bool().unwrap();
+        assert!(r.finish().is_err());
+    }
+}
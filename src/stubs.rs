@@ -0,0 +1,112 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Stubs for functions and methods touchHLE doesn't implement (`--lenient` and
+//! `--stub-values-file=`).
+//!
+//! Normally, calling something missing is a panic, which is the most useful
+//! behaviour when implementing it. When trying to find out how far an app can
+//! get, it's more useful to carry on and see what goes wrong first. Stubs
+//! return a fixed value (0, unless the stub values file says otherwise) and
+//! have no other effect.
+
+use crate::options::Options;
+use crate::Environment;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+pub struct Stubs {
+    /// If [true], everything missing is stubbed, otherwise only things with a
+    /// value in the stub values file are.
+    lenient: bool,
+    return_values: HashMap<String, u32>,
+    /// Used to only log each stub once.
+    used: HashSet<String>,
+}
+
+/// Parse a return value: decimal (possibly negative) or hexadecimal with `0x`.
+fn parse_value(value: &str) -> Option<u32> {
+    if let Some(hex) = value.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if value.starts_with('-') {
+        value.parse::<i32>().ok().map(|value| value as u32)
+    } else {
+        value.parse().ok()
+    }
+}
+
+impl Stubs {
+    /// Set up stubs, if the options ask for them.
+    pub fn from_options(options: &Options) -> Result<Option<Stubs>, String> {
+        if !options.lenient && options.stub_values_file.is_none() {
+            return Ok(None);
+        }
+
+        let mut return_values = HashMap::new();
+        if let Some(ref path) = options.stub_values_file {
+            let file = File::open(path)
+                .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+            for (line_no, line) in BufReader::new(file).lines().enumerate() {
+                // Line numbering usually starts from 1
+                let line_no = line_no + 1;
+                let line = line.map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                // Selectors can't contain '=', but they can contain spaces
+                // (between the class and selector), so split on the last one.
+                let value = line
+                    .rsplit_once('=')
+                    .and_then(|(name, value)| Some((name.trim(), parse_value(value.trim())?)));
+                let Some((name, value)) = value else {
+                    return Err(format!(
+                        "Line {} of {} should be a symbol or method, '=' and a number",
+                        line_no,
+                        path.display()
+                    ));
+                };
+                return_values.insert(name.to_string(), value);
+            }
+        }
+
+        Ok(Some(Stubs {
+            lenient: options.lenient,
+            return_values,
+            used: HashSet::new(),
+        }))
+    }
+
+    /// Get the value a stub for something missing should return, or [None] if
+    /// it shouldn't be stubbed. `name` is a mangled symbol name for functions,
+    /// or `-[Class selector]`/`+[Class selector]` for methods.
+    pub fn return_value(&mut self, name: &str) -> Option<u32> {
+        let value = match self.return_values.get(name) {
+            Some(&value) => value,
+            None if self.lenient => 0,
+            None => return None,
+        };
+        if !self.used.contains(name) {
+            log!(
+                "Warning: {} is unimplemented, using a stub that returns {}",
+                name,
+                value
+            );
+            self.used.insert(name.to_string());
+        }
+        Some(value)
+    }
+}
+
+/// Report an error that should raise an Objective-C exception, which touchHLE
+/// can't do yet. Normally this panics, but with `--lenient` it's only logged and
+/// the caller carries on with a nil or zero result.
+pub fn exception_not_raised(env: &Environment, message: &str) {
+    if !env.stubs.as_ref().is_some_and(|stubs| stubs.lenient) {
+        panic!("{}", message);
+    }
+    log!("Warning: {} (exception not raised, ignoring)", message);
+}
@@ -77,7 +77,7 @@ pub enum FingerId {
 }
 pub type Coords = (f32, f32);
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Event {
     /// User requested quit.
     Quit,
@@ -166,6 +166,27 @@ impl Window {
         launch_image: Option<Image>,
         options: &Options,
     ) -> Window {
+        Self::new_inner(title, icon, launch_image, options, false)
+    }
+
+    /// Create an invisible window, so that OpenGL ES can be used in headless
+    /// mode. SDL's offscreen video driver is used so that no display is needed,
+    /// unless the `SDL_VIDEODRIVER` environment variable says otherwise.
+    pub fn new_hidden(options: &Options) -> Window {
+        Self::new_inner("touchHLE (headless)", None, None, options, true)
+    }
+
+    fn new_inner(
+        title: &str,
+        icon: Option<Image>,
+        launch_image: Option<Image>,
+        options: &Options,
+        hidden: bool,
+    ) -> Window {
+        if hidden {
+            // Environment variables take precedence over hints.
+            sdl2::hint::set("SDL_VIDEODRIVER", "offscreen");
+        }
         let sdl_ctx = sdl2::init().unwrap();
         let video_ctx = sdl_ctx.video().unwrap();
 
@@ -199,9 +220,18 @@ impl Window {
         // TODO: some apps specify their orientation in Info.plist, we could use
         // that here.
         let device_orientation = options.initial_orientation;
-        let fullscreen = options.fullscreen;
+        let fullscreen = options.fullscreen && !hidden;
 
-        let mut window = if Self::rotatable_fullscreen() {
+        let mut window = if hidden {
+            let (width, height) = size_for_orientation(device_orientation, scale_hack);
+            let window = video_ctx
+                .window(title, width, height)
+                .hidden()
+                .opengl()
+                .build()
+                .unwrap();
+            window
+        } else if Self::rotatable_fullscreen() {
             // Without this, SDL will force fullscreen mode to be portrait.
             set_sdl2_orientation(device_orientation);
             let screen_size = video_ctx.display_bounds(0).unwrap().size();
@@ -0,0 +1,482 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Tests related to the Objective-C runtime.
+// We can't compile Objective-C (see tests/README.md), so these use the C API
+// of the runtime and send messages with objc_msgSend() directly. Classes and
+// protocols defined by the app are written out by hand below, in the form the
+// compiler would emit them.
+
+// === Declarations ===
+
+// <objc/objc.h>
+typedef struct objc_object *id;
+typedef struct objc_class *Class;
+typedef struct objc_selector *SEL;
+typedef id (*IMP)(id, SEL, ...);
+typedef signed char BOOL;
+#define nil ((id)0)
+
+// <objc/message.h>
+id objc_msgSend(id, SEL, ...);
+void objc_msgSend_stret(void *, id, SEL, ...);
+
+// <objc/runtime.h>
+typedef struct objc_method *Method;
+typedef struct objc_object Protocol;
+Class objc_getClass(const char *);
+Class object_getClass(id);
+SEL sel_registerName(const char *);
+BOOL class_addMethod(Class, SEL, IMP, const char *);
+Method class_getInstanceMethod(Class, SEL);
+SEL method_getName(Method);
+IMP method_getImplementation(Method);
+IMP method_setImplementation(Method, IMP);
+void method_exchangeImplementations(Method, Method);
+Protocol *objc_getProtocol(const char *);
+const char *protocol_getName(Protocol *);
+BOOL protocol_conformsToProtocol(Protocol *, Protocol *);
+BOOL class_conformsToProtocol(Class, Protocol *);
+
+// Cast objc_msgSend() to the type of a particular message, e.g.
+// MSG(int, id)(receiver, selector, argument). Calling it through its variadic
+// declaration would get the calling convention wrong.
+#define MSG(ret, ...) ((ret(*)(id, SEL, ##__VA_ARGS__))objc_msgSend)
+#define MSG_STRET(ret, ...)                                                    \
+  ((void (*)(ret *, id, SEL, ##__VA_ARGS__))objc_msgSend_stret)
+
+// === Objective-C metadata ===
+
+// Layouts of the runtime's data structures for 32-bit ARM.
+
+struct method_t {
+  const char *name;
+  const char *types;
+  void *imp;
+};
+
+struct protocol_t {
+  id isa;
+  const char *name;
+  const void *protocols;
+  const void *instance_methods;
+  const void *class_methods;
+  const void *optional_instance_methods;
+  const void *optional_class_methods;
+  const void *instance_properties;
+  unsigned size;
+  unsigned flags;
+};
+
+struct class_ro_t {
+  unsigned flags;
+  unsigned instance_start;
+  unsigned instance_size;
+  const char *ivar_layout;
+  const char *name;
+  const void *base_methods;
+  const void *base_protocols;
+  const void *ivars;
+  const char *weak_ivar_layout;
+  const void *base_properties;
+};
+#define RO_META 0x1
+
+struct class_t {
+  struct class_t *isa;
+  struct class_t *superclass;
+  const void *cache;
+  const void *vtable;
+  const struct class_ro_t *data;
+};
+
+extern struct class_t OBJC_CLASS_$_NSObject;
+extern struct class_t OBJC_METACLASS_$_NSObject;
+
+// @protocol TestBaseProtocol
+// @end
+struct protocol_t TestBaseProtocol_protocol = {
+    .name = "TestBaseProtocol",
+    .size = sizeof(struct protocol_t),
+};
+
+// @protocol TestProtocol <TestBaseProtocol>
+// @end
+struct {
+  unsigned count;
+  struct protocol_t *list[2];
+} TestProtocol_protocols = {1, {&TestBaseProtocol_protocol, 0}};
+struct protocol_t TestProtocol_protocol = {
+    .name = "TestProtocol",
+    .protocols = &TestProtocol_protocols,
+    .size = sizeof(struct protocol_t),
+};
+
+__attribute__((used, section("__DATA,__objc_protolist"))) struct protocol_t
+    *protocol_list[] = {&TestBaseProtocol_protocol, &TestProtocol_protocol};
+
+// @interface TestClass : NSObject <TestProtocol>
+// @end
+struct {
+  unsigned count;
+  struct protocol_t *list[2];
+} TestClass_protocols = {1, {&TestProtocol_protocol, 0}};
+struct class_ro_t TestClass_metaclass_ro = {
+    .flags = RO_META,
+    .instance_start = sizeof(struct class_t),
+    .instance_size = sizeof(struct class_t),
+    .name = "TestClass",
+};
+struct class_t TestClass_metaclass = {
+    .isa = &OBJC_METACLASS_$_NSObject,
+    .superclass = &OBJC_METACLASS_$_NSObject,
+    .data = &TestClass_metaclass_ro,
+};
+struct class_ro_t TestClass_ro = {
+    .instance_start = sizeof(id),
+    .instance_size = sizeof(id),
+    .name = "TestClass",
+    .base_protocols = &TestClass_protocols,
+};
+struct class_t TestClass_class = {
+    .isa = &TestClass_metaclass,
+    .superclass = &OBJC_CLASS_$_NSObject,
+    .data = &TestClass_ro,
+};
+
+// @interface TestForwarder : NSObject
+// @end
+// (The methods are defined in the tests below.)
+int TestForwarder_sum(id, SEL, int, int, int, int, int);
+BOOL TestForwarder_resolveInstanceMethod(Class, SEL, SEL);
+id TestForwarder_forwardingTargetForSelector(id, SEL, SEL);
+id TestForwarder_methodSignatureForSelector(id, SEL, SEL);
+void TestForwarder_forwardInvocation(id, SEL, id);
+struct {
+  unsigned entsize;
+  unsigned count;
+  struct method_t list[1];
+} TestForwarder_class_methods = {
+    sizeof(struct method_t),
+    1,
+    {{"resolveInstanceMethod:", "c8@0:4:8",
+      (void *)TestForwarder_resolveInstanceMethod}},
+};
+struct {
+  unsigned entsize;
+  unsigned count;
+  struct method_t list[4];
+} TestForwarder_instance_methods = {
+    sizeof(struct method_t),
+    4,
+    {
+        {"sumOf:and:and:and:and:", "i28@0:4i8i12i16i20i24",
+         (void *)TestForwarder_sum},
+        {"forwardingTargetForSelector:", "@12@0:4:8",
+         (void *)TestForwarder_forwardingTargetForSelector},
+        {"methodSignatureForSelector:", "@12@0:4:8",
+         (void *)TestForwarder_methodSignatureForSelector},
+        {"forwardInvocation:", "v12@0:4@8",
+         (void *)TestForwarder_forwardInvocation},
+    },
+};
+struct class_ro_t TestForwarder_metaclass_ro = {
+    .flags = RO_META,
+    .instance_start = sizeof(struct class_t),
+    .instance_size = sizeof(struct class_t),
+    .name = "TestForwarder",
+    .base_methods = &TestForwarder_class_methods,
+};
+struct class_t TestForwarder_metaclass = {
+    .isa = &OBJC_METACLASS_$_NSObject,
+    .superclass = &OBJC_METACLASS_$_NSObject,
+    .data = &TestForwarder_metaclass_ro,
+};
+struct class_ro_t TestForwarder_ro = {
+    .instance_start = sizeof(id),
+    .instance_size = sizeof(id),
+    .name = "TestForwarder",
+    .base_methods = &TestForwarder_instance_methods,
+};
+struct class_t TestForwarder_class = {
+    .isa = &TestForwarder_metaclass,
+    .superclass = &OBJC_CLASS_$_NSObject,
+    .data = &TestForwarder_ro,
+};
+
+__attribute__((used, section("__DATA,__objc_classlist"))) struct class_t
+    *class_list[] = {&TestClass_class, &TestForwarder_class};
+
+// === Tests ===
+
+unsigned answer_imp(id self, SEL _cmd) { return 42; }
+
+int test_objc_method_swizzling() {
+  Class NSObject = objc_getClass("NSObject");
+  id object = MSG(id)((id)NSObject, sel_registerName("new"));
+
+  // Add a guest method to a host class.
+  SEL hash = sel_registerName("hash");
+  SEL answer = sel_registerName("touchHLETestAnswer");
+  if (!class_addMethod(NSObject, answer, (IMP)answer_imp, "I@:"))
+    return 1;
+  if (class_addMethod(NSObject, answer, (IMP)answer_imp, "I@:"))
+    return 2;
+
+  Method hash_method = class_getInstanceMethod(NSObject, hash);
+  Method answer_method = class_getInstanceMethod(NSObject, answer);
+  if (hash_method == 0 || answer_method == 0)
+    return 3;
+  if (method_getName(hash_method) != hash ||
+      method_getName(answer_method) != answer)
+    return 4;
+  IMP host_imp = method_getImplementation(hash_method);
+  if (host_imp == 0 ||
+      method_getImplementation(answer_method) != (IMP)answer_imp)
+    return 5;
+  if (MSG(unsigned)(object, hash) != (unsigned)object ||
+      MSG(unsigned)(object, answer) != 42)
+    return 6;
+
+  // Swap the host implementation with the guest one.
+  method_exchangeImplementations(hash_method, answer_method);
+  if (MSG(unsigned)(object, hash) != 42 ||
+      MSG(unsigned)(object, answer) != (unsigned)object)
+    return 7;
+  if (method_getImplementation(hash_method) != (IMP)answer_imp ||
+      method_getImplementation(answer_method) != host_imp)
+    return 8;
+  // The host implementation can still be called directly.
+  if (((unsigned (*)(id, SEL))host_imp)(object, hash) != (unsigned)object)
+    return 9;
+  method_exchangeImplementations(hash_method, answer_method);
+  if (MSG(unsigned)(object, hash) != (unsigned)object ||
+      MSG(unsigned)(object, answer) != 42)
+    return 10;
+
+  // Replace the guest implementation with the host one and back.
+  if (method_setImplementation(answer_method, host_imp) !=
+      (IMP)answer_imp)
+    return 11;
+  if (MSG(unsigned)(object, answer) != (unsigned)object)
+    return 12;
+  if (method_setImplementation(answer_method, (IMP)answer_imp) !=
+      host_imp)
+    return 13;
+  if (MSG(unsigned)(object, answer) != 42)
+    return 14;
+
+  // NULL methods are ignored.
+  if (method_getName(0) != 0 || method_getImplementation(0) != 0)
+    return 15;
+  method_exchangeImplementations(hash_method, 0);
+  if (MSG(unsigned)(object, hash) != (unsigned)object)
+    return 16;
+
+  MSG(void)(object, sel_registerName("release"));
+  return 0;
+}
+
+int test_objc_protocols() {
+  Protocol *base_protocol = objc_getProtocol("TestBaseProtocol");
+  Protocol *protocol = objc_getProtocol("TestProtocol");
+  if (base_protocol != (Protocol *)&TestBaseProtocol_protocol ||
+      protocol != (Protocol *)&TestProtocol_protocol)
+    return 1;
+  if (protocol_getName(protocol) != TestProtocol_protocol.name)
+    return 2;
+  if (objc_getProtocol("NotARealProtocol") != 0)
+    return 3;
+
+  // Incorporated protocols
+  if (!protocol_conformsToProtocol(protocol, base_protocol) ||
+      !protocol_conformsToProtocol(protocol, protocol) ||
+      protocol_conformsToProtocol(base_protocol, protocol))
+    return 4;
+
+  // Host classes
+  SEL conforms = sel_registerName("conformsToProtocol:");
+  Class NSObject = objc_getClass("NSObject");
+  Class NSString = objc_getClass("NSString");
+  Protocol *NSObject_protocol = objc_getProtocol("NSObject");
+  Protocol *NSCopying_protocol = objc_getProtocol("NSCopying");
+  if (NSObject_protocol == 0 || NSCopying_protocol == 0)
+    return 5;
+  if (!class_conformsToProtocol(NSString, NSCopying_protocol) ||
+      class_conformsToProtocol(NSObject, NSCopying_protocol) ||
+      class_conformsToProtocol(NSString, protocol))
+    return 6;
+  // class_conformsToProtocol() ignores superclasses, conformsToProtocol:
+  // doesn't.
+  if (class_conformsToProtocol(NSString, NSObject_protocol) ||
+      !MSG(BOOL, id)((id)NSString, conforms, NSObject_protocol) ||
+      MSG(BOOL, id)((id)NSObject, conforms, NSCopying_protocol))
+    return 7;
+
+  // Guest classes
+  Class TestClass = objc_getClass("TestClass");
+  if (TestClass != (Class)&TestClass_class)
+    return 8;
+  if (!class_conformsToProtocol(TestClass, protocol) ||
+      !class_conformsToProtocol(TestClass, base_protocol) ||
+      class_conformsToProtocol(TestClass, NSObject_protocol))
+    return 9;
+  id object = MSG(id)((id)TestClass, sel_registerName("new"));
+  if (!MSG(BOOL, id)(object, conforms, base_protocol) ||
+      !MSG(BOOL, id)(object, conforms, NSObject_protocol) ||
+      MSG(BOOL, id)(object, conforms, NSCopying_protocol))
+    return 10;
+  MSG(void)(object, sel_registerName("release"));
+
+  if (class_conformsToProtocol(0, protocol))
+    return 11;
+
+  return 0;
+}
+
+struct TestRect {
+  int x, y, width, height;
+};
+
+id forwarding_target;
+
+int TestForwarder_sum(id self, SEL _cmd, int a, int b, int c, int d, int e) {
+  return a + b + c + d + e;
+}
+
+int TestForwarder_resolved(id self, SEL _cmd) { return 7; }
+
+BOOL TestForwarder_resolveInstanceMethod(Class self, SEL _cmd, SEL sel) {
+  if (sel == sel_registerName("resolvedMethod"))
+    return class_addMethod(self, sel, (IMP)TestForwarder_resolved, "i8@0:4");
+  return 0;
+}
+
+id TestForwarder_forwardingTargetForSelector(id self, SEL _cmd, SEL sel) {
+  if (sel == sel_registerName("length"))
+    return forwarding_target;
+  return nil;
+}
+
+id TestForwarder_methodSignatureForSelector(id self, SEL _cmd, SEL sel) {
+  const char *types;
+  if (sel == sel_registerName("forwardedSumOf:and:and:and:and:"))
+    types = "i@:iiiii";
+  else if (sel == sel_registerName("rectAt:"))
+    types = "{TestRect=iiii}@:i";
+  else
+    return MSG(id, SEL)((id)object_getClass(self),
+                        sel_registerName("instanceMethodSignatureForSelector:"),
+                        sel);
+  return MSG(id, const char *)((id)objc_getClass("NSMethodSignature"),
+                               sel_registerName("signatureWithObjCTypes:"),
+                               types);
+}
+
+void TestForwarder_forwardInvocation(id self, SEL _cmd, id invocation) {
+  SEL sel = MSG(SEL)(invocation, sel_registerName("selector"));
+  if (sel == sel_registerName("forwardedSumOf:and:and:and:and:")) {
+    // Pass the message on to the real method.
+    MSG(void, SEL)
+    (invocation, sel_registerName("setSelector:"),
+     sel_registerName("sumOf:and:and:and:and:"));
+    MSG(void)(invocation, sel_registerName("invoke"));
+  } else if (sel == sel_registerName("rectAt:")) {
+    int x;
+    MSG(void, void *, int)
+    (invocation, sel_registerName("getArgument:atIndex:"), &x, 2);
+    struct TestRect rect = {x, x + 1, x + 2, x + 3};
+    MSG(void, void *)(invocation, sel_registerName("setReturnValue:"), &rect);
+  }
+}
+
+int test_objc_message_forwarding() {
+  id pool = MSG(id)((id)objc_getClass("NSAutoreleasePool"),
+                    sel_registerName("new"));
+  forwarding_target =
+      MSG(id, const char *)((id)objc_getClass("NSString"),
+                            sel_registerName("stringWithUTF8String:"), "abc");
+  id forwarder =
+      MSG(id)((id)objc_getClass("TestForwarder"), sel_registerName("new"));
+
+  // +resolveInstanceMethod:
+  if (MSG(int)(forwarder, sel_registerName("resolvedMethod")) != 7)
+    return 1;
+
+  // -forwardingTargetForSelector:
+  if (MSG(unsigned)(forwarder, sel_registerName("length")) != 3)
+    return 2;
+
+  // -forwardInvocation:, with arguments on the stack
+  if (MSG(int, int, int, int, int, int)(
+          forwarder, sel_registerName("forwardedSumOf:and:and:and:and:"), 1,
+          2, 3, 4, 5) != 15)
+    return 3;
+
+  // -forwardInvocation:, with a struct return value
+  struct TestRect rect;
+  MSG_STRET(struct TestRect, int)
+  (&rect, forwarder, sel_registerName("rectAt:"), 10);
+  if (rect.x != 10 || rect.y != 11 || rect.width != 12 || rect.height != 13)
+    return 4;
+
+  // -methodSignatureForSelector: for a host method
+  id signature =
+      MSG(id, SEL)(forwarder, sel_registerName("methodSignatureForSelector:"),
+                   sel_registerName("hash"));
+  if (signature == nil)
+    return 5;
+  const char *return_type =
+      MSG(const char *)(signature, sel_registerName("methodReturnType"));
+  if (MSG(unsigned)(signature, sel_registerName("numberOfArguments")) != 2 ||
+      return_type[0] != 'I' || return_type[1] != '\0')
+    return 6;
+
+  // -[NSInvocation invoke] for a guest method
+  SEL sum = sel_registerName("sumOf:and:and:and:and:");
+  signature = MSG(id, SEL)(
+      forwarder, sel_registerName("methodSignatureForSelector:"), sum);
+  if (signature == nil)
+    return 7;
+  id invocation = MSG(id, id)((id)objc_getClass("NSInvocation"),
+                              sel_registerName("invocationWithMethodSignature:"),
+                              signature);
+  MSG(void, id)(invocation, sel_registerName("setTarget:"), forwarder);
+  MSG(void, SEL)(invocation, sel_registerName("setSelector:"), sum);
+  int i;
+  for (i = 2; i < 7; i++) {
+    int argument = i * 10;
+    MSG(void, void *, int)
+    (invocation, sel_registerName("setArgument:atIndex:"), &argument, i);
+  }
+  MSG(void)(invocation, sel_registerName("invoke"));
+  int sum_result;
+  MSG(void, void *)
+  (invocation, sel_registerName("getReturnValue:"), &sum_result);
+  if (sum_result != 200)
+    return 8;
+
+  // -[NSInvocation invoke] for a host method
+  signature = MSG(id, SEL)(
+      (id)objc_getClass("NSString"),
+      sel_registerName("instanceMethodSignatureForSelector:"),
+      sel_registerName("length"));
+  invocation = MSG(id, id)((id)objc_getClass("NSInvocation"),
+                           sel_registerName("invocationWithMethodSignature:"),
+                           signature);
+  MSG(void, id)(invocation, sel_registerName("setTarget:"), forwarding_target);
+  MSG(void, SEL)
+  (invocation, sel_registerName("setSelector:"), sel_registerName("length"));
+  MSG(void)(invocation, sel_registerName("invoke"));
+  unsigned length;
+  MSG(void, void *)(invocation, sel_registerName("getReturnValue:"), &length);
+  if (length != 3)
+    return 9;
+
+  MSG(void)(forwarder, sel_registerName("release"));
+  MSG(void)(pool, sel_registerName("release"));
+  return 0;
+}
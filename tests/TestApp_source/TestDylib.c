@@ -0,0 +1,31 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// A small dylib that is put in the TestApp bundle, so that test_dlfcn() in
+// main.c can load it with dlopen() and look up its symbols. See also
+// tests/integration.rs for how it is compiled.
+
+// <dlfcn.h>
+#define RTLD_NEXT ((void *)-1)
+#define RTLD_SELF ((void *)-3)
+void *dlsym(void *, const char *);
+
+int dylib_value = 42;
+
+int dylib_add(int a, int b) { return a + b; }
+
+// The app has a function with the same name, returning 1 instead.
+int dylib_shadowed(void) { return 2; }
+
+// The app is loaded before this dylib, so this shouldn't find anything.
+void *dylib_find_next_shadowed(void) {
+  return dlsym(RTLD_NEXT, "dylib_shadowed");
+}
+
+// This should find the dylib's own function.
+void *dylib_find_self_shadowed(void) {
+  return dlsym(RTLD_SELF, "dylib_shadowed");
+}
@@ -13,6 +13,7 @@
 // For convenience, let's just include the other source files.
 
 #include "CGAffineTransform.c"
+#include "ObjCRuntime.c"
 
 // === Declarations ===
 
@@ -23,9 +24,22 @@
 #define NULL ((void *)0)
 typedef unsigned long size_t;
 
+// <dlfcn.h>
+#define RTLD_LAZY 0x1
+#define RTLD_NOLOAD 0x10
+#define RTLD_NEXT ((void *)-1)
+#define RTLD_DEFAULT ((void *)-2)
+int dlclose(void *);
+char *dlerror(void);
+void *dlopen(const char *, int);
+void *dlsym(void *, const char *);
+
 // <errno.h>
 int *__error(void);
 #define errno (*__error())
+#define EBUSY 16
+#define EDEADLK 11
+#define ETIMEDOUT 60
 
 // <stdarg.h>
 typedef __builtin_va_list va_list;
@@ -65,6 +79,21 @@ int chdir(const char *);
 char *getcwd(char *, size_t);
 int usleep(useconds_t);
 
+// <sys/time.h>
+typedef int time_t;
+typedef int suseconds_t;
+struct timeval {
+  time_t tv_sec;
+  suseconds_t tv_usec;
+};
+int gettimeofday(struct timeval *, void *);
+
+// <time.h>
+struct timespec {
+  time_t tv_sec;
+  long tv_nsec;
+};
+
 // <fcntl.h>
 #define O_CREAT 0x00000200
 
@@ -77,6 +106,53 @@ typedef struct opaque_pthread_attr_t *__pthread_attr_t;
 typedef __pthread_attr_t pthread_attr_t;
 int pthread_create(pthread_t *, const pthread_attr_t *, void *(*)(void *),
                    void *);
+int pthread_join(pthread_t, void **);
+int pthread_detach(pthread_t);
+void pthread_exit(void *);
+int pthread_equal(pthread_t, pthread_t);
+pthread_t pthread_self(void);
+int pthread_attr_init(pthread_attr_t *);
+int pthread_attr_setstacksize(pthread_attr_t *, size_t);
+int pthread_attr_getstacksize(const pthread_attr_t *, size_t *);
+int pthread_attr_destroy(pthread_attr_t *);
+struct sched_param {
+  int sched_priority;
+  char __opaque[4];
+};
+int pthread_getschedparam(pthread_t, int *, struct sched_param *);
+int pthread_setschedparam(pthread_t, int, const struct sched_param *);
+typedef struct {
+  long __sig;
+  char __opaque[40];
+} pthread_mutex_t;
+int pthread_mutex_init(pthread_mutex_t *, const void *);
+int pthread_mutex_lock(pthread_mutex_t *);
+int pthread_mutex_trylock(pthread_mutex_t *);
+int pthread_mutex_unlock(pthread_mutex_t *);
+int pthread_mutex_destroy(pthread_mutex_t *);
+typedef struct {
+  long __sig;
+  char __opaque[24];
+} pthread_cond_t;
+#define PTHREAD_COND_INITIALIZER {0x3CB0B1BB, {0}}
+int pthread_cond_init(pthread_cond_t *, const void *);
+int pthread_cond_wait(pthread_cond_t *, pthread_mutex_t *);
+int pthread_cond_timedwait(pthread_cond_t *, pthread_mutex_t *,
+                           const struct timespec *);
+int pthread_cond_signal(pthread_cond_t *);
+int pthread_cond_broadcast(pthread_cond_t *);
+int pthread_cond_destroy(pthread_cond_t *);
+typedef struct {
+  long __sig;
+  char __opaque[124];
+} pthread_rwlock_t;
+#define PTHREAD_RWLOCK_INITIALIZER {0x2DA8B3B4, {0}}
+int pthread_rwlock_rdlock(pthread_rwlock_t *);
+int pthread_rwlock_tryrdlock(pthread_rwlock_t *);
+int pthread_rwlock_wrlock(pthread_rwlock_t *);
+int pthread_rwlock_trywrlock(pthread_rwlock_t *);
+int pthread_rwlock_unlock(pthread_rwlock_t *);
+int pthread_rwlock_destroy(pthread_rwlock_t *);
 
 // <semaphore.h>
 #define SEM_FAILED ((sem_t *)-1)
@@ -275,6 +351,263 @@ int test_sem() {
   return shared_int == 1 ? 0 : -1;
 }
 
+pthread_mutex_t cond_mutex;
+pthread_cond_t cond_var = PTHREAD_COND_INITIALIZER;
+int cond_ready = 0;
+int cond_woken = 0;
+
+void *cond_signal_thread_func(void *arg) {
+  pthread_mutex_lock(&cond_mutex);
+  cond_ready = 1;
+  pthread_cond_signal(&cond_var);
+  pthread_mutex_unlock(&cond_mutex);
+  return NULL;
+}
+
+void *cond_wait_thread_func(void *arg) {
+  pthread_mutex_lock(&cond_mutex);
+  while (!cond_ready) {
+    if (pthread_cond_wait(&cond_var, &cond_mutex))
+      break;
+  }
+  cond_woken++;
+  pthread_mutex_unlock(&cond_mutex);
+  return NULL;
+}
+
+int test_pthread_cond() {
+  pthread_t threads[2];
+
+  if (pthread_mutex_init(&cond_mutex, NULL))
+    return 1;
+
+  // Signal: this thread waits, another thread signals.
+  pthread_mutex_lock(&cond_mutex);
+  pthread_create(&threads[0], NULL, cond_signal_thread_func, NULL);
+  while (!cond_ready) {
+    if (pthread_cond_wait(&cond_var, &cond_mutex))
+      return 2;
+  }
+  // The mutex must have been reacquired.
+  if (pthread_mutex_unlock(&cond_mutex))
+    return 3;
+  pthread_join(threads[0], NULL);
+
+  // Broadcast: several threads wait, this thread wakes all of them.
+  cond_ready = 0;
+  pthread_create(&threads[0], NULL, cond_wait_thread_func, NULL);
+  pthread_create(&threads[1], NULL, cond_wait_thread_func, NULL);
+  usleep(1000);
+  pthread_mutex_lock(&cond_mutex);
+  cond_ready = 1;
+  pthread_cond_broadcast(&cond_var);
+  pthread_mutex_unlock(&cond_mutex);
+  pthread_join(threads[0], NULL);
+  pthread_join(threads[1], NULL);
+  if (cond_woken != 2)
+    return 4;
+
+  // Timed wait: nobody signals, so it should time out.
+  struct timeval now;
+  gettimeofday(&now, NULL);
+  struct timespec deadline;
+  deadline.tv_sec = now.tv_sec;
+  deadline.tv_nsec = (now.tv_usec + 10000) * 1000;
+  if (deadline.tv_nsec >= 1000000000) {
+    deadline.tv_sec++;
+    deadline.tv_nsec -= 1000000000;
+  }
+  pthread_mutex_lock(&cond_mutex);
+  if (pthread_cond_timedwait(&cond_var, &cond_mutex, &deadline) != ETIMEDOUT)
+    return 5;
+  if (pthread_mutex_unlock(&cond_mutex))
+    return 6;
+
+  if (pthread_cond_destroy(&cond_var))
+    return 7;
+  if (pthread_mutex_destroy(&cond_mutex))
+    return 8;
+
+  return 0;
+}
+
+pthread_cond_t destroyed_cond_var;
+int destroyed_cond_ready = 0;
+
+void *destroyed_cond_wait_thread_func(void *arg) {
+  pthread_mutex_lock(&cond_mutex);
+  while (!destroyed_cond_ready) {
+    if (pthread_cond_wait(&destroyed_cond_var, &cond_mutex))
+      break;
+  }
+  pthread_mutex_unlock(&cond_mutex);
+  return NULL;
+}
+
+int test_pthread_cond_broadcast_destroy() {
+  pthread_t threads[2];
+
+  if (pthread_mutex_init(&cond_mutex, NULL))
+    return 1;
+  if (pthread_cond_init(&destroyed_cond_var, NULL))
+    return 2;
+
+  pthread_create(&threads[0], NULL, destroyed_cond_wait_thread_func, NULL);
+  pthread_create(&threads[1], NULL, destroyed_cond_wait_thread_func, NULL);
+  usleep(1000);
+  // Destroying the condition variable is allowed once no thread is waiting on
+  // it, even if the woken threads haven't run yet.
+  pthread_mutex_lock(&cond_mutex);
+  destroyed_cond_ready = 1;
+  pthread_cond_broadcast(&destroyed_cond_var);
+  if (pthread_cond_destroy(&destroyed_cond_var))
+    return 3;
+  pthread_mutex_unlock(&cond_mutex);
+  pthread_join(threads[0], NULL);
+  pthread_join(threads[1], NULL);
+
+  if (pthread_mutex_destroy(&cond_mutex))
+    return 4;
+
+  return 0;
+}
+
+pthread_mutex_t trylock_mutex;
+
+void *trylock_thread_func(void *arg) {
+  // The main thread holds the lock.
+  return (void *)pthread_mutex_trylock(&trylock_mutex);
+}
+
+int test_pthread_mutex_trylock() {
+  pthread_t thread;
+  void *result;
+
+  if (pthread_mutex_init(&trylock_mutex, NULL))
+    return 1;
+  if (pthread_mutex_trylock(&trylock_mutex))
+    return 2;
+  pthread_create(&thread, NULL, trylock_thread_func, NULL);
+  pthread_join(thread, &result);
+  if ((int)result != EBUSY)
+    return 3;
+  // A non-recursive mutex can't be try-locked twice by the same thread either.
+  if (pthread_mutex_trylock(&trylock_mutex) != EBUSY)
+    return 4;
+  if (pthread_mutex_unlock(&trylock_mutex))
+    return 5;
+  if (pthread_mutex_trylock(&trylock_mutex))
+    return 6;
+  pthread_mutex_unlock(&trylock_mutex);
+  if (pthread_mutex_destroy(&trylock_mutex))
+    return 7;
+  return 0;
+}
+
+pthread_rwlock_t rwlock = PTHREAD_RWLOCK_INITIALIZER;
+int rwlock_shared = 0;
+
+void *rwlock_thread_func(void *arg) {
+  // The main thread holds a read lock.
+  if (pthread_rwlock_tryrdlock(&rwlock))
+    return (void *)1;
+  pthread_rwlock_unlock(&rwlock);
+  if (pthread_rwlock_trywrlock(&rwlock) != EBUSY)
+    return (void *)2;
+  // This should block until the main thread releases its read lock.
+  if (pthread_rwlock_wrlock(&rwlock))
+    return (void *)3;
+  int seen = rwlock_shared;
+  rwlock_shared = 2;
+  pthread_rwlock_unlock(&rwlock);
+  return seen == 1 ? NULL : (void *)4;
+}
+
+int test_pthread_rwlock() {
+  pthread_t thread;
+  void *result;
+
+  if (pthread_rwlock_rdlock(&rwlock))
+    return 1;
+  // Read locks can be shared, even with the same thread.
+  if (pthread_rwlock_rdlock(&rwlock))
+    return 2;
+  pthread_rwlock_unlock(&rwlock);
+  if (pthread_rwlock_wrlock(&rwlock) != EDEADLK)
+    return 3;
+  pthread_create(&thread, NULL, rwlock_thread_func, NULL);
+  usleep(1000);
+  rwlock_shared = 1;
+  pthread_rwlock_unlock(&rwlock);
+  pthread_join(thread, &result);
+  if (result)
+    return 10 + (int)result;
+  if (rwlock_shared != 2)
+    return 4;
+  if (pthread_rwlock_unlock(&rwlock) == 0)
+    return 5;
+  if (pthread_rwlock_destroy(&rwlock))
+    return 6;
+  return 0;
+}
+
+void exit_thread_helper(void) { pthread_exit((void *)42); }
+
+void *exit_thread_func(void *arg) {
+  exit_thread_helper();
+  // Unreachable
+  return NULL;
+}
+
+void *detached_thread_func(void *arg) { return NULL; }
+
+int test_pthread_misc() {
+  pthread_t thread;
+  pthread_attr_t attr;
+  size_t stack_size;
+  void *result;
+
+  if (!pthread_equal(pthread_self(), pthread_self()))
+    return 1;
+
+  pthread_attr_init(&attr);
+  if (pthread_attr_setstacksize(&attr, 100) == 0)
+    return 2;
+  if (pthread_attr_setstacksize(&attr, 64 * 1024))
+    return 3;
+  pthread_attr_getstacksize(&attr, &stack_size);
+  if (stack_size != 64 * 1024)
+    return 4;
+
+  pthread_create(&thread, &attr, exit_thread_func, NULL);
+  pthread_attr_destroy(&attr);
+  if (pthread_equal(thread, pthread_self()))
+    return 5;
+  pthread_join(thread, &result);
+  if ((int)result != 42)
+    return 6;
+
+  int policy;
+  struct sched_param param;
+  if (pthread_getschedparam(pthread_self(), &policy, &param))
+    return 7;
+  param.sched_priority += 1;
+  if (pthread_setschedparam(pthread_self(), policy, &param))
+    return 8;
+  param.sched_priority = 0;
+  pthread_getschedparam(pthread_self(), &policy, &param);
+  if (param.sched_priority == 0)
+    return 9;
+
+  pthread_create(&thread, NULL, detached_thread_func, NULL);
+  if (pthread_detach(thread))
+    return 10;
+  if (pthread_join(thread, NULL) == 0)
+    return 11;
+
+  return 0;
+}
+
 int test_strncpy() {
   char *src = "test\0abcd";
   char dst[10];
@@ -335,6 +668,113 @@ int test_strncat() {
   return 0;
 }
 
+// TestDylib.c is built into the bundle by integration.rs.
+#define TEST_DYLIB_PATH                                                        \
+  "/var/mobile/Applications/00000000-0000-0000-0000-000000000000/TestApp.app/" \
+  "TestDylib.dylib"
+
+// The dylib has a function with the same name, returning 2 instead.
+int dylib_shadowed(void) { return 1; }
+
+int test_dlfcn_dylib() {
+  // RTLD_NOLOAD mustn't load anything.
+  if (dlopen(TEST_DYLIB_PATH, RTLD_LAZY | RTLD_NOLOAD) != NULL)
+    return 15;
+  if (dlerror() == NULL)
+    return 16;
+
+  void *handle = dlopen(TEST_DYLIB_PATH, RTLD_LAZY);
+  if (handle == NULL)
+    return 17;
+  if (dlopen(TEST_DYLIB_PATH, RTLD_LAZY | RTLD_NOLOAD) != handle)
+    return 18;
+
+  int (*add)(int, int) = dlsym(handle, "dylib_add");
+  if (add == NULL || add(2, 3) != 5)
+    return 19;
+  int *value = dlsym(handle, "dylib_value");
+  if (value == NULL || *value != 42)
+    return 20;
+  // A handle for an image only finds symbols from it and its dependencies.
+  if (dlsym(handle, "strcmp") != NULL)
+    return 21;
+  if (dlerror() == NULL)
+    return 22;
+
+  // The app's definition comes first, and then the dylib's.
+  int (*shadowed)(void) = dlsym(RTLD_DEFAULT, "dylib_shadowed");
+  if (shadowed == NULL || shadowed() != 1)
+    return 23;
+  shadowed = dlsym(RTLD_NEXT, "dylib_shadowed");
+  if (shadowed == NULL || shadowed() != 2)
+    return 24;
+  shadowed = dlsym(handle, "dylib_shadowed");
+  if (shadowed == NULL || shadowed() != 2)
+    return 25;
+
+  // RTLD_NEXT and RTLD_SELF used by the dylib itself.
+  void *(*find_next)(void) = dlsym(handle, "dylib_find_next_shadowed");
+  if (find_next == NULL || find_next() != NULL)
+    return 26;
+  if (dlerror() == NULL)
+    return 27;
+  void *(*find_self)(void) = dlsym(handle, "dylib_find_self_shadowed");
+  if (find_self == NULL || find_self() != (void *)shadowed)
+    return 28;
+
+  if (dlclose(handle) != 0 || dlclose(handle) != 0)
+    return 29;
+
+  return 0;
+}
+
+int test_dlfcn() {
+  void *handle = dlopen("/usr/lib/libSystem.B.dylib", RTLD_LAZY);
+  if (handle == NULL)
+    return 1;
+
+  int (*strcmp_ptr)(const char *, const char *) = dlsym(handle, "strcmp");
+  if (strcmp_ptr == NULL)
+    return 2;
+  if (strcmp_ptr("abc", "abc") != 0 || strcmp_ptr("abc", "abd") >= 0)
+    return 3;
+  if (dlsym(RTLD_DEFAULT, "strcmp") == NULL)
+    return 4;
+  if (dlsym(RTLD_NEXT, "strcmp") == NULL)
+    return 5;
+
+  if (dlerror() != NULL)
+    return 6;
+  if (dlsym(handle, "not_a_real_symbol") != NULL)
+    return 7;
+  if (dlerror() == NULL)
+    return 8;
+  if (dlerror() != NULL)
+    return 9;
+
+  if (dlopen("/usr/lib/libNotARealLibrary.dylib", RTLD_LAZY) != NULL)
+    return 10;
+  if (dlerror() == NULL)
+    return 11;
+
+  if (dlclose(handle) != 0)
+    return 12;
+  if (dlclose(handle) != -1)
+    return 13;
+  if (dlerror() == NULL)
+    return 14;
+
+  return test_dlfcn_dylib();
+}
+
+// This isn't in test_func_array because it doesn't return: touchHLE should
+// stop emulation when the write happens, since `const` globals are put in
+// __TEXT, which isn't writable. See main() and integration.rs.
+const int const_global = 0x12345678;
+void test_write_to_const_global() {
+  *(volatile int *)&const_global = 0;
+}
+
 #define FUNC_DEF(func)                                                         \
   { &func, #func }
 struct {
@@ -346,6 +786,11 @@ struct {
     FUNC_DEF(test_realloc), FUNC_DEF(test_getcwd_chdir),
     FUNC_DEF(test_sem),     FUNC_DEF(test_CGAffineTransform),
     FUNC_DEF(test_strncpy), FUNC_DEF(test_strncat),
+    FUNC_DEF(test_pthread_cond), FUNC_DEF(test_pthread_cond_broadcast_destroy),
+    FUNC_DEF(test_pthread_mutex_trylock), FUNC_DEF(test_pthread_rwlock),
+    FUNC_DEF(test_pthread_misc), FUNC_DEF(test_dlfcn),
+    FUNC_DEF(test_objc_method_swizzling), FUNC_DEF(test_objc_protocols),
+    FUNC_DEF(test_objc_message_forwarding),
 };
 
 // Because no libc is linked into this executable, there is no libc entry point
@@ -372,5 +817,19 @@ int main() {
   }
 
   printf("Passed %d out of %d tests\n", tests_passed, tests_run);
-  exit(tests_run == tests_passed ? 0 : 1);
+  if (tests_run != tests_passed)
+    exit(1);
+
+  // When input is recorded or replayed, the time depends only on what was
+  // executed, so this lets the integration tests check that a replay matches
+  // its recording.
+  struct timeval tv;
+  gettimeofday(&tv, NULL);
+  printf("Finished at %d.%06d\n", tv.tv_sec, tv.tv_usec);
+
+  // This must come last, as it should end emulation with a ProtectionFault.
+  printf("Writing to a const global...\n");
+  test_write_to_const_global();
+  printf("Write to a const global was not caught!\n");
+  exit(1);
 }
@@ -1,6 +1,7 @@
 use std::env;
 use std::env::current_dir;
 use std::error::Error;
+use std::ffi::{OsStr, OsString};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -26,23 +27,18 @@ fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
         .position(|window| window == needle)
 }
 
-fn build_test_app(tests_dir: &Path, test_app_path: &Path) -> Result<(), Box<dyn Error>> {
-    let clang_path = tests_dir
-        .join("llvm")
-        .join("bin")
-        .join(format!("clang{}", env::consts::EXE_SUFFIX));
-
-    if !clang_path.exists() {
-        panic!(
-            "Couldn't find Clang at {}. Please see {} for more details.",
-            clang_path.display(),
-            tests_dir.join("README.md").display()
-        );
-    }
-
-    let test_bin_path = test_app_path.join("TestApp");
-
-    eprintln!("Building {} for iPhone OS 2...", test_bin_path.display());
+/// Compile and link the C file `source` with Clang for `target` (a Clang
+/// target triple), writing the result to `output_path`. `link_args` is passed
+/// on to the linker.
+fn run_clang(
+    clang_path: &Path,
+    target: &str,
+    source: &Path,
+    extra_args: &[&str],
+    link_args: &str,
+    output_path: &Path,
+) {
+    eprintln!("Building {} for {}...", output_path.display(), target);
 
     let mut cmd = Command::new(clang_path);
 
@@ -54,26 +50,19 @@ fn build_test_app(tests_dir: &Path, test_app_path: &Path) -> Result<(), Box<dyn
         // Telling it not to use newer flags like this seems to avoid this, but
         // I suspect there may be a better fix.
         .arg("-mlinker-version=0")
-        // Target iPhone OS 2
-        .args(["-target", "armv6-apple-ios2"])
+        .args(["-target", target])
         // We don't have a libc to link against, don't try
         .arg("-nostdlib")
         // If enabled, the stack protection causes a null pointer crash in some
         // functions. This is probably because ___stack_chk_guard isn't linked.
         .arg("-fno-stack-protector")
-        // Pass four args to the linker:
-        // `-e _main` sets the mangled C main() function as the entry point
-        // (normally the libc provides an entry point calling main(), but we
-        // have no libc)
-        // `-undefined dynamic_lookup` makes the linker tolerate undefined
-        // references, falling back to dynamic linking instead. This is needed
-        // because we have no system libraries/frameworks for it to link to.
-        .arg("-Wl,-e,_main,-undefined,dynamic_lookup")
+        .args(extra_args)
+        .arg(format!("-Wl,{}", link_args))
         // Input
-        .arg(tests_dir.join("TestApp_source").join("main.c"))
+        .arg(source)
         // Write the output to the bundle.
         .arg("-o")
-        .arg(&test_bin_path)
+        .arg(output_path)
         .output()
         .expect("failed to execute Clang process");
 
@@ -83,18 +72,62 @@ fn build_test_app(tests_dir: &Path, test_app_path: &Path) -> Result<(), Box<dyn
     assert!(output.status.success());
 
     eprintln!("Built successfully.");
-
-    Ok(())
 }
 
-#[test]
-fn run_test_app() -> Result<(), Box<dyn Error>> {
-    let tests_dir = current_dir()?.join("tests");
+/// Build the TestApp binary and the dylib it loads for `target` (a Clang
+/// target triple) and put them in the bundle at `test_app_path`.
+fn build_test_app(
+    tests_dir: &Path,
+    test_app_path: &Path,
+    target: &str,
+) -> Result<(), Box<dyn Error>> {
+    let clang_path = tests_dir
+        .join("llvm")
+        .join("bin")
+        .join(format!("clang{}", env::consts::EXE_SUFFIX));
 
-    let test_app_path = tests_dir.join("TestApp.app");
+    if !clang_path.exists() {
+        panic!(
+            "Couldn't find Clang at {}. Please see {} for more details.",
+            clang_path.display(),
+            tests_dir.join("README.md").display()
+        );
+    }
+
+    let source_dir = tests_dir.join("TestApp_source");
+
+    // `-undefined dynamic_lookup` makes the linker tolerate undefined
+    // references, falling back to dynamic linking instead. This is needed
+    // because we have no system libraries/frameworks for it to link to.
+    run_clang(
+        &clang_path,
+        target,
+        &source_dir.join("TestDylib.c"),
+        &["-dynamiclib"],
+        "-undefined,dynamic_lookup",
+        &test_app_path.join("TestDylib.dylib"),
+    );
 
-    build_test_app(&tests_dir, &test_app_path)?;
+    // `-e _main` sets the mangled C main() function as the entry point
+    // (normally the libc provides an entry point calling main(), but we
+    // have no libc)
+    run_clang(
+        &clang_path,
+        target,
+        &source_dir.join("main.c"),
+        &[],
+        "-e,_main,-undefined,dynamic_lookup",
+        &test_app_path.join("TestApp"),
+    );
 
+    Ok(())
+}
+
+/// Run touchHLE in headless mode with the TestApp bundle at `test_app_path`
+/// and check that all its tests pass. After the tests, the TestApp writes to
+/// read-only memory, so touchHLE should then stop with a protection fault
+/// rather than exiting normally. Returns the TestApp's output.
+fn run_test_app(test_app_path: &Path, extra_args: &[&OsStr]) -> Result<Vec<u8>, Box<dyn Error>> {
     let binary_name = "touchHLE";
     let binary_path = target_dir().join(format!("{}{}", binary_name, env::consts::EXE_SUFFIX));
 
@@ -105,18 +138,86 @@ fn run_test_app() -> Result<(), Box<dyn Error>> {
         // headless mode avoids a distracting window briefly appearing during
         // testing, and works in CI.
         .arg("--headless")
+        .args(extra_args)
         .output()
         .expect("failed to execute touchHLE process");
 
     std::io::stdout().write_all(&output.stdout).unwrap();
     std::io::stderr().write_all(&output.stderr).unwrap();
 
-    assert!(output.status.success());
     // sanity check: check that emulation actually happened
     assert_ne!(
         find_subsequence(output.stderr.as_slice(), b"CPU emulation begins now."),
         None
     );
+    assert_ne!(
+        find_subsequence(output.stdout.as_slice(), b"Writing to a const global..."),
+        None,
+        "TestApp's tests didn't all pass"
+    );
+    assert!(!output.status.success());
+    assert_ne!(
+        find_subsequence(output.stderr.as_slice(), b"ProtectionFault(write access at"),
+        None
+    );
+
+    Ok(output.stdout)
+}
+
+/// Build the TestApp in the bundle called `bundle_name` for `target` (a Clang
+/// target triple) and run it.
+fn build_and_run_test_app(bundle_name: &str, target: &str) -> Result<(), Box<dyn Error>> {
+    let tests_dir = current_dir()?.join("tests");
+
+    let test_app_path = tests_dir.join(bundle_name);
+
+    build_test_app(&tests_dir, &test_app_path, target)?;
+
+    run_test_app(&test_app_path, &[])?;
+
+    Ok(())
+}
+
+#[test]
+fn run_test_app_armv6() -> Result<(), Box<dyn Error>> {
+    // Target iPhone OS 2
+    build_and_run_test_app("TestApp.app", "armv6-apple-ios2")
+}
+
+#[test]
+fn run_test_app_armv7() -> Result<(), Box<dyn Error>> {
+    // Target iPhone OS 3, the first version with ARMv7 devices. This uses a
+    // separate bundle so it can run in parallel with the ARMv6 test.
+    build_and_run_test_app("TestApp_armv7.app", "armv7-apple-ios3")
+}
+
+#[test]
+fn record_and_replay_test_app() -> Result<(), Box<dyn Error>> {
+    // This also uses a separate bundle, so it can run in parallel with the
+    // other tests.
+    let tests_dir = current_dir()?.join("tests");
+    let test_app_path = tests_dir.join("TestApp_replay.app");
+    build_test_app(&tests_dir, &test_app_path, "armv6-apple-ios2")?;
+
+    let recording_path = target_dir().join("TestApp_replay.txt");
+    let mut record_arg = OsString::from("--record-input=");
+    record_arg.push(&recording_path);
+    let mut replay_arg = OsString::from("--replay-input=");
+    replay_arg.push(&recording_path);
+
+    let recorded_output = run_test_app(&test_app_path, &[record_arg.as_os_str()])?;
+    let replayed_output = run_test_app(&test_app_path, &[replay_arg.as_os_str()])?;
+
+    // The output includes the time the tests finished at, which should be
+    // exactly the same, since the replay sees the same virtual clock.
+    assert_ne!(
+        find_subsequence(recorded_output.as_slice(), b"Finished at "),
+        None
+    );
+    assert!(
+        recorded_output == replayed_output,
+        "Replay's output differs from the recording's"
+    );
 
     Ok(())
 }